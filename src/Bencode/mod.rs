@@ -1,2 +0,0 @@
-pub mod decode;
-pub mod encode;
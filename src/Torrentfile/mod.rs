@@ -1,2 +0,0 @@
-pub mod magnet;
-pub mod torrent;
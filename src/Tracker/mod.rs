@@ -1,2 +0,0 @@
-pub mod tracker;
-pub mod udp;
@@ -0,0 +1,237 @@
+//! A client for the control API, used by the `minibit` subcommands that talk to a daemon
+
+use crate::api::error::ApiError;
+use crate::api::{
+    AddTorrent, Added, Endpoint, ErrorBody, FileUpdate, Settings, SettingsUpdate, TorrentUpdate,
+};
+use crate::ipfilter::IpFilterStatus;
+use crate::peers::stats::PeerInfo;
+use crate::session::torrent::{FileInfo, TorrentStatus, TrackerInfo};
+use crate::storage::files::FilePriority;
+use bytes::Bytes;
+use data_encoding::BASE64;
+use http_body_util::{BodyExt, Full};
+use hyper::client::conn::http1;
+use hyper::{Method, Request, header};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub struct ApiClient {
+    endpoint: Endpoint,
+    token: Option<String>,
+}
+
+impl ApiClient {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            token: None,
+        }
+    }
+
+    /// Send `token` with every request, for a daemon that requires one
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub async fn list(&self) -> Result<Vec<TorrentStatus>, ApiError> {
+        self.get("/api/v1/torrents").await
+    }
+
+    /// Add a `.torrent` file's contents; returns the info hash in hex
+    pub async fn add_torrent(
+        &self,
+        metainfo: &[u8],
+        download_dir: Option<PathBuf>,
+    ) -> Result<String, ApiError> {
+        let req = AddTorrent {
+            metainfo: Some(BASE64.encode(metainfo)),
+            download_dir,
+            ..Default::default()
+        };
+        Ok(self
+            .send_json::<Added>(Method::POST, "/api/v1/torrents", &req)
+            .await?
+            .info_hash)
+    }
+
+    pub async fn add_magnet(
+        &self,
+        magnet: &str,
+        download_dir: Option<PathBuf>,
+    ) -> Result<String, ApiError> {
+        let req = AddTorrent {
+            magnet: Some(magnet.to_string()),
+            download_dir,
+            ..Default::default()
+        };
+        Ok(self
+            .send_json::<Added>(Method::POST, "/api/v1/torrents", &req)
+            .await?
+            .info_hash)
+    }
+
+    pub async fn status(&self, info_hash: &str) -> Result<TorrentStatus, ApiError> {
+        self.get(&format!("/api/v1/torrents/{info_hash}")).await
+    }
+
+    pub async fn peers(&self, info_hash: &str) -> Result<Vec<PeerInfo>, ApiError> {
+        self.get(&format!("/api/v1/torrents/{info_hash}/peers"))
+            .await
+    }
+
+    pub async fn trackers(&self, info_hash: &str) -> Result<Vec<TrackerInfo>, ApiError> {
+        self.get(&format!("/api/v1/torrents/{info_hash}/trackers"))
+            .await
+    }
+
+    pub async fn files(&self, info_hash: &str) -> Result<Vec<FileInfo>, ApiError> {
+        self.get(&format!("/api/v1/torrents/{info_hash}/files"))
+            .await
+    }
+
+    pub async fn set_file_priority(
+        &self,
+        info_hash: &str,
+        index: usize,
+        priority: FilePriority,
+    ) -> Result<(), ApiError> {
+        let path = format!("/api/v1/torrents/{info_hash}/files/{index}");
+        let body = serde_json::to_vec(&FileUpdate { priority })?;
+        self.request(Method::PUT, &path, Some(body)).await.map(drop)
+    }
+
+    pub async fn update(&self, info_hash: &str, update: &TorrentUpdate) -> Result<(), ApiError> {
+        let path = format!("/api/v1/torrents/{info_hash}");
+        let body = serde_json::to_vec(update)?;
+        self.request(Method::PUT, &path, Some(body)).await.map(drop)
+    }
+
+    pub async fn pause(&self, info_hash: &str) -> Result<(), ApiError> {
+        let path = format!("/api/v1/torrents/{info_hash}/pause");
+        self.request(Method::POST, &path, None).await.map(drop)
+    }
+
+    pub async fn resume(&self, info_hash: &str) -> Result<(), ApiError> {
+        let path = format!("/api/v1/torrents/{info_hash}/resume");
+        self.request(Method::POST, &path, None).await.map(drop)
+    }
+
+    /// Stop a torrent and forget it; its data stays on disk
+    pub async fn remove(&self, info_hash: &str) -> Result<(), ApiError> {
+        let path = format!("/api/v1/torrents/{info_hash}");
+        self.request(Method::DELETE, &path, None).await.map(drop)
+    }
+
+    pub async fn settings(&self) -> Result<Settings, ApiError> {
+        self.get("/api/v1/settings").await
+    }
+
+    pub async fn update_settings(&self, update: &SettingsUpdate) -> Result<Settings, ApiError> {
+        self.send_json(Method::PUT, "/api/v1/settings", update)
+            .await
+    }
+
+    pub async fn ip_filter(&self) -> Result<IpFilterStatus, ApiError> {
+        self.get("/api/v1/ip-filter").await
+    }
+
+    /// Have the daemon read its filter file again
+    pub async fn reload_ip_filter(&self) -> Result<IpFilterStatus, ApiError> {
+        let body = self
+            .request(Method::POST, "/api/v1/ip-filter/reload", None)
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ApiError> {
+        let body = self.request(Method::GET, path, None).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, ApiError> {
+        let body = self
+            .request(method, path, Some(serde_json::to_vec(body)?))
+            .await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    // One connection per request; the daemon is local so there is little to gain from reuse
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Bytes, ApiError> {
+        let unreachable = |error| ApiError::Unreachable {
+            endpoint: self.endpoint.to_string(),
+            error,
+        };
+        match &self.endpoint {
+            Endpoint::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr)
+                    .await
+                    .map_err(unreachable)?;
+                send(stream, method, path, body, self.token.as_deref()).await
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(socket) => {
+                let stream = tokio::net::UnixStream::connect(socket)
+                    .await
+                    .map_err(unreachable)?;
+                send(stream, method, path, body, self.token.as_deref()).await
+            }
+        }
+    }
+}
+
+async fn send<S>(
+    stream: S,
+    method: Method,
+    path: &str,
+    body: Option<Vec<u8>>,
+    token: Option<&str>,
+) -> Result<Bytes, ApiError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    // The server wants a JSON content type on every POST, even one without a body
+    let json = body.is_some() || method == Method::POST;
+    let mut req = Request::builder()
+        .method(method)
+        .uri(path)
+        .header(header::HOST, "localhost");
+    if json {
+        req = req.header(header::CONTENT_TYPE, "application/json");
+    }
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let req = req
+        .body(Full::new(Bytes::from(body.unwrap_or_default())))
+        .expect("method, path and headers are valid");
+    let resp = sender.send_request(req).await?;
+    let status = resp.status();
+    let body = resp.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        let message = serde_json::from_slice::<ErrorBody>(&body)
+            .map(|e| e.error)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+        return Err(ApiError::Status {
+            status: status.as_u16(),
+            message,
+        });
+    }
+    Ok(body)
+}
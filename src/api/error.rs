@@ -0,0 +1,22 @@
+use std::io;
+use std::net::SocketAddr;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("invalid API endpoint: {0}")]
+    InvalidEndpoint(String),
+    #[error("refusing to serve the API on {0} without a token; use a loopback address or set one")]
+    Exposed(SocketAddr),
+    #[error("cannot reach the daemon at {endpoint}: {error}")]
+    Unreachable { endpoint: String, error: io::Error },
+    /// The daemon answered with an error status
+    #[error("{message} (HTTP {status})")]
+    Status { status: u16, message: String },
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("invalid API response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,381 @@
+//! HTTP+JSON control API for a long-running [`Session`](crate::Session), served on a TCP
+//! address or a Unix socket, and a client for it.
+//!
+//! | Method   | Path                               | Body / result                        |
+//! |----------|------------------------------------|--------------------------------------|
+//! | `GET`    | `/api/v1/torrents`                 | `[TorrentStatus]`                    |
+//! | `POST`   | `/api/v1/torrents`                 | [`AddTorrent`] JSON, or a raw `.torrent` as `application/x-bittorrent` |
+//! | `GET`    | `/api/v1/torrents/{hash}`          | `TorrentStatus`                      |
+//! | `PUT`    | `/api/v1/torrents/{hash}`          | [`TorrentUpdate`]                    |
+//! | `DELETE` | `/api/v1/torrents/{hash}`          | removes the torrent, keeps its data  |
+//! | `POST`   | `/api/v1/torrents/{hash}/pause`    |                                      |
+//! | `POST`   | `/api/v1/torrents/{hash}/resume`   |                                      |
+//! | `GET`    | `/api/v1/torrents/{hash}/peers`    | `[PeerInfo]`                         |
+//! | `GET`    | `/api/v1/torrents/{hash}/trackers` | `[TrackerInfo]`                      |
+//! | `GET`    | `/api/v1/torrents/{hash}/files`    | `[FileInfo]`                         |
+//! | `PUT`    | `/api/v1/torrents/{hash}/files/{index}` | [`FileUpdate`]                  |
+//! | `GET`    | `/api/v1/settings`                 | [`Settings`]                         |
+//! | `PUT`    | `/api/v1/settings`                 | [`SettingsUpdate`], returns `Settings` |
+//! | `GET`    | `/api/v1/ip-filter`                | [`IpFilterStatus`]                   |
+//! | `POST`   | `/api/v1/ip-filter/reload`         | reads the file again, returns `IpFilterStatus` |
+//!
+//! [`IpFilterStatus`]: crate::ipfilter::IpFilterStatus
+//!
+//! Errors come back with a 4xx or 5xx status and `{"error": "..."}`.
+//!
+//! Without a token the server only listens on loopback addresses and Unix sockets, and
+//! refuses requests whose `Host` is neither `localhost` nor an IP address. With one, it may
+//! listen anywhere and every request must carry the token; see [`server::router`].
+//! Either way, requests with an `Origin` other than the server's own are refused, and every
+//! `POST` needs an `application/json` or `application/x-bittorrent` content type, even
+//! without a body, so web pages can't drive the API.
+//!
+//! The same server also answers Transmission RPC calls at `/transmission/rpc`; see
+//! [`transmission`].
+
+pub mod client;
+pub mod error;
+pub mod server;
+pub mod transmission;
+
+use crate::api::error::ApiError;
+use crate::peers::ratelimit::RateLimit;
+use crate::storage::files::FilePriority;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where `minibit daemon` listens unless told otherwise
+pub const DEFAULT_ENDPOINT: &str = "127.0.0.1:9091";
+
+/// A TCP address such as `127.0.0.1:9091`, or a Unix socket given as `unix:/path` or as a
+/// path starting with `/` or `.`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, ApiError> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(path.into()));
+        }
+        #[cfg(unix)]
+        if s.starts_with(['/', '.']) {
+            return Ok(Endpoint::Unix(s.into()));
+        }
+        s.parse()
+            .map(Endpoint::Tcp)
+            .map_err(|_| ApiError::InvalidEndpoint(s.to_string()))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "http://{addr}"),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Body of `POST /api/v1/torrents`; exactly one of `magnet` and `metainfo` is set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddTorrent {
+    pub magnet: Option<String>,
+    /// The `.torrent` file, base64 encoded
+    pub metainfo: Option<String>,
+    /// Defaults to the session's download directory
+    pub download_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Added {
+    pub info_hash: String,
+}
+
+/// Body of `PUT /api/v1/torrents/{hash}`; fields left out keep their value
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TorrentUpdate {
+    /// Fetch pieces in order from the playback position instead of rarest first
+    pub sequential: Option<bool>,
+    /// Move the playback position, which also turns sequential mode on
+    pub seek: Option<Seek>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Seek {
+    pub file: usize,
+    /// Bytes into the file
+    pub offset: u64,
+}
+
+/// Body of `PUT /api/v1/torrents/{hash}/files/{index}`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileUpdate {
+    /// `skip`, `low`, `normal` or `high`
+    pub priority: FilePriority,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub listen_port: u16,
+    pub download_dir: PathBuf,
+    /// Shared by every torrent, in bytes per second
+    pub rate: RateLimit,
+}
+
+/// Body of `PUT /api/v1/settings`; fields left out keep their value
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SettingsUpdate {
+    pub rate: Option<RateLimit>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::client::ApiClient;
+    use super::*;
+    use crate::session::manager::{Session, SessionConfig};
+    use crate::session::torrent::TorrentState;
+    use crate::torrentfile::create::TorrentBuilder;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(
+            "127.0.0.1:9091".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp("127.0.0.1:9091".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/minibit.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix("/run/minibit.sock".into())
+        );
+        assert_eq!(
+            "./minibit.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix("./minibit.sock".into())
+        );
+        assert!("localhost".parse::<Endpoint>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn controls_a_session_over_a_unix_socket() {
+        let dir = std::env::temp_dir().join(format!("rustor-api-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![9u8; 50_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.clone(),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let endpoint = Endpoint::Unix(dir.join("api.sock"));
+        let server = tokio::spawn({
+            let endpoint = endpoint.clone();
+            async move { server::serve(Arc::new(session), &endpoint, None).await }
+        });
+        while !dir.join("api.sock").exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let client = ApiClient::new(endpoint);
+        let info_hash = client
+            .add_torrent(&tf.to_bytes().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(info_hash, hex::encode(tf.info_hash));
+        let err = client.add_torrent(&tf.to_bytes().unwrap(), None).await;
+        assert!(matches!(err, Err(ApiError::Status { status: 409, .. })));
+
+        let mut status = client.status(&info_hash).await.unwrap();
+        for _ in 0..100 {
+            if status.state == TorrentState::Finished {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            status = client.status(&info_hash).await.unwrap();
+        }
+        assert_eq!(status.state, TorrentState::Finished);
+        let files = client.files(&info_hash).await.unwrap();
+        assert_eq!(files[0].path, PathBuf::from("data.bin"));
+        assert_eq!(files[0].done, 50_000);
+
+        let seek = TorrentUpdate {
+            sequential: None,
+            seek: Some(Seek {
+                file: 0,
+                offset: 20_000,
+            }),
+        };
+        client.update(&info_hash, &seek).await.unwrap();
+        assert!(client.status(&info_hash).await.unwrap().sequential);
+        let bad = TorrentUpdate {
+            seek: Some(Seek { file: 1, offset: 0 }),
+            ..seek
+        };
+        let err = client.update(&info_hash, &bad).await;
+        assert!(matches!(err, Err(ApiError::Status { status: 404, .. })));
+
+        client.pause(&info_hash).await.unwrap();
+        assert_eq!(client.list().await.unwrap()[0].state, TorrentState::Paused);
+
+        let limit = RateLimit {
+            download: Some(1 << 20),
+            upload: None,
+        };
+        let settings = client
+            .update_settings(&SettingsUpdate { rate: Some(limit) })
+            .await
+            .unwrap();
+        assert_eq!(settings.rate, limit);
+        assert_eq!(client.settings().await.unwrap().rate, limit);
+
+        client.remove(&info_hash).await.unwrap();
+        assert!(client.list().await.unwrap().is_empty());
+        let err = client.status(&info_hash).await;
+        assert!(matches!(err, Err(ApiError::Status { status: 404, .. })));
+        let err = client.status("not-a-hash").await;
+        assert!(matches!(err, Err(ApiError::Status { status: 400, .. })));
+
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn guards_tcp_access() {
+        let dir = std::env::temp_dir().join(format!("rustor-api-auth-{}", std::process::id()));
+        let session = Arc::new(
+            Session::new(SessionConfig {
+                listen_port: 0,
+                port_mapping: None,
+                download_dir: dir,
+                #[cfg(feature = "dht")]
+                dht: false,
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+        let anywhere = Endpoint::Tcp("0.0.0.0:0".parse().unwrap());
+        let err = server::serve(session.clone(), &anywhere, None).await;
+        assert!(matches!(err, Err(ApiError::Exposed(_))));
+
+        let serve = |token: Option<&str>| {
+            let app = server::router(session.clone(), token.map(str::to_string));
+            async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tokio::spawn(async move { axum::serve(listener, app).await });
+                addr
+            }
+        };
+        let http = reqwest::Client::new();
+        let get = |addr: SocketAddr, host: &str| {
+            http.get(format!("http://{addr}/api/v1/settings"))
+                .header("Host", host)
+        };
+
+        // Without a token, only names that can't be rebound to another address are answered
+        let open = serve(None).await;
+        for host in ["localhost:9091", "127.0.0.1", "[::1]:9091"] {
+            let resp = get(open, host).send().await.unwrap();
+            assert_eq!(resp.status(), 200, "{host}");
+        }
+        let resp = get(open, "attacker.example").send().await.unwrap();
+        assert_eq!(resp.status(), 403);
+
+        let locked = serve(Some("s3cret")).await;
+        let resp = get(locked, "localhost").send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+        assert!(resp.headers().contains_key("www-authenticate"));
+        let resp = get(locked, "localhost")
+            .bearer_auth("wrong")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+        let resp = get(locked, "seedbox.lan")
+            .bearer_auth("s3cret")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        let resp = get(locked, "seedbox.lan")
+            .basic_auth("anyone", Some("s3cret"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+
+        let client = ApiClient::new(Endpoint::Tcp(locked));
+        assert!(matches!(
+            client.settings().await,
+            Err(ApiError::Status { status: 401, .. })
+        ));
+        let client = client.with_token(Some("s3cret".into()));
+        assert_eq!(client.settings().await.unwrap().rate, RateLimit::default());
+
+        // What a page on another site can send without a preflight is refused
+        let magnet =
+            r#"{"magnet": "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567"}"#;
+        let add = |content_type: &str| {
+            http.post(format!("http://{open}/api/v1/torrents"))
+                .header("Content-Type", content_type)
+                .body(magnet)
+        };
+        for content_type in ["text/plain", "application/x-www-form-urlencoded"] {
+            let resp = add(content_type).send().await.unwrap();
+            assert_eq!(resp.status(), 415, "{content_type}");
+        }
+        let resp = http
+            .post(format!("http://{open}/api/v1/ip-filter/reload"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 415);
+        let resp = add("application/json")
+            .header("Origin", "https://attacker.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 403);
+        assert!(session.list().is_empty());
+        let resp = add("application/json")
+            .header("Origin", format!("http://{open}"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 201);
+        let client = ApiClient::new(Endpoint::Tcp(open));
+        assert!(
+            client
+                .reload_ip_filter()
+                .await
+                .is_err_and(|e| matches!(e, ApiError::Status { status, .. } if status != 415))
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! The axum side of the control API
+
+use crate::api::error::ApiError;
+use crate::api::transmission;
+use crate::api::{
+    AddTorrent, Added, Endpoint, ErrorBody, FileUpdate, Settings, SettingsUpdate, TorrentUpdate,
+};
+use crate::error::Error;
+use crate::ipfilter::error::IpFilterError;
+use crate::session::manager::Session;
+use crate::session::torrent::TorrentSource;
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::magnet::parse_magnet_link;
+use crate::torrentfile::torrent::TorrentFile;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::uri::Authority;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use data_encoding::BASE64;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Content type for uploading a `.torrent` file as the raw request body
+pub const BITTORRENT_CONTENT_TYPE: &str = "application/x-bittorrent";
+
+type AppState = Arc<Session>;
+
+// An error status with a JSON body naming the problem
+struct Failure(StatusCode, String);
+
+impl IntoResponse for Failure {
+    fn into_response(self) -> Response {
+        (self.0, Json(ErrorBody { error: self.1 })).into_response()
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        let status = match e {
+            Error::UnknownTorrent(_) | Error::UnknownFile { .. } | Error::UnknownPiece { .. } => {
+                StatusCode::NOT_FOUND
+            }
+            Error::DuplicateTorrent(_) | Error::NoMetadata(_) => StatusCode::CONFLICT,
+            Error::Torrent(_) | Error::Bencode(_) => StatusCode::BAD_REQUEST,
+            Error::IpFilter(IpFilterError::NoFile) => StatusCode::CONFLICT,
+            Error::PathNotAllowed(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Failure(status, e.to_string())
+    }
+}
+
+impl From<TorrentError> for Failure {
+    fn from(e: TorrentError) -> Self {
+        Error::from(e).into()
+    }
+}
+
+fn bad_request(msg: impl Into<String>) -> Failure {
+    Failure(StatusCode::BAD_REQUEST, msg.into())
+}
+
+fn parse_hash(hash: &str) -> Result<[u8; 20], Failure> {
+    let mut info_hash = [0u8; 20];
+    hex::decode_to_slice(hash, &mut info_hash)
+        .map_err(|_| bad_request(format!("invalid info hash: {hash}")))?;
+    Ok(info_hash)
+}
+
+/// Every request must carry `token`, as `Authorization: Bearer <token>` or as the password
+/// of HTTP basic auth, which is what Transmission clients send. Without a token, requests
+/// must name the server by a loopback name or an IP address, so that a web page can't reach
+/// it through DNS rebinding.
+pub fn router(session: Arc<Session>, token: Option<String>) -> Router {
+    Router::new()
+        .route("/api/v1/torrents", get(list).post(add))
+        .route(
+            "/api/v1/torrents/{hash}",
+            get(status).put(update).delete(remove),
+        )
+        .route("/api/v1/torrents/{hash}/pause", post(pause))
+        .route("/api/v1/torrents/{hash}/resume", post(resume))
+        .route("/api/v1/torrents/{hash}/peers", get(peers))
+        .route("/api/v1/torrents/{hash}/trackers", get(trackers))
+        .route("/api/v1/torrents/{hash}/files", get(files))
+        .route("/api/v1/torrents/{hash}/files/{index}", put(update_file))
+        .route("/api/v1/settings", get(settings).put(update_settings))
+        .route("/api/v1/ip-filter", get(ip_filter))
+        .route("/api/v1/ip-filter/reload", post(reload_ip_filter))
+        .route_layer(middleware::from_fn(check_content_type))
+        .with_state(session.clone())
+        .merge(transmission::router(session))
+        .layer(middleware::from_fn_with_state(
+            token.map(Arc::from),
+            check_access,
+        ))
+}
+
+async fn check_access(State(token): State<Option<Arc<str>>>, req: Request, next: Next) -> Response {
+    let headers = req.headers();
+    if let Some(origin) = headers.get(header::ORIGIN)
+        && !same_origin(origin, headers.get(header::HOST))
+    {
+        let msg = "requests from pages on other sites are refused";
+        return Failure(StatusCode::FORBIDDEN, msg.into()).into_response();
+    }
+    match &token {
+        Some(token) if !authorized(headers, token) => {
+            let mut resp = Failure(
+                StatusCode::UNAUTHORIZED,
+                "missing or wrong API token".into(),
+            )
+            .into_response();
+            let challenge = HeaderValue::from_static("Basic realm=\"minibit\"");
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, challenge);
+            return resp;
+        }
+        None if !headers.get(header::HOST).is_none_or(local_host) => {
+            let msg = "the API only answers to localhost or an IP address";
+            return Failure(StatusCode::FORBIDDEN, msg.into()).into_response();
+        }
+        _ => {}
+    }
+    next.run(req).await
+}
+
+// A page on another site can POST to the API without a CORS preflight, but only as a form or
+// plain text. Insisting on JSON or a .torrent keeps those out, as the API never approves a
+// preflight. The Transmission RPC has its session id header for this instead.
+async fn check_content_type(req: Request, next: Next) -> Response {
+    let allowed = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|t| {
+            let t = t.trim();
+            t.eq_ignore_ascii_case("application/json")
+                || t.eq_ignore_ascii_case(BITTORRENT_CONTENT_TYPE)
+        });
+    if req.method() == Method::POST && !allowed {
+        let msg = format!("POST requests must be application/json or {BITTORRENT_CONTENT_TYPE}");
+        return Failure(StatusCode::UNSUPPORTED_MEDIA_TYPE, msg).into_response();
+    }
+    next.run(req).await
+}
+
+// Browsers send `Origin` on cross-site requests; it has to name the server being asked
+fn same_origin(origin: &HeaderValue, host: Option<&HeaderValue>) -> bool {
+    let origin = origin.to_str().ok().and_then(|o| o.parse::<Uri>().ok());
+    match (origin.as_ref().and_then(Uri::authority), host) {
+        (Some(origin), Some(host)) => host
+            .to_str()
+            .is_ok_and(|host| origin.as_str().eq_ignore_ascii_case(host)),
+        _ => false,
+    }
+}
+
+fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(value) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let given = if let Some(bearer) = value.strip_prefix("Bearer ") {
+        bearer.as_bytes().to_vec()
+    } else if let Some(basic) = value.strip_prefix("Basic ") {
+        let Ok(decoded) = BASE64.decode(basic.trim().as_bytes()) else {
+            return false;
+        };
+        match decoded.iter().position(|&b| b == b':') {
+            Some(colon) => decoded[colon + 1..].to_vec(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+    // Compare every byte so the time taken doesn't reveal how much of the token matched
+    given.len() == token.len()
+        && given
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// DNS rebinding needs a name the attacker controls, so IP addresses are fine
+fn local_host(host: &HeaderValue) -> bool {
+    let Some(authority) = host.to_str().ok().and_then(|h| h.parse::<Authority>().ok()) else {
+        return false;
+    };
+    let name = authority.host();
+    name.eq_ignore_ascii_case("localhost")
+        || name
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok()
+}
+
+/// Fails for a non-loopback TCP address without a token, which [`serve`] refuses
+pub fn check_exposure(endpoint: &Endpoint, token: Option<&str>) -> Result<(), ApiError> {
+    match endpoint {
+        Endpoint::Tcp(addr) if !addr.ip().is_loopback() && token.is_none() => {
+            Err(ApiError::Exposed(*addr))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Serve the API until the listener fails. Without a token only loopback addresses and
+/// Unix sockets are allowed, since anyone who reaches the API can add torrents and choose
+/// where they are saved. A stale Unix socket left by an earlier run is replaced; any other
+/// file at that path is an error.
+pub async fn serve(
+    session: Arc<Session>,
+    endpoint: &Endpoint,
+    token: Option<String>,
+) -> Result<(), ApiError> {
+    check_exposure(endpoint, token.as_deref())?;
+    let app = router(session, token);
+    match endpoint {
+        Endpoint::Tcp(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            axum::serve(listener, app).await?;
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = tokio::net::UnixListener::bind(path)?;
+            axum::serve(listener, app).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn list(State(session): State<AppState>) -> impl IntoResponse {
+    Json(session.list())
+}
+
+#[derive(Deserialize)]
+struct AddQuery {
+    download_dir: Option<PathBuf>,
+}
+
+async fn add(
+    State(session): State<AppState>,
+    Query(query): Query<AddQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, Failure> {
+    let raw = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(BITTORRENT_CONTENT_TYPE.as_bytes()));
+    let (source, download_dir) = if raw {
+        let tf = TorrentFile::from_bytes(&body)?;
+        (TorrentSource::File(tf), query.download_dir)
+    } else {
+        let req: AddTorrent =
+            serde_json::from_slice(&body).map_err(|e| bad_request(e.to_string()))?;
+        let source = match (req.magnet, req.metainfo) {
+            (Some(magnet), None) => TorrentSource::Magnet(parse_magnet_link(&magnet)?),
+            (None, Some(metainfo)) => {
+                let bytes = BASE64
+                    .decode(metainfo.as_bytes())
+                    .map_err(|_| bad_request("metainfo is not valid base64"))?;
+                TorrentSource::File(TorrentFile::from_bytes(&bytes)?)
+            }
+            _ => return Err(bad_request("give exactly one of magnet and metainfo")),
+        };
+        (source, req.download_dir.or(query.download_dir))
+    };
+    let download_dir = download_dir
+        .map(|dir| session.allowed_path(&dir))
+        .transpose()?;
+    let info_hash = session.add(source, download_dir)?;
+    let added = Added {
+        info_hash: hex::encode(info_hash),
+    };
+    Ok((StatusCode::CREATED, Json(added)))
+}
+
+async fn status(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<impl IntoResponse, Failure> {
+    Ok(Json(session.status(&parse_hash(&hash)?)?))
+}
+
+async fn update(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+    Json(update): Json<TorrentUpdate>,
+) -> Result<StatusCode, Failure> {
+    let info_hash = parse_hash(&hash)?;
+    if let Some(sequential) = update.sequential {
+        session.set_sequential(&info_hash, sequential)?;
+    }
+    if let Some(seek) = update.seek {
+        session.seek(&info_hash, seek.file, seek.offset)?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, Failure> {
+    session.remove(&parse_hash(&hash)?)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn pause(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, Failure> {
+    session.pause(&parse_hash(&hash)?)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<StatusCode, Failure> {
+    session.resume(&parse_hash(&hash)?)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn peers(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<impl IntoResponse, Failure> {
+    Ok(Json(session.peers(&parse_hash(&hash)?)?))
+}
+
+async fn trackers(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<impl IntoResponse, Failure> {
+    Ok(Json(session.trackers(&parse_hash(&hash)?)?))
+}
+
+async fn files(
+    State(session): State<AppState>,
+    Path(hash): Path<String>,
+) -> Result<impl IntoResponse, Failure> {
+    Ok(Json(session.files(&parse_hash(&hash)?)?))
+}
+
+async fn update_file(
+    State(session): State<AppState>,
+    Path((hash, index)): Path<(String, usize)>,
+    Json(update): Json<FileUpdate>,
+) -> Result<StatusCode, Failure> {
+    session.set_file_priority(&parse_hash(&hash)?, index, update.priority)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn current_settings(session: &Session) -> Settings {
+    Settings {
+        listen_port: session.listen_port(),
+        download_dir: session.download_dir().to_path_buf(),
+        rate: session.rate_limit(),
+    }
+}
+
+async fn settings(State(session): State<AppState>) -> impl IntoResponse {
+    Json(current_settings(&session))
+}
+
+async fn update_settings(
+    State(session): State<AppState>,
+    Json(update): Json<SettingsUpdate>,
+) -> impl IntoResponse {
+    if let Some(rate) = update.rate {
+        session.set_rate_limit(rate);
+    }
+    Json(current_settings(&session))
+}
+
+async fn ip_filter(State(session): State<AppState>) -> impl IntoResponse {
+    Json(session.ip_filter_status())
+}
+
+async fn reload_ip_filter(State(session): State<AppState>) -> Result<impl IntoResponse, Failure> {
+    Ok(Json(session.reload_ip_filter()?))
+}
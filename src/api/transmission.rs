@@ -0,0 +1,640 @@
+//! A subset of the Transmission RPC protocol at `/transmission/rpc`, so front ends and
+//! automation written for Transmission can drive a daemon.
+//!
+//! Supported methods: `session-get`, `blocklist-update`, `torrent-add`, `torrent-get`,
+//! `torrent-set` (file selection, priorities and `sequential_download` only), `torrent-start`,
+//! `torrent-start-now`, `torrent-stop` and `torrent-remove`. Clients first get a 409 carrying
+//! an `X-Transmission-Session-Id` header and repeat the request with it, as with Transmission.
+//! Torrent ids are small integers handed out the first time the RPC sees a torrent.
+//! `torrent-add` only reads .torrent files from, and saves to, the download directory and
+//! the configured `allowed-dirs`; relative paths are taken from the download directory.
+
+use crate::error::Error;
+use crate::session::manager::Session;
+use crate::session::torrent::{FileInfo, TorrentSource, TorrentState, TorrentStatus};
+use crate::storage::files::FilePriority;
+use crate::torrentfile::magnet::parse_magnet_link;
+use crate::torrentfile::torrent::TorrentFile;
+use axum::extract::State;
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use data_encoding::BASE64;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+// Transmission 3.00 speaks RPC version 16; the fields used here have not changed since
+const RPC_VERSION: u32 = 16;
+const RPC_VERSION_MINIMUM: u32 = 14;
+
+// Values of the `status` field
+const STATUS_STOPPED: u8 = 0;
+const STATUS_CHECK: u8 = 2;
+const STATUS_DOWNLOAD: u8 = 4;
+// Values of the `error` field
+const ERROR_NONE: u8 = 0;
+const ERROR_LOCAL: u8 = 3;
+
+struct RpcState {
+    session: Arc<Session>,
+    session_id: String,
+    ids: Mutex<TorrentIds>,
+}
+
+#[derive(Default)]
+struct TorrentIds {
+    by_hash: HashMap<[u8; 20], i64>,
+    next: i64,
+}
+
+impl TorrentIds {
+    fn id(&mut self, info_hash: [u8; 20]) -> i64 {
+        *self.by_hash.entry(info_hash).or_insert_with(|| {
+            self.next += 1;
+            self.next
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+    tag: Option<Value>,
+}
+
+pub fn router(session: Arc<Session>) -> Router {
+    let state = Arc::new(RpcState {
+        session,
+        session_id: hex::encode(rand::random::<[u8; 16]>()),
+        ids: Mutex::default(),
+    });
+    Router::new()
+        .route("/transmission/rpc", post(rpc).get(rpc_get))
+        .with_state(state)
+}
+
+fn conflict(state: &RpcState) -> Response {
+    let mut resp = (
+        StatusCode::CONFLICT,
+        format!("{SESSION_ID_HEADER}: {}", state.session_id),
+    )
+        .into_response();
+    let value = HeaderValue::from_str(&state.session_id).expect("hex is a valid header value");
+    resp.headers_mut().insert(SESSION_ID_HEADER, value);
+    resp
+}
+
+// Clients fetch the session id with a bare request before their first call
+async fn rpc_get(State(state): State<Arc<RpcState>>) -> Response {
+    conflict(&state)
+}
+
+async fn rpc(State(state): State<Arc<RpcState>>, headers: HeaderMap, body: String) -> Response {
+    let session_id = headers.get(SESSION_ID_HEADER).and_then(|v| v.to_str().ok());
+    if session_id != Some(state.session_id.as_str()) {
+        return conflict(&state);
+    }
+    let req: RpcRequest = match serde_json::from_str(&body) {
+        Ok(req) => req,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    let (result, arguments) = match state.call(&req.method, &req.arguments) {
+        Ok(arguments) => ("success".to_string(), arguments),
+        Err(message) => (message, Map::new()),
+    };
+    let mut resp = json!({ "result": result, "arguments": arguments });
+    if let Some(tag) = req.tag {
+        resp["tag"] = tag;
+    }
+    Json(resp).into_response()
+}
+
+type RpcResult = Result<Map<String, Value>, String>;
+
+impl RpcState {
+    fn call(&self, method: &str, args: &Map<String, Value>) -> RpcResult {
+        match method {
+            "session-get" => Ok(self.session_get()),
+            "blocklist-update" => self.blocklist_update(),
+            "torrent-add" => self.torrent_add(args),
+            "torrent-get" => self.torrent_get(args),
+            "torrent-set" => self.torrent_set(args),
+            "torrent-start" | "torrent-start-now" => self.each_torrent(args, Session::resume),
+            "torrent-stop" => self.each_torrent(args, Session::pause),
+            "torrent-remove" => {
+                if args.get("delete-local-data") == Some(&Value::Bool(true)) {
+                    return Err("deleting local data is not supported".to_string());
+                }
+                self.each_torrent(args, Session::remove)
+            }
+            _ => Err("method name not recognized".to_string()),
+        }
+    }
+
+    fn session_get(&self) -> Map<String, Value> {
+        let rate = self.session.rate_limit();
+        // Transmission speed limits are in kB/s
+        let kb = |limit: Option<u64>| limit.map_or(0, |bytes| bytes / 1000);
+        let ip_filter = self.session.ip_filter_status();
+        let Value::Object(map) = json!({
+            "version": concat!("RusTor ", env!("CARGO_PKG_VERSION")),
+            "rpc-version": RPC_VERSION,
+            "rpc-version-minimum": RPC_VERSION_MINIMUM,
+            "session-id": self.session_id,
+            "download-dir": self.session.download_dir(),
+            "peer-port": self.session.listen_port(),
+            "speed-limit-down": kb(rate.download),
+            "speed-limit-down-enabled": rate.download.is_some(),
+            "speed-limit-up": kb(rate.upload),
+            "speed-limit-up-enabled": rate.upload.is_some(),
+            "blocklist-enabled": ip_filter.file.is_some(),
+            "blocklist-size": ip_filter.ranges,
+            "units": {
+                "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+                "speed-bytes": 1000,
+                "size-units": ["kB", "MB", "GB", "TB"],
+                "size-bytes": 1000,
+                "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+                "memory-bytes": 1024,
+            },
+        }) else {
+            unreachable!("a JSON object literal")
+        };
+        map
+    }
+
+    // Transmission downloads its blocklist from a URL; ours is reread from its file
+    fn blocklist_update(&self) -> RpcResult {
+        let status = self.session.reload_ip_filter().map_err(|e| e.to_string())?;
+        let mut map = Map::new();
+        map.insert("blocklist-size".to_string(), json!(status.ranges));
+        Ok(map)
+    }
+
+    fn torrent_add(&self, args: &Map<String, Value>) -> RpcResult {
+        let source = if let Some(metainfo) = args.get("metainfo").and_then(Value::as_str) {
+            let bytes = BASE64
+                .decode(metainfo.as_bytes())
+                .map_err(|_| "invalid or corrupt torrent file".to_string())?;
+            TorrentSource::File(TorrentFile::from_bytes(&bytes).map_err(|e| e.to_string())?)
+        } else if let Some(filename) = args.get("filename").and_then(Value::as_str) {
+            if filename.starts_with("magnet:?") {
+                TorrentSource::Magnet(parse_magnet_link(filename).map_err(|e| e.to_string())?)
+            } else {
+                // A path on the daemon's machine; fetching URLs is not supported
+                let path = self
+                    .session
+                    .allowed_path(filename.as_ref())
+                    .map_err(|e| e.to_string())?;
+                TorrentSource::File(TorrentFile::from_file(path).map_err(|e| e.to_string())?)
+            }
+        } else {
+            return Err("no filename or metainfo specified".to_string());
+        };
+        let download_dir = args
+            .get("download-dir")
+            .and_then(Value::as_str)
+            .map(|dir| self.session.allowed_path(dir.as_ref()))
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let (key, info_hash) = match self.session.add(source, download_dir) {
+            Ok(info_hash) => ("torrent-added", info_hash),
+            Err(Error::DuplicateTorrent(info_hash)) => ("torrent-duplicate", info_hash),
+            Err(e) => return Err(e.to_string()),
+        };
+        if key == "torrent-added" && args.get("paused") == Some(&Value::Bool(true)) {
+            self.session.pause(&info_hash).map_err(|e| e.to_string())?;
+        }
+        let status = self.session.status(&info_hash).map_err(|e| e.to_string())?;
+        let torrent = json!({
+            "id": self.ids.lock().unwrap().id(info_hash),
+            "name": status.name.unwrap_or_else(|| status.info_hash.clone()),
+            "hashString": status.info_hash,
+        });
+        Ok(Map::from_iter([(key.to_string(), torrent)]))
+    }
+
+    fn torrent_get(&self, args: &Map<String, Value>) -> RpcResult {
+        let fields: Vec<&str> = args
+            .get("fields")
+            .and_then(Value::as_array)
+            .ok_or("no fields specified")?
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        let torrents = self
+            .selected(args)?
+            .into_iter()
+            .map(|(id, info_hash, status)| {
+                let fields = fields.iter().filter_map(|&f| {
+                    Some((f.to_string(), self.field(f, id, &info_hash, &status)?))
+                });
+                Value::Object(fields.collect())
+            })
+            .collect();
+        Ok(Map::from_iter([(
+            "torrents".to_string(),
+            Value::Array(torrents),
+        )]))
+    }
+
+    // Wanted and unwanted are applied before priorities, which leave skipped files skipped
+    fn torrent_set(&self, args: &Map<String, Value>) -> RpcResult {
+        let indexes = |key: &str| -> Vec<usize> {
+            let list = args.get(key).and_then(Value::as_array);
+            list.map_or_else(Vec::new, |list| {
+                list.iter()
+                    .filter_map(Value::as_u64)
+                    .map(|i| i as usize)
+                    .collect()
+            })
+        };
+        let changes = [
+            ("files-unwanted", FilePriority::Skip),
+            ("files-wanted", FilePriority::Normal),
+            ("priority-low", FilePriority::Low),
+            ("priority-normal", FilePriority::Normal),
+            ("priority-high", FilePriority::High),
+        ];
+        let sequential = args.get("sequential_download").and_then(Value::as_bool);
+        for (_, info_hash, _) in self.selected(args)? {
+            if let Some(sequential) = sequential {
+                self.session
+                    .set_sequential(&info_hash, sequential)
+                    .map_err(|e| e.to_string())?;
+            }
+            let mut files: Vec<FilePriority> = self
+                .session
+                .files(&info_hash)
+                .map_err(|e| e.to_string())?
+                .iter()
+                .map(|f| f.priority)
+                .collect();
+            let before = files.clone();
+            for (key, priority) in changes {
+                for index in indexes(key) {
+                    let file = files.get_mut(index).ok_or("file index out of range")?;
+                    let skipped = *file == FilePriority::Skip;
+                    *file = match key {
+                        "files-wanted" if !skipped => *file,
+                        "files-wanted" | "files-unwanted" => priority,
+                        _ if skipped => *file,
+                        _ => priority,
+                    };
+                }
+            }
+            for (index, (&old, &new)) in before.iter().zip(&files).enumerate() {
+                if old != new {
+                    self.session
+                        .set_file_priority(&info_hash, index, new)
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        Ok(Map::new())
+    }
+
+    fn each_torrent(
+        &self,
+        args: &Map<String, Value>,
+        action: fn(&Session, &[u8; 20]) -> crate::error::Result<()>,
+    ) -> RpcResult {
+        for (_, info_hash, _) in self.selected(args)? {
+            action(&self.session, &info_hash).map_err(|e| e.to_string())?;
+        }
+        Ok(Map::new())
+    }
+
+    // The torrents named by `ids`: one id or hash string, a list of them, or every torrent
+    // when it is missing or "recently-active". Anything else is an error rather than everything,
+    // since stop and remove act on what this returns.
+    fn selected(
+        &self,
+        args: &Map<String, Value>,
+    ) -> Result<Vec<(i64, [u8; 20], TorrentStatus)>, String> {
+        let wanted: Option<Vec<&Value>> = match args.get("ids") {
+            None => None,
+            Some(Value::String(s)) if s == "recently-active" => None,
+            Some(Value::Array(ids)) => Some(ids.iter().collect()),
+            Some(id) => Some(vec![id]),
+        };
+        let valid = |id: &&Value| match id {
+            Value::Number(n) => n.is_i64(),
+            Value::String(s) => s.len() == 40 && hex::decode(s).is_ok(),
+            _ => false,
+        };
+        if let Some(bad) = wanted.iter().flatten().find(|id| !valid(id)) {
+            return Err(format!("invalid torrent id {bad}"));
+        }
+        let mut ids = self.ids.lock().unwrap();
+        Ok(self
+            .session
+            .list()
+            .into_iter()
+            .filter_map(|status| {
+                let mut info_hash = [0u8; 20];
+                hex::decode_to_slice(&status.info_hash, &mut info_hash).ok()?;
+                let id = ids.id(info_hash);
+                let matches = wanted.as_ref().is_none_or(|wanted| {
+                    wanted.iter().any(|w| match w {
+                        Value::Number(n) => n.as_i64() == Some(id),
+                        Value::String(s) => s.eq_ignore_ascii_case(&status.info_hash),
+                        _ => false,
+                    })
+                });
+                matches.then_some((id, info_hash, status))
+            })
+            .collect())
+    }
+
+    // None for fields this server doesn't know, which are left out like Transmission does
+    fn field(&self, name: &str, id: i64, info_hash: &[u8; 20], s: &TorrentStatus) -> Option<Value> {
+        let total = s.total_size.unwrap_or(0);
+        let percent_done = match s.pieces_total {
+            0 => 0.0,
+            n => s.pieces_done as f64 / n as f64,
+        };
+        let files = || self.session.files(info_hash).unwrap_or_default();
+        let wanted = |f: &&FileInfo| f.priority != FilePriority::Skip;
+        let left = || {
+            let files = files();
+            files
+                .iter()
+                .filter(wanted)
+                .map(|f| f.length - f.done)
+                .sum::<u64>()
+        };
+        let size_when_done = || files().iter().filter(wanted).map(|f| f.length).sum::<u64>();
+        Some(match name {
+            "id" => json!(id),
+            "name" => json!(s.name.clone().unwrap_or_else(|| s.info_hash.clone())),
+            "hashString" => json!(s.info_hash),
+            "status" => json!(match s.state {
+                // Finished torrents don't seed, like a Transmission torrent past its seed ratio
+                TorrentState::Paused | TorrentState::Error | TorrentState::Finished => {
+                    STATUS_STOPPED
+                }
+                TorrentState::Checking => STATUS_CHECK,
+                TorrentState::FetchingMetadata | TorrentState::Downloading => STATUS_DOWNLOAD,
+            }),
+            "error" => json!(if s.error.is_some() {
+                ERROR_LOCAL
+            } else {
+                ERROR_NONE
+            }),
+            "errorString" => json!(s.error.clone().unwrap_or_default()),
+            "percentDone" => json!(percent_done),
+            "metadataPercentComplete" => {
+                json!(if s.state == TorrentState::FetchingMetadata {
+                    0.0
+                } else {
+                    1.0
+                })
+            }
+            "totalSize" => json!(total),
+            "sizeWhenDone" => json!(size_when_done()),
+            "leftUntilDone" => json!(left()),
+            "haveValid" => json!(size_when_done().saturating_sub(left())),
+            "isFinished" => json!(s.state == TorrentState::Finished),
+            "rateDownload" => json!(s.download_rate),
+            "rateUpload" | "uploadedEver" | "uploadRatio" => json!(0),
+            "downloadedEver" => json!(s.downloaded),
+            "eta" => json!(s.eta.map_or(-1, |eta| eta as i64)),
+            "peersConnected" => json!(s.peers),
+            "downloadDir" => json!(s.download_dir),
+            "sequential_download" => json!(s.sequential),
+            "fileStats" => json!(
+                files()
+                    .iter()
+                    .map(|f| json!({
+                        "bytesCompleted": f.done,
+                        "wanted": wanted(&f),
+                        "priority": transmission_priority(f.priority),
+                    }))
+                    .collect::<Vec<_>>()
+            ),
+            "wanted" => json!(files().iter().map(|f| wanted(&f) as u8).collect::<Vec<_>>()),
+            "priorities" => json!(
+                files()
+                    .iter()
+                    .map(|f| transmission_priority(f.priority))
+                    .collect::<Vec<_>>()
+            ),
+            "files" => {
+                let files = files();
+                json!(
+                    files
+                        .iter()
+                        .map(|f| json!({
+                            "name": f.path,
+                            "length": f.length,
+                            "bytesCompleted": f.done,
+                        }))
+                        .collect::<Vec<_>>()
+                )
+            }
+            "trackers" => {
+                let trackers = self.session.trackers(info_hash).unwrap_or_default();
+                json!(
+                    trackers
+                        .iter()
+                        .enumerate()
+                        .map(|(i, t)| json!({ "id": i, "announce": t.url, "tier": i }))
+                        .collect::<Vec<_>>()
+                )
+            }
+            "peers" => {
+                let peers = self.session.peers(info_hash).unwrap_or_default();
+                json!(
+                    peers
+                        .iter()
+                        .map(|p| json!({
+                            "address": p.addr.ip(),
+                            "port": p.addr.port(),
+                            "clientName": p.client.clone().unwrap_or_default(),
+                            "rateToClient": p.download_rate,
+                            "rateToPeer": 0,
+                            "peerIsChoking": p.choked,
+                        }))
+                        .collect::<Vec<_>>()
+                )
+            }
+            _ => return None,
+        })
+    }
+}
+
+// Transmission keeps wanted apart from the priority, which is -1, 0 or 1
+fn transmission_priority(priority: FilePriority) -> i8 {
+    match priority {
+        FilePriority::Skip | FilePriority::Normal => 0,
+        FilePriority::Low => -1,
+        FilePriority::High => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::manager::SessionConfig;
+    use crate::torrentfile::create::TorrentBuilder;
+    use std::time::Duration;
+
+    async fn call(client: &reqwest::Client, url: &str, session_id: &str, body: Value) -> Value {
+        let resp = client
+            .post(url)
+            .header(SESSION_ID_HEADER, session_id)
+            .body(body.to_string())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        serde_json::from_slice(&resp.bytes().await.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn speaks_the_transmission_rpc() {
+        let dir = std::env::temp_dir().join(format!("rustor-transmission-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![5u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.clone(),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/transmission/rpc", listener.local_addr().unwrap());
+        let session = Arc::new(session);
+        let app = router(session.clone());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+
+        // Without the session id the server answers 409 and hands one out
+        let resp = client.post(&url).body("{}").send().await.unwrap();
+        assert_eq!(resp.status(), 409);
+        let session_id = resp.headers()[SESSION_ID_HEADER]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let metainfo = BASE64.encode(&tf.to_bytes().unwrap());
+        let add = json!({
+            "method": "torrent-add",
+            "arguments": { "metainfo": metainfo, "paused": true },
+            "tag": 7,
+        });
+        let resp = call(&client, &url, &session_id, add.clone()).await;
+        assert_eq!(resp["result"], "success");
+        assert_eq!(resp["tag"], 7);
+        let added = &resp["arguments"]["torrent-added"];
+        assert_eq!(added["id"], 1);
+        assert_eq!(added["name"], "data.bin");
+        assert_eq!(added["hashString"], hex::encode(tf.info_hash));
+        let resp = call(&client, &url, &session_id, add).await;
+        assert_eq!(resp["arguments"]["torrent-duplicate"]["id"], 1);
+
+        // Paths outside the download directory are refused
+        for arguments in [
+            json!({ "filename": "/etc/passwd" }),
+            json!({ "filename": "../data.torrent" }),
+            json!({ "metainfo": metainfo, "download-dir": "/etc" }),
+        ] {
+            let add = json!({ "method": "torrent-add", "arguments": arguments });
+            let resp = call(&client, &url, &session_id, add).await;
+            let result = resp["result"].as_str().unwrap();
+            assert!(
+                result.contains("outside the download directory"),
+                "{result}"
+            );
+        }
+
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "ids": [1], "fields": ["id", "status", "isFinished", "percentDone", "files", "bogus"] },
+        });
+        let resp = call(&client, &url, &session_id, get.clone()).await;
+        let torrent = &resp["arguments"]["torrents"][0];
+        assert_eq!(torrent["status"], STATUS_STOPPED);
+        assert_eq!(torrent["isFinished"], false);
+        assert!(torrent.get("bogus").is_none());
+
+        let start = json!({ "method": "torrent-start", "arguments": { "ids": 1 } });
+        assert_eq!(
+            call(&client, &url, &session_id, start).await["result"],
+            "success"
+        );
+        let mut torrent = Value::Null;
+        for _ in 0..100 {
+            let resp = call(&client, &url, &session_id, get.clone()).await;
+            torrent = resp["arguments"]["torrents"][0].clone();
+            if torrent["isFinished"] == true {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(torrent["isFinished"], true);
+        assert_eq!(torrent["status"], STATUS_STOPPED);
+        assert_eq!(torrent["percentDone"], 1.0);
+        assert_eq!(torrent["files"][0]["bytesCompleted"], 40_000);
+
+        let resp = call(
+            &client,
+            &url,
+            &session_id,
+            json!({ "method": "session-get" }),
+        )
+        .await;
+        assert_eq!(resp["arguments"]["rpc-version"], RPC_VERSION);
+        assert_eq!(resp["arguments"]["download-dir"], json!(dir));
+
+        let resp = call(
+            &client,
+            &url,
+            &session_id,
+            json!({ "method": "torrent-frobnicate" }),
+        )
+        .await;
+        assert_eq!(resp["result"], "method name not recognized");
+
+        // A bare hash string names one torrent; anything unparseable selects nothing
+        let hash = hex::encode(tf.info_hash);
+        let get = json!({
+            "method": "torrent-get",
+            "arguments": { "ids": hash.to_uppercase(), "fields": ["id"] },
+        });
+        let resp = call(&client, &url, &session_id, get).await;
+        assert_eq!(resp["arguments"]["torrents"], json!([{ "id": 1 }]));
+        for ids in [json!("typo"), json!([1, "typo"]), json!(1.5), json!({})] {
+            let remove = json!({ "method": "torrent-remove", "arguments": { "ids": ids } });
+            let resp = call(&client, &url, &session_id, remove).await;
+            assert!(
+                resp["result"]
+                    .as_str()
+                    .unwrap()
+                    .contains("invalid torrent id")
+            );
+        }
+        assert_eq!(session.list().len(), 1);
+
+        server.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
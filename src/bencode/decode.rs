@@ -0,0 +1,134 @@
+use crate::bencode::error::BencodeError;
+use serde::de::DeserializeOwned;
+use serde_bencode::{self};
+use std::fs;
+
+pub fn decode_torrent_file<T: DeserializeOwned>(path: &str) -> Result<T, BencodeError> {
+    let content = fs::read(path)?;
+    decode_bencode(&content)
+}
+
+/// Lists and dictionaries nested deeper than this are refused. serde_bencode recurses once
+/// per level, and much of what gets decoded comes from peers.
+pub const MAX_DEPTH: usize = 64;
+
+pub fn decode_bencode<T: DeserializeOwned>(content: &[u8]) -> Result<T, BencodeError> {
+    value_len(content)?;
+    let value: T = serde_bencode::from_bytes(content)?;
+    Ok(value)
+}
+
+/// Length in bytes of the single bencoded value at the start of `buf`
+pub fn value_len(buf: &[u8]) -> Result<usize, BencodeError> {
+    // A loop with a depth count rather than recursion, so the input can't exhaust the stack
+    let mut pos = 0;
+    let mut depth = 0;
+    loop {
+        let rest = &buf[pos..];
+        match rest.first() {
+            Some(b'i') => {
+                let end = rest
+                    .iter()
+                    .position(|&b| b == b'e')
+                    .ok_or(BencodeError::Syntax("unterminated integer"))?;
+                pos += end + 1;
+            }
+            Some(b'l') | Some(b'd') => {
+                depth += 1;
+                if depth > MAX_DEPTH {
+                    return Err(BencodeError::Syntax(
+                        "lists or dictionaries nested too deep",
+                    ));
+                }
+                pos += 1;
+                continue;
+            }
+            Some(b'e') if depth > 0 => {
+                depth -= 1;
+                pos += 1;
+            }
+            Some(b'0'..=b'9') => {
+                let colon = rest
+                    .iter()
+                    .position(|&b| b == b':')
+                    .ok_or(BencodeError::Syntax("missing string length separator"))?;
+                let len: usize = std::str::from_utf8(&rest[..colon])
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or(BencodeError::Syntax("invalid string length"))?;
+                pos = (pos + colon + 1)
+                    .checked_add(len)
+                    .filter(|&end| end <= buf.len())
+                    .ok_or(BencodeError::Syntax("string runs past end of input"))?;
+            }
+            None if depth > 0 => {
+                return Err(BencodeError::Syntax("unterminated list or dictionary"));
+            }
+            _ => return Err(BencodeError::Syntax("invalid bencode value")),
+        }
+        if depth == 0 {
+            return Ok(pos);
+        }
+    }
+}
+
+/// Raw bytes of `key`'s value in the top-level dictionary, e.g. the info dict an info hash covers
+pub fn dict_value<'a>(buf: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, BencodeError> {
+    if buf.first() != Some(&b'd') {
+        return Err(BencodeError::Syntax("expected a bencoded dictionary"));
+    }
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        if pos >= buf.len() {
+            return Err(BencodeError::Syntax("unterminated dictionary"));
+        }
+        let key_len = value_len(&buf[pos..])?;
+        let raw_key = &buf[pos..pos + key_len];
+        let colon = raw_key
+            .iter()
+            .position(|&b| b == b':')
+            .ok_or(BencodeError::Syntax("dictionary key is not a string"))?;
+        pos += key_len;
+        let val_len = value_len(&buf[pos..])?;
+        if &raw_key[colon + 1..] == key {
+            return Ok(Some(&buf[pos..pos + val_len]));
+        }
+        pos += val_len;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_nested_values() {
+        assert_eq!(value_len(b"d3:keyli1e4:spamee").unwrap(), 18);
+        assert_eq!(value_len(b"i42etrailing").unwrap(), 4);
+        assert_eq!(value_len(b"le").unwrap(), 2);
+        assert!(value_len(b"l4:spam").is_err());
+        assert!(value_len(b"e").is_err());
+    }
+
+    #[test]
+    fn refuses_deep_nesting_without_recursing() {
+        let mut deep = vec![b'l'; 1 << 20];
+        assert!(matches!(value_len(&deep), Err(BencodeError::Syntax(_))));
+        deep.extend(vec![b'e'; 1 << 20]);
+        assert!(value_len(&deep).is_err());
+        assert!(decode_bencode::<Vec<u8>>(&deep).is_err());
+
+        let ok = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        assert_eq!(value_len(&ok).unwrap(), ok.len());
+    }
+
+    #[test]
+    fn refuses_string_lengths_past_the_input() {
+        let huge = format!("{}:x", usize::MAX);
+        assert!(value_len(huge.as_bytes()).is_err());
+        let wraps = format!("{}:x", usize::MAX - 1);
+        assert!(value_len(wraps.as_bytes()).is_err());
+        assert!(value_len(b"5:abc").is_err());
+    }
+}
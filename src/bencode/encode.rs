@@ -1,8 +1,8 @@
-use anyhow::Result;
+use crate::bencode::error::BencodeError;
 use serde::Serialize;
 use serde_bencode;
 
-pub fn encode_bencode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+pub fn encode_bencode<T: Serialize>(value: &T) -> Result<Vec<u8>, BencodeError> {
     let encoded = serde_bencode::to_bytes(value)?;
     Ok(encoded)
 }
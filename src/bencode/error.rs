@@ -0,0 +1,14 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BencodeError {
+    /// The bytes are not well-formed bencode
+    #[error("invalid bencode: {0}")]
+    Syntax(&'static str),
+    /// Well-formed bencode that doesn't fit the expected type
+    #[error("bencode: {0}")]
+    Serde(#[from] serde_bencode::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,5 @@
+//! Bencode encoding and decoding.
+
+pub mod decode;
+pub mod encode;
+pub mod error;
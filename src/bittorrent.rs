@@ -0,0 +1,122 @@
+use crate::peers::error::PeerError;
+use crate::torrentfile::error::TorrentError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TorrentInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(rename = "piece length")]
+    pub piece_length: u64,
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>, // Raw concatenated 20-byte SHA-1 hashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl TorrentInfo {
+    pub fn piece_hashes(&self) -> Result<Vec<[u8; 20]>, TorrentError> {
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(TorrentError::Invalid(
+                "pieces length not divisible by 20".into(),
+            ));
+        }
+        let hashes: Vec<[u8; 20]> = self
+            .pieces
+            .chunks_exact(20)
+            .map(|chunk| {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(chunk);
+                hash
+            })
+            .collect();
+        Ok(hashes)
+    }
+
+    pub fn total_length(&self) -> u64 {
+        if let Some(len) = self.length {
+            len
+        } else {
+            self.files
+                .as_ref()
+                .map(|v| v.iter().map(|f| f.length).sum())
+                .unwrap_or(0)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Torrent {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub announce: String,
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>, // Handle announce-list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+    // BEP 19 web seeds; some encoders write a single string instead of a list
+    #[serde(
+        rename = "url-list",
+        default,
+        skip_serializing_if = "Option::is_none",
+        deserialize_with = "deserialize_url_list"
+    )]
+    pub url_list: Option<Vec<String>>,
+    pub info: TorrentInfo,
+}
+
+fn deserialize_url_list<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlList {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Option::<UrlList>::deserialize(deserializer)? {
+        Some(UrlList::One(url)) if url.is_empty() => None,
+        Some(UrlList::One(url)) => Some(vec![url]),
+        Some(UrlList::Many(urls)) => Some(urls),
+        None => None,
+    })
+}
+
+pub async fn connect_to_peer(
+    addr: std::net::SocketAddrV4,
+    connect_timeout: Duration,
+) -> Result<TcpStream, PeerError> {
+    let stream = tokio::time::timeout(connect_timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| PeerError::Timeout("connecting"))??;
+    Ok(stream)
+}
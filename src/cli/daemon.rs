@@ -0,0 +1,313 @@
+//! `minibit daemon` and the subcommands that control it over the HTTP API
+
+use crate::cli::progress::{format_eta, format_rate, percent};
+use anyhow::{Result, anyhow, bail};
+use clap::Subcommand;
+use rustor::Session;
+use rustor::SessionConfig;
+use rustor::api::client::ApiClient;
+use rustor::api::{Endpoint, Seek, SettingsUpdate, TorrentUpdate, server};
+use rustor::peers::ratelimit::RateLimit;
+use rustor::storage::files::FilePriority;
+use rustor::torrentfile::info::format_size;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Subcommand)]
+pub enum RemoteCommand {
+    /// Add a .torrent file or magnet link to a running daemon
+    Add {
+        torrent: String,
+        /// Directory to save the content in, defaults to the daemon's
+        #[arg(short, long)]
+        output_dir: Option<PathBuf>,
+    },
+    /// List the daemon's torrents
+    List {
+        /// Print machine readable JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show a torrent with its peers, trackers and files
+    Status {
+        /// Info hash, or enough of its start to be unique
+        info_hash: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// Pause a torrent on the daemon
+    Pause { info_hash: String },
+    /// Resume a paused torrent on the daemon
+    Resume { info_hash: String },
+    /// Remove a torrent from the daemon; downloaded data stays on disk
+    Remove { info_hash: String },
+    /// Set how files of a torrent are downloaded: skip, low, normal or high
+    Priority {
+        info_hash: String,
+        priority: FilePriority,
+        /// File numbers as `status` lists them
+        #[arg(required = true)]
+        files: Vec<usize>,
+    },
+    /// Fetch a torrent's pieces in order so its files can be played while they download
+    Sequential {
+        info_hash: String,
+        /// Go back to fetching the rarest pieces first
+        #[arg(long, conflicts_with = "seek")]
+        off: bool,
+        /// Continue from byte OFFSET of file number FILE
+        #[arg(long, value_name = "FILE:OFFSET", value_parser = parse_seek)]
+        seek: Option<Seek>,
+    },
+    /// Show the daemon's settings, changing the given ones first
+    Set {
+        /// Download limit in KiB/s, 0 for unlimited
+        #[arg(long)]
+        max_download_rate: Option<u64>,
+        /// Upload limit in KiB/s, 0 for unlimited
+        #[arg(long)]
+        max_upload_rate: Option<u64>,
+    },
+    /// Show the daemon's IP filter, reading its file again first with --reload
+    IpFilter {
+        #[arg(long)]
+        reload: bool,
+    },
+}
+
+/// Serve the API for a new session until interrupted
+pub async fn run_daemon(
+    endpoint: Endpoint,
+    token: Option<String>,
+    config: SessionConfig,
+) -> Result<()> {
+    server::check_exposure(&endpoint, token.as_deref())?;
+    let session = Arc::new(Session::new(config).await?);
+    eprintln!(
+        "minibit daemon listening on {endpoint} (peers on port {})",
+        session.listen_port()
+    );
+    let result = tokio::select! {
+        result = server::serve(session.clone(), &endpoint, token) => result.map_err(Into::into),
+        result = tokio::signal::ctrl_c() => result.map_err(Into::into),
+        result = reload_on_hangup(&session) => result,
+    };
+    session.shutdown().await;
+    #[cfg(unix)]
+    if let Endpoint::Unix(path) = &endpoint {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+// SIGHUP rereads the IP filter, as daemons conventionally reload their lists
+#[cfg(unix)]
+async fn reload_on_hangup(session: &Session) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match session.reload_ip_filter() {
+            Ok(status) => eprintln!("IP filter reloaded, {} ranges", status.ranges),
+            Err(e) => eprintln!("IP filter not reloaded: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(_session: &Session) -> Result<()> {
+    std::future::pending().await
+}
+
+pub async fn run(endpoint: Endpoint, token: Option<String>, command: RemoteCommand) -> Result<()> {
+    let client = ApiClient::new(endpoint).with_token(token);
+    match command {
+        RemoteCommand::Add {
+            torrent,
+            output_dir,
+        } => {
+            // The daemon may run in another directory, so relative paths are resolved here
+            let output_dir = output_dir.map(std::path::absolute).transpose()?;
+            let info_hash = if torrent.starts_with("magnet:?") {
+                client.add_magnet(&torrent, output_dir).await?
+            } else {
+                client
+                    .add_torrent(&std::fs::read(&torrent)?, output_dir)
+                    .await?
+            };
+            println!("Added {info_hash}");
+        }
+        RemoteCommand::List { json } => {
+            let list = client.list().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&list)?);
+                return Ok(());
+            }
+            for s in list {
+                println!(
+                    "{}  {:<17} {:5.1}%  {:>12}  {:>6}  {}",
+                    &s.info_hash[..8],
+                    format!("{:?}", s.state),
+                    percent(&s),
+                    format_rate(s.download_rate),
+                    format_eta(s.eta),
+                    s.name.as_deref().unwrap_or("?"),
+                );
+            }
+        }
+        RemoteCommand::Status { info_hash, json } => {
+            let info_hash = resolve(&client, &info_hash).await?;
+            let status = client.status(&info_hash).await?;
+            let peers = client.peers(&info_hash).await?;
+            let trackers = client.trackers(&info_hash).await?;
+            let files = client.files(&info_hash).await?;
+            if json {
+                let all = serde_json::json!({
+                    "status": status,
+                    "peers": peers,
+                    "trackers": trackers,
+                    "files": files,
+                });
+                println!("{}", serde_json::to_string_pretty(&all)?);
+                return Ok(());
+            }
+            println!("Name:     {}", status.name.as_deref().unwrap_or("?"));
+            println!("Hash:     {}", status.info_hash);
+            match status.sequential {
+                true => println!("State:    {:?}, sequential", status.state),
+                false => println!("State:    {:?}", status.state),
+            }
+            if let Some(error) = &status.error {
+                println!("Error:    {error}");
+            }
+            println!(
+                "Progress: {:.1}% of {} ({}, ETA {})",
+                percent(&status),
+                format_size(status.total_size.unwrap_or(0)),
+                format_rate(status.download_rate),
+                format_eta(status.eta),
+            );
+            println!("Saved in: {}", status.download_dir.display());
+            for t in trackers {
+                let result = match (t.error, t.peers) {
+                    (Some(e), _) => e,
+                    (None, Some(n)) => format!("{n} peers"),
+                    (None, None) => "not contacted".to_string(),
+                };
+                println!("Tracker:  {}  {result}", t.url);
+            }
+            for p in peers {
+                println!(
+                    "Peer:     {}  {}  {}",
+                    p.addr,
+                    p.client.as_deref().unwrap_or("?"),
+                    format_rate(p.download_rate),
+                );
+            }
+            for (i, f) in files.iter().enumerate() {
+                println!(
+                    "{:<10}{}  {} / {}  {}",
+                    format!("File {i}:"),
+                    f.path.display(),
+                    format_size(f.done),
+                    format_size(f.length),
+                    f.priority,
+                );
+            }
+        }
+        RemoteCommand::Pause { info_hash } => {
+            client.pause(&resolve(&client, &info_hash).await?).await?
+        }
+        RemoteCommand::Resume { info_hash } => {
+            client.resume(&resolve(&client, &info_hash).await?).await?
+        }
+        RemoteCommand::Remove { info_hash } => {
+            client.remove(&resolve(&client, &info_hash).await?).await?
+        }
+        RemoteCommand::Priority {
+            info_hash,
+            priority,
+            files,
+        } => {
+            let info_hash = resolve(&client, &info_hash).await?;
+            for index in files {
+                client
+                    .set_file_priority(&info_hash, index, priority)
+                    .await?;
+            }
+        }
+        RemoteCommand::Sequential {
+            info_hash,
+            off,
+            seek,
+        } => {
+            let update = TorrentUpdate {
+                sequential: Some(!off),
+                seek,
+            };
+            client
+                .update(&resolve(&client, &info_hash).await?, &update)
+                .await?;
+        }
+        RemoteCommand::Set {
+            max_download_rate,
+            max_upload_rate,
+        } => {
+            let mut settings = client.settings().await?;
+            if max_download_rate.is_some() || max_upload_rate.is_some() {
+                // 0 lifts the limit; an option left out keeps the current one
+                let kib = |v: u64| (v > 0).then_some(v * 1024);
+                let rate = RateLimit {
+                    download: max_download_rate.map_or(settings.rate.download, kib),
+                    upload: max_upload_rate.map_or(settings.rate.upload, kib),
+                };
+                let update = SettingsUpdate { rate: Some(rate) };
+                settings = client.update_settings(&update).await?;
+            }
+            println!("{}", serde_json::to_string_pretty(&settings)?);
+        }
+        RemoteCommand::IpFilter { reload } => {
+            let status = match reload {
+                true => client.reload_ip_filter().await?,
+                false => client.ip_filter().await?,
+            };
+            match &status.file {
+                Some(file) => println!("File:     {}", file.display()),
+                None => println!("File:     none"),
+            }
+            println!("Ranges:   {}", status.ranges);
+            println!("Blocked:  {}", status.blocked);
+        }
+    }
+    Ok(())
+}
+
+fn parse_seek(s: &str) -> Result<Seek> {
+    let (file, offset) = s
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected FILE:OFFSET"))?;
+    Ok(Seek {
+        file: file.parse()?,
+        offset: offset.parse()?,
+    })
+}
+
+// Accept an unambiguous prefix of an info hash, like git does for commits
+async fn resolve(client: &ApiClient, prefix: &str) -> Result<String> {
+    let prefix = prefix.to_ascii_lowercase();
+    if prefix.len() == 40 {
+        return Ok(prefix);
+    }
+    let matches: Vec<_> = client
+        .list()
+        .await?
+        .into_iter()
+        .filter(|s| s.info_hash.starts_with(&prefix))
+        .collect();
+    match matches.as_slice() {
+        [one] => Ok(one.info_hash.clone()),
+        [] => Err(anyhow!("no torrent matches {prefix}")),
+        _ => bail!("{prefix} matches {} torrents", matches.len()),
+    }
+}
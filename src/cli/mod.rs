@@ -0,0 +1,7 @@
+//! Terminal front ends of the `minibit` binary
+
+#[cfg(feature = "daemon")]
+pub mod daemon;
+pub mod progress;
+#[cfg(feature = "tui")]
+pub mod tui;
@@ -0,0 +1,115 @@
+//! The one-line live progress display of `minibit download --progress`
+
+use rustor::TorrentStatus;
+use rustor::peers::bitfield::Bitfield;
+use rustor::torrentfile::info::format_size;
+use std::io::{IsTerminal, Write};
+
+// Shades for a cell of the piece map, from no pieces to all of them
+const SHADES: [char; 5] = [' ', '░', '▒', '▓', '█'];
+const BAR_WIDTH: usize = 30;
+
+/// Squeeze the piece bitfield into at most `width` cells, each shaded by how many of its
+/// pieces are done
+pub fn piece_map(pieces: &Bitfield, width: usize) -> String {
+    let cells = width.min(pieces.len());
+    (0..cells)
+        .map(|cell| {
+            let (start, end) = (
+                cell * pieces.len() / cells,
+                (cell + 1) * pieces.len() / cells,
+            );
+            let done = (start..end).filter(|&i| pieces.get(i)).count();
+            SHADES[done * (SHADES.len() - 1) / (end - start)]
+        })
+        .collect()
+}
+
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    format!("{}/s", format_size(bytes_per_sec))
+}
+
+pub fn format_eta(secs: Option<u64>) -> String {
+    match secs {
+        None => "--".to_string(),
+        Some(s) if s >= 3600 => format!("{}h{:02}m", s / 3600, s / 60 % 60),
+        Some(s) if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        Some(s) => format!("{s}s"),
+    }
+}
+
+pub fn percent(status: &TorrentStatus) -> f64 {
+    if status.pieces_total == 0 {
+        return 0.0;
+    }
+    status.pieces_done as f64 * 100.0 / status.pieces_total as f64
+}
+
+pub fn progress_line(status: &TorrentStatus, pieces: &Bitfield) -> String {
+    format!(
+        "[{:<width$}] {:5.1}%  {}  ETA {}  {} peers",
+        piece_map(pieces, BAR_WIDTH),
+        percent(status),
+        format_rate(status.download_rate),
+        format_eta(status.eta),
+        status.peers,
+        width = BAR_WIDTH.min(pieces.len()),
+    )
+}
+
+/// Redraws a status line in place on stderr. Does nothing when stderr is not a terminal,
+/// so logs and pipes only get the regular messages.
+pub struct ProgressLine {
+    enabled: bool,
+    drawn: bool,
+}
+
+impl ProgressLine {
+    pub fn new() -> Self {
+        Self {
+            enabled: std::io::stderr().is_terminal(),
+            drawn: false,
+        }
+    }
+
+    pub fn draw(&mut self, line: &str) {
+        if self.enabled {
+            let mut stderr = std::io::stderr();
+            let _ = write!(stderr, "\r\x1b[2K{line}");
+            let _ = stderr.flush();
+            self.drawn = true;
+        }
+    }
+
+    /// Wipe the line so a regular message can be printed in its place
+    pub fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[2K");
+            self.drawn = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piece_map_shades_by_completion() {
+        let mut pieces = Bitfield::new(8);
+        for i in [0, 1, 2, 3, 4] {
+            pieces.set(i);
+        }
+        assert_eq!(piece_map(&pieces, 4), "██▒ ");
+        assert_eq!(piece_map(&pieces, 100).chars().count(), 8);
+        assert_eq!(piece_map(&Bitfield::new(0), 10), "");
+    }
+
+    #[test]
+    fn formats_eta() {
+        assert_eq!(format_eta(None), "--");
+        assert_eq!(format_eta(Some(42)), "42s");
+        assert_eq!(format_eta(Some(125)), "2m05s");
+        assert_eq!(format_eta(Some(3 * 3600 + 7 * 60 + 9)), "3h07m");
+    }
+}
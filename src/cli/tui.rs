@@ -0,0 +1,385 @@
+//! Full-screen terminal UI for `minibit tui`: the torrents of a session with the peers,
+//! trackers and files of the selected one
+
+use crate::cli::progress::{format_eta, format_rate, percent, piece_map};
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event as TermEvent, KeyCode, KeyEvent, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Tabs};
+use ratatui::{DefaultTerminal, Frame};
+use rustor::Session;
+use rustor::storage::files::FilePriority;
+use rustor::torrentfile::info::format_size;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const REFRESH: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tab {
+    Peers,
+    Trackers,
+    Files,
+}
+
+impl Tab {
+    const ALL: [Tab; 3] = [Tab::Peers, Tab::Trackers, Tab::Files];
+
+    fn title(self) -> &'static str {
+        match self {
+            Tab::Peers => "Peers",
+            Tab::Trackers => "Trackers",
+            Tab::Files => "Files",
+        }
+    }
+
+    fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+struct App {
+    torrents: TableState,
+    // The selected file of the selected torrent, in the Files tab
+    files: TableState,
+    tab: Tab,
+    // The outcome of the last key press, shown in the footer
+    message: Option<String>,
+}
+
+/// Run the UI until the user quits. The terminal is restored even if drawing fails.
+pub async fn run(session: &Session) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = run_app(&mut terminal, session).await;
+    ratatui::restore();
+    result
+}
+
+async fn run_app(terminal: &mut DefaultTerminal, session: &Session) -> Result<()> {
+    let mut keys = spawn_key_reader();
+    let mut app = App {
+        torrents: TableState::default().with_selected(0),
+        files: TableState::default().with_selected(0),
+        tab: Tab::Peers,
+        message: None,
+    };
+    let mut refresh = tokio::time::interval(REFRESH);
+    loop {
+        terminal.draw(|frame| app.draw(frame, session))?;
+        tokio::select! {
+            _ = refresh.tick() => {}
+            key = keys.recv() => match key {
+                Some(key) => {
+                    if !app.handle_key(key, session) {
+                        return Ok(());
+                    }
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+// crossterm's reads block, so they get a thread of their own that stops once the UI is gone
+fn spawn_key_reader() -> mpsc::UnboundedReceiver<KeyEvent> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        while !tx.is_closed() {
+            if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+                continue;
+            }
+            match event::read() {
+                Ok(TermEvent::Key(key)) if key.kind == KeyEventKind::Press => {
+                    if tx.send(key).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+impl App {
+    fn selected(&self, session: &Session) -> Option<[u8; 20]> {
+        let list = session.list();
+        let status = list.get(self.torrents.selected()?)?;
+        let mut info_hash = [0u8; 20];
+        hex::decode_to_slice(&status.info_hash, &mut info_hash).ok()?;
+        Some(info_hash)
+    }
+
+    /// False when the user asked to quit
+    fn handle_key(&mut self, key: KeyEvent, session: &Session) -> bool {
+        let count = session.list().len();
+        self.message = None;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Down | KeyCode::Char('j') if count > 0 => {
+                let next = self
+                    .torrents
+                    .selected()
+                    .map_or(0, |i| (i + 1).min(count - 1));
+                self.torrents.select(Some(next));
+            }
+            KeyCode::Up | KeyCode::Char('k') => {
+                let prev = self.torrents.selected().map_or(0, |i| i.saturating_sub(1));
+                self.torrents.select(Some(prev));
+            }
+            KeyCode::Tab => self.tab = self.tab.next(),
+            KeyCode::Char('p') => self.act(session, "Paused", Session::pause),
+            KeyCode::Char('r') => self.act(session, "Resumed", Session::resume),
+            KeyCode::Char('s') => self.toggle_sequential(session),
+            KeyCode::Char(']') if self.tab == Tab::Files => {
+                let next = self.files.selected().map_or(0, |i| i + 1);
+                self.files.select(Some(next));
+            }
+            KeyCode::Char('[') if self.tab == Tab::Files => {
+                let prev = self.files.selected().map_or(0, |i| i.saturating_sub(1));
+                self.files.select(Some(prev));
+            }
+            KeyCode::Char('+') if self.tab == Tab::Files => self.change_priority(session, true),
+            KeyCode::Char('-') if self.tab == Tab::Files => self.change_priority(session, false),
+            _ => {}
+        }
+        true
+    }
+
+    // One step up or down skip, low, normal, high for the selected file
+    fn change_priority(&mut self, session: &Session, raise: bool) {
+        const ORDER: [FilePriority; 4] = [
+            FilePriority::Skip,
+            FilePriority::Low,
+            FilePriority::Normal,
+            FilePriority::High,
+        ];
+        let Some(info_hash) = self.selected(session) else {
+            return;
+        };
+        let files = session.files(&info_hash).unwrap_or_default();
+        let Some((index, file)) = self.files.selected().and_then(|i| Some((i, files.get(i)?)))
+        else {
+            return;
+        };
+        let step = ORDER.iter().position(|&p| p == file.priority).unwrap_or(2);
+        let step = match raise {
+            true => (step + 1).min(ORDER.len() - 1),
+            false => step.saturating_sub(1),
+        };
+        let priority = ORDER[step];
+        self.message = Some(
+            match session.set_file_priority(&info_hash, index, priority) {
+                Ok(()) => format!("{} set to {priority}", file.path.display()),
+                Err(e) => e.to_string(),
+            },
+        );
+    }
+
+    fn toggle_sequential(&mut self, session: &Session) {
+        let Some(info_hash) = self.selected(session) else {
+            return;
+        };
+        let sequential = !session.status(&info_hash).is_ok_and(|s| s.sequential);
+        self.message = Some(match session.set_sequential(&info_hash, sequential) {
+            Ok(()) if sequential => "Fetching pieces in order".to_string(),
+            Ok(()) => "Fetching rarest pieces first".to_string(),
+            Err(e) => e.to_string(),
+        });
+    }
+
+    fn act(
+        &mut self,
+        session: &Session,
+        done: &str,
+        action: fn(&Session, &[u8; 20]) -> rustor::Result<()>,
+    ) {
+        let Some(info_hash) = self.selected(session) else {
+            return;
+        };
+        self.message = Some(match action(session, &info_hash) {
+            Ok(()) => done.to_string(),
+            Err(e) => e.to_string(),
+        });
+    }
+
+    fn draw(&mut self, frame: &mut Frame, session: &Session) {
+        let [list_area, map_area, detail_area, help_area] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Length(3),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let list = session.list();
+        if self.torrents.selected().is_none_or(|i| i >= list.len()) && !list.is_empty() {
+            self.torrents.select(Some(list.len() - 1));
+        }
+        let rows = list.iter().map(|s| {
+            Row::new([
+                s.name.clone().unwrap_or_else(|| s.info_hash.clone()),
+                match s.sequential {
+                    true => format!("{:?} (seq)", s.state),
+                    false => format!("{:?}", s.state),
+                },
+                format!("{:.1}%", percent(s)),
+                s.total_size.map_or_else(String::new, format_size),
+                format_rate(s.download_rate),
+                format_eta(s.eta),
+                s.peers.to_string(),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(22),
+                Constraint::Length(7),
+                Constraint::Length(11),
+                Constraint::Length(13),
+                Constraint::Length(7),
+                Constraint::Length(5),
+            ],
+        )
+        .header(
+            Row::new(["Name", "State", "Done", "Size", "Down", "ETA", "Peers"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .row_highlight_style(Style::new().reversed())
+        .block(Block::bordered().title(" Torrents "));
+        frame.render_stateful_widget(table, list_area, &mut self.torrents);
+
+        let selected = self.selected(session);
+        let map = selected
+            .and_then(|h| session.pieces(&h).ok())
+            .map(|pieces| piece_map(&pieces, map_area.width.saturating_sub(2) as usize))
+            .unwrap_or_default();
+        frame.render_widget(
+            Paragraph::new(map).block(Block::bordered().title(" Pieces ")),
+            map_area,
+        );
+
+        self.draw_details(frame, detail_area, session, selected);
+
+        let help = match self.tab {
+            Tab::Files => {
+                "↑/↓ select  tab switch view  [/] file  +/- priority  s sequential  p pause  r resume  q quit"
+            }
+            _ => "↑/↓ select  tab switch view  s sequential  p pause  r resume  q quit",
+        };
+        let footer = match &self.message {
+            Some(message) => format!("{message}  |  {help}"),
+            None => help.to_string(),
+        };
+        frame.render_widget(Line::from(footer).dim(), help_area);
+    }
+
+    fn draw_details(
+        &mut self,
+        frame: &mut Frame,
+        area: Rect,
+        session: &Session,
+        selected: Option<[u8; 20]>,
+    ) {
+        let [tabs_area, body_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(area);
+        let tabs = Tabs::new(Tab::ALL.map(Tab::title))
+            .select(self.tab as usize)
+            .highlight_style(Style::new().bold().reversed());
+        frame.render_widget(tabs, tabs_area);
+
+        let block = Block::bordered();
+        let Some(info_hash) = selected else {
+            frame.render_widget(Paragraph::new("No torrents").block(block), body_area);
+            return;
+        };
+        let bold = Style::new().add_modifier(Modifier::BOLD);
+        let table = match self.tab {
+            Tab::Peers => {
+                let peers = session.peers(&info_hash).unwrap_or_default();
+                Table::new(
+                    peers.iter().map(|p| {
+                        Row::new([
+                            p.addr.to_string(),
+                            p.client.clone().unwrap_or_default(),
+                            p.pieces.to_string(),
+                            if p.choked { "yes" } else { "no" }.to_string(),
+                            format_size(p.downloaded),
+                            format_rate(p.download_rate),
+                        ])
+                    }),
+                    [
+                        Constraint::Length(22),
+                        Constraint::Fill(1),
+                        Constraint::Length(7),
+                        Constraint::Length(7),
+                        Constraint::Length(11),
+                        Constraint::Length(13),
+                    ],
+                )
+                .header(
+                    Row::new(["Address", "Client", "Pieces", "Choked", "Received", "Down"])
+                        .style(bold),
+                )
+            }
+            Tab::Trackers => {
+                let trackers = session.trackers(&info_hash).unwrap_or_default();
+                Table::new(
+                    trackers.iter().map(|t| {
+                        let status = match (&t.error, t.peers) {
+                            (Some(e), _) => e.clone(),
+                            (None, Some(_)) => t.warning.clone().unwrap_or("ok".into()),
+                            (None, None) => "not contacted".to_string(),
+                        };
+                        Row::new([
+                            t.url.clone(),
+                            t.peers.map_or_else(String::new, |n| n.to_string()),
+                            status,
+                        ])
+                    }),
+                    [
+                        Constraint::Percentage(50),
+                        Constraint::Length(6),
+                        Constraint::Fill(1),
+                    ],
+                )
+                .header(Row::new(["URL", "Peers", "Status"]).style(bold))
+            }
+            Tab::Files => {
+                let files = session.files(&info_hash).unwrap_or_default();
+                if self.files.selected().is_none_or(|i| i >= files.len()) && !files.is_empty() {
+                    self.files.select(Some(files.len() - 1));
+                }
+                let table = Table::new(
+                    files.iter().map(|f| {
+                        let done = match f.length {
+                            0 => 100.0,
+                            len => f.done as f64 * 100.0 / len as f64,
+                        };
+                        Row::new([
+                            f.path.display().to_string(),
+                            format_size(f.length),
+                            format!("{done:.1}%"),
+                            f.priority.to_string(),
+                        ])
+                    }),
+                    [
+                        Constraint::Fill(1),
+                        Constraint::Length(11),
+                        Constraint::Length(7),
+                        Constraint::Length(8),
+                    ],
+                )
+                .header(Row::new(["Path", "Size", "Done", "Priority"]).style(bold))
+                .row_highlight_style(Style::new().reversed());
+                frame.render_stateful_widget(table.block(block), body_area, &mut self.files);
+                return;
+            }
+        };
+        frame.render_widget(table.block(block), body_area);
+    }
+}
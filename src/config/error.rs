@@ -0,0 +1,17 @@
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("cannot read config file {}: {error}", path.display())]
+    Io { path: PathBuf, error: io::Error },
+    #[error("invalid config file {}: {error}", path.display())]
+    Parse {
+        path: PathBuf,
+        error: Box<toml::de::Error>,
+    },
+    /// A value that parsed but can't be used
+    #[error("invalid {key}: {reason}")]
+    Invalid { key: &'static str, reason: String },
+}
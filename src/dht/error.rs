@@ -0,0 +1,20 @@
+use crate::bencode::error::BencodeError;
+use std::io;
+use std::net::SocketAddrV4;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    #[error("DHT node {0} did not answer")]
+    Timeout(SocketAddrV4),
+    /// A KRPC error message from the remote node
+    #[error("DHT error {code}: {message}")]
+    Remote { code: i64, message: String },
+    /// A well-formed message that breaks KRPC rules, e.g. a bad token or a short node id
+    #[error("KRPC protocol error: {0}")]
+    Protocol(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
@@ -0,0 +1,172 @@
+//! KRPC, the bencoded query/response protocol DHT nodes speak over UDP (BEP 5)
+
+use crate::bencode::decode::decode_bencode;
+use crate::bencode::encode::encode_bencode;
+use crate::dht::error::DhtError;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Args {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KrpcMessage {
+    pub t: ByteBuf,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Args>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<(i64, String)>,
+}
+
+impl KrpcMessage {
+    pub fn query(t: &[u8], q: &str, a: Args) -> Self {
+        Self {
+            t: ByteBuf::from(t),
+            y: "q".into(),
+            q: Some(q.into()),
+            a: Some(a),
+            r: None,
+            e: None,
+        }
+    }
+
+    pub fn response(t: ByteBuf, r: Response) -> Self {
+        Self {
+            t,
+            y: "r".into(),
+            q: None,
+            a: None,
+            r: Some(r),
+            e: None,
+        }
+    }
+
+    pub fn error(t: ByteBuf, code: i64, msg: &str) -> Self {
+        Self {
+            t,
+            y: "e".into(),
+            q: None,
+            a: None,
+            r: None,
+            e: Some((code, msg.into())),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DhtError> {
+        Ok(encode_bencode(self)?)
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, DhtError> {
+        Ok(decode_bencode(buf)?)
+    }
+}
+
+pub fn node_id(buf: &[u8]) -> Result<NodeId, DhtError> {
+    buf.try_into()
+        .map_err(|_| DhtError::Protocol(format!("node id of {} bytes", buf.len())))
+}
+
+/// 6 bytes per peer: IPv4 address and port, both big-endian
+pub fn encode_peer(addr: SocketAddrV4) -> ByteBuf {
+    let mut out = addr.ip().octets().to_vec();
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    ByteBuf::from(out)
+}
+
+pub fn decode_peer(buf: &[u8]) -> Option<SocketAddrV4> {
+    let b: [u8; 6] = buf.try_into().ok()?;
+    Some(SocketAddrV4::new(
+        Ipv4Addr::new(b[0], b[1], b[2], b[3]),
+        u16::from_be_bytes([b[4], b[5]]),
+    ))
+}
+
+/// 26 bytes per node: id followed by its compact address
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddrV4)]) -> ByteBuf {
+    let mut out = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&encode_peer(*addr));
+    }
+    ByteBuf::from(out)
+}
+
+pub fn decode_nodes(buf: &[u8]) -> Vec<(NodeId, SocketAddrV4)> {
+    buf.chunks_exact(26)
+        .filter_map(|c| Some((node_id(&c[..20]).ok()?, decode_peer(&c[20..])?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_bep5_example() {
+        let ping = KrpcMessage::query(
+            b"aa",
+            "ping",
+            Args {
+                id: ByteBuf::from(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            ping.to_bytes().unwrap(),
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe"
+        );
+        let reply = KrpcMessage::from_bytes(
+            b"d1:rd2:id20:mnopqrstuvwxyz1234565:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        let r = reply.r.unwrap();
+        assert_eq!(r.token.unwrap().as_ref(), b"aoeusnth");
+        assert_eq!(
+            decode_peer(&r.values.unwrap()[0]),
+            Some("97.120.106.101:11893".parse().unwrap())
+        );
+        let err = KrpcMessage::from_bytes(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee")
+            .unwrap();
+        assert_eq!(err.e.unwrap().0, 201);
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let nodes = vec![
+            ([1; 20], "1.2.3.4:5".parse().unwrap()),
+            ([2; 20], "10.0.0.1:6881".parse().unwrap()),
+        ];
+        assert_eq!(decode_nodes(&encode_nodes(&nodes)), nodes);
+    }
+}
@@ -0,0 +1,6 @@
+//! A Mainline DHT node (BEP 5) for finding peers without trackers.
+
+pub mod error;
+pub mod krpc;
+pub mod node;
+pub mod routing;
@@ -0,0 +1,435 @@
+//! A mainline DHT node (BEP 5): answers queries from other nodes and finds peers for torrents
+
+use crate::dht::error::DhtError;
+use crate::dht::krpc::{
+    Args, KrpcMessage, NodeId, Response, decode_nodes, decode_peer, encode_nodes, encode_peer,
+    node_id,
+};
+use crate::dht::routing::{K, RoutingTable, distance};
+use crate::proxy::DatagramSocket;
+use futures::future::join_all;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, timeout};
+
+pub const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// Lookups query this many nodes at a time
+const ALPHA: usize = 3;
+const MAX_LOOKUP_ROUNDS: usize = 16;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 200;
+
+type Reply = Result<(NodeId, Response), DhtError>;
+
+struct Secrets {
+    current: [u8; 16],
+    previous: [u8; 16],
+    rotated: Instant,
+}
+
+type AnnouncedPeers = HashMap<[u8; 20], Vec<(SocketAddrV4, Instant)>>;
+
+/// Datagrams that arrived on the node's port but aren't KRPC, such as uTP packets
+pub type Forwarded = mpsc::Receiver<(Vec<u8>, SocketAddr)>;
+
+pub struct Dht {
+    socket: Arc<DatagramSocket>,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<[u8; 2], oneshot::Sender<Reply>>>,
+    next_tid: AtomicU16,
+    secrets: Mutex<Secrets>,
+    // Peers other nodes announced to us
+    peers: Mutex<AnnouncedPeers>,
+}
+
+impl Dht {
+    /// Bind the node's UDP socket and start answering queries
+    pub async fn bind(port: u16) -> Result<Arc<Self>, DhtError> {
+        Ok(Self::bind_shared(port).await?.0)
+    }
+
+    /// Like `bind`, but hand over everything on the port that isn't for the DHT, so another
+    /// protocol can share it through [`Dht::socket`]
+    pub async fn bind_shared(port: u16) -> Result<(Arc<Self>, Forwarded), DhtError> {
+        Ok(Self::with_socket(
+            DatagramSocket::bind(("0.0.0.0", port)).await?,
+        ))
+    }
+
+    /// Run on a socket bound elsewhere, such as one relaying through a SOCKS5 proxy
+    pub fn with_socket(socket: DatagramSocket) -> (Arc<Self>, Forwarded) {
+        let socket = Arc::new(socket);
+        let (forward, forwarded) = mpsc::channel(256);
+        let id: NodeId = rand::random();
+        let dht = Arc::new(Self {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_tid: AtomicU16::new(rand::random()),
+            secrets: Mutex::new(Secrets {
+                current: rand::random(),
+                previous: rand::random(),
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::new(HashMap::new()),
+        });
+        // The receive loop only holds a weak reference so dropping the node stops it
+        let weak = Arc::downgrade(&dht);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let Some(dht) = weak.upgrade() else { break };
+                // Socket errors are ICMP replies from unreachable nodes; their queries time out
+                let received =
+                    timeout(Duration::from_secs(1), dht.socket.recv_from(&mut buf)).await;
+                match received {
+                    // KRPC messages are bencoded dictionaries
+                    Ok(Ok((len, SocketAddr::V4(from)))) if buf[..len].starts_with(b"d") => {
+                        dht.handle_packet(&buf[..len], from).await
+                    }
+                    // Dropped when nobody is reading, or can't keep up
+                    Ok(Ok((len, from))) => {
+                        let _ = forward.try_send((buf[..len].to_vec(), from));
+                    }
+                    _ => {}
+                }
+            }
+        });
+        (dht, forwarded)
+    }
+
+    pub fn socket(&self) -> &Arc<DatagramSocket> {
+        &self.socket
+    }
+
+    pub fn port(&self) -> u16 {
+        self.socket.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    /// Join the network through well-known routers and fill the routing table around our id.
+    /// Routers that do not resolve are skipped. Returns how many nodes the table holds.
+    pub async fn bootstrap(&self, routers: &[String]) -> usize {
+        for router in routers {
+            let Ok(addrs) = tokio::net::lookup_host(router.as_str()).await else {
+                continue;
+            };
+            for addr in addrs {
+                if let SocketAddr::V4(addr) = addr {
+                    let _ = self.ping(addr).await;
+                }
+            }
+        }
+        self.lookup(self.id, false).await;
+        self.node_count()
+    }
+
+    /// Check a node is alive and add it to the routing table
+    pub async fn ping(&self, addr: SocketAddrV4) -> Result<NodeId, DhtError> {
+        let (id, _) = self.query(addr, "ping", self.args()).await?;
+        Ok(id)
+    }
+
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddrV4> {
+        self.lookup(info_hash, true).await.0
+    }
+
+    /// Find peers and tell the nodes closest to the torrent that we have it on `port`
+    pub async fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<SocketAddrV4> {
+        let (peers, closest) = self.lookup(info_hash, true).await;
+        let announces = closest.into_iter().filter_map(|(addr, token)| {
+            let args = Args {
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port),
+                token: Some(token?),
+                ..self.args()
+            };
+            Some(async move { self.query(addr, "announce_peer", args).await })
+        });
+        join_all(announces).await;
+        peers
+    }
+
+    fn args(&self) -> Args {
+        Args {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        }
+    }
+
+    async fn query(&self, addr: SocketAddrV4, q: &str, args: Args) -> Reply {
+        let tid = self.next_tid.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(tid, tx);
+        let packet = KrpcMessage::query(&tid, q, args).to_bytes()?;
+        let result = async {
+            self.socket.send_to(&packet, addr).await?;
+            timeout(QUERY_TIMEOUT, rx)
+                .await
+                .map_err(|_| DhtError::Timeout(addr))?
+                .map_err(|_| DhtError::Timeout(addr))?
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&tid);
+        if result.is_err() {
+            self.table.lock().unwrap().remove(addr);
+        }
+        result
+    }
+
+    // Iterative Kademlia lookup towards `target`. Returns any peers found and the closest
+    // nodes that answered, with the tokens they handed out for announcing.
+    async fn lookup(
+        &self,
+        target: [u8; 20],
+        want_peers: bool,
+    ) -> (Vec<SocketAddrV4>, Vec<(SocketAddrV4, Option<ByteBuf>)>) {
+        let mut shortlist: BTreeMap<NodeId, SocketAddrV4> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|(id, addr)| (distance(&id, &target), addr))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered: BTreeMap<NodeId, (SocketAddrV4, Option<ByteBuf>)> = BTreeMap::new();
+        let mut peers = Vec::new();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            let batch: Vec<SocketAddrV4> = shortlist
+                .values()
+                .take(K)
+                .filter(|addr| !queried.contains(*addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            let queries = batch.iter().map(|&addr| {
+                queried.insert(addr);
+                let mut args = self.args();
+                if want_peers {
+                    args.info_hash = Some(ByteBuf::from(target.to_vec()));
+                } else {
+                    args.target = Some(ByteBuf::from(target.to_vec()));
+                }
+                let q = if want_peers { "get_peers" } else { "find_node" };
+                async move { (addr, self.query(addr, q, args).await) }
+            });
+            for (addr, reply) in join_all(queries).await {
+                let Ok((id, r)) = reply else {
+                    shortlist.retain(|_, a| *a != addr);
+                    continue;
+                };
+                answered.insert(distance(&id, &target), (addr, r.token));
+                for (node, node_addr) in decode_nodes(bytes(&r.nodes)) {
+                    if node != self.id {
+                        shortlist.insert(distance(&node, &target), node_addr);
+                    }
+                }
+                for value in r.values.unwrap_or_default() {
+                    if let Some(peer) = decode_peer(&value)
+                        && !peers.contains(&peer)
+                    {
+                        peers.push(peer);
+                    }
+                }
+            }
+        }
+        (peers, answered.into_values().take(K).collect())
+    }
+
+    async fn handle_packet(&self, buf: &[u8], from: SocketAddrV4) {
+        let Ok(msg) = KrpcMessage::from_bytes(buf) else {
+            return;
+        };
+        match msg.y.as_str() {
+            "r" | "e" => {
+                let Ok(tid) = <[u8; 2]>::try_from(msg.t.as_ref()) else {
+                    return;
+                };
+                let Some(tx) = self.pending.lock().unwrap().remove(&tid) else {
+                    return;
+                };
+                let reply = match (msg.r, msg.e) {
+                    (Some(r), _) => node_id(&r.id).map(|id| (id, r)),
+                    (_, Some((code, message))) => Err(DhtError::Remote { code, message }),
+                    _ => Err(protocol("reply without a result or an error")),
+                };
+                if let Ok((id, _)) = &reply {
+                    self.table.lock().unwrap().insert(*id, from);
+                }
+                let _ = tx.send(reply);
+            }
+            "q" => {
+                let reply = match self.answer(&msg, from) {
+                    Ok(r) => KrpcMessage::response(msg.t, r),
+                    Err(e) => KrpcMessage::error(msg.t, 203, &e.to_string()),
+                };
+                if let Ok(packet) = reply.to_bytes() {
+                    let _ = self.socket.send_to(&packet, from).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn answer(&self, msg: &KrpcMessage, from: SocketAddrV4) -> Result<Response, DhtError> {
+        let (Some(q), Some(a)) = (&msg.q, &msg.a) else {
+            return Err(protocol("query without method or arguments"));
+        };
+        let querier = node_id(&a.id)?;
+        self.table.lock().unwrap().insert(querier, from);
+        let mut r = Response {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        match q.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let target = node_id(bytes(&a.target))?;
+                r.nodes = Some(self.closest_nodes(&target));
+            }
+            "get_peers" => {
+                let info_hash = node_id(bytes(&a.info_hash))?;
+                r.token = Some(ByteBuf::from(self.token(from, false)));
+                let stored = self.stored_peers(&info_hash);
+                if stored.is_empty() {
+                    r.nodes = Some(self.closest_nodes(&info_hash));
+                } else {
+                    r.values = Some(stored.into_iter().map(encode_peer).collect());
+                }
+            }
+            "announce_peer" => {
+                let info_hash = node_id(bytes(&a.info_hash))?;
+                let token = bytes(&a.token);
+                if *token != self.token(from, false) && *token != self.token(from, true) {
+                    return Err(protocol("bad token"));
+                }
+                let port = match (a.implied_port, a.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) => port,
+                    _ => return Err(protocol("announce without a port")),
+                };
+                self.store_peer(info_hash, SocketAddrV4::new(*from.ip(), port));
+            }
+            other => return Err(DhtError::Protocol(format!("unknown method {other}"))),
+        }
+        Ok(r)
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        encode_nodes(&self.table.lock().unwrap().closest(target, K))
+    }
+
+    // Tokens prove the announcer did a get_peers from the same address recently
+    fn token(&self, from: SocketAddrV4, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() > TOKEN_ROTATION {
+            secrets.previous = secrets.current;
+            secrets.current = rand::random();
+            secrets.rotated = Instant::now();
+        }
+        let secret = if previous {
+            secrets.previous
+        } else {
+            secrets.current
+        };
+        let digest = Sha1::new()
+            .chain_update(secret)
+            .chain_update(from.ip().octets())
+            .finalize();
+        digest[..8].to_vec()
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], peer: SocketAddrV4) {
+        let mut peers = self.peers.lock().unwrap();
+        let list = peers.entry(info_hash).or_default();
+        list.retain(|(p, _)| *p != peer);
+        if list.len() >= MAX_PEERS_PER_TORRENT {
+            list.remove(0);
+        }
+        list.push((peer, Instant::now()));
+    }
+
+    fn stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(list) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        list.retain(|(_, seen)| seen.elapsed() < PEER_TTL);
+        list.iter().map(|(p, _)| *p).collect()
+    }
+}
+
+fn protocol(msg: &str) -> DhtError {
+    DhtError::Protocol(msg.into())
+}
+
+fn bytes(field: &Option<ByteBuf>) -> &[u8] {
+    field.as_ref().map_or(&[], |b| b.as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(dht: &Dht) -> SocketAddrV4 {
+        SocketAddrV4::new([127, 0, 0, 1].into(), dht.port())
+    }
+
+    #[tokio::test]
+    async fn announced_peers_are_found_through_other_nodes() {
+        let router = Dht::bind(0).await.unwrap();
+        let seeder = Dht::bind(0).await.unwrap();
+        let leecher = Dht::bind(0).await.unwrap();
+        seeder.ping(local(&router)).await.unwrap();
+        leecher.ping(local(&router)).await.unwrap();
+        assert_eq!(router.node_count(), 2);
+
+        let info_hash = [7u8; 20];
+        assert!(seeder.announce(info_hash, 6881).await.is_empty());
+        let found = leecher.get_peers(info_hash).await;
+        assert_eq!(found, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn rejects_announces_without_a_valid_token() {
+        let node = Dht::bind(0).await.unwrap();
+        let client = Dht::bind(0).await.unwrap();
+        let args = Args {
+            info_hash: Some(ByteBuf::from(vec![1; 20])),
+            port: Some(1234),
+            token: Some(ByteBuf::from(b"made up".to_vec())),
+            ..client.args()
+        };
+        let err = client
+            .query(local(&node), "announce_peer", args)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, DhtError::Remote { code: 203, message } if message.contains("bad token")),
+            "{err}"
+        );
+        assert!(node.stored_peers(&[1; 20]).is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+//! Kademlia routing table: one bucket of up to K nodes per shared-prefix length with our id
+
+use crate::dht::krpc::NodeId;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const K: usize = 8;
+// BEP 5: nodes not heard from in 15 minutes are questionable and may be replaced
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone)]
+struct Entry {
+    id: NodeId,
+    addr: SocketAddrV4,
+    last_seen: Instant,
+}
+
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> Self {
+        Self {
+            own,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    fn bucket(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own, id);
+        let zeros = d
+            .iter()
+            .position(|&b| b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(Vec::is_empty)
+    }
+
+    /// Record a node we heard from. A full bucket only makes room by evicting a stale node.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        let Some(index) = self.bucket(&id) else {
+            return;
+        };
+        let now = Instant::now();
+        let bucket = &mut self.buckets[index];
+        if let Some(entry) = bucket.iter_mut().find(|e| e.id == id) {
+            entry.addr = addr;
+            entry.last_seen = now;
+            return;
+        }
+        let entry = Entry {
+            id,
+            addr,
+            last_seen: now,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+        } else if let Some(stale) = bucket.iter_mut().find(|e| now - e.last_seen > STALE_AFTER) {
+            *stale = entry;
+        }
+    }
+
+    pub fn remove(&mut self, addr: SocketAddrV4) {
+        for bucket in &mut self.buckets {
+            bucket.retain(|e| e.addr != addr);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<(NodeId, SocketAddrV4)> {
+        let mut all: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|e| (e.id, e.addr))
+            .collect();
+        all.sort_by_key(|(id, _)| distance(id, target));
+        all.truncate(n);
+        all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([127, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn buckets_fill_by_prefix_and_sort_by_distance() {
+        let mut table = RoutingTable::new([0; 20]);
+        // All of these share no prefix bit with our id and land in bucket 0
+        for i in 0..20u8 {
+            let mut id = [0; 20];
+            id[0] = 0x80;
+            id[19] = i;
+            table.insert(id, addr(i as u16));
+        }
+        assert_eq!(table.len(), K);
+        let mut near = [0; 20];
+        near[19] = 1;
+        table.insert(near, addr(100));
+        table.insert([0; 20], addr(101));
+        assert_eq!(table.len(), K + 1);
+
+        let closest = table.closest(&[0; 20], 2);
+        assert_eq!(closest[0], (near, addr(100)));
+        assert_eq!(closest[1].0[0], 0x80);
+        table.remove(addr(100));
+        assert_eq!(table.len(), K);
+    }
+}
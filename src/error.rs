@@ -0,0 +1,70 @@
+//! Errors returned by the library. Each module has its own error type; [`Error`]
+//! wraps them all for callers that drive several modules at once, such as a
+//! [`Session`](crate::Session).
+
+#[cfg(feature = "daemon")]
+use crate::api::error::ApiError;
+#[cfg(feature = "dht")]
+use crate::dht::error::DhtError;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+pub use crate::bencode::error::BencodeError;
+pub use crate::config::error::ConfigError;
+pub use crate::ipfilter::error::IpFilterError;
+pub use crate::peers::error::PeerError;
+pub use crate::portmap::error::PortMapError;
+pub use crate::proxy::error::ProxyError;
+pub use crate::storage::error::StorageError;
+pub use crate::torrentfile::error::TorrentError;
+pub use crate::tracker::error::TrackerError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    #[error(transparent)]
+    Torrent(#[from] TorrentError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error(transparent)]
+    IpFilter(#[from] IpFilterError),
+    #[error(transparent)]
+    PortMap(#[from] PortMapError),
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+    #[cfg(feature = "dht")]
+    #[error(transparent)]
+    Dht(#[from] DhtError),
+    #[cfg(feature = "daemon")]
+    #[error(transparent)]
+    Api(#[from] ApiError),
+    #[error("torrent {} is already in the session", hex::encode(.0))]
+    DuplicateTorrent([u8; 20]),
+    #[error("no torrent {} in the session", hex::encode(.0))]
+    UnknownTorrent([u8; 20]),
+    /// A magnet link's files aren't known until its metadata arrives
+    #[error("torrent {} has no metadata yet", hex::encode(.0))]
+    NoMetadata([u8; 20]),
+    /// The torrent stopped on an error before what was waited for happened
+    #[error("torrent {} failed: {error}", hex::encode(info_hash))]
+    TorrentFailed { info_hash: [u8; 20], error: String },
+    #[error("torrent {} has no file {index}", hex::encode(info_hash))]
+    UnknownFile { info_hash: [u8; 20], index: usize },
+    #[error("torrent {} has no piece {piece}", hex::encode(info_hash))]
+    UnknownPiece { info_hash: [u8; 20], piece: u32 },
+    /// An API client named a path outside the download and allowed directories
+    #[error("{} is outside the download directory and allowed-dirs", .0.display())]
+    PathNotAllowed(PathBuf),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
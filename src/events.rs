@@ -0,0 +1,145 @@
+//! Structured events published by a [`Session`](crate::Session) and the swarms it runs.
+//!
+//! Events go out on a tokio broadcast channel. Subscribers that fall more than
+//! [`EVENT_CAPACITY`] events behind get `RecvError::Lagged` and miss the oldest ones.
+
+use crate::portmap::{Method, Protocol};
+use crate::session::torrent::TorrentState;
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::sync::broadcast;
+
+pub const EVENT_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    TorrentAdded {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+    },
+    TorrentRemoved {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+    },
+    StateChanged {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        state: TorrentState,
+    },
+    /// The info dict of a magnet link arrived from a peer and was verified
+    MetadataReceived {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        name: String,
+    },
+    /// A piece passed its hash check and is on disk
+    PieceFinished {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        piece: u32,
+    },
+    HashFailed {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        piece: u32,
+    },
+    /// Every piece is verified on disk
+    TorrentFinished {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+    },
+    PeerConnected {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+    },
+    PeerDisconnected {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        addr: SocketAddr,
+        /// None for a clean shutdown on our side, e.g. the torrent finished
+        reason: Option<String>,
+    },
+    TrackerReply {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        tracker: String,
+        peers: usize,
+    },
+    TrackerWarning {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        tracker: String,
+        message: String,
+    },
+    TrackerError {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        tracker: String,
+        error: String,
+    },
+    DhtReply {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        peers: usize,
+    },
+    /// Reading or writing the torrent's files failed; the torrent stops
+    StorageError {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        error: String,
+    },
+    /// The torrent stopped on an error and is in the `error` state
+    TorrentError {
+        #[serde(with = "hex::serde")]
+        info_hash: [u8; 20],
+        error: String,
+    },
+    DhtBootstrapped {
+        nodes: usize,
+    },
+    /// An incoming connection could not be accepted
+    ListenError {
+        error: String,
+    },
+    /// The gateway forwards `external` to our listen port
+    PortMapped {
+        protocol: Protocol,
+        port: u16,
+        external: SocketAddr,
+        method: Method,
+    },
+    /// Neither PCP, NAT-PMP nor UPnP could map the port; peers behind other NATs won't
+    /// reach us
+    PortMapFailed {
+        protocol: Protocol,
+        port: u16,
+        error: String,
+    },
+}
+
+/// Cloneable publishing side of the event channel. Publishing never blocks and is a
+/// no-op while nobody is subscribed.
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: Event) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
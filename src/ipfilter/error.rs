@@ -0,0 +1,13 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum IpFilterError {
+    #[error("line {line}: {reason}")]
+    Parse { line: usize, reason: String },
+    /// Reloading needs the file the filter came from
+    #[error("no IP filter file configured")]
+    NoFile,
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
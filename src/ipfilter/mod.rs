@@ -0,0 +1,300 @@
+//! Address blocklists. [`IpFilter`] reads eMule `.dat` and PeerGuardian `.p2p` range files
+//! and plain CIDR lists, one entry per line, in any mix:
+//!
+//! ```text
+//! # comments start with # or //
+//! 001.002.004.000 - 001.002.004.255 , 000 , eMule: range, access level, description
+//! Some Organisation:1.2.8.0-1.2.8.255
+//! 10.0.0.0/8
+//! 2001:db8::/32
+//! 192.0.2.7
+//! ```
+//!
+//! eMule entries with an access level above 127 allow rather than block, and are skipped.
+
+pub mod error;
+
+use crate::ipfilter::error::IpFilterError;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+// eMule levels at or below this block
+const MAX_BLOCKING_LEVEL: u32 = 127;
+
+/// Sorted, merged ranges of blocked addresses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, IpFilterError> {
+        // Published lists often have Latin-1 descriptions, which only ever end up ignored
+        Self::parse(&String::from_utf8_lossy(&std::fs::read(path)?))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, IpFilterError> {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            let range = parse_line(line).map_err(|reason| IpFilterError::Parse {
+                line: i + 1,
+                reason,
+            })?;
+            match range {
+                Some((IpAddr::V4(first), IpAddr::V4(last))) => v4.push((first.into(), last.into())),
+                Some((IpAddr::V6(first), IpAddr::V6(last))) => v6.push((first.into(), last.into())),
+                Some(_) => {
+                    return Err(IpFilterError::Parse {
+                        line: i + 1,
+                        reason: "range mixes IPv4 and IPv6".into(),
+                    });
+                }
+                None => {}
+            }
+        }
+        Ok(Self {
+            v4: merge(v4),
+            v6: merge(v6),
+        })
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, ip.into()),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, ip.into()),
+                None => contains(&self.v6, ip.into()),
+            },
+        }
+    }
+
+    /// Number of ranges once overlapping and adjacent ones are merged
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// What a session's filter holds and has done
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpFilterStatus {
+    /// The file it was loaded from and reloads from
+    pub file: Option<PathBuf>,
+    pub ranges: usize,
+    /// Peers not connected to, and connections refused, since the session started
+    pub blocked: u64,
+}
+
+/// A filter shared by everything that connects to or accepts peers. It can be swapped
+/// while running, and counts what it blocks.
+#[derive(Debug, Default)]
+pub struct PeerFilter {
+    filter: RwLock<Arc<IpFilter>>,
+    blocked: AtomicU64,
+}
+
+impl PeerFilter {
+    pub fn new(filter: IpFilter) -> Self {
+        Self {
+            filter: RwLock::new(Arc::new(filter)),
+            blocked: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, filter: IpFilter) {
+        *self.filter.write().unwrap() = Arc::new(filter);
+    }
+
+    /// Whether `ip` is blocked, counting it if so
+    pub fn blocks(&self, ip: IpAddr) -> bool {
+        let blocked = self.filter.read().unwrap().is_blocked(ip);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    pub fn ranges(&self) -> usize {
+        self.filter.read().unwrap().len()
+    }
+
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+// A blocking range, or None for an eMule entry that allows
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, String> {
+    if let Some(range) = parse_cidr(line).or_else(|| parse_range(line)) {
+        return Ok(Some(range));
+    }
+    // PeerGuardian: the description may contain colons and commas too, the range never does
+    if let Some(range) = line
+        .rsplit_once(':')
+        .and_then(|(_, range)| parse_range(range))
+    {
+        return Ok(Some(range));
+    }
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        let level: u32 = level
+            .parse()
+            .map_err(|_| format!("invalid access level {level:?}"))?;
+        let range = parse_range(range).ok_or_else(|| format!("invalid range {range:?}"))?;
+        return Ok((level <= MAX_BLOCKING_LEVEL).then_some(range));
+    }
+    Err(format!("not a range, CIDR block or address: {line:?}"))
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = (parse_ip(first.trim())?, parse_ip(last.trim())?);
+    (first <= last).then_some((first, last))
+}
+
+fn parse_cidr(line: &str) -> Option<(IpAddr, IpAddr)> {
+    let Some((ip, bits)) = line.split_once('/') else {
+        let ip = parse_ip(line)?;
+        return Some((ip, ip));
+    };
+    let bits: u32 = bits.trim().parse().ok()?;
+    match parse_ip(ip.trim())? {
+        IpAddr::V4(ip) if bits <= 32 => {
+            let mask = u32::MAX.checked_shr(bits).unwrap_or(0);
+            let first = u32::from(ip) & !mask;
+            Some((
+                Ipv4Addr::from(first).into(),
+                Ipv4Addr::from(first | mask).into(),
+            ))
+        }
+        IpAddr::V6(ip) if bits <= 128 => {
+            let mask = u128::MAX.checked_shr(bits).unwrap_or(0);
+            let first = u128::from(ip) & !mask;
+            Some((
+                Ipv6Addr::from(first).into(),
+                Ipv6Addr::from(first | mask).into(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+// eMule files pad IPv4 octets with zeros, which the standard parser rejects
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = s.split('.');
+    for octet in &mut octets {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    parts
+        .next()
+        .is_none()
+        .then(|| Ipv4Addr::from(octets).into())
+}
+
+fn merge<T: Ord + Copy + Successor>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1.successor() => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+// Adjacent ranges merge as well as overlapping ones
+trait Successor {
+    fn successor(self) -> Self;
+}
+
+impl Successor for u32 {
+    fn successor(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+impl Successor for u128 {
+    fn successor(self) -> Self {
+        self.saturating_add(1)
+    }
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let i = ranges.partition_point(|&(first, _)| first <= ip);
+    i > 0 && ip <= ranges[i - 1].1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "\
+# blocklist
+001.002.004.000 - 001.002.004.255 , 000 , China Internet Information Center
+001.002.005.000 - 001.002.005.255 , 200 , allowed by its access level
+Bogon: reserved:10.0.0.0-10.0.0.255
+Acme, Inc:198.51.100.0-198.51.100.255
+10.0.1.0/24
+2001:db8::/32
+
+// single addresses
+192.0.2.7
+";
+
+    #[test]
+    fn reads_mixed_formats() {
+        let filter = IpFilter::parse(LIST).unwrap();
+        // The two 10.0.x.0/24 ranges are adjacent and merge into one
+        assert_eq!(filter.len(), 5);
+        let blocked = |ip: &str| filter.is_blocked(ip.parse().unwrap());
+        assert!(blocked("1.2.4.0") && blocked("1.2.4.255"));
+        assert!(!blocked("1.2.5.1") && !blocked("1.2.3.255"));
+        assert!(blocked("10.0.0.9") && blocked("10.0.1.255") && !blocked("10.0.2.0"));
+        assert!(blocked("2001:db8:ffff::1") && !blocked("2001:db9::1"));
+        assert!(blocked("192.0.2.7") && blocked("::ffff:192.0.2.7"));
+        assert!(!blocked("192.0.2.8"));
+        assert!(blocked("198.51.100.42"));
+
+        assert!(matches!(
+            IpFilter::parse("10.0.0.0/8\nnot an address\n"),
+            Err(IpFilterError::Parse { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn loads_lists_that_are_not_utf8() {
+        let path = std::env::temp_dir().join(format!("rustor-latin1-{}.p2p", std::process::id()));
+        // "Société" in Latin-1
+        std::fs::write(&path, b"Soci\xe9t\xe9:203.0.113.0-203.0.113.255\n").unwrap();
+        let filter = IpFilter::load(&path).unwrap();
+        assert!(filter.is_blocked("203.0.113.9".parse().unwrap()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn counts_what_it_blocks_and_can_be_swapped() {
+        let filter = PeerFilter::new(IpFilter::parse("192.0.2.0/24").unwrap());
+        assert!(filter.blocks("192.0.2.1".parse().unwrap()));
+        assert!(!filter.blocks("198.51.100.1".parse().unwrap()));
+        filter.set(IpFilter::parse("198.51.100.0/24").unwrap());
+        assert!(filter.blocks("198.51.100.1".parse().unwrap()));
+        assert_eq!((filter.ranges(), filter.blocked()), (1, 2));
+    }
+}
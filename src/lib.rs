@@ -0,0 +1,64 @@
+//! RusTor is a BitTorrent library: bencode, `.torrent` and magnet parsing,
+//! HTTP/UDP trackers, the peer wire protocol over TCP or uTP, a Mainline DHT
+//! node and a multi-torrent [`Session`] that ties them together.
+//!
+//! ```no_run
+//! use rustor::{Session, SessionConfig, TorrentFile, TorrentSource};
+//!
+//! # async fn run() -> rustor::Result<()> {
+//! let session = Session::new(SessionConfig::default()).await?;
+//! let tf = TorrentFile::from_file("debian.torrent")?;
+//! let info_hash = session.add(TorrentSource::File(tf), None)?;
+//! println!("{:?}", session.status(&info_hash)?.state);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Files can be read while they download; reads wait for the pieces they need and fetch
+//! them first:
+//!
+//! ```no_run
+//! # use rustor::Session;
+//! use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+//!
+//! # async fn read(session: &Session, info_hash: [u8; 20]) -> std::io::Result<()> {
+//! let mut file = session.open_file(&info_hash, 0).await.map_err(std::io::Error::other)?;
+//! file.seek(SeekFrom::Start(1 << 20)).await?;
+//! let mut header = [0u8; 4096];
+//! file.read_exact(&mut header).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Cargo features:
+//! - `dht` (default): the Mainline DHT node in [`dht`], used by sessions to find peers
+//! - `cli` (default): the `minibit` command line client
+//! - `tui` (default): the `minibit tui` full-screen interface, built on ratatui
+//! - `daemon` (default): the HTTP+JSON control API in [`api`] that `minibit daemon` serves
+
+#[cfg(feature = "daemon")]
+pub mod api;
+pub mod bencode;
+pub mod bittorrent;
+pub mod config;
+#[cfg(feature = "dht")]
+pub mod dht;
+pub mod error;
+pub mod events;
+pub mod ipfilter;
+pub mod peers;
+pub mod portmap;
+pub mod proxy;
+pub mod session;
+pub mod storage;
+pub mod torrentfile;
+pub mod tracker;
+pub mod utp;
+
+pub use config::Config;
+pub use error::{Error, Result};
+pub use events::Event;
+pub use session::manager::{Session, SessionConfig};
+pub use session::torrent::{TorrentSource, TorrentState, TorrentStatus};
+pub use torrentfile::magnet::{MagnetLink, parse_magnet_link};
+pub use torrentfile::torrent::TorrentFile;
@@ -1,122 +1,465 @@
-use crate::Peers::peer::{Handshake, download_first_piece};
-use crate::Torrentfile::magnet::parse_magnet_link;
-use crate::Torrentfile::torrent::TorrentFile;
-use crate::Tracker::{tracker::query_http_tracker, udp::query_udp_tracker};
-use crate::bittorent::connect_to_peer;
-use anyhow::{Result, anyhow};
-use clap::{Parser, Subcommand};
-// use tokio::io::AsyncReadExt;
+mod cli;
 
-#[allow(non_snake_case)]
-mod Bencode;
-#[allow(non_snake_case)]
-mod Peers;
-#[allow(non_snake_case)]
-mod Torrentfile;
-#[allow(non_snake_case)]
-mod Tracker;
-mod bittorent;
+use anyhow::{Result, anyhow};
+use clap::{Args, Parser, Subcommand};
+#[cfg(feature = "daemon")]
+use cli::daemon::RemoteCommand;
+use cli::progress::{ProgressLine, progress_line};
+#[cfg(feature = "daemon")]
+use rustor::api::{DEFAULT_ENDPOINT, Endpoint};
+use rustor::config::{Config, EncryptionPolicy};
+use rustor::events::Event;
+use rustor::proxy::Proxy;
+use rustor::session::manager::{Session, SessionConfig};
+use rustor::session::torrent::{TorrentSource, TorrentState};
+use rustor::storage::files::FilePriority;
+use rustor::torrentfile::create::TorrentBuilder;
+use rustor::torrentfile::info::{TorrentSummary, format_size};
+use rustor::torrentfile::magnet::{MagnetLink, parse_magnet_link};
+use rustor::torrentfile::torrent::TorrentFile;
+use std::path::{Path, PathBuf};
+use tokio::sync::broadcast::error::RecvError;
 
 #[derive(Parser)]
 #[command(name = "minibit", version, about = "Minimal Bittorrent client")]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Config file to read instead of minibit/config.toml in the XDG config directories
+    #[arg(long, global = true, env = "MINIBIT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Where the daemon's API listens: host:port, or a Unix socket path
+    #[cfg(feature = "daemon")]
+    #[arg(long, global = true, env = "MINIBIT_API", default_value = DEFAULT_ENDPOINT)]
+    api: Endpoint,
+    /// Secret the daemon's API requires, and which lets it listen on non-loopback addresses
+    #[cfg(feature = "daemon")]
+    #[arg(long, global = true, env = "MINIBIT_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+}
+
+/// Overrides for the config file; each can also be set in the environment
+#[derive(Args)]
+struct SessionArgs {
+    /// Directory to save the content in
+    #[arg(short, long, env = "MINIBIT_DOWNLOAD_DIR")]
+    output_dir: Option<PathBuf>,
+    /// TCP port for peers and UDP port for the DHT, 0 for any free port
+    #[arg(long, env = "MINIBIT_LISTEN_PORT")]
+    listen_port: Option<u16>,
+    /// Download limit in KiB/s, 0 for unlimited
+    #[arg(long, env = "MINIBIT_MAX_DOWNLOAD_RATE")]
+    max_download_rate: Option<u64>,
+    /// Upload limit in KiB/s, 0 for unlimited
+    #[arg(long, env = "MINIBIT_MAX_UPLOAD_RATE")]
+    max_upload_rate: Option<u64>,
+    /// Connections per torrent
+    #[arg(long, env = "MINIBIT_MAX_PEERS")]
+    max_peers: Option<usize>,
+    /// Don't apply rate limits to peers on the local network
+    #[arg(long, env = "MINIBIT_EXEMPT_LOCAL_PEERS")]
+    exempt_local_peers: bool,
+    /// Don't use the DHT to find peers
+    #[cfg(feature = "dht")]
+    #[arg(long, env = "MINIBIT_NO_DHT")]
+    no_dht: bool,
+    /// Peer connection encryption: disabled, enabled or forced
+    #[arg(long, env = "MINIBIT_ENCRYPTION")]
+    encryption: Option<EncryptionPolicy>,
+    /// Only use TCP for peer connections
+    #[arg(long, env = "MINIBIT_NO_UTP")]
+    no_utp: bool,
+    /// Send tracker announces through a proxy: socks5://[user:pass@]host:port or
+    /// http://[user:pass@]host:port
+    #[arg(long, env = "MINIBIT_TRACKER_PROXY")]
+    tracker_proxy: Option<Proxy>,
+    /// Connect to peers through a proxy; SOCKS5 also carries the DHT and uTP
+    #[arg(long, env = "MINIBIT_PEER_PROXY")]
+    peer_proxy: Option<Proxy>,
+    /// Don't ask the router to forward the listen port
+    #[arg(long, env = "MINIBIT_NO_PORT_MAPPING")]
+    no_port_mapping: bool,
+    /// Don't connect to or accept peers listed in this file: eMule .dat, PeerGuardian .p2p
+    /// or one CIDR block per line
+    #[arg(long, env = "MINIBIT_IP_FILTER")]
+    ip_filter: Option<PathBuf>,
+}
+
+impl SessionArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(dir) = &self.output_dir {
+            config.download_dir = dir.clone();
+        }
+        if let Some(port) = self.listen_port {
+            config.network.listen_port = port;
+        }
+        if let Some(rate) = self.max_download_rate {
+            config.limits.max_download_rate = rate;
+        }
+        if let Some(rate) = self.max_upload_rate {
+            config.limits.max_upload_rate = rate;
+        }
+        if let Some(peers) = self.max_peers {
+            config.limits.max_peers = peers;
+        }
+        if self.exempt_local_peers {
+            config.limits.exempt_local_peers = true;
+        }
+        #[cfg(feature = "dht")]
+        if self.no_dht {
+            config.discovery.dht = false;
+        }
+        if let Some(encryption) = self.encryption {
+            config.network.encryption = encryption;
+        }
+        if self.no_utp {
+            config.network.utp = false;
+        }
+        if let Some(proxy) = &self.tracker_proxy {
+            config.proxy.tracker = Some(proxy.clone());
+        }
+        if let Some(proxy) = &self.peer_proxy {
+            config.proxy.peer = Some(proxy.clone());
+        }
+        if self.no_port_mapping {
+            config.network.natpmp = false;
+            config.network.upnp = false;
+        }
+        if let Some(path) = &self.ip_filter {
+            config.network.ip_filter = Some(path.clone());
+        }
+    }
+}
+
+/// Read the config file, apply command line and environment overrides, and report the
+/// file used and any settings without effect
+fn load_config(path: Option<&Path>, overrides: Option<&SessionArgs>) -> Result<Config> {
+    let (mut config, found) = Config::load(path, "minibit")?;
+    if let Some(overrides) = overrides {
+        overrides.apply(&mut config);
+    }
+    let warnings = config.validate()?;
+    if let Some(found) = found {
+        eprintln!("Using config {}", found.display());
+    }
+    for warning in warnings {
+        eprintln!("warning: {warning}");
+    }
+    Ok(config)
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Download { torrent: String },
+    /// Download a .torrent file or magnet link
+    Download {
+        torrent: String,
+        #[command(flatten)]
+        session: SessionArgs,
+        /// Show a live progress line with the piece map, rate, ETA and peers
+        #[arg(short, long)]
+        progress: bool,
+        /// Only download the files at or under this path in the torrent; can be repeated
+        #[arg(long, value_name = "PATH")]
+        only: Vec<PathBuf>,
+        /// Fetch pieces in order, so the files can be played while they download
+        #[arg(long)]
+        sequential: bool,
+    },
+    /// Download torrents in a full-screen terminal UI
+    #[cfg(feature = "tui")]
+    Tui {
+        /// .torrent files or magnet links to start with
+        torrents: Vec<String>,
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    /// Run a session in the background, controlled over an HTTP+JSON API at --api
+    #[cfg(feature = "daemon")]
+    Daemon {
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    #[cfg(feature = "daemon")]
+    #[command(flatten)]
+    Remote(RemoteCommand),
+    /// Print the settings in effect after the config file, environment and options
+    Config {
+        #[command(flatten)]
+        session: SessionArgs,
+    },
+    /// Create a .torrent from a file or directory
+    Create {
+        path: String,
+        /// Output file, defaults to <name>.torrent
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Tracker URL; repeat for more tiers, or separate trackers of one tier with commas
+        #[arg(short = 't', long = "tracker")]
+        trackers: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        /// Piece length in bytes, chosen from the content size when omitted
+        #[arg(long)]
+        piece_length: Option<u64>,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        source: Option<String>,
+        #[arg(long = "web-seed")]
+        web_seeds: Vec<String>,
+        /// Leave out the creation date so identical content yields identical files
+        #[arg(long)]
+        no_date: bool,
+    },
+    /// Show the metadata of a .torrent file or magnet link
+    Info {
+        torrent: String,
+        /// Print machine readable JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Fetch a magnet link's metadata from peers and save it as a .torrent
+    MagnetToTorrent {
+        magnet: String,
+        /// Output file, defaults to <name>.torrent
+        #[arg(short, long)]
+        output: Option<String>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
+    let config_path = cli.config.as_deref();
     match cli.command {
-        Commands::Download { torrent } => run_download(&torrent).await?,
+        Commands::Download {
+            torrent,
+            session,
+            progress,
+            only,
+            sequential,
+        } => {
+            let config = load_config(config_path, Some(&session))?;
+            run_download(
+                &torrent,
+                config.session_config(),
+                progress,
+                &only,
+                sequential,
+            )
+            .await?
+        }
+        #[cfg(feature = "tui")]
+        Commands::Tui { torrents, session } => {
+            let config = load_config(config_path, Some(&session))?;
+            let session = Session::new(config.session_config()).await?;
+            for target in &torrents {
+                session.add(torrent_source(target)?, None)?;
+            }
+            let result = cli::tui::run(&session).await;
+            session.shutdown().await;
+            result?
+        }
+        #[cfg(feature = "daemon")]
+        Commands::Daemon { session } => {
+            let config = load_config(config_path, Some(&session))?;
+            cli::daemon::run_daemon(cli.api, cli.api_token, config.session_config()).await?
+        }
+        Commands::Config { session } => {
+            print!("{}", load_config(config_path, Some(&session))?.to_toml())
+        }
+        #[cfg(feature = "daemon")]
+        Commands::Remote(command) => cli::daemon::run(cli.api, cli.api_token, command).await?,
+        Commands::Info { torrent, json } => {
+            let summary = if torrent.starts_with("magnet:?") {
+                TorrentSummary::from_magnet(&parse_magnet_link(&torrent)?)
+            } else {
+                TorrentSummary::from_torrent_file(&TorrentFile::from_file(&torrent)?)
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&summary)?);
+            } else {
+                print!("{}", summary.render());
+            }
+        }
+        Commands::MagnetToTorrent { magnet, output } => {
+            let config = load_config(config_path, None)?;
+            run_magnet_to_torrent(&magnet, output, &config).await?
+        }
+        Commands::Create {
+            path,
+            output,
+            trackers,
+            comment,
+            piece_length,
+            private,
+            source,
+            web_seeds,
+            no_date,
+        } => {
+            let mut builder = TorrentBuilder::new(&path).private(private);
+            for tier in trackers {
+                builder = builder.tracker_tier(tier.split(',').map(str::to_string).collect());
+            }
+            for url in web_seeds {
+                builder = builder.web_seed(url);
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+            if let Some(source) = source {
+                builder = builder.source(source);
+            }
+            if let Some(len) = piece_length {
+                builder = builder.piece_length(len);
+            }
+            if no_date {
+                builder = builder.creation_date(None);
+            }
+            // Hashing is CPU bound and runs on the rayon pool
+            let tf = tokio::task::spawn_blocking(move || builder.build()).await??;
+            let output = output.unwrap_or_else(|| format!("{}.torrent", tf.torrent.info.name));
+            tf.write(&output)?;
+            println!("Wrote {output} (info hash {})", hex::encode(tf.info_hash));
+            println!("{}", MagnetLink::from_torrent_file(&tf));
+        }
     }
     Ok(())
 }
 
-async fn run_download(target: &str) -> Result<()> {
-    use rand::Rng;
-
-    let mut peer_id = [0u8; 20];
-    peer_id[0..8].copy_from_slice(b"-RS0001-");
-    rand::thread_rng().fill(&mut peer_id[8..]);
-
-    let (info_hash, trackers, total_len_opt, piece_length_opt, piece0_hash_opt): (
-        [u8; 20],
-        Vec<String>,
-        Option<u64>,
-        Option<u64>,
-        Option<[u8; 20]>,
-    ) = if target.starts_with("magnet:?") {
-        let m = parse_magnet_link(target)?;
-        (
-            m.infohash,
-            if m.trackers.is_empty() {
-                vec![]
-            } else {
-                m.trackers
-            },
-            None,
-            None,
-            None,
-        )
-    } else {
-        let p0 = [0u8; 20];
-        let tf = TorrentFile::from_file(target)?;
-        (
-            tf.info_hash,
-            vec![tf.torrent.announce.clone()],
-            Some(tf.torrent.info.length),
-            Some(tf.torrent.info.piece_length),
-            Some(p0),
-        )
-    };
+async fn run_magnet_to_torrent(link: &str, output: Option<String>, config: &Config) -> Result<()> {
+    let m = parse_magnet_link(link)?;
+    // A short-lived session on any free port, so a running daemon doesn't get in the way
+    let session = Session::new(SessionConfig {
+        listen_port: 0,
+        port_mapping: None,
+        ..config.session_config()
+    })
+    .await?;
+    let torrent = session.fetch_torrent(m).await;
+    session.shutdown().await;
+    let torrent = torrent?;
+    let tf = TorrentFile::from_bytes(&torrent)?;
 
-    let announce = trackers
-        .get(0)
-        .ok_or_else(|| {
-            anyhow!("No tracker availabl; magnet without trackers rquires DHT (not implemented)")
-        })?
-        .clone();
+    let output = output.unwrap_or_else(|| format!("{}.torrent", tf.torrent.info.name));
+    std::fs::write(&output, &torrent)?;
+    println!("Wrote {output} (info hash {})", hex::encode(tf.info_hash));
+    Ok(())
+}
 
-    let port = 6881u16;
-    let peers = if announce.starts_with("http") {
-        let left = total_len_opt.unwrap_or(0);
-        query_http_tracker(&announce, info_hash, peer_id, port, 0, 0, left)
-            .await?
-            .peers
-    } else if announce.starts_with("udp") {
-        let left = total_len_opt.unwrap_or(0);
-        query_udp_tracker(&announce, info_hash, peer_id, port, left)
-            .await?
-            .peers
+fn torrent_source(target: &str) -> Result<TorrentSource> {
+    Ok(if target.starts_with("magnet:?") {
+        TorrentSource::Magnet(parse_magnet_link(target)?)
     } else {
-        anyhow::bail!("Unsupported tracker protocol: {announce}");
-    };
-    let Some(peer) = peers.first() else {
-        anyhow::bail!("Tracker returned no peers");
-    };
+        TorrentSource::File(TorrentFile::from_file(target)?)
+    })
+}
 
-    let mut stream = connect_to_peer(*peer).await?;
-    let hs = Handshake::new(info_hash, peer_id);
-    Handshake::send_handshake(&mut stream, &hs).await?;
-    Handshake::send_interested(&mut stream).await?;
+async fn run_download(
+    target: &str,
+    config: SessionConfig,
+    progress: bool,
+    only: &[PathBuf],
+    sequential: bool,
+) -> Result<()> {
+    let source = torrent_source(target)?;
+    let session = Session::new(config).await?;
+    let result = download(&session, source, progress, only, sequential).await;
+    session.shutdown().await;
+    result
+}
 
-    if let (Some(total_len), Some(piece_len), Some(piece0_hash)) =
-        (total_len_opt, piece_length_opt, piece0_hash_opt)
-    {
-        let data = download_first_piece(&mut stream, piece_len, total_len, piece0_hash).await?;
-        std::fs::write("piece0.bin", &data)?;
-        println!("Wrote piece0.bin ({} bytes)", data.len());
-    } else {
-        println!(
-            "Connected to peer via magnet; full downloading needs BEP-9/10 metadata exchange."
-        );
+// Skip every file that isn't at or under one of `only`, which may start with the torrent's
+// name or leave it out
+fn select_files(session: &Session, info_hash: &[u8; 20], only: &[PathBuf]) -> Result<()> {
+    let mut selected = 0;
+    for (index, file) in session.files(info_hash)?.iter().enumerate() {
+        let inner: PathBuf = file.path.components().skip(1).collect();
+        if only
+            .iter()
+            .any(|path| file.path.starts_with(path) || inner.starts_with(path))
+        {
+            selected += 1;
+        } else {
+            session.set_file_priority(info_hash, index, FilePriority::Skip)?;
+        }
+    }
+    if selected == 0 {
+        anyhow::bail!("No file in the torrent is under --only");
     }
-
     Ok(())
 }
+
+// Follow the torrent's events until it is finished or fails
+async fn download(
+    session: &Session,
+    source: TorrentSource,
+    progress: bool,
+    only: &[PathBuf],
+    sequential: bool,
+) -> Result<()> {
+    let mut events = session.subscribe();
+    let info_hash = session.add(source, None)?;
+    session.set_sequential(&info_hash, sequential)?;
+    // Magnet links have no files to choose from until their metadata is in
+    let has_files = !session.files(&info_hash)?.is_empty();
+    if !only.is_empty() && has_files {
+        select_files(session, &info_hash, only)?;
+    }
+    let mut line = ProgressLine::new();
+    let mut redraw = tokio::time::interval(std::time::Duration::from_millis(500));
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Err(anyhow!("the session stopped")),
+            },
+            _ = redraw.tick(), if progress => {
+                let status = session.status(&info_hash)?;
+                line.draw(&progress_line(&status, &session.pieces(&info_hash)?));
+                continue;
+            }
+        };
+        let message = match event {
+            Event::StateChanged {
+                state: TorrentState::Finished,
+                ..
+            } => {
+                line.clear();
+                let status = session.status(&info_hash)?;
+                println!(
+                    "Downloaded {} ({}) to {}",
+                    status.name.unwrap_or_else(|| hex::encode(info_hash)),
+                    format_size(status.total_size.unwrap_or(0)),
+                    status.download_dir.display()
+                );
+                return Ok(());
+            }
+            Event::TorrentError { error, .. } => {
+                line.clear();
+                return Err(anyhow!(error));
+            }
+            Event::MetadataReceived { name, .. } => {
+                if !only.is_empty() {
+                    select_files(session, &info_hash, only)?;
+                }
+                format!("Fetched metadata for {name}")
+            }
+            Event::HashFailed { piece, .. } => format!("Piece {piece} failed its hash check"),
+            Event::TrackerError { tracker, error, .. } => {
+                format!("Tracker {tracker} failed: {error}")
+            }
+            Event::TrackerWarning {
+                tracker, message, ..
+            } => format!("Tracker warning from {tracker}: {message}"),
+            Event::PortMapped {
+                protocol, external, ..
+            } => format!("Router forwards {protocol} {external} to us"),
+            _ => continue,
+        };
+        // The progress line comes back on the next redraw
+        line.clear();
+        eprintln!("{message}");
+    }
+}
@@ -0,0 +1,66 @@
+use crate::peers::error::PeerError;
+
+/// Piece availability, most significant bit of the first byte is piece 0 (BEP 3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bits: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bits: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bf = Self::new(len);
+        for i in 0..len {
+            bf.set(i);
+        }
+        bf
+    }
+
+    /// Validate a peer's bitfield message against the torrent's piece count
+    pub fn from_payload(bits: Vec<u8>, len: usize) -> Result<Self, PeerError> {
+        if bits.len() != len.div_ceil(8) {
+            return Err(PeerError::violation(format!(
+                "bitfield of {} bytes for {len} pieces",
+                bits.len()
+            )));
+        }
+        let spare = bits.len() * 8 - len;
+        if spare > 0 && bits.last().is_some_and(|b| b & ((1u8 << spare) - 1) != 0) {
+            return Err(PeerError::violation("bitfield has spare bits set"));
+        }
+        Ok(Self { bits, len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bits[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bits[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.get(i))
+    }
+}
@@ -0,0 +1,106 @@
+//! Works out which peer to ban for a piece that failed its hash check.
+//!
+//! Blocks of one piece are pipelined across peers, so a failed piece alone doesn't say who
+//! sent the bad data. When one address sent the whole piece it is to blame. Otherwise the
+//! hash of every block and its sender are kept until the piece downloads correctly, and the
+//! peers whose blocks differ from the good ones are the ones banned.
+
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Default)]
+pub struct Blame {
+    // For each failed piece, the sender and hash of every block of every failed attempt
+    suspects: HashMap<u32, Vec<(usize, SocketAddr, [u8; 20])>>,
+}
+
+impl Blame {
+    /// Whether `piece` failed before, so the blocks of its next attempt need hashing
+    pub fn is_suspect(&self, piece: u32) -> bool {
+        self.suspects.contains_key(&piece)
+    }
+
+    /// A failed attempt at `piece`, with the sender of each block and the hash of what it
+    /// sent. Returns the addresses to ban.
+    pub fn failed(
+        &mut self,
+        piece: u32,
+        senders: &[SocketAddr],
+        hashes: Vec<[u8; 20]>,
+    ) -> Vec<IpAddr> {
+        if let Some(first) = senders.first()
+            && senders.iter().all(|s| s.ip() == first.ip())
+        {
+            self.suspects.remove(&piece);
+            return vec![first.ip()];
+        }
+        let blocks = senders.iter().zip(hashes).enumerate();
+        self.suspects
+            .entry(piece)
+            .or_default()
+            .extend(blocks.map(|(i, (&sender, hash))| (i, sender, hash)));
+        Vec::new()
+    }
+
+    /// `piece` passed its hash check with blocks hashing to `hashes`. Returns the addresses
+    /// that sent something else in an earlier attempt.
+    pub fn verified(&mut self, piece: u32, hashes: &[[u8; 20]]) -> Vec<IpAddr> {
+        let mut liars: Vec<IpAddr> = self
+            .suspects
+            .remove(&piece)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|&(i, _, hash)| hashes.get(i) != Some(&hash))
+            .map(|(_, sender, _)| sender.ip())
+            .collect();
+        liars.sort_unstable();
+        liars.dedup();
+        liars
+    }
+}
+
+/// The SHA-1 of each `block_size` block of a piece
+pub fn block_hashes(data: &[u8], block_size: u32) -> Vec<[u8; 20]> {
+    data.chunks(block_size as usize)
+        .map(|block| Sha1::digest(block).into())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 6881).into()
+    }
+
+    #[test]
+    fn bans_the_only_sender_of_a_bad_piece_at_once() {
+        let mut blame = Blame::default();
+        let bad = block_hashes(&[0; 32], 16);
+        assert_eq!(blame.failed(3, &[peer(1), peer(1)], bad), [peer(1).ip()]);
+        assert!(!blame.is_suspect(3));
+    }
+
+    #[test]
+    fn bans_only_the_sender_of_the_bad_block_of_a_shared_piece() {
+        let mut blame = Blame::default();
+        let mut data = vec![1u8; 48];
+        let good = block_hashes(&data, 16);
+        data[20] = 9;
+        // Peer 2 lied about the middle block; nobody can tell yet
+        let senders = [peer(1), peer(2), peer(3)];
+        assert!(
+            blame
+                .failed(0, &senders, block_hashes(&data, 16))
+                .is_empty()
+        );
+        assert!(blame.is_suspect(0));
+        assert!(blame.verified(1, &good).is_empty());
+
+        assert_eq!(blame.verified(0, &good), [peer(2).ip()]);
+        assert!(!blame.is_suspect(0));
+    }
+}
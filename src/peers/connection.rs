@@ -0,0 +1,282 @@
+use crate::peers::error::PeerError;
+use crate::peers::message::{Message, MessageCodec};
+use crate::peers::mse::{self, EncryptionPolicy, MseStream};
+use crate::peers::peer::{Handshake, PeerFramed};
+use crate::peers::ratelimit::RateLimits;
+use crate::peers::transport::{BoxedStream, Transports};
+use futures::{SinkExt, StreamExt};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, timeout, timeout_at};
+use tokio_util::codec::Framed;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTimeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    /// How long an outstanding block request may go unanswered
+    pub request: Duration,
+    /// Drop peers that send nothing at all for this long
+    pub idle: Duration,
+    pub keep_alive: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            request: Duration::from_secs(60),
+            idle: Duration::from_secs(180),
+            // BEP 3: keep-alives are generally sent once every two minutes
+            keep_alive: Duration::from_secs(120),
+        }
+    }
+}
+
+/// A framed peer connection that keeps itself alive and gives up on unresponsive peers
+pub struct PeerConnection<S> {
+    framed: PeerFramed<S>,
+    timeouts: PeerTimeouts,
+    last_sent: Instant,
+    last_received: Instant,
+    limits: RateLimits,
+}
+
+impl PeerConnection<MseStream<BoxedStream>> {
+    /// Connect over one of `transports`, negotiate encryption as `encryption` allows,
+    /// handshake and frame, each step bounded by its timeout
+    pub async fn establish(
+        addr: SocketAddrV4,
+        handshake: &Handshake,
+        transports: &Transports,
+        timeouts: PeerTimeouts,
+        encryption: EncryptionPolicy,
+    ) -> Result<(Self, Handshake), PeerError> {
+        let addr = SocketAddr::V4(addr);
+        let stream = transports.connect(addr, timeouts.connect).await?;
+        let negotiate = |stream, allow_plaintext| {
+            timeout(
+                timeouts.handshake,
+                mse::initiate(stream, handshake.infohash, allow_plaintext),
+            )
+        };
+        let mut stream = match encryption {
+            EncryptionPolicy::Disabled => MseStream::plaintext(stream),
+            EncryptionPolicy::Enabled => match negotiate(stream, true).await {
+                Ok(Ok(stream)) => stream,
+                // Peers without MSE hang up on our key exchange; try them again in plaintext
+                _ => MseStream::plaintext(transports.connect(addr, timeouts.connect).await?),
+            },
+            EncryptionPolicy::Forced => negotiate(stream, false)
+                .await
+                .map_err(|_| PeerError::Timeout("during encryption handshake"))??,
+        };
+        let remote = timeout(
+            timeouts.handshake,
+            Handshake::send_handshake(&mut stream, handshake),
+        )
+        .await
+        .map_err(|_| PeerError::Timeout("during handshake"))??;
+        Ok((Self::new(stream, timeouts), remote))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnection<S> {
+    /// Wrap a stream that has already completed the handshake
+    pub fn new(stream: S, timeouts: PeerTimeouts) -> Self {
+        Self {
+            framed: Framed::new(stream, MessageCodec::new()),
+            timeouts,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+            limits: RateLimits::default(),
+        }
+    }
+
+    pub fn timeouts(&self) -> &PeerTimeouts {
+        &self.timeouts
+    }
+
+    /// Throttle this connection's traffic from now on
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+
+    pub async fn send(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.limits.upload(msg.wire_len()).await;
+        self.framed.send(msg).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Queue a message without flushing, for batching requests
+    pub async fn feed(&mut self, msg: Message) -> Result<(), PeerError> {
+        self.limits.upload(msg.wire_len()).await;
+        self.framed.feed(msg).await?;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), PeerError> {
+        self.framed.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Next message from the peer, dropping it if it stays silent for the idle timeout.
+    /// Keep-alives are handled here and never returned.
+    pub async fn recv(&mut self) -> Result<Message, PeerError> {
+        loop {
+            if let Some(msg) = self.recv_for(self.timeouts.idle).await? {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Next message within `limit`; the peer's keep-alives don't extend the deadline
+    pub async fn recv_timeout(
+        &mut self,
+        limit: Duration,
+        what: &'static str,
+    ) -> Result<Message, PeerError> {
+        match self.recv_for(limit).await? {
+            Some(msg) => Ok(msg),
+            None => Err(self.disconnect(PeerError::Timeout(what)).await),
+        }
+    }
+
+    /// Like `recv` but gives up quietly after `limit`, so callers can do periodic work.
+    /// The idle timeout still counts from the last thing the peer sent.
+    pub async fn recv_for(&mut self, limit: Duration) -> Result<Option<Message>, PeerError> {
+        let until = Instant::now() + limit;
+        loop {
+            let idle_at = self.last_received + self.timeouts.idle;
+            let keep_alive_at = self.last_sent + self.timeouts.keep_alive;
+            let wake = until.min(idle_at).min(keep_alive_at);
+            match timeout_at(wake, self.framed.next()).await {
+                Ok(Some(Ok(msg))) => {
+                    self.last_received = Instant::now();
+                    // Not reading while throttled lets TCP push back on the peer
+                    self.limits.download(msg.wire_len()).await;
+                    if !matches!(msg, Message::KeepAlive) {
+                        return Ok(Some(msg));
+                    }
+                }
+                Ok(Some(Err(e))) => return Err(self.disconnect(e).await),
+                Ok(None) => return Err(PeerError::Disconnected),
+                Err(_) => {
+                    let now = Instant::now();
+                    if now >= idle_at {
+                        return Err(self.disconnect(PeerError::Timeout("while idle")).await);
+                    }
+                    if now >= until {
+                        return Ok(None);
+                    }
+                    self.send(Message::KeepAlive).await?;
+                }
+            }
+        }
+    }
+
+    /// Close the connection and hand back the reason so callers can propagate it with `return Err(..)`
+    pub async fn disconnect(&mut self, reason: PeerError) -> PeerError {
+        if !matches!(reason, PeerError::Disconnected) {
+            let _ = self.framed.close().await;
+        }
+        reason
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{DuplexStream, duplex};
+
+    fn timeouts() -> PeerTimeouts {
+        PeerTimeouts {
+            request: Duration::from_secs(20),
+            idle: Duration::from_secs(60),
+            keep_alive: Duration::from_secs(15),
+            ..PeerTimeouts::default()
+        }
+    }
+
+    fn pair() -> (
+        PeerConnection<DuplexStream>,
+        Framed<DuplexStream, MessageCodec>,
+    ) {
+        let (ours, theirs) = duplex(1024);
+        (
+            PeerConnection::new(ours, timeouts()),
+            Framed::new(theirs, MessageCodec::new()),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_a_silent_peer_after_the_idle_timeout() {
+        let (mut conn, mut peer) = pair();
+        // Drain our keep-alives so the peer looks alive from its side but never talks
+        tokio::spawn(async move { while peer.next().await.is_some() {} });
+
+        let start = Instant::now();
+        let err = conn.recv().await.unwrap_err();
+        assert!(matches!(err, PeerError::Timeout("while idle")));
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_keep_alives_while_waiting() {
+        let (mut conn, mut peer) = pair();
+        let start = Instant::now();
+        assert!(
+            conn.recv_for(Duration::from_secs(40))
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(40));
+        drop(conn);
+
+        let mut sent = Vec::new();
+        while let Some(msg) = peer.next().await {
+            sent.push(msg.unwrap());
+        }
+        assert_eq!(sent, vec![Message::KeepAlive, Message::KeepAlive]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_a_request_despite_keep_alives() {
+        let (mut conn, mut peer) = pair();
+        // The peer keeps the connection alive but never answers
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                if peer.send(Message::KeepAlive).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let limit = conn.timeouts().request;
+        let err = conn
+            .recv_timeout(limit, "waiting for a block")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PeerError::Timeout("waiting for a block")));
+        assert_eq!(start.elapsed(), Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hands_over_messages_and_swallows_keep_alives() {
+        let (mut conn, mut peer) = pair();
+        peer.send(Message::KeepAlive).await.unwrap();
+        peer.send(Message::Unchoke).await.unwrap();
+        let msg = conn
+            .recv_timeout(Duration::from_secs(1), "waiting")
+            .await
+            .unwrap();
+        assert_eq!(msg, Message::Unchoke);
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+use thiserror::Error;
+
+/// Why a peer connection ended; callers use it to decide whether to retry or ban the peer
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("peer timed out {0}")]
+    Timeout(&'static str),
+    #[error("protocol violation: {0}")]
+    ProtocolViolation(String),
+    #[error("piece {0} failed the hash check")]
+    BadHash(u32),
+    #[error("peer disconnected")]
+    Disconnected,
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+    /// Every peer we tried failed to hand over the info dict
+    #[error("no peer provided the metadata")]
+    MetadataUnavailable,
+}
+
+impl PeerError {
+    pub fn violation(msg: impl Into<String>) -> Self {
+        PeerError::ProtocolViolation(msg.into())
+    }
+}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => PeerError::Disconnected,
+            _ => PeerError::Io(e),
+        }
+    }
+}
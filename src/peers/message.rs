@@ -0,0 +1,502 @@
+//! Peer wire messages (BEP 3, BEP 6 fast extension, BEP 10 extension protocol)
+
+use crate::peers::error::PeerError;
+use crate::peers::peer::MsgId;
+use bytes::{Buf, BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast {
+        index: u32,
+    },
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    pub fn id(&self) -> Option<MsgId> {
+        Some(match self {
+            Message::KeepAlive => return None,
+            Message::Choke => MsgId::Choke,
+            Message::Unchoke => MsgId::Unchoke,
+            Message::Interested => MsgId::Interested,
+            Message::NotInterested => MsgId::NotInterested,
+            Message::Have { .. } => MsgId::Have,
+            Message::Bitfield(_) => MsgId::Bitfield,
+            Message::Request { .. } => MsgId::Request,
+            Message::Piece { .. } => MsgId::Piece,
+            Message::Cancel { .. } => MsgId::Cancel,
+            Message::Port(_) => MsgId::Port,
+            Message::SuggestPiece { .. } => MsgId::SuggestPiece,
+            Message::HaveAll => MsgId::HaveAll,
+            Message::HaveNone => MsgId::HaveNone,
+            Message::RejectRequest { .. } => MsgId::RejectRequest,
+            Message::AllowedFast { .. } => MsgId::AllowedFast,
+            Message::Extended { .. } => MsgId::Extended,
+        })
+    }
+
+    // Length of the id byte plus payload, i.e. the value of the 4-byte length prefix
+    fn body_len(&self) -> usize {
+        match self {
+            Message::KeepAlive => 0,
+            Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => 1,
+            Message::Have { .. } | Message::SuggestPiece { .. } | Message::AllowedFast { .. } => 5,
+            Message::Bitfield(bits) => 1 + bits.len(),
+            Message::Request { .. } | Message::Cancel { .. } | Message::RejectRequest { .. } => 13,
+            Message::Piece { block, .. } => 9 + block.len(),
+            Message::Port(_) => 3,
+            Message::Extended { payload, .. } => 2 + payload.len(),
+        }
+    }
+
+    /// Bytes the message occupies on the wire, length prefix included
+    pub fn wire_len(&self) -> usize {
+        4 + self.body_len()
+    }
+
+    pub fn encode(&self, dst: &mut BytesMut) {
+        let len = self.body_len();
+        dst.reserve(4 + len);
+        dst.put_u32(len as u32);
+        let Some(id) = self.id() else {
+            return;
+        };
+        dst.put_u8(id as u8);
+        match self {
+            Message::Have { index }
+            | Message::SuggestPiece { index }
+            | Message::AllowedFast { index } => dst.put_u32(*index),
+            Message::Bitfield(bits) => dst.put_slice(bits),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_slice(block);
+            }
+            Message::Port(port) => dst.put_u16(*port),
+            Message::Extended { id, payload } => {
+                dst.put_u8(*id);
+                dst.put_slice(payload);
+            }
+            _ => {}
+        }
+    }
+
+    /// `body` is everything after the length prefix: the id byte followed by the payload
+    pub fn decode(mut body: &[u8]) -> Result<Self, PeerError> {
+        if body.is_empty() {
+            return Ok(Message::KeepAlive);
+        }
+        let id = body.get_u8();
+        // Fixed-size messages must match exactly; anything else means we've lost framing
+        let exact = |n: usize, body: &[u8]| {
+            if body.len() == n {
+                Ok(())
+            } else {
+                Err(PeerError::violation(format!(
+                    "message {id} has a {} byte payload, expected {n}",
+                    body.len()
+                )))
+            }
+        };
+        let at_least = |n: usize, body: &[u8]| {
+            if body.len() >= n {
+                Ok(())
+            } else {
+                Err(PeerError::violation(format!(
+                    "message {id} payload too short"
+                )))
+            }
+        };
+        let msg = match id {
+            x if x == MsgId::Choke as u8 => {
+                exact(0, body)?;
+                Message::Choke
+            }
+            x if x == MsgId::Unchoke as u8 => {
+                exact(0, body)?;
+                Message::Unchoke
+            }
+            x if x == MsgId::Interested as u8 => {
+                exact(0, body)?;
+                Message::Interested
+            }
+            x if x == MsgId::NotInterested as u8 => {
+                exact(0, body)?;
+                Message::NotInterested
+            }
+            x if x == MsgId::Have as u8 => {
+                exact(4, body)?;
+                Message::Have {
+                    index: body.get_u32(),
+                }
+            }
+            x if x == MsgId::Bitfield as u8 => Message::Bitfield(body.to_vec()),
+            x if x == MsgId::Request as u8 => {
+                exact(12, body)?;
+                Message::Request {
+                    index: body.get_u32(),
+                    begin: body.get_u32(),
+                    length: body.get_u32(),
+                }
+            }
+            x if x == MsgId::Piece as u8 => {
+                at_least(8, body)?;
+                Message::Piece {
+                    index: body.get_u32(),
+                    begin: body.get_u32(),
+                    block: body.to_vec(),
+                }
+            }
+            x if x == MsgId::Cancel as u8 => {
+                exact(12, body)?;
+                Message::Cancel {
+                    index: body.get_u32(),
+                    begin: body.get_u32(),
+                    length: body.get_u32(),
+                }
+            }
+            x if x == MsgId::Port as u8 => {
+                exact(2, body)?;
+                Message::Port(body.get_u16())
+            }
+            x if x == MsgId::SuggestPiece as u8 => {
+                exact(4, body)?;
+                Message::SuggestPiece {
+                    index: body.get_u32(),
+                }
+            }
+            x if x == MsgId::HaveAll as u8 => {
+                exact(0, body)?;
+                Message::HaveAll
+            }
+            x if x == MsgId::HaveNone as u8 => {
+                exact(0, body)?;
+                Message::HaveNone
+            }
+            x if x == MsgId::RejectRequest as u8 => {
+                exact(12, body)?;
+                Message::RejectRequest {
+                    index: body.get_u32(),
+                    begin: body.get_u32(),
+                    length: body.get_u32(),
+                }
+            }
+            x if x == MsgId::AllowedFast as u8 => {
+                exact(4, body)?;
+                Message::AllowedFast {
+                    index: body.get_u32(),
+                }
+            }
+            x if x == MsgId::Extended as u8 => {
+                at_least(1, body)?;
+                Message::Extended {
+                    id: body.get_u8(),
+                    payload: body.to_vec(),
+                }
+            }
+            other => return Err(PeerError::violation(format!("unknown message id {other}"))),
+        };
+        Ok(msg)
+    }
+}
+
+/// Largest frame we accept. A 16 KiB block is ~16 KiB on the wire and even a bitfield for
+/// millions of pieces stays well below this; without a cap a peer can make us allocate 4 GiB.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
+/// Length-prefixed framing for use with tokio_util::codec::Framed over any AsyncRead + AsyncWrite
+#[derive(Debug, Clone, Copy)]
+pub struct MessageCodec {
+    max_len: usize,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        Self::with_max_len(MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self { max_len }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = PeerError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, PeerError> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        // Check before reserving so the length prefix alone can't drive allocation
+        if len > self.max_len {
+            return Err(PeerError::violation(format!(
+                "{len} byte message exceeds the {} byte limit",
+                self.max_len
+            )));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let body = src.split_to(len);
+        Message::decode(&body).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = PeerError;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> Result<(), PeerError> {
+        msg.encode(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    fn all_messages() -> Vec<Message> {
+        vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 0xdead_beef },
+            Message::Bitfield(vec![0b1010_0000, 0xff, 0]),
+            Message::Bitfield(Vec::new()),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 7,
+                begin: 32768,
+                block: (0..=255).collect(),
+            },
+            Message::Piece {
+                index: 0,
+                begin: 0,
+                block: Vec::new(),
+            },
+            Message::Cancel {
+                index: 3,
+                begin: 0,
+                length: 1,
+            },
+            Message::Port(6881),
+            Message::SuggestPiece { index: 42 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest {
+                index: u32::MAX,
+                begin: 1,
+                length: 2,
+            },
+            Message::AllowedFast { index: 9 },
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md11:ut_metadatai1eee".to_vec(),
+            },
+            Message::Extended {
+                id: 3,
+                payload: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip_every_message() {
+        for msg in all_messages() {
+            let mut buf = BytesMut::new();
+            MessageCodec::new().encode(msg.clone(), &mut buf).unwrap();
+            assert_eq!(buf.len(), 4 + msg.body_len(), "{msg:?}");
+            let decoded = MessageCodec::new().decode(&mut buf).unwrap();
+            assert_eq!(decoded, Some(msg));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn wire_format_matches_spec() {
+        let mut buf = BytesMut::new();
+        Message::Interested.encode(&mut buf);
+        assert_eq!(&buf[..], &[0, 0, 0, 1, 2]);
+
+        buf.clear();
+        Message::Request {
+            index: 1,
+            begin: 2,
+            length: 3,
+        }
+        .encode(&mut buf);
+        assert_eq!(
+            &buf[..],
+            &[0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+
+        buf.clear();
+        Message::KeepAlive.encode(&mut buf);
+        assert_eq!(&buf[..], &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let mut wire = BytesMut::new();
+        for msg in all_messages() {
+            msg.encode(&mut wire);
+        }
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in wire {
+            buf.put_u8(byte);
+            while let Some(msg) = MessageCodec::new().decode(&mut buf).unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert_eq!(decoded, all_messages());
+    }
+
+    #[test]
+    fn rejects_bad_payload_lengths_and_unknown_ids() {
+        let bodies: [&[u8]; 9] = [
+            &[4, 0, 0],
+            &[4, 0, 0, 0, 0, 0],
+            &[6, 0, 0, 0, 1],
+            &[7, 0],
+            &[9, 1],
+            &[1, 0],
+            &[14, 0, 0],
+            &[20],
+            &[99],
+        ];
+        for body in bodies {
+            let mut buf = BytesMut::new();
+            buf.put_u32(body.len() as u32);
+            buf.put_slice(body);
+            assert!(
+                matches!(
+                    MessageCodec::new().decode(&mut buf),
+                    Err(PeerError::ProtocolViolation(_))
+                ),
+                "{body:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_length_prefix_without_allocating() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(u32::MAX);
+        buf.put_u8(7);
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buf),
+            Err(PeerError::ProtocolViolation(_))
+        ));
+        assert!(buf.capacity() < 1024);
+
+        let mut buf = BytesMut::new();
+        Message::Bitfield(vec![0; 64]).encode(&mut buf);
+        assert!(MessageCodec::with_max_len(32).decode(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn round_trip_over_framed_stream() {
+        let (a, b) = tokio::io::duplex(64);
+        let mut tx = Framed::new(a, MessageCodec::new());
+        let mut rx = Framed::new(b, MessageCodec::new());
+
+        let sender = tokio::spawn(async move {
+            for msg in all_messages() {
+                tx.send(msg).await.unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        for _ in 0..all_messages().len() {
+            received.push(rx.next().await.unwrap().unwrap());
+        }
+        sender.await.unwrap();
+        assert_eq!(received, all_messages());
+    }
+}
@@ -0,0 +1,358 @@
+//! BEP 9 metadata exchange over the BEP 10 extension protocol
+
+use crate::bencode::decode::{decode_bencode, value_len};
+use crate::bencode::encode::encode_bencode;
+use crate::bencode::error::BencodeError;
+use crate::peers::connection::{PeerConnection, PeerTimeouts};
+use crate::peers::error::PeerError;
+use crate::peers::message::Message;
+use crate::peers::mse::EncryptionPolicy;
+use crate::peers::peer::Handshake;
+use crate::peers::transport::Transports;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::net::SocketAddrV4;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Far above any real info dict; stops a peer from making us allocate arbitrary amounts
+const MAX_METADATA_SIZE: usize = 64 * 1024 * 1024;
+// Extended message id we advertise for ut_metadata; peers send metadata to us with it
+const UT_METADATA_ID: u8 = 1;
+pub const CLIENT_VERSION: &str = concat!("RusTor/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+    /// How many outstanding requests the peer is willing to queue
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<u64>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_message(&self) -> Result<Message, PeerError> {
+        Ok(Message::Extended {
+            id: 0,
+            payload: encode_local(self)?,
+        })
+    }
+
+    pub fn from_payload(payload: &[u8]) -> Result<Self, PeerError> {
+        decode_bencode(payload)
+            .map_err(|e| PeerError::violation(format!("bad extended handshake: {e}")))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMessage {
+    msg_type: i64,
+    piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+/// Download the info dictionary from a peer that completed a handshake with the extension bit set
+pub async fn fetch_metadata<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut PeerConnection<S>,
+    info_hash: [u8; 20],
+) -> Result<Vec<u8>, PeerError> {
+    let ours = ExtendedHandshake {
+        m: BTreeMap::from([("ut_metadata".to_string(), UT_METADATA_ID as i64)]),
+        v: Some(CLIENT_VERSION.to_string()),
+        ..Default::default()
+    };
+    conn.send(ours.to_message()?).await?;
+
+    let theirs = loop {
+        if let Message::Extended { id: 0, payload } = conn.recv().await? {
+            break ExtendedHandshake::from_payload(&payload)?;
+        }
+    };
+    let their_id = theirs
+        .m
+        .get("ut_metadata")
+        .copied()
+        .filter(|&id| id > 0 && id <= u8::MAX as i64)
+        .ok_or_else(|| PeerError::violation("peer does not support ut_metadata"))?
+        as u8;
+    let size = theirs
+        .metadata_size
+        .ok_or_else(|| PeerError::violation("peer did not advertise metadata_size"))?
+        as usize;
+    if size == 0 || size > MAX_METADATA_SIZE {
+        return Err(PeerError::violation(format!(
+            "peer advertised an invalid metadata_size of {size}"
+        )));
+    }
+
+    let piece_count = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..piece_count {
+        let req = MetadataMessage {
+            msg_type: MSG_REQUEST,
+            piece: piece as i64,
+            total_size: None,
+        };
+        conn.feed(Message::Extended {
+            id: their_id,
+            payload: encode_local(&req)?,
+        })
+        .await?;
+    }
+    conn.flush().await?;
+
+    let mut metadata = vec![0u8; size];
+    let mut received = vec![false; piece_count];
+    while received.iter().any(|r| !r) {
+        let limit = conn.timeouts().request;
+        let body = match conn.recv_timeout(limit, "waiting for metadata").await? {
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload,
+            } => payload,
+            _ => continue,
+        };
+        // Data messages carry the raw piece right after the bencoded header
+        let body = &body[..];
+        let (header_len, header) = parse_header(body)
+            .map_err(|e| PeerError::violation(format!("bad ut_metadata message: {e}")))?;
+        match header.msg_type {
+            MSG_DATA => {
+                let piece = usize::try_from(header.piece)
+                    .ok()
+                    .filter(|&p| p < piece_count)
+                    .ok_or_else(|| PeerError::violation("out of range metadata piece"))?;
+                if header.total_size.is_some_and(|total| total != size as i64) {
+                    return Err(PeerError::violation(
+                        "metadata piece disagrees with the advertised metadata_size",
+                    ));
+                }
+                let start = piece * METADATA_PIECE_SIZE;
+                let expected = std::cmp::min(METADATA_PIECE_SIZE, size - start);
+                let data = &body[header_len..];
+                if data.len() != expected {
+                    return Err(PeerError::violation(format!(
+                        "metadata piece {piece} has wrong length"
+                    )));
+                }
+                metadata[start..start + expected].copy_from_slice(data);
+                received[piece] = true;
+            }
+            MSG_REJECT => {
+                return Err(PeerError::violation(format!(
+                    "peer rejected metadata piece {}",
+                    header.piece
+                )));
+            }
+            // We don't serve metadata, so the peer's own requests go unanswered
+            MSG_REQUEST => {}
+            other => {
+                return Err(PeerError::violation(format!(
+                    "unknown ut_metadata msg_type {other}"
+                )));
+            }
+        }
+    }
+
+    let got: [u8; 20] = Sha1::digest(&metadata).into();
+    if got != info_hash {
+        let reason = PeerError::violation("metadata does not match info hash");
+        return Err(conn.disconnect(reason).await);
+    }
+    Ok(metadata)
+}
+
+fn parse_header(body: &[u8]) -> Result<(usize, MetadataMessage), BencodeError> {
+    let len = value_len(body)?;
+    Ok((len, decode_bencode(&body[..len])?))
+}
+
+// Encoding our own messages only fails on a programming error, not because of the peer
+fn encode_local<T: Serialize>(value: &T) -> Result<Vec<u8>, PeerError> {
+    encode_bencode(value).map_err(|e| PeerError::Io(std::io::Error::other(e)))
+}
+
+/// Try peers one after another until one hands over verified metadata
+pub async fn fetch_metadata_from_peers(
+    peers: &[SocketAddrV4],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    transports: &Transports,
+    timeouts: PeerTimeouts,
+    encryption: EncryptionPolicy,
+) -> Result<Vec<u8>, PeerError> {
+    let handshake = Handshake::new(info_hash, peer_id);
+    for peer in peers {
+        let attempt = async {
+            let (mut conn, remote) =
+                PeerConnection::establish(*peer, &handshake, transports, timeouts, encryption)
+                    .await?;
+            if !remote.supports_extensions() {
+                return Err(PeerError::violation(
+                    "peer does not support the extension protocol",
+                ));
+            }
+            fetch_metadata(&mut conn, info_hash).await
+        };
+        // A peer that fails is skipped; the caller only learns that none of them worked
+        if let Ok(metadata) = attempt.await {
+            return Ok(metadata);
+        }
+    }
+    Err(PeerError::MetadataUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_bencode::value::Value;
+    use tokio::io::{DuplexStream, duplex};
+
+    // An info dict spanning a full and a partial metadata piece
+    fn info_dict() -> Vec<u8> {
+        encode_bencode(&BTreeMap::from([
+            ("length", Value::Int(1000 * 16384)),
+            ("name", Value::Bytes(b"file.bin".to_vec())),
+            ("piece length", Value::Int(16384)),
+            ("pieces", Value::Bytes(vec![7; 20 * 1000])),
+        ]))
+        .unwrap()
+    }
+
+    fn data_message(piece: i64, total_size: usize, data: &[u8]) -> Message {
+        let header = MetadataMessage {
+            msg_type: MSG_DATA,
+            piece,
+            total_size: Some(total_size as i64),
+        };
+        let mut payload = encode_bencode(&header).unwrap();
+        payload.extend_from_slice(data);
+        Message::Extended {
+            id: UT_METADATA_ID,
+            payload,
+        }
+    }
+
+    /// Peer side of the exchange: answers our handshake advertising `metadata`, then hands
+    /// each requested piece to `reply`
+    async fn serve(
+        stream: DuplexStream,
+        metadata: Vec<u8>,
+        reply: impl Fn(i64, &[u8]) -> Message,
+    ) -> Result<(), PeerError> {
+        let mut conn = PeerConnection::new(stream, PeerTimeouts::default());
+        let theirs = ExtendedHandshake {
+            m: BTreeMap::from([("ut_metadata".to_string(), 3)]),
+            metadata_size: Some(metadata.len() as u64),
+            ..Default::default()
+        };
+        conn.send(theirs.to_message()?).await?;
+        loop {
+            let Message::Extended { id, payload } = conn.recv().await? else {
+                continue;
+            };
+            if id == 0 {
+                let ours = ExtendedHandshake::from_payload(&payload)?;
+                assert_eq!(ours.m["ut_metadata"], UT_METADATA_ID as i64);
+                continue;
+            }
+            assert_eq!(id, 3);
+            let (_, request) = parse_header(&payload).unwrap();
+            assert_eq!(request.msg_type, MSG_REQUEST);
+            let start = request.piece as usize * METADATA_PIECE_SIZE;
+            let end = (start + METADATA_PIECE_SIZE).min(metadata.len());
+            conn.send(reply(request.piece, &metadata[start..end]))
+                .await?;
+        }
+    }
+
+    async fn fetch_from(
+        metadata: Vec<u8>,
+        info_hash: [u8; 20],
+        reply: impl Fn(i64, &[u8]) -> Message + Send + 'static,
+    ) -> Result<Vec<u8>, PeerError> {
+        let (ours, theirs) = duplex(64 * 1024);
+        tokio::spawn(serve(theirs, metadata, reply));
+        let mut conn = PeerConnection::new(ours, PeerTimeouts::default());
+        fetch_metadata(&mut conn, info_hash).await
+    }
+
+    #[tokio::test]
+    async fn fetches_the_info_dict_from_a_peer() {
+        let info = info_dict();
+        assert!(info.len() > METADATA_PIECE_SIZE);
+        let hash = Sha1::digest(&info).into();
+        let size = info.len();
+        let got = fetch_from(info.clone(), hash, move |piece, data| {
+            data_message(piece, size, data)
+        })
+        .await
+        .unwrap();
+        assert_eq!(got, info);
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_that_does_not_match_the_info_hash() {
+        let info = info_dict();
+        let size = info.len();
+        let err = fetch_from(info, [0; 20], move |piece, data| {
+            data_message(piece, size, data)
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PeerError::ProtocolViolation(m) if m.contains("info hash")));
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_headers() {
+        let info = info_dict();
+        let hash: [u8; 20] = Sha1::digest(&info).into();
+        let size = info.len();
+
+        let err = fetch_from(info.clone(), hash, move |piece, data| {
+            data_message(piece, size + 1, data)
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PeerError::ProtocolViolation(m) if m.contains("metadata_size")));
+
+        let err = fetch_from(info.clone(), hash, |piece, _| {
+            let header = MetadataMessage {
+                msg_type: 9,
+                piece,
+                total_size: None,
+            };
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload: encode_bencode(&header).unwrap(),
+            }
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PeerError::ProtocolViolation(m) if m.contains("msg_type")));
+
+        let err = fetch_from(info, hash, |piece, _| {
+            let header = MetadataMessage {
+                msg_type: MSG_REJECT,
+                piece,
+                total_size: None,
+            };
+            Message::Extended {
+                id: UT_METADATA_ID,
+                payload: encode_bencode(&header).unwrap(),
+            }
+        })
+        .await
+        .unwrap_err();
+        assert!(matches!(err, PeerError::ProtocolViolation(m) if m.contains("rejected")));
+    }
+}
@@ -0,0 +1,16 @@
+//! The peer wire protocol: connections, messages, piece picking and the download swarm.
+
+pub mod bitfield;
+pub mod blame;
+pub mod connection;
+pub mod error;
+pub mod message;
+pub mod metadata;
+pub mod mse;
+pub mod peer;
+pub mod picker;
+pub mod pipeline;
+pub mod ratelimit;
+pub mod stats;
+pub mod swarm;
+pub mod transport;
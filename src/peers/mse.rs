@@ -0,0 +1,542 @@
+//! Message Stream Encryption: a Diffie-Hellman key exchange followed by RC4, negotiated
+//! before the BitTorrent handshake so the connection doesn't look like BitTorrent on the wire.
+//!
+//! The initiator A and the receiver B exchange DH public keys with random padding, then A
+//! proves it knows the torrent's info hash (SKEY) and offers crypto methods. B picks one,
+//! and from there each side encrypts with its own RC4 key derived from the shared secret
+//! and SKEY, or both continue in plaintext if that was picked.
+
+use crate::peers::error::PeerError;
+use bytes::{Buf, BytesMut};
+use num_bigint::BigUint;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Whether peer connections use Message Stream Encryption
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    /// Plaintext only; encrypted incoming connections are refused
+    Disabled,
+    /// Try encryption first on outgoing connections and fall back to plaintext; accept both
+    #[default]
+    Enabled,
+    /// Encrypted connections only, in both directions
+    Forced,
+}
+
+impl fmt::Display for EncryptionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EncryptionPolicy::Disabled => "disabled",
+            EncryptionPolicy::Enabled => "enabled",
+            EncryptionPolicy::Forced => "forced",
+        })
+    }
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "disabled" => Ok(EncryptionPolicy::Disabled),
+            "enabled" => Ok(EncryptionPolicy::Enabled),
+            "forced" => Ok(EncryptionPolicy::Forced),
+            _ => Err(format!("{s:?} is not one of disabled, enabled, forced")),
+        }
+    }
+}
+
+// The 768-bit prime from the spec; the generator is 2
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+static P: LazyLock<BigUint> =
+    LazyLock::new(|| BigUint::parse_bytes(PRIME.as_bytes(), 16).expect("valid hex"));
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+// A plaintext connection starts with the handshake's length byte and protocol name
+const PLAINTEXT_START: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// RC4 keyed from the shared secret. The first 1024 bytes of keystream are thrown away, as
+/// the spec requires.
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut s = [0u8; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        let mut rc4 = Self { s, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut out = [0u8; KEY_LEN];
+    out[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    fn generate() -> Self {
+        // The spec asks for at least 128 bits of private key; 160 is what clients use
+        let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+        let public = to_key_bytes(&BigUint::from(2u32).modpow(&private, &P));
+        Self { private, public }
+    }
+
+    fn secret(&self, remote: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        to_key_bytes(&BigUint::from_bytes_be(remote).modpow(&self.private, &P))
+    }
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let mut pad = vec![0u8; rng.gen_range(0..=MAX_PAD)];
+    rng.fill(&mut pad[..]);
+    pad
+}
+
+fn failed(msg: &str) -> PeerError {
+    PeerError::violation(format!("encryption handshake: {msg}"))
+}
+
+/// A peer connection after MSE negotiation, encrypted or not. Bytes read during negotiation
+/// that belong to the payload are replayed first.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    // Already decrypted payload read ahead during the handshake
+    prefix: BytesMut,
+    // Encrypted bytes accepted by poll_write but not yet written to `inner`
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<S> MseStream<S> {
+    /// A stream that skipped MSE altogether
+    pub fn plaintext(inner: S) -> Self {
+        Self::new(inner, None, None, BytesMut::new())
+    }
+
+    fn new(
+        inner: S,
+        read_cipher: Option<Rc4>,
+        write_cipher: Option<Rc4>,
+        prefix: BytesMut,
+    ) -> Self {
+        Self {
+            inner,
+            read_cipher,
+            write_cipher,
+            prefix,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.pending.len() {
+            let n =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.pending.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..n]);
+            this.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // RC4 state can't be rewound, so whatever gets encrypted must be written eventually
+        ready!(this.poll_pending(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut this.pending);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+// Reads that first use up bytes read past a sync point
+struct Reader<'a, S> {
+    stream: &'a mut S,
+    buf: BytesMut,
+}
+
+impl<S: AsyncRead + Unpin> Reader<'_, S> {
+    async fn fill(&mut self) -> Result<(), PeerError> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(PeerError::Disconnected);
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, n: usize) -> Result<BytesMut, PeerError> {
+        while self.buf.len() < n {
+            self.fill().await?;
+        }
+        Ok(self.buf.split_to(n))
+    }
+
+    async fn read_decrypted(&mut self, n: usize, cipher: &mut Rc4) -> Result<BytesMut, PeerError> {
+        let mut data = self.read(n).await?;
+        cipher.apply(&mut data);
+        Ok(data)
+    }
+
+    // Skip up to `max_skip` bytes of padding to just past `pattern`
+    async fn sync(&mut self, pattern: &[u8], max_skip: usize) -> Result<(), PeerError> {
+        loop {
+            if let Some(pos) = self.buf.windows(pattern.len()).position(|w| w == pattern) {
+                self.buf.advance(pos + pattern.len());
+                return Ok(());
+            }
+            if self.buf.len() >= max_skip + pattern.len() {
+                return Err(failed("no sync point after the padding"));
+            }
+            self.fill().await?;
+        }
+    }
+}
+
+/// Negotiate MSE as the connecting side for `info_hash`. With `allow_plaintext` the other
+/// side may choose to continue unencrypted.
+pub async fn initiate<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hash: [u8; 20],
+    allow_plaintext: bool,
+) -> Result<MseStream<S>, PeerError> {
+    let keys = KeyPair::generate();
+    stream
+        .write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+    let mut reader = Reader {
+        stream: &mut stream,
+        buf: BytesMut::new(),
+    };
+    let remote: [u8; KEY_LEN] = reader.read(KEY_LEN).await?[..].try_into().unwrap();
+    let secret = keys.secret(&remote);
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let provide = if allow_plaintext {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    } else {
+        CRYPTO_RC4
+    };
+    let req2 = hash(&[b"req2", &info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    let skey: Vec<u8> = req2.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+    let pad = random_pad();
+    // The BitTorrent handshake goes after this rather than as initial payload
+    let mut offer = [
+        &VC[..],
+        &provide.to_be_bytes(),
+        &(pad.len() as u16).to_be_bytes(),
+        &pad,
+        &0u16.to_be_bytes(),
+    ]
+    .concat();
+    encrypt.apply(&mut offer);
+    let msg = [&hash(&[b"req1", &secret])[..], &skey, &offer].concat();
+    reader.stream.write_all(&msg).await?;
+
+    // B's reply starts with the encrypted VC, somewhere after its padding
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    reader.sync(&vc, MAX_PAD).await?;
+    let reply = reader.read_decrypted(6, &mut decrypt).await?;
+    let select = u32::from_be_bytes(reply[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(reply[4..6].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(failed("padding too long"));
+    }
+    reader.read_decrypted(pad_len, &mut decrypt).await?;
+
+    let rest = reader.buf;
+    match select {
+        CRYPTO_RC4 => {
+            let mut prefix = rest;
+            decrypt.apply(&mut prefix);
+            Ok(MseStream::new(stream, Some(decrypt), Some(encrypt), prefix))
+        }
+        CRYPTO_PLAINTEXT if allow_plaintext => Ok(MseStream::new(stream, None, None, rest)),
+        _ => Err(failed("peer selected a method we didn't offer")),
+    }
+}
+
+/// Take an incoming connection under `policy`: a plaintext BitTorrent handshake is passed
+/// through as is, anything else is taken as MSE for one of `info_hashes`
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<MseStream<S>, PeerError> {
+    let mut reader = Reader {
+        stream: &mut stream,
+        buf: BytesMut::new(),
+    };
+    let start = reader.read(PLAINTEXT_START.len()).await?;
+    if &start[..] == PLAINTEXT_START {
+        if policy == EncryptionPolicy::Forced {
+            return Err(failed("plaintext connections are not allowed"));
+        }
+        let mut prefix = start;
+        prefix.unsplit(reader.buf);
+        return Ok(MseStream::new(stream, None, None, prefix));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(failed("encrypted connections are not allowed"));
+    }
+
+    let mut remote = [0u8; KEY_LEN];
+    remote[..start.len()].copy_from_slice(&start);
+    remote[start.len()..].copy_from_slice(&reader.read(KEY_LEN - start.len()).await?);
+    let keys = KeyPair::generate();
+    reader
+        .stream
+        .write_all(&[&keys.public[..], &random_pad()].concat())
+        .await?;
+    let secret = keys.secret(&remote);
+
+    reader.sync(&hash(&[b"req1", &secret]), MAX_PAD).await?;
+    let skey = reader.read(20).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = info_hashes
+        .iter()
+        .find(|ih| {
+            let req2 = hash(&[b"req2", &ih[..]]);
+            req2.iter()
+                .zip(req3)
+                .map(|(a, b)| a ^ b)
+                .eq(skey.iter().copied())
+        })
+        .ok_or_else(|| failed("unknown torrent"))?;
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let offer = reader.read_decrypted(14, &mut decrypt).await?;
+    if offer[..8] != VC {
+        return Err(failed("bad verification constant"));
+    }
+    let provide = u32::from_be_bytes(offer[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes(offer[12..14].try_into().unwrap()) as usize;
+    if pad_len > MAX_PAD {
+        return Err(failed("padding too long"));
+    }
+    reader.read_decrypted(pad_len, &mut decrypt).await?;
+    let ia_len = reader.read_decrypted(2, &mut decrypt).await?;
+    let ia_len = u16::from_be_bytes(ia_len[..].try_into().unwrap()) as usize;
+    let initial = reader.read_decrypted(ia_len, &mut decrypt).await?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Forced {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(failed("no crypto method in common"));
+    };
+    let pad = random_pad();
+    let mut reply = [
+        &VC[..],
+        &select.to_be_bytes(),
+        &(pad.len() as u16).to_be_bytes(),
+        &pad,
+    ]
+    .concat();
+    encrypt.apply(&mut reply);
+    reader.stream.write_all(&reply).await?;
+
+    // The initial payload always comes encrypted; what follows depends on the method
+    let mut prefix = initial;
+    let mut rest = reader.buf;
+    if select == CRYPTO_RC4 {
+        decrypt.apply(&mut rest);
+        prefix.unsplit(rest);
+        Ok(MseStream::new(stream, Some(decrypt), Some(encrypt), prefix))
+    } else {
+        prefix.unsplit(rest);
+        Ok(MseStream::new(stream, None, None, prefix))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    const HASH: [u8; 20] = [7; 20];
+
+    async fn exchange(
+        a: &mut MseStream<tokio::io::DuplexStream>,
+        b: &mut MseStream<tokio::io::DuplexStream>,
+    ) {
+        a.write_all(b"hello from a").await.unwrap();
+        a.flush().await.unwrap();
+        b.write_all(b"hello from b").await.unwrap();
+        b.flush().await.unwrap();
+        let mut buf = [0u8; 12];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from a");
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from b");
+    }
+
+    #[test]
+    fn rc4_matches_a_known_vector() {
+        // RFC 6229 keystream for key 0x0102030405 at offset 1024, which Rc4::new skips to
+        let mut rc4 = Rc4::new(&[1, 2, 3, 4, 5]);
+        let mut out = [0u8; 8];
+        rc4.apply(&mut out);
+        assert_eq!(out, [0x30, 0xab, 0xbc, 0xc7, 0xc2, 0x0b, 0x01, 0x60]);
+    }
+
+    #[tokio::test]
+    async fn negotiates_rc4_and_falls_back_to_plaintext() {
+        let (a, b) = duplex(4096);
+        let (a, b) = tokio::join!(
+            initiate(a, HASH, true),
+            accept(b, &[[1; 20], HASH], EncryptionPolicy::Enabled)
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        assert!(a.is_encrypted() && b.is_encrypted());
+        exchange(&mut a, &mut b).await;
+
+        // A peer without MSE starts with the plaintext handshake, which is replayed
+        let (mut a, b) = duplex(4096);
+        a.write_all(PLAINTEXT_START).await.unwrap();
+        let mut b = accept(b, &[HASH], EncryptionPolicy::Enabled).await.unwrap();
+        assert!(!b.is_encrypted());
+        let mut start = [0u8; 20];
+        b.read_exact(&mut start).await.unwrap();
+        assert_eq!(&start, PLAINTEXT_START);
+    }
+
+    #[tokio::test]
+    async fn enforces_the_policy() {
+        let (mut a, b) = duplex(4096);
+        a.write_all(PLAINTEXT_START).await.unwrap();
+        assert!(accept(b, &[HASH], EncryptionPolicy::Forced).await.is_err());
+
+        let (a, b) = duplex(4096);
+        let (a, b) = tokio::join!(
+            initiate(a, HASH, false),
+            accept(b, &[HASH], EncryptionPolicy::Disabled)
+        );
+        assert!(a.is_err() && b.is_err());
+
+        let (a, b) = duplex(4096);
+        let (a, b) = tokio::join!(
+            initiate(a, HASH, false),
+            accept(b, &[[1; 20]], EncryptionPolicy::Forced)
+        );
+        assert!(a.is_err() && b.is_err());
+    }
+}
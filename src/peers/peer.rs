@@ -0,0 +1,101 @@
+use crate::peers::error::PeerError;
+use crate::peers::message::MessageCodec;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+pub type PeerFramed<S> = Framed<S, MessageCodec>;
+
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+pub struct Handshake {
+    pub length: u8,
+    pub protocol: [u8; 19],
+    pub reserved: [u8; 8],
+    pub infohash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(infohash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut reserved = [0u8; 8];
+        reserved[5] |= EXTENSION_PROTOCOL_BIT;
+        Self {
+            length: 19,
+            protocol: *b"BitTorrent protocol",
+            reserved,
+            infohash,
+            peer_id,
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8; 68]) -> Self {
+        let mut hs = Self::new([0; 20], [0; 20]);
+        hs.length = bytes[0];
+        hs.protocol.copy_from_slice(&bytes[1..20]);
+        hs.reserved.copy_from_slice(&bytes[20..28]);
+        hs.infohash.copy_from_slice(&bytes[28..48]);
+        hs.peer_id.copy_from_slice(&bytes[48..68]);
+        hs
+    }
+
+    /// BEP 10 extension protocol support
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.push(self.length);
+        bytes.extend_from_slice(&self.protocol);
+        bytes.extend_from_slice(&self.reserved);
+        bytes.extend_from_slice(&self.infohash);
+        bytes.extend_from_slice(&self.peer_id);
+        bytes
+    }
+
+    /// Returns the remote side's handshake
+    pub async fn send_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+        handshake: &Handshake,
+    ) -> Result<Handshake, PeerError> {
+        stream.write_all(&handshake.to_bytes()).await?;
+        let response = Self::read_from(stream).await?;
+        if response.infohash != handshake.infohash {
+            return Err(PeerError::violation("Mismatched hash in handshake!..."));
+        }
+        Ok(response)
+    }
+
+    /// Read the other side's handshake, e.g. on an incoming connection before answering
+    pub async fn read_from<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Handshake, PeerError> {
+        let mut response = [0u8; 68];
+        stream.read_exact(&mut response).await?;
+        if response[0] != 19 || &response[1..20] != b"BitTorrent protocol" {
+            return Err(PeerError::violation("Invalid handshake response"));
+        }
+        Ok(Handshake::from_bytes(&response))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MsgId {
+    Choke = 0,
+    Unchoke = 1,
+    Interested = 2,
+    NotInterested = 3,
+    Have = 4,
+    Bitfield = 5,
+    Request = 6,
+    Piece = 7,
+    Cancel = 8,
+    Port = 9,
+    // BEP 6 fast extension
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    // BEP 10 extension protocol
+    Extended = 20,
+}
@@ -0,0 +1,595 @@
+//! Decides which blocks to request from which peer, across every connection of a torrent
+
+use crate::peers::bitfield::Bitfield;
+use crate::storage::files::FilePriority;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use tokio::time::Instant;
+
+/// The block size nearly every client requests, and the largest most of them will serve
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    // `avoid` is a peer that let an earlier request for this block time out or rejected it
+    Open { avoid: Option<SocketAddr> },
+    Requested(SocketAddr),
+    // Requested again from this peer because the first one was late for a deadline
+    Racing(SocketAddr),
+    Received,
+}
+
+struct PartialPiece {
+    blocks: Vec<BlockState>,
+    data: Vec<u8>,
+    received: usize,
+    // Who sent each block, to blame if the piece fails its hash check
+    from: Vec<Option<SocketAddr>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockOutcome {
+    // Unrequested, duplicate or misaligned data
+    Ignored,
+    Stored,
+    // Every block of the piece is in; the data still needs its hash checked
+    Completed(Vec<u8>),
+}
+
+pub struct PiecePicker {
+    piece_length: u64,
+    total_length: u64,
+    block_size: u32,
+    have: Bitfield,
+    // How many connected peers have each piece, for rarest-first
+    availability: Vec<u32>,
+    priorities: Vec<FilePriority>,
+    partial: BTreeMap<u32, PartialPiece>,
+    // Completed pieces waiting for their hash check, with the peer that sent each block
+    verifying: HashMap<u32, Vec<SocketAddr>>,
+    // When someone reading the data needs each of these pieces
+    deadlines: HashMap<u32, Instant>,
+    // In sequential mode, the piece new ones are started from
+    sequential: Option<u32>,
+}
+
+impl PiecePicker {
+    pub fn new(piece_length: u64, total_length: u64) -> Self {
+        let count = total_length.div_ceil(piece_length) as usize;
+        Self {
+            piece_length,
+            total_length,
+            block_size: BLOCK_SIZE,
+            have: Bitfield::new(count),
+            availability: vec![0; count],
+            priorities: vec![FilePriority::Normal; count],
+            partial: BTreeMap::new(),
+            verifying: HashMap::new(),
+            deadlines: HashMap::new(),
+            sequential: None,
+        }
+    }
+
+    /// Request blocks of `block_size` bytes instead of [`BLOCK_SIZE`]
+    pub fn with_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn piece_len(&self, piece: u32) -> u64 {
+        let start = piece as u64 * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start))
+    }
+
+    /// The piece holding byte `offset` of the torrent
+    pub fn piece_at(&self, offset: u64) -> u32 {
+        (offset / self.piece_length) as u32
+    }
+
+    /// Where `piece` starts in the torrent
+    pub fn piece_offset(&self, piece: u32) -> u64 {
+        piece as u64 * self.piece_length
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.count() == self.have.len()
+    }
+
+    /// Every piece that isn't skipped is verified
+    pub fn is_finished(&self) -> bool {
+        (0..self.have.len()).all(|i| self.have.get(i) || !self.is_wanted(i as u32))
+    }
+
+    pub fn is_wanted(&self, piece: u32) -> bool {
+        self.priorities[piece as usize] != FilePriority::Skip
+    }
+
+    /// Set the priority of every piece. Higher ones are started first; skipped pieces are
+    /// not requested, and any of their blocks already in are dropped.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+        let priorities = &self.priorities;
+        self.partial
+            .retain(|&piece, _| priorities[piece as usize] != FilePriority::Skip);
+    }
+
+    /// Start new pieces in order from `from` instead of rarest first, so a file can be read
+    /// while it downloads; pieces before `from` come last. `None` goes back to rarest first.
+    pub fn set_sequential(&mut self, from: Option<u32>) {
+        self.sequential = from;
+    }
+
+    /// Fetch `piece` ahead of pieces without a deadline, earliest deadline first. Blocks still
+    /// outstanding once the deadline has passed are requested again from other peers. A
+    /// piece keeps the earliest deadline it was given.
+    pub fn set_deadline(&mut self, piece: u32, deadline: Instant) {
+        if (piece as usize) < self.have.len() && !self.have.get(piece as usize) {
+            self.deadlines
+                .entry(piece)
+                .and_modify(|d| *d = (*d).min(deadline))
+                .or_insert(deadline);
+        }
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    pub fn add_availability(&mut self, pieces: &Bitfield) {
+        for i in pieces.iter_set() {
+            self.availability[i] += 1;
+        }
+    }
+
+    pub fn remove_availability(&mut self, pieces: &Bitfield) {
+        for i in pieces.iter_set() {
+            self.availability[i] = self.availability[i].saturating_sub(1);
+        }
+    }
+
+    pub fn add_have(&mut self, piece: u32) {
+        if let Some(count) = self.availability.get_mut(piece as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Hand out up to `n` blocks for `peer`. Pieces with a deadline come first, then
+    /// partially downloaded pieces are finished, then new pieces are started by priority and
+    /// rarest first (or in order in sequential mode), so several pieces can be in flight at
+    /// once.
+    pub fn pick(&mut self, peer: SocketAddr, peer_has: &Bitfield, n: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+        if n == 0 {
+            return picked;
+        }
+        self.pick_deadlines(peer, peer_has, n, &mut picked);
+        self.pick_partial(peer, peer_has, n, false, &mut picked);
+
+        while picked.len() < n {
+            let Some(piece) = self.rarest_new_piece(peer_has) else {
+                break;
+            };
+            self.start(piece);
+            self.pick_from(piece, peer, n, false, &mut picked);
+        }
+
+        // Retry blocks this peer previously failed to deliver where nobody else has the piece
+        if picked.len() < n {
+            self.pick_partial(peer, peer_has, n, true, &mut picked);
+        }
+        picked
+    }
+
+    fn start(&mut self, piece: u32) {
+        let blocks = self.piece_len(piece).div_ceil(self.block_size as u64) as usize;
+        self.partial.insert(
+            piece,
+            PartialPiece {
+                blocks: vec![BlockState::Open { avoid: None }; blocks],
+                data: vec![0; self.piece_len(piece) as usize],
+                received: 0,
+                from: vec![None; blocks],
+            },
+        );
+    }
+
+    fn pick_deadlines(
+        &mut self,
+        peer: SocketAddr,
+        peer_has: &Bitfield,
+        n: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        let now = Instant::now();
+        let mut pieces: Vec<(Instant, u32)> = self
+            .deadlines
+            .iter()
+            .filter(|&(&p, _)| {
+                peer_has.get(p as usize) && self.is_wanted(p) && !self.verifying.contains_key(&p)
+            })
+            .map(|(&p, &deadline)| (deadline, p))
+            .collect();
+        pieces.sort_unstable();
+        for (deadline, piece) in pieces {
+            if picked.len() >= n {
+                break;
+            }
+            if !self.partial.contains_key(&piece) {
+                self.start(piece);
+            }
+            self.pick_from(piece, peer, n, false, picked);
+            if deadline <= now {
+                self.race(piece, peer, n, picked);
+            }
+        }
+    }
+
+    // Ask `peer` too for blocks of an overdue piece that another peer hasn't delivered;
+    // whichever copy arrives first is kept
+    fn race(&mut self, piece: u32, peer: SocketAddr, n: usize, picked: &mut Vec<Block>) {
+        let (block_size, piece_len) = (self.block_size, self.piece_len(piece));
+        let Some(partial) = self.partial.get_mut(&piece) else {
+            return;
+        };
+        for (i, state) in partial.blocks.iter_mut().enumerate() {
+            if picked.len() >= n {
+                break;
+            }
+            if matches!(*state, BlockState::Requested(other) if other != peer) {
+                *state = BlockState::Racing(peer);
+                picked.push(block_at(piece, i, block_size, piece_len));
+            }
+        }
+    }
+
+    fn pick_partial(
+        &mut self,
+        peer: SocketAddr,
+        peer_has: &Bitfield,
+        n: usize,
+        include_avoided: bool,
+        picked: &mut Vec<Block>,
+    ) {
+        let mut pieces: Vec<u32> = self
+            .partial
+            .keys()
+            .copied()
+            .filter(|&p| peer_has.get(p as usize))
+            .collect();
+        pieces.sort_by_key(|&p| (Reverse(self.priorities[p as usize]), self.distance(p)));
+        for piece in pieces {
+            if picked.len() >= n {
+                break;
+            }
+            self.pick_from(piece, peer, n, include_avoided, picked);
+        }
+    }
+
+    fn pick_from(
+        &mut self,
+        piece: u32,
+        peer: SocketAddr,
+        n: usize,
+        include_avoided: bool,
+        picked: &mut Vec<Block>,
+    ) {
+        let (block_size, piece_len) = (self.block_size, self.piece_len(piece));
+        let availability = self.availability[piece as usize];
+        let Some(partial) = self.partial.get_mut(&piece) else {
+            return;
+        };
+        for (i, state) in partial.blocks.iter_mut().enumerate() {
+            if picked.len() >= n {
+                break;
+            }
+            let BlockState::Open { avoid } = *state else {
+                continue;
+            };
+            if avoid == Some(peer) && !(include_avoided && availability <= 1) {
+                continue;
+            }
+            *state = BlockState::Requested(peer);
+            picked.push(block_at(piece, i, block_size, piece_len));
+        }
+    }
+
+    // How far past the sequential position a piece is; pieces before it wrap to the end
+    fn distance(&self, piece: u32) -> u32 {
+        self.sequential.map_or(0, |from| piece.wrapping_sub(from))
+    }
+
+    fn rarest_new_piece(&self, peer_has: &Bitfield) -> Option<u32> {
+        peer_has
+            .iter_set()
+            .map(|i| i as u32)
+            .filter(|&p| {
+                self.is_wanted(p)
+                    && !self.have.get(p as usize)
+                    && !self.partial.contains_key(&p)
+                    && !self.verifying.contains_key(&p)
+            })
+            .min_by_key(|&p| {
+                let priority = Reverse(self.priorities[p as usize]);
+                (priority, self.distance(p), self.availability[p as usize], p)
+            })
+    }
+
+    pub fn on_block(
+        &mut self,
+        peer: SocketAddr,
+        piece: u32,
+        begin: u32,
+        data: &[u8],
+    ) -> BlockOutcome {
+        let piece_len = self.piece_len(piece);
+        let Some(partial) = self.partial.get_mut(&piece) else {
+            return BlockOutcome::Ignored;
+        };
+        let index = (begin / self.block_size) as usize;
+        let expected = (self.block_size as u64).min(piece_len.saturating_sub(begin as u64));
+        if !begin.is_multiple_of(self.block_size)
+            || index >= partial.blocks.len()
+            || data.len() as u64 != expected
+        {
+            return BlockOutcome::Ignored;
+        }
+        if partial.blocks[index] == BlockState::Received {
+            return BlockOutcome::Ignored;
+        }
+        // Accept a late block even after its request was handed to another peer
+        let begin = begin as usize;
+        partial.data[begin..begin + data.len()].copy_from_slice(data);
+        partial.blocks[index] = BlockState::Received;
+        partial.received += 1;
+        partial.from[index] = Some(peer);
+        if partial.received < partial.blocks.len() {
+            return BlockOutcome::Stored;
+        }
+        let done = self.partial.remove(&piece).expect("piece is partial");
+        let from = done.from.into_iter().flatten().collect();
+        self.verifying.insert(piece, from);
+        BlockOutcome::Completed(done.data)
+    }
+
+    /// Result of checking a completed piece; a failed piece is downloaded again from scratch.
+    /// Returns the peer that sent each block of the piece.
+    pub fn on_verified(&mut self, piece: u32, valid: bool) -> Vec<SocketAddr> {
+        let from = self.verifying.remove(&piece).unwrap_or_default();
+        if valid {
+            self.have.set(piece as usize);
+            self.deadlines.remove(&piece);
+        }
+        from
+    }
+
+    /// Make blocks `peer` will no longer deliver available to others. With `avoid` set they
+    /// go to other peers first.
+    pub fn release(&mut self, peer: SocketAddr, blocks: &[Block], avoid: bool) {
+        for block in blocks {
+            let Some(partial) = self.partial.get_mut(&block.piece) else {
+                continue;
+            };
+            let Some(state) = partial
+                .blocks
+                .get_mut((block.begin / self.block_size) as usize)
+            else {
+                continue;
+            };
+            if matches!(*state, BlockState::Requested(p) | BlockState::Racing(p) if p == peer) {
+                *state = BlockState::Open {
+                    avoid: avoid.then_some(peer),
+                };
+            }
+        }
+    }
+
+    /// Forget everything a disconnecting peer had outstanding
+    pub fn release_peer(&mut self, peer: SocketAddr) {
+        for partial in self.partial.values_mut() {
+            for state in &mut partial.blocks {
+                match *state {
+                    BlockState::Requested(p) | BlockState::Racing(p) if p == peer => {
+                        *state = BlockState::Open { avoid: None }
+                    }
+                    BlockState::Open { avoid: Some(p) } if p == peer => {
+                        *state = BlockState::Open { avoid: None }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+fn block_at(piece: u32, index: usize, block_size: u32, piece_len: u64) -> Block {
+    let begin = index as u64 * block_size as u64;
+    Block {
+        piece,
+        begin: begin as u32,
+        length: (block_size as u64).min(piece_len - begin) as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn spreads_requests_over_several_pieces() {
+        // Three pieces of two blocks each, the last one short
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 5 * BLOCK_SIZE as u64 + 10);
+        let all = Bitfield::full(picker.piece_count());
+        let blocks = picker.pick(peer(1), &all, 5);
+        assert_eq!(blocks.len(), 5);
+        let pieces: HashSet<u32> = blocks.iter().map(|b| b.piece).collect();
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(
+            blocks[4],
+            Block {
+                piece: 2,
+                begin: 0,
+                length: BLOCK_SIZE
+            }
+        );
+
+        let last = picker.pick(peer(2), &all, 5);
+        assert_eq!(
+            last,
+            vec![Block {
+                piece: 2,
+                begin: BLOCK_SIZE,
+                length: 10
+            }]
+        );
+        assert!(picker.pick(peer(2), &all, 5).is_empty());
+    }
+
+    #[test]
+    fn prefers_rare_pieces() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(3);
+        let mut common = Bitfield::new(3);
+        common.set(0);
+        common.set(1);
+        picker.add_availability(&all);
+        picker.add_availability(&common);
+        assert_eq!(picker.pick(peer(1), &all, 1)[0].piece, 2);
+    }
+
+    #[test]
+    fn completes_pieces_and_retries_failed_hashes() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(1);
+        let blocks = picker.pick(peer(1), &all, 2);
+        let data = vec![7u8; BLOCK_SIZE as usize];
+        assert_eq!(picker.on_block(peer(1), 0, 0, &data), BlockOutcome::Stored);
+        assert_eq!(picker.on_block(peer(1), 0, 0, &data), BlockOutcome::Ignored);
+        assert_eq!(picker.on_block(peer(1), 0, 1, &data), BlockOutcome::Ignored);
+        // A late block from a peer the request was handed on to still counts
+        let BlockOutcome::Completed(piece) = picker.on_block(peer(2), 0, blocks[1].begin, &data)
+        else {
+            panic!("piece should be complete");
+        };
+        assert_eq!(piece.len(), 2 * BLOCK_SIZE as usize);
+        // Not handed out again while its hash is being checked
+        assert!(picker.pick(peer(2), &all, 2).is_empty());
+
+        let from = picker.on_verified(0, false);
+        assert_eq!(from, [peer(1), peer(2)]);
+        assert_eq!(picker.pick(peer(2), &all, 2).len(), 2);
+        assert!(!picker.is_complete());
+    }
+
+    #[test]
+    fn timed_out_blocks_go_to_other_peers_first() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(1);
+        let blocks = picker.pick(peer(1), &all, 2);
+        picker.release(peer(1), &blocks[..1], true);
+
+        // The slow peer only gets the block back when nobody else has the piece
+        picker.add_availability(&all);
+        assert_eq!(picker.pick(peer(1), &all, 1), blocks[..1]);
+        picker.release(peer(1), &blocks[..1], true);
+        picker.add_availability(&all);
+        assert!(picker.pick(peer(1), &all, 1).is_empty());
+        assert_eq!(picker.pick(peer(2), &all, 1), blocks[..1]);
+
+        picker.release_peer(peer(1));
+        assert_eq!(picker.pick(peer(3), &all, 2), blocks[1..]);
+    }
+
+    #[test]
+    fn skips_unwanted_pieces_and_starts_high_priority_ones_first() {
+        use FilePriority::{High, Low, Skip};
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 3 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(3);
+        picker.set_priorities(vec![Low, Skip, High]);
+        let picked: Vec<u32> = picker
+            .pick(peer(1), &all, 3)
+            .iter()
+            .map(|b| b.piece)
+            .collect();
+        assert_eq!(picked, [2, 0]);
+
+        let data = vec![0u8; BLOCK_SIZE as usize];
+        for piece in [0, 2] {
+            assert!(matches!(
+                picker.on_block(peer(1), piece, 0, &data),
+                BlockOutcome::Completed(_)
+            ));
+            picker.on_verified(piece, true);
+        }
+        assert!(picker.is_finished() && !picker.is_complete());
+    }
+
+    #[test]
+    fn sequential_mode_starts_pieces_in_order_from_the_position() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 4 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(4);
+        let mut rare = Bitfield::new(4);
+        rare.set(3);
+        picker.add_availability(&all);
+        picker.add_availability(&Bitfield::full(4));
+        picker.remove_availability(&rare);
+        picker.set_sequential(Some(picker.piece_at(2 * BLOCK_SIZE as u64 + 5)));
+        let picked: Vec<u32> = picker
+            .pick(peer(1), &all, 4)
+            .iter()
+            .map(|b| b.piece)
+            .collect();
+        assert_eq!(picked, [2, 3, 0, 1]);
+    }
+
+    #[test]
+    fn deadlines_go_first_and_overdue_blocks_are_raced() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 6 * BLOCK_SIZE as u64);
+        let all = Bitfield::full(3);
+        picker.set_deadline(2, Instant::now() + Duration::from_secs(60));
+        picker.set_deadline(1, Instant::now() + Duration::from_secs(30));
+        let slow = picker.pick(peer(1), &all, 3);
+        let pieces: Vec<u32> = slow.iter().map(|b| b.piece).collect();
+        assert_eq!(pieces, [1, 1, 2]);
+        // Not due yet, so another peer starts on other blocks
+        let next = Block {
+            begin: BLOCK_SIZE,
+            ..slow[2]
+        };
+        assert_eq!(picker.pick(peer(2), &all, 1), [next]);
+
+        picker.set_deadline(1, Instant::now());
+        assert_eq!(picker.pick(peer(2), &all, 2), slow[..2]);
+        // Raced blocks aren't handed out a third time
+        assert!(picker.pick(peer(3), &all, 2).iter().all(|b| b.piece == 0));
+
+        let data = vec![0u8; BLOCK_SIZE as usize];
+        for block in &slow[..2] {
+            picker.on_block(peer(1), block.piece, block.begin, &data);
+        }
+        picker.on_verified(1, true);
+        assert!(!picker.deadlines.contains_key(&1));
+    }
+}
@@ -0,0 +1,190 @@
+//! Per-peer request pipelining. The queue keeps enough requests outstanding to cover the
+//! peer's bandwidth-delay product, measured from the blocks it actually delivers.
+
+use crate::peers::picker::Block;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub const MIN_QUEUE_DEPTH: usize = 2;
+/// BEP 10 gives 250 as libtorrent's default when a peer doesn't send `reqq`
+pub const DEFAULT_REQQ: usize = 250;
+/// What we advertise as our own `reqq`
+pub const MAX_REQQ: usize = 500;
+const INITIAL_DEPTH: usize = 4;
+// Keep this much data requested ahead on top of the path latency
+const QUEUE_TIME: Duration = Duration::from_secs(2);
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+pub struct RequestQueue {
+    // Outstanding requests in the order they were sent
+    pending: Vec<(Block, Instant)>,
+    depth: usize,
+    max_depth: usize,
+    // Lowest round trip seen, an estimate of latency without our own queueing
+    min_rtt: Option<Duration>,
+    // Smoothed download rate in bytes per second, None until the first full window
+    rate: Option<f64>,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            depth: INITIAL_DEPTH,
+            max_depth: DEFAULT_REQQ,
+            min_rtt: None,
+            rate: None,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Apply the peer's advertised `reqq`
+    pub fn set_max_depth(&mut self, reqq: usize) {
+        self.max_depth = reqq.max(1);
+        self.depth = self.depth.min(self.max_depth);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// How many more requests to send now
+    pub fn wanted(&self) -> usize {
+        self.depth.saturating_sub(self.pending.len())
+    }
+
+    pub fn sent(&mut self, block: Block, now: Instant) {
+        self.pending.push((block, now));
+    }
+
+    /// Record a delivered block, returning whether we were waiting for it
+    pub fn received(&mut self, block: Block, now: Instant) -> bool {
+        let Some(pos) = self.pending.iter().position(|(b, _)| *b == block) else {
+            return false;
+        };
+        let (_, sent_at) = self.pending.remove(pos);
+        let rtt = now - sent_at;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |m| m.min(rtt)));
+
+        self.window_bytes += block.length as u64;
+        let elapsed = now - self.window_start;
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = Some(self.rate.map_or(sample, |r| r * 0.7 + sample * 0.3));
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+
+        self.depth = match self.rate {
+            Some(rate) => {
+                let ahead = QUEUE_TIME + self.min_rtt.unwrap_or_default();
+                (rate * ahead.as_secs_f64() / block.length as f64).ceil() as usize
+            }
+            // Slow start until there is a rate to go by
+            None => self.depth + 1,
+        }
+        .clamp(MIN_QUEUE_DEPTH.min(self.max_depth), self.max_depth);
+        true
+    }
+
+    /// Drop a request the peer won't serve, e.g. after a reject
+    pub fn remove(&mut self, block: Block) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|(b, _)| *b != block);
+        self.pending.len() != before
+    }
+
+    /// Requests unanswered for longer than `timeout`; the queue shrinks since the peer
+    /// is evidently slower than we assumed
+    pub fn expired(&mut self, now: Instant, timeout: Duration) -> Vec<Block> {
+        let (late, pending): (Vec<_>, Vec<_>) = self
+            .pending
+            .drain(..)
+            .partition(|(_, sent_at)| now - *sent_at >= timeout);
+        self.pending = pending;
+        if !late.is_empty() {
+            self.depth = (self.depth / 2).max(MIN_QUEUE_DEPTH.min(self.max_depth));
+        }
+        late.into_iter().map(|(b, _)| b).collect()
+    }
+
+    /// Everything outstanding, e.g. when the peer chokes us
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.pending.drain(..).map(|(b, _)| b).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::picker::BLOCK_SIZE;
+
+    fn block(i: u32) -> Block {
+        Block {
+            piece: i / 4,
+            begin: (i % 4) * BLOCK_SIZE,
+            length: BLOCK_SIZE,
+        }
+    }
+
+    #[test]
+    fn grows_with_throughput_and_respects_reqq() {
+        let mut q = RequestQueue::new();
+        let start = Instant::now();
+        assert_eq!(q.wanted(), INITIAL_DEPTH);
+
+        // 100 blocks per second with 50 ms latency
+        let mut i = 0;
+        for tick in 0..200u64 {
+            let now = start + Duration::from_millis(tick * 10);
+            q.sent(block(i), now);
+            assert!(q.received(block(i), now + Duration::from_millis(50)));
+            i += 1;
+        }
+        // ~1.6 MB/s * 2.05 s / 16 KiB
+        assert!((190..=215).contains(&q.depth()), "depth {}", q.depth());
+
+        q.set_max_depth(32);
+        assert_eq!(q.depth(), 32);
+        q.sent(block(i), start);
+        q.received(block(i), start + Duration::from_secs(3));
+        assert_eq!(q.depth(), 32);
+    }
+
+    #[test]
+    fn expired_requests_shrink_the_queue() {
+        let mut q = RequestQueue::new();
+        let start = Instant::now();
+        q.sent(block(0), start);
+        q.sent(block(1), start + Duration::from_secs(5));
+        assert!(
+            q.expired(start + Duration::from_secs(4), Duration::from_secs(5))
+                .is_empty()
+        );
+
+        let late = q.expired(start + Duration::from_secs(6), Duration::from_secs(5));
+        assert_eq!(late, vec![block(0)]);
+        assert_eq!(q.len(), 1);
+        assert_eq!(q.depth(), MIN_QUEUE_DEPTH);
+        assert!(!q.received(block(0), start + Duration::from_secs(7)));
+        assert_eq!(q.clear(), vec![block(1)]);
+        assert!(q.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Token bucket rate limiting for peer wire traffic. Limiters are shared at the global,
+//! torrent and peer level, and a transfer has to pass every level it belongs to.
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+
+// Longest a throttled transfer sleeps before looking at the rate again, so limit
+// changes apply to transfers that are already waiting
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+/// Bytes per second in each direction, None for unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+struct Bucket {
+    rate: Option<u64>,
+    // Goes negative when a transfer larger than the available tokens is let through
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = (now - self.last).as_secs_f64();
+        self.last = now;
+        let Some(rate) = self.rate else {
+            self.tokens = 0.0;
+            return;
+        };
+        // Allow at most one second worth of burst
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+    }
+
+    // How long until the bucket is out of debt, None once it is
+    fn wait(&self) -> Option<Duration> {
+        match self.rate {
+            Some(rate) if self.tokens < 0.0 => {
+                Some(Duration::from_secs_f64(-self.tokens / rate.max(1) as f64))
+            }
+            _ => None,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        self.refill(Instant::now());
+        if let Some(rate) = rate {
+            self.tokens = self.tokens.min(rate as f64);
+        }
+        self.rate = rate;
+    }
+}
+
+pub struct TokenBucket {
+    inner: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            inner: Mutex::new(Bucket::new(rate)),
+        }
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.inner.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.inner.lock().unwrap().set_rate(rate);
+    }
+
+    /// Take `n` bytes worth of tokens, waiting until the bucket has paid them back
+    pub async fn consume(&self, n: usize) {
+        {
+            let mut bucket = self.inner.lock().unwrap();
+            if bucket.rate.is_none() {
+                return;
+            }
+            bucket.refill(Instant::now());
+            bucket.tokens -= n as f64;
+        }
+        loop {
+            let wait = {
+                let mut bucket = self.inner.lock().unwrap();
+                bucket.refill(Instant::now());
+                bucket.wait()
+            };
+            match wait {
+                Some(wait) => sleep(wait.min(MAX_WAIT)).await,
+                None => return,
+            }
+        }
+    }
+}
+
+pub struct RateLimiter {
+    download: TokenBucket,
+    upload: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            download: TokenBucket::new(limit.download),
+            upload: TokenBucket::new(limit.upload),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(RateLimit::default())
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        RateLimit {
+            download: self.download.rate(),
+            upload: self.upload.rate(),
+        }
+    }
+
+    pub fn set_limit(&self, limit: RateLimit) {
+        self.download.set_rate(limit.download);
+        self.upload.set_rate(limit.upload);
+    }
+}
+
+/// The limiters one connection is subject to, from the widest scope to the narrowest
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    chain: Vec<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    pub fn new(chain: Vec<Arc<RateLimiter>>) -> Self {
+        Self { chain }
+    }
+
+    pub async fn download(&self, n: usize) {
+        for limiter in &self.chain {
+            limiter.download.consume(n).await;
+        }
+    }
+
+    pub async fn upload(&self, n: usize) {
+        for limiter in &self.chain {
+            limiter.upload.consume(n).await;
+        }
+    }
+}
+
+/// Peers on the same machine or LAN, which can be exempted from limits
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.is_unique_local() || v6.is_unicast_link_local(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn throttles_to_the_configured_rate() {
+        let limiter = RateLimiter::new(RateLimit {
+            download: Some(100_000),
+            upload: None,
+        });
+        let limits = RateLimits::new(vec![Arc::new(limiter)]);
+        let start = Instant::now();
+        // The first second's worth passes as a burst
+        limits.download(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(1));
+        limits.download(50_000).await;
+        limits.download(50_000).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(990), "{elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(1100), "{elapsed:?}");
+
+        limits.upload(10_000_000).await;
+        assert_eq!(start.elapsed(), elapsed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_changes_apply_to_waiting_transfers() {
+        let global = Arc::new(RateLimiter::new(RateLimit {
+            download: Some(1_000),
+            upload: None,
+        }));
+        let peer = Arc::new(RateLimiter::unlimited());
+        let limits = RateLimits::new(vec![global.clone(), peer.clone()]);
+        limits.download(1_000).await;
+
+        let start = Instant::now();
+        let waiting = tokio::spawn({
+            let limits = limits.clone();
+            async move { limits.download(100_000).await }
+        });
+        sleep(Duration::from_secs(1)).await;
+        global.set_limit(RateLimit::default());
+        waiting.await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1200));
+        assert_eq!(global.limit(), RateLimit::default());
+
+        // The narrowest level still applies
+        peer.set_limit(RateLimit {
+            download: Some(10_000),
+            upload: None,
+        });
+        let start = Instant::now();
+        limits.download(30_000).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn recognises_local_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.5",
+            "169.254.1.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(is_local(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "2001:db8::1"] {
+            assert!(!is_local(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
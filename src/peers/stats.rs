@@ -0,0 +1,128 @@
+//! Transfer counters for progress displays: byte totals, moving-average rates and what a
+//! session reports about each connected peer.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Rates average over the last few seconds so a single slow block doesn't make them jump
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+const BUCKET: Duration = Duration::from_millis(500);
+
+/// Bytes moved in one direction, in total and per second over the last few seconds
+#[derive(Debug)]
+pub struct TransferCounter {
+    total: AtomicU64,
+    // (start of bucket, bytes in it), oldest first
+    buckets: Mutex<VecDeque<(Instant, u64)>>,
+    started: Instant,
+}
+
+impl Default for TransferCounter {
+    fn default() -> Self {
+        Self {
+            total: AtomicU64::new(0),
+            buckets: Mutex::new(VecDeque::new()),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl TransferCounter {
+    pub fn record(&self, bytes: u64) {
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        match buckets.back_mut() {
+            Some((start, n)) if now.duration_since(*start) < BUCKET => *n += bytes,
+            _ => buckets.push_back((now, bytes)),
+        }
+        while buckets
+            .front()
+            .is_some_and(|(start, _)| now.duration_since(*start) > RATE_WINDOW)
+        {
+            buckets.pop_front();
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Bytes per second over the last few seconds, or since the counter was created if
+    /// that is more recent
+    pub fn rate(&self) -> u64 {
+        let now = Instant::now();
+        let window = now.duration_since(self.started).clamp(BUCKET, RATE_WINDOW);
+        let bytes: u64 = self
+            .buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(start, _)| now.duration_since(*start) <= window)
+            .map(|(_, n)| n)
+            .sum();
+        (bytes as f64 / window.as_secs_f64()) as u64
+    }
+}
+
+/// Live counters for one connection, shared between its task and the swarm
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub downloaded: TransferCounter,
+    pub client: Mutex<Option<String>>,
+    pub pieces: AtomicUsize,
+    pub choked: AtomicBool,
+}
+
+impl PeerStats {
+    pub fn info(&self, addr: SocketAddr) -> PeerInfo {
+        PeerInfo {
+            addr,
+            client: self.client.lock().unwrap().clone(),
+            pieces: self.pieces.load(Ordering::Relaxed),
+            choked: self.choked.load(Ordering::Relaxed),
+            downloaded: self.downloaded.total(),
+            download_rate: self.downloaded.rate(),
+        }
+    }
+}
+
+/// A snapshot of one connected peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    /// From the `v` key of the extension handshake
+    pub client: Option<String>,
+    /// How many pieces the peer has
+    pub pieces: usize,
+    /// Whether the peer is choking us
+    pub choked: bool,
+    pub downloaded: u64,
+    pub download_rate: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_averages_over_the_window() {
+        let counter = TransferCounter::default();
+        for _ in 0..10 {
+            tokio::time::advance(Duration::from_millis(500)).await;
+            counter.record(50_000);
+        }
+        assert_eq!(counter.total(), 500_000);
+        assert_eq!(counter.rate(), 100_000);
+
+        // Traffic that stops drains out of the rate
+        tokio::time::advance(RATE_WINDOW + BUCKET).await;
+        assert_eq!(counter.rate(), 0);
+        assert_eq!(counter.total(), 500_000);
+    }
+}
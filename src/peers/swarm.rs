@@ -0,0 +1,828 @@
+//! Downloads a torrent from many peers at once, sharing one piece picker between them
+
+use crate::error::Result;
+use crate::events::{Event, Events};
+use crate::ipfilter::PeerFilter;
+use crate::peers::bitfield::Bitfield;
+use crate::peers::blame::{Blame, block_hashes};
+use crate::peers::connection::{PeerConnection, PeerTimeouts};
+use crate::peers::error::PeerError;
+use crate::peers::message::Message;
+use crate::peers::metadata::{CLIENT_VERSION, ExtendedHandshake};
+use crate::peers::mse::{EncryptionPolicy, MseStream};
+use crate::peers::peer::Handshake;
+use crate::peers::picker::{BLOCK_SIZE, Block, BlockOutcome, PiecePicker};
+use crate::peers::pipeline::{MAX_REQQ, RequestQueue};
+use crate::peers::ratelimit::{RateLimit, RateLimiter, RateLimits, is_local};
+use crate::peers::stats::{PeerInfo, PeerStats, TransferCounter};
+use crate::peers::transport::{BoxedStream, Transports};
+use crate::storage::disk::DiskPool;
+use crate::storage::error::StorageError;
+use crate::storage::files::{FilePriority, Storage, StorageFile};
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::torrent::TorrentFile;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+
+// How often a peer loop wakes up without traffic to expire requests
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwarmConfig {
+    pub max_peers: usize,
+    pub timeouts: PeerTimeouts,
+    pub torrent_rate: RateLimit,
+    /// Applied to each peer connection separately
+    pub peer_rate: RateLimit,
+    /// Leave peers on the local network out of every rate limit
+    pub exempt_local: bool,
+    /// Size of the blocks requested from peers
+    pub block_size: u32,
+    pub encryption: EncryptionPolicy,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 30,
+            timeouts: PeerTimeouts::default(),
+            torrent_rate: RateLimit::default(),
+            peer_rate: RateLimit::default(),
+            exempt_local: false,
+            block_size: BLOCK_SIZE,
+            encryption: EncryptionPolicy::default(),
+        }
+    }
+}
+
+pub struct Swarm {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    piece_hashes: Vec<[u8; 20]>,
+    config: SwarmConfig,
+    // Never held across an await
+    picker: Mutex<PiecePicker>,
+    storage: Arc<Storage>,
+    file_priorities: Mutex<Vec<FilePriority>>,
+    complete: AtomicBool,
+    failure: Mutex<Option<StorageError>>,
+    global_limiter: Arc<RateLimiter>,
+    limiter: Arc<RateLimiter>,
+    peer_rate: Mutex<RateLimit>,
+    peer_limiters: Mutex<HashMap<SocketAddr, Arc<RateLimiter>>>,
+    disk: DiskPool,
+    transports: Transports,
+    ip_filter: Arc<PeerFilter>,
+    candidates: Mutex<VecDeque<SocketAddrV4>>,
+    connected: Mutex<HashSet<SocketAddr>>,
+    // Addresses that sent bad blocks, and the piece they were in
+    banned: Mutex<HashMap<IpAddr, u32>>,
+    blame: Mutex<Blame>,
+    peer_stats: Mutex<HashMap<SocketAddr, Arc<PeerStats>>>,
+    downloaded: TransferCounter,
+    paused: AtomicBool,
+    events: Events,
+    // Wakes `run` for new peers, freed connection slots and state changes
+    wake: Notify,
+    // Wakes readers waiting for a piece whenever one has been checked
+    verified: Notify,
+}
+
+impl Swarm {
+    pub fn new(
+        tf: &TorrentFile,
+        peer_id: [u8; 20],
+        download_dir: impl AsRef<Path>,
+        config: SwarmConfig,
+    ) -> Result<Self> {
+        let info = &tf.torrent.info;
+        let storage = Storage::new(info, download_dir)?;
+        let piece_hashes = info.piece_hashes()?;
+        let picker = PiecePicker::new(info.piece_length, info.total_length())
+            .with_block_size(config.block_size);
+        if picker.piece_count() != piece_hashes.len() {
+            return Err(TorrentError::Invalid(format!(
+                "{} piece hashes for {} pieces",
+                piece_hashes.len(),
+                picker.piece_count()
+            ))
+            .into());
+        }
+        Ok(Self {
+            info_hash: tf.info_hash,
+            peer_id,
+            piece_hashes,
+            config,
+            complete: AtomicBool::new(picker.is_finished()),
+            picker: Mutex::new(picker),
+            file_priorities: Mutex::new(vec![FilePriority::Normal; storage.files().len()]),
+            storage: Arc::new(storage),
+            failure: Mutex::new(None),
+            global_limiter: Arc::new(RateLimiter::unlimited()),
+            limiter: Arc::new(RateLimiter::new(config.torrent_rate)),
+            peer_rate: Mutex::new(config.peer_rate),
+            peer_limiters: Mutex::new(HashMap::new()),
+            disk: DiskPool::default(),
+            transports: Transports::default(),
+            ip_filter: Arc::default(),
+            candidates: Mutex::new(VecDeque::new()),
+            connected: Mutex::new(HashSet::new()),
+            banned: Mutex::new(HashMap::new()),
+            blame: Mutex::default(),
+            peer_stats: Mutex::new(HashMap::new()),
+            downloaded: TransferCounter::default(),
+            paused: AtomicBool::new(false),
+            events: Events::default(),
+            wake: Notify::new(),
+            verified: Notify::new(),
+        })
+    }
+
+    /// Share a disk I/O pool with other torrents
+    pub fn with_disk_pool(mut self, disk: DiskPool) -> Self {
+        self.disk = disk;
+        self
+    }
+
+    /// Share a limiter with other torrents
+    pub fn with_global_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+        self.global_limiter = limiter;
+        self
+    }
+
+    /// Reach peers over these transports instead of TCP alone
+    pub fn with_transports(mut self, transports: Transports) -> Self {
+        self.transports = transports;
+        self
+    }
+
+    /// Skip peers the filter blocks; checked as each one is about to be connected to, so a
+    /// reloaded filter covers peers already queued
+    pub fn with_ip_filter(mut self, filter: Arc<PeerFilter>) -> Self {
+        self.ip_filter = filter;
+        self
+    }
+
+    /// Publish this swarm's events on a shared channel
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// This torrent's own limit, adjustable while it runs
+    pub fn limiter(&self) -> &Arc<RateLimiter> {
+        &self.limiter
+    }
+
+    /// Change the limit every peer connection gets, including current ones
+    pub fn set_peer_limit(&self, limit: RateLimit) {
+        *self.peer_rate.lock().unwrap() = limit;
+        for limiter in self.peer_limiters.lock().unwrap().values() {
+            limiter.set_limit(limit);
+        }
+    }
+
+    fn rate_limits(&self, key: SocketAddr) -> RateLimits {
+        if self.config.exempt_local && is_local(key.ip()) {
+            return RateLimits::default();
+        }
+        let peer = Arc::new(RateLimiter::new(*self.peer_rate.lock().unwrap()));
+        self.peer_limiters.lock().unwrap().insert(key, peer.clone());
+        RateLimits::new(vec![
+            self.global_limiter.clone(),
+            self.limiter.clone(),
+            peer,
+        ])
+    }
+
+    /// Every piece of a file that isn't skipped is verified on disk
+    pub fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::Acquire)
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.is_complete()
+            || self.paused.load(Ordering::Acquire)
+            || self.failure.lock().unwrap().is_some()
+    }
+
+    pub fn progress(&self) -> (usize, usize) {
+        let picker = self.picker.lock().unwrap();
+        (picker.have().count(), picker.piece_count())
+    }
+
+    /// What trackers are told is `left`; skipped files don't count
+    pub fn bytes_left(&self) -> u64 {
+        let picker = self.picker.lock().unwrap();
+        (0..picker.piece_count() as u32)
+            .filter(|&i| picker.is_wanted(i) && !picker.have().get(i as usize))
+            .map(|i| picker.piece_len(i))
+            .sum()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.connected.lock().unwrap().len()
+    }
+
+    /// Which pieces are verified on disk
+    pub fn pieces(&self) -> Bitfield {
+        self.picker.lock().unwrap().have().clone()
+    }
+
+    /// Payload bytes received from peers, including blocks of pieces that failed their check
+    pub fn downloaded(&self) -> &TransferCounter {
+        &self.downloaded
+    }
+
+    /// Peers that finished the handshake, ordered by address
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<_> = self
+            .peer_stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, stats)| stats.info(*addr))
+            .collect();
+        peers.sort_by_key(|p| p.addr);
+        peers
+    }
+
+    /// Every file with how many of its bytes are in verified pieces
+    pub fn file_progress(&self) -> Vec<(StorageFile, u64)> {
+        let picker = self.picker.lock().unwrap();
+        let piece_length = picker.piece_len(0);
+        let mut done = vec![0u64; self.storage.files().len()];
+        for piece in picker.have().iter_set() {
+            let start = piece as u64 * piece_length;
+            let end = start + picker.piece_len(piece as u32);
+            for (file, done) in self.storage.files().iter().zip(&mut done) {
+                let (from, to) = (start.max(file.offset), end.min(file.offset + file.length));
+                *done += to.saturating_sub(from);
+            }
+        }
+        self.storage.files().iter().cloned().zip(done).collect()
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        self.storage.files()
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.file_priorities.lock().unwrap().clone()
+    }
+
+    /// Change which files are downloaded and in what order, one priority per file. The picker
+    /// follows straight away. Files that become skipped keep what they have; the pieces they
+    /// share with wanted files move to the part-file on the disk pool, in the returned task.
+    /// A failure there stops the swarm, like any other storage error.
+    pub fn set_file_priorities(self: &Arc<Self>, priorities: &[FilePriority]) -> JoinHandle<()> {
+        let mut current = self.file_priorities.lock().unwrap();
+        if *current != priorities {
+            let mut picker = self.picker.lock().unwrap();
+            picker.set_priorities(self.storage.piece_priorities(priorities));
+            *current = priorities.to_vec();
+            let finished = picker.is_finished();
+            drop((picker, current));
+            if self.complete.swap(finished, Ordering::AcqRel) != finished && finished {
+                self.events.publish(Event::TorrentFinished {
+                    info_hash: self.info_hash,
+                });
+            }
+            self.wake.notify_one();
+        }
+        tokio::spawn(self.clone().move_shared_pieces())
+    }
+
+    // Bring the files on disk in line with the latest priorities, whichever call set them
+    async fn move_shared_pieces(self: Arc<Self>) {
+        let swarm = self.clone();
+        let result = self
+            .disk
+            .run(move || {
+                let priorities = swarm.file_priorities.lock().unwrap().clone();
+                let skipped = priorities.iter().map(|&p| p == FilePriority::Skip);
+                let have = swarm.picker.lock().unwrap().have().clone();
+                swarm.storage.set_skipped(skipped.collect(), &have)
+            })
+            .await;
+        if let Err(e) = result {
+            self.events.publish(Event::StorageError {
+                info_hash: self.info_hash,
+                error: e.to_string(),
+            });
+            self.failure.lock().unwrap().get_or_insert(e);
+            self.wake.notify_one();
+        }
+    }
+
+    /// Start new pieces in order from the one holding byte `offset` of the torrent, for
+    /// reading it while it downloads; `None` goes back to rarest first
+    pub fn set_sequential(&self, offset: Option<u64>) {
+        let mut picker = self.picker.lock().unwrap();
+        let from = offset.map(|offset| picker.piece_at(offset));
+        picker.set_sequential(from);
+    }
+
+    /// Fetch `piece` ahead of others and race overdue blocks against other peers if it
+    /// isn't verified within `within`
+    pub fn set_piece_deadline(&self, piece: u32, within: Duration) {
+        self.picker
+            .lock()
+            .unwrap()
+            .set_deadline(piece, Instant::now() + within);
+    }
+
+    pub fn clear_piece_deadlines(&self) {
+        self.picker.lock().unwrap().clear_deadlines();
+    }
+
+    /// Give the first `count` pieces from the one holding byte `offset` of the torrent
+    /// deadlines `spacing` apart, so they are fetched in order ahead of others. False if the
+    /// piece holding `offset` is in skipped files only and so will never be fetched.
+    pub fn prioritise(&self, offset: u64, count: u32, spacing: Duration) -> bool {
+        let mut picker = self.picker.lock().unwrap();
+        let first = picker.piece_at(offset);
+        if !picker.have().get(first as usize) && !picker.is_wanted(first) {
+            return false;
+        }
+        let now = Instant::now();
+        for (i, piece) in (first..first.saturating_add(count)).enumerate() {
+            if (piece as usize) < picker.piece_count() && picker.is_wanted(piece) {
+                picker.set_deadline(piece, now + spacing * (i as u32 + 1));
+            }
+        }
+        true
+    }
+
+    /// Read up to `len` bytes at `offset` of the torrent, stopping at the end of the piece
+    /// holding `offset`. Waits until that piece is verified on disk, which is forever if
+    /// nothing fetches it.
+    pub async fn read_verified(
+        self: &Arc<Self>,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        let len = loop {
+            // Registered before looking, so a piece checked in between still wakes us
+            let verified = self.verified.notified();
+            tokio::pin!(verified);
+            verified.as_mut().enable();
+            let available = {
+                let picker = self.picker.lock().unwrap();
+                let piece = picker.piece_at(offset);
+                let end = picker.piece_offset(piece) + picker.piece_len(piece);
+                picker.have().get(piece as usize).then_some(end - offset)
+            };
+            match available {
+                Some(available) => break len.min(available as usize),
+                None => verified.await,
+            }
+        };
+        let storage = self.storage.clone();
+        self.disk
+            .run(move || {
+                let mut buf = vec![0; len];
+                storage.read_at(offset, &mut buf)?;
+                Ok(buf)
+            })
+            .await
+    }
+
+    /// Queue peers to connect to; ones already connected or queued are skipped
+    pub fn add_peers(&self, peers: impl IntoIterator<Item = SocketAddrV4>) {
+        let connected = self.connected.lock().unwrap();
+        let mut candidates = self.candidates.lock().unwrap();
+        for peer in peers {
+            if !connected.contains(&SocketAddr::V4(peer)) && !candidates.contains(&peer) {
+                candidates.push_back(peer);
+            }
+        }
+        drop((connected, candidates));
+        self.wake.notify_one();
+    }
+
+    /// Pausing drops every connection; `run` returns and can be called again to resume
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Release);
+        self.wake.notify_one();
+    }
+
+    /// Mark pieces that are already on disk from an earlier run
+    pub async fn check_existing(&self) -> Result<(), StorageError> {
+        let files = self.storage.files();
+        if !files.iter().any(|f| f.path.exists()) && !self.storage.part_file().exists() {
+            return Ok(());
+        }
+        for index in 0..self.piece_hashes.len() as u32 {
+            let storage = self.storage.clone();
+            let expected = self.piece_hashes[index as usize];
+            let valid = self
+                .disk
+                .run(move || {
+                    Ok(storage
+                        .read_piece(index)
+                        .is_ok_and(|data| Sha1::digest(&data)[..] == expected))
+                })
+                .await?;
+            if valid {
+                self.picker.lock().unwrap().on_verified(index, true);
+            }
+        }
+        self.complete
+            .store(self.picker.lock().unwrap().is_finished(), Ordering::Release);
+        Ok(())
+    }
+
+    /// Connect to queued peers, at most `max_peers` at a time, until every piece is verified
+    /// on disk or the swarm is paused. Waits for more peers when it runs out.
+    pub async fn run(self: Arc<Self>) -> Result<(), StorageError> {
+        self.storage.prepare()?;
+        let mut tasks = JoinSet::new();
+        while !self.is_stopped() {
+            while self.peer_count() < self.config.max_peers
+                && let Some(addr) = self.candidates.lock().unwrap().pop_front()
+            {
+                let ip = IpAddr::V4(*addr.ip());
+                if self.ip_filter.blocks(ip) || self.banned_for(ip).is_some() {
+                    continue;
+                }
+                self.connected.lock().unwrap().insert(SocketAddr::V4(addr));
+                let swarm = self.clone();
+                tasks.spawn(swarm.connect(addr));
+            }
+            tokio::select! {
+                Some(_) = tasks.join_next() => {}
+                _ = self.wake.notified() => {}
+            }
+        }
+        tasks.shutdown().await;
+
+        match self.failure.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    async fn connect(self: Arc<Self>, addr: SocketAddrV4) -> Result<(), PeerError> {
+        let key = SocketAddr::V4(addr);
+        let handshake = Handshake::new(self.info_hash, self.peer_id);
+        let config = &self.config;
+        let established = PeerConnection::establish(
+            addr,
+            &handshake,
+            &self.transports,
+            config.timeouts,
+            config.encryption,
+        );
+        let _connected = Connected(&self, key);
+        match established.await {
+            Ok((conn, remote)) => self.serve(conn, &remote, key).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Take over an incoming connection whose handshake named this torrent
+    pub async fn accept(
+        self: Arc<Self>,
+        mut stream: MseStream<BoxedStream>,
+        remote: Handshake,
+        addr: SocketAddr,
+    ) -> Result<(), PeerError> {
+        if self.is_stopped()
+            || self.banned_for(addr.ip()).is_some()
+            || !self.connected.lock().unwrap().insert(addr)
+        {
+            return Ok(());
+        }
+        let connected = Connected(&self, addr);
+        let result = async {
+            if self.peer_count() > self.config.max_peers {
+                return Ok(());
+            }
+            let ours = Handshake::new(self.info_hash, self.peer_id);
+            stream.write_all(&ours.to_bytes()).await?;
+            let conn = PeerConnection::new(stream, self.config.timeouts);
+            self.serve(conn, &remote, addr).await
+        }
+        .await;
+        drop(connected);
+        self.wake.notify_one();
+        result
+    }
+
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        self: &Arc<Self>,
+        mut conn: PeerConnection<S>,
+        remote: &Handshake,
+        key: SocketAddr,
+    ) -> Result<(), PeerError> {
+        conn.set_rate_limits(self.rate_limits(key));
+        let stats = Arc::new(PeerStats {
+            choked: AtomicBool::new(true),
+            ..Default::default()
+        });
+        self.peer_stats.lock().unwrap().insert(key, stats.clone());
+        self.events.publish(Event::PeerConnected {
+            info_hash: self.info_hash,
+            addr: key,
+        });
+        let mut session = PeerSession {
+            key,
+            has: Bitfield::new(self.piece_hashes.len()),
+            choked: true,
+            queue: RequestQueue::new(),
+            swarm: self.clone(),
+            stats,
+        };
+        let result = session.run(&mut conn, remote).await;
+        drop(session);
+        self.events.publish(Event::PeerDisconnected {
+            info_hash: self.info_hash,
+            addr: key,
+            reason: result.as_ref().err().map(ToString::to_string),
+        });
+        result
+    }
+
+    /// The piece that got `ip` banned, if it sent bad data
+    fn banned_for(&self, ip: IpAddr) -> Option<u32> {
+        self.banned.lock().unwrap().get(&ip).copied()
+    }
+
+    // Hash and store a completed piece off the async runtime
+    async fn finish_piece(self: Arc<Self>, index: u32, data: Vec<u8>) {
+        let swarm = self.clone();
+        let result = self
+            .disk
+            .run(move || {
+                let digest: [u8; 20] = Sha1::digest(&data).into();
+                let valid = digest == swarm.piece_hashes[index as usize];
+                // Only needed to tell who sent the bad block of a piece from several peers
+                let hashes = (!valid || swarm.blame.lock().unwrap().is_suspect(index))
+                    .then(|| block_hashes(&data, swarm.config.block_size));
+                if valid {
+                    swarm.storage.write_piece(index, &data)?;
+                }
+                Ok((valid, hashes))
+            })
+            .await;
+
+        let info_hash = self.info_hash;
+        let (valid, hashes) = match result {
+            Ok((valid, hashes)) => {
+                self.events.publish(if valid {
+                    Event::PieceFinished {
+                        info_hash,
+                        piece: index,
+                    }
+                } else {
+                    Event::HashFailed {
+                        info_hash,
+                        piece: index,
+                    }
+                });
+                (valid, hashes)
+            }
+            Err(e) => {
+                self.events.publish(Event::StorageError {
+                    info_hash,
+                    error: e.to_string(),
+                });
+                self.failure.lock().unwrap().get_or_insert(e);
+                (false, None)
+            }
+        };
+        let mut picker = self.picker.lock().unwrap();
+        let from = picker.on_verified(index, valid);
+        // Banned peers are dropped and not let back in; their sessions notice on their next turn
+        if let Some(hashes) = hashes {
+            let mut blame = self.blame.lock().unwrap();
+            let liars = if valid {
+                blame.verified(index, &hashes)
+            } else {
+                blame.failed(index, &from, hashes)
+            };
+            let mut banned = self.banned.lock().unwrap();
+            for ip in liars {
+                banned.entry(ip).or_insert(index);
+            }
+        }
+        let finished = picker.is_finished() && !self.complete.swap(true, Ordering::AcqRel);
+        drop(picker);
+        self.verified.notify_waiters();
+        if finished {
+            self.events.publish(Event::TorrentFinished { info_hash });
+        }
+        self.wake.notify_one();
+    }
+}
+
+// Forgets a connected peer when its task ends, also when the run loop aborts the task
+struct Connected<'a>(&'a Swarm, SocketAddr);
+
+impl Drop for Connected<'_> {
+    fn drop(&mut self) {
+        self.0.connected.lock().unwrap().remove(&self.1);
+    }
+}
+
+struct PeerSession {
+    key: SocketAddr,
+    has: Bitfield,
+    choked: bool,
+    queue: RequestQueue,
+    swarm: Arc<Swarm>,
+    stats: Arc<PeerStats>,
+}
+
+// Cleaned up on drop so that a torrent restarted after its tasks were aborted doesn't
+// still count the old peers as connected, or their blocks as requested
+impl Drop for PeerSession {
+    fn drop(&mut self) {
+        let swarm = &self.swarm;
+        swarm.peer_limiters.lock().unwrap().remove(&self.key);
+        swarm.peer_stats.lock().unwrap().remove(&self.key);
+        let mut picker = swarm.picker.lock().unwrap();
+        picker.release_peer(self.key);
+        picker.remove_availability(&self.has);
+    }
+}
+
+impl PeerSession {
+    async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        conn: &mut PeerConnection<S>,
+        remote: &Handshake,
+    ) -> Result<(), PeerError> {
+        if remote.supports_extensions() {
+            let ours = ExtendedHandshake {
+                v: Some(CLIENT_VERSION.to_string()),
+                reqq: Some(MAX_REQQ as u64),
+                ..Default::default()
+            };
+            conn.feed(ours.to_message()?).await?;
+        }
+        conn.send(Message::Interested).await?;
+
+        while !self.swarm.is_stopped() {
+            if let Some(piece) = self.swarm.banned_for(self.key.ip()) {
+                return Err(conn.disconnect(PeerError::BadHash(piece)).await);
+            }
+            self.expire_requests(conn.timeouts().request);
+            self.fill_queue(conn).await?;
+            if let Some(msg) = conn.recv_for(TICK).await? {
+                self.handle(conn, msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Requests the peer sat on for too long go back to the picker for other peers to take
+    fn expire_requests(&mut self, timeout: Duration) {
+        let late = self.queue.expired(Instant::now(), timeout);
+        if !late.is_empty() {
+            let mut picker = self.swarm.picker.lock().unwrap();
+            picker.release(self.key, &late, true);
+        }
+    }
+
+    async fn fill_queue<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        conn: &mut PeerConnection<S>,
+    ) -> Result<(), PeerError> {
+        if self.choked || self.queue.wanted() == 0 {
+            return Ok(());
+        }
+        let blocks = {
+            let mut picker = self.swarm.picker.lock().unwrap();
+            picker.pick(self.key, &self.has, self.queue.wanted())
+        };
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        for block in blocks {
+            conn.feed(Message::Request {
+                index: block.piece,
+                begin: block.begin,
+                length: block.length,
+            })
+            .await?;
+            self.queue.sent(block, now);
+        }
+        conn.flush().await
+    }
+
+    async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        conn: &mut PeerConnection<S>,
+        msg: Message,
+    ) -> Result<(), PeerError> {
+        match msg {
+            Message::Choke => {
+                self.choked = true;
+                self.stats.choked.store(true, Ordering::Relaxed);
+                let dropped = self.queue.clear();
+                self.swarm
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .release(self.key, &dropped, false);
+            }
+            Message::Unchoke => {
+                self.choked = false;
+                self.stats.choked.store(false, Ordering::Relaxed);
+            }
+            Message::Have { index } => {
+                if index as usize >= self.has.len() {
+                    let reason = PeerError::violation(format!("have for unknown piece {index}"));
+                    return Err(conn.disconnect(reason).await);
+                }
+                if !self.has.get(index as usize) {
+                    self.has.set(index as usize);
+                    self.stats.pieces.store(self.has.count(), Ordering::Relaxed);
+                    self.swarm.picker.lock().unwrap().add_have(index);
+                }
+            }
+            Message::Bitfield(bits) => {
+                let has = match Bitfield::from_payload(bits, self.has.len()) {
+                    Ok(has) => has,
+                    Err(reason) => return Err(conn.disconnect(reason).await),
+                };
+                self.replace_has(has);
+            }
+            Message::HaveAll => self.replace_has(Bitfield::full(self.has.len())),
+            Message::HaveNone => self.replace_has(Bitfield::new(self.has.len())),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                self.queue.received(
+                    Block {
+                        piece: index,
+                        begin,
+                        length: block.len() as u32,
+                    },
+                    Instant::now(),
+                );
+                self.stats.downloaded.record(block.len() as u64);
+                self.swarm.downloaded.record(block.len() as u64);
+                let outcome = self
+                    .swarm
+                    .picker
+                    .lock()
+                    .unwrap()
+                    .on_block(self.key, index, begin, &block);
+                if let BlockOutcome::Completed(data) = outcome {
+                    tokio::spawn(self.swarm.clone().finish_piece(index, data));
+                }
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                if self.queue.remove(block) {
+                    self.swarm
+                        .picker
+                        .lock()
+                        .unwrap()
+                        .release(self.key, &[block], true);
+                }
+            }
+            Message::Extended { id: 0, payload } => {
+                let theirs = ExtendedHandshake::from_payload(&payload)?;
+                *self.stats.client.lock().unwrap() = theirs.v;
+                if let Some(reqq) = theirs.reqq {
+                    self.queue.set_max_depth(reqq.min(MAX_REQQ as u64) as usize);
+                }
+            }
+            // We don't upload yet, so peers stay choked and their requests are ignored
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn replace_has(&mut self, has: Bitfield) {
+        let mut picker = self.swarm.picker.lock().unwrap();
+        picker.remove_availability(&self.has);
+        picker.add_availability(&has);
+        self.stats.pieces.store(has.count(), Ordering::Relaxed);
+        self.has = has;
+    }
+}
@@ -0,0 +1,105 @@
+//! Byte streams to peers. The wire protocol doesn't care whether a peer is reached over TCP
+//! or uTP, so connections are made through [`Transports`] and handled as [`BoxedStream`]s.
+
+use crate::peers::error::PeerError;
+use crate::proxy::Proxy;
+use crate::utp::socket::UtpSocket;
+use futures::future::{BoxFuture, select_ok};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// How long each transport gets before the next one in line is tried alongside it
+pub const HEAD_START: Duration = Duration::from_millis(500);
+
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for S {}
+
+pub type BoxedStream = Box<dyn PeerStream>;
+
+/// A way of opening streams to peers
+pub trait Transport: Send + Sync {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>>;
+}
+
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        Box::pin(async move { Ok(Box::new(TcpStream::connect(addr).await?) as BoxedStream) })
+    }
+}
+
+/// TCP through a proxy
+impl Transport for Proxy {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        Box::pin(async move {
+            let stream = Proxy::connect(self, &addr.ip().to_string(), addr.port()).await?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+impl Transport for UtpSocket {
+    fn connect(&self, addr: SocketAddr) -> BoxFuture<'_, io::Result<BoxedStream>> {
+        Box::pin(async move { Ok(Box::new(UtpSocket::connect(self, addr).await?) as BoxedStream) })
+    }
+}
+
+/// The transports to reach peers over, most preferred first. Defaults to TCP alone.
+#[derive(Clone)]
+pub struct Transports(Arc<[Arc<dyn Transport>]>);
+
+impl Default for Transports {
+    fn default() -> Self {
+        Self::new(vec![Arc::new(Tcp)])
+    }
+}
+
+impl Transports {
+    pub fn new(transports: Vec<Arc<dyn Transport>>) -> Self {
+        Self(transports.into())
+    }
+
+    /// uTP first, falling back to TCP
+    pub fn with_utp(socket: UtpSocket) -> Self {
+        Self::new(vec![Arc::new(socket), Arc::new(Tcp)])
+    }
+
+    /// uTP if there's a socket for it, then TCP, through `proxy` when there is one. The uTP
+    /// socket should relay through the proxy as well, or uTP peers see our own address.
+    pub fn with_parts(utp: Option<UtpSocket>, proxy: Option<Proxy>) -> Self {
+        let tcp: Arc<dyn Transport> = match proxy {
+            Some(proxy) => Arc::new(proxy),
+            None => Arc::new(Tcp),
+        };
+        match utp {
+            Some(utp) => Self::new(vec![Arc::new(utp), tcp]),
+            None => Self::new(vec![tcp]),
+        }
+    }
+
+    /// Connect over whichever transport answers first, giving each one a head start on the
+    /// ones after it so peers are reached over the preferred transport when they support it
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        connect_timeout: Duration,
+    ) -> Result<BoxedStream, PeerError> {
+        let attempts = self.0.iter().enumerate().map(|(i, transport)| {
+            Box::pin(async move {
+                sleep(HEAD_START * i as u32).await;
+                transport.connect(addr).await
+            })
+        });
+        let (stream, _) = timeout(connect_timeout, select_ok(attempts))
+            .await
+            .map_err(|_| PeerError::Timeout("connecting"))??;
+        Ok(stream)
+    }
+}
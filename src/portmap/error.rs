@@ -0,0 +1,22 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PortMapError {
+    #[error("no default gateway found")]
+    NoGateway,
+    #[error("the gateway did not answer")]
+    Timeout,
+    /// A NAT-PMP or PCP result code other than success
+    #[error("gateway refused the mapping: {0}")]
+    Refused(&'static str),
+    /// A UPnP SOAP fault, e.g. 718 when the port is mapped to another host
+    #[error("UPnP error {code}: {description}")]
+    Upnp { code: u16, description: String },
+    #[error("malformed gateway reply: {0}")]
+    Malformed(String),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
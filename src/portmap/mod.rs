@@ -0,0 +1,360 @@
+//! Automatic port forwarding, so peers outside our NAT can connect to the listen port.
+//!
+//! The gateway is asked over PCP or NAT-PMP first, then over UPnP IGD. Leases are renewed
+//! halfway through and the mappings removed again on [`PortMapper::shutdown`].
+
+pub mod error;
+pub mod natpmp;
+pub mod upnp;
+
+use crate::events::{Event, Events};
+use crate::portmap::error::PortMapError;
+use crate::portmap::natpmp::PmpClient;
+use crate::portmap::upnp::{Gateway, SSDP_ADDR};
+use serde::Serialize;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+/// How long to wait before trying again after the gateway refused or didn't answer
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MIN_RENEWAL: Duration = Duration::from_secs(30);
+/// Removing mappings shouldn't hold up exiting for long
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        })
+    }
+}
+
+impl Protocol {
+    fn iana_number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Method {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapConfig {
+    pub natpmp: bool,
+    /// PCP and NAT-PMP server; None uses the default gateway
+    pub gateway: Option<SocketAddrV4>,
+    pub upnp: bool,
+    /// Where UPnP gateways are searched for
+    pub ssdp: SocketAddr,
+    /// Lease asked for; mappings are renewed halfway through it
+    pub lease: Duration,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        Self {
+            natpmp: true,
+            gateway: None,
+            upnp: true,
+            ssdp: SSDP_ADDR,
+            lease: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// A port the gateway forwards to us
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Mapping {
+    pub protocol: Protocol,
+    pub port: u16,
+    /// Where peers on the internet reach the port
+    pub external: SocketAddrV4,
+    pub method: Method,
+}
+
+/// Keeps ports mapped on the gateway from a background task
+pub struct PortMapper {
+    gateways: Arc<Gateways>,
+    mappings: Arc<Mutex<Vec<Mapping>>>,
+    task: JoinHandle<()>,
+}
+
+impl PortMapper {
+    pub fn start(ports: Vec<(Protocol, u16)>, config: PortMapConfig, events: Events) -> Self {
+        let pmp = config.natpmp.then(|| {
+            let gateway = config
+                .gateway
+                .or_else(|| default_gateway().map(|ip| SocketAddrV4::new(ip, natpmp::PORT)));
+            gateway.map(PmpClient::new)
+        });
+        let gateways = Arc::new(Gateways {
+            pmp: pmp.flatten(),
+            upnp: tokio::sync::Mutex::new(None),
+            config,
+        });
+        let mappings = Arc::new(Mutex::new(Vec::new()));
+        let task = tokio::spawn(keep_mapped(
+            ports,
+            gateways.clone(),
+            mappings.clone(),
+            events,
+        ));
+        Self {
+            gateways,
+            mappings,
+            task,
+        }
+    }
+
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.mappings.lock().unwrap().clone()
+    }
+
+    /// Stop renewing and remove every mapping from the gateway
+    pub async fn shutdown(&self) {
+        self.task.abort();
+        let mappings = std::mem::take(&mut *self.mappings.lock().unwrap());
+        let remove = async {
+            for mapping in mappings {
+                let _ = self.gateways.unmap(&mapping).await;
+            }
+        };
+        let _ = timeout(SHUTDOWN_TIMEOUT, remove).await;
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Gateways {
+    pmp: Option<PmpClient>,
+    // Found on first use
+    upnp: tokio::sync::Mutex<Option<Arc<Gateway>>>,
+    config: PortMapConfig,
+}
+
+impl Gateways {
+    /// Map `port` to the same port here, returning the mapping and the lease granted
+    async fn map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+    ) -> Result<(Mapping, Duration), PortMapError> {
+        let mut error = PortMapError::NoGateway;
+        if let Some(pmp) = &self.pmp {
+            match pmp.map(protocol, port, self.config.lease).await {
+                Ok(mapped) => {
+                    let mapping = Mapping {
+                        protocol,
+                        port,
+                        external: mapped.external,
+                        method: mapped.method,
+                    };
+                    return Ok((mapping, mapped.lifetime));
+                }
+                Err(e) => error = e,
+            }
+        }
+        if !self.config.upnp {
+            return Err(error);
+        }
+        let gateway = self.upnp_gateway().await?;
+        let lease = gateway
+            .add_port_mapping(protocol, port, self.config.lease)
+            .await?;
+        let mapping = Mapping {
+            protocol,
+            port,
+            external: SocketAddrV4::new(gateway.external_ip().await?, port),
+            method: Method::Upnp,
+        };
+        Ok((mapping, lease))
+    }
+
+    async fn unmap(&self, mapping: &Mapping) -> Result<(), PortMapError> {
+        match (mapping.method, &self.pmp) {
+            (Method::Upnp, _) => {
+                let gateway = self.upnp_gateway().await?;
+                gateway
+                    .delete_port_mapping(mapping.protocol, mapping.port)
+                    .await
+            }
+            (_, Some(pmp)) => pmp.unmap(mapping.protocol, mapping.port).await,
+            (_, None) => Err(PortMapError::NoGateway),
+        }
+    }
+
+    async fn upnp_gateway(&self) -> Result<Arc<Gateway>, PortMapError> {
+        let mut gateway = self.upnp.lock().await;
+        if gateway.is_none() {
+            *gateway = Some(Arc::new(Gateway::discover(self.config.ssdp).await?));
+        }
+        Ok(gateway.clone().unwrap())
+    }
+}
+
+// Map every port, then sleep until the first lease is half over; failures are retried
+// every RETRY_INTERVAL. Events go out when a mapping appears, changes or is lost.
+async fn keep_mapped(
+    ports: Vec<(Protocol, u16)>,
+    gateways: Arc<Gateways>,
+    mappings: Arc<Mutex<Vec<Mapping>>>,
+    events: Events,
+) {
+    let mut failed = Vec::new();
+    loop {
+        let mut renew_in = RETRY_INTERVAL;
+        for &(protocol, port) in &ports {
+            let result = gateways.map(protocol, port).await;
+            let mut mappings = mappings.lock().unwrap();
+            let previous = mappings
+                .iter()
+                .position(|m| m.protocol == protocol && m.port == port)
+                .map(|i| mappings.remove(i));
+            match result {
+                Ok((mapping, lease)) => {
+                    if lease > Duration::ZERO {
+                        renew_in = renew_in.min(lease / 2);
+                    } else {
+                        renew_in = renew_in.min(gateways.config.lease / 2);
+                    }
+                    failed.retain(|&p| p != (protocol, port));
+                    if previous != Some(mapping) {
+                        events.publish(Event::PortMapped {
+                            protocol,
+                            port,
+                            external: mapping.external.into(),
+                            method: mapping.method,
+                        });
+                    }
+                    mappings.push(mapping);
+                }
+                Err(e) => {
+                    if !failed.contains(&(protocol, port)) {
+                        failed.push((protocol, port));
+                        events.publish(Event::PortMapFailed {
+                            protocol,
+                            port,
+                            error: e.to_string(),
+                        });
+                    }
+                }
+            }
+        }
+        sleep(renew_in.max(MIN_RENEWAL)).await;
+    }
+}
+
+/// The IPv4 default gateway from the kernel routing table; Linux only
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_route(&routes)
+}
+
+// Addresses in /proc/net/route are hex in host byte order
+fn parse_default_route(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portmap::natpmp::tests::{EXTERNAL_IP, mock_gateway};
+    use crate::portmap::upnp::tests::mock_router;
+
+    const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0
+";
+
+    #[test]
+    fn finds_the_default_route() {
+        assert_eq!(
+            parse_default_route(ROUTES),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(parse_default_route(ROUTES.lines().next().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn maps_over_natpmp_and_removes_mappings_on_shutdown() {
+        let (gateway, lifetimes) = mock_gateway(false).await;
+        let events = Events::default();
+        let mut rx = events.subscribe();
+        let config = PortMapConfig {
+            gateway: Some(gateway),
+            upnp: false,
+            ..Default::default()
+        };
+        let mapper = PortMapper::start(vec![(Protocol::Tcp, 6881)], config, events);
+        let event = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        let expected = SocketAddrV4::new(EXTERNAL_IP, 6882);
+        assert!(matches!(
+            event.unwrap(),
+            Event::PortMapped { external, method: Method::NatPmp, .. } if external == expected.into()
+        ));
+        assert_eq!(mapper.mappings()[0].external, expected);
+
+        mapper.shutdown().await;
+        assert!(mapper.mappings().is_empty());
+        assert_eq!(*lifetimes.lock().unwrap(), [3600, 0]);
+    }
+
+    #[tokio::test]
+    async fn maps_over_upnp() {
+        let (ssdp, calls) = mock_router().await;
+        let events = Events::default();
+        let mut rx = events.subscribe();
+        let config = PortMapConfig {
+            natpmp: false,
+            ssdp,
+            ..Default::default()
+        };
+        let mapper = PortMapper::start(vec![(Protocol::Udp, 6881)], config, events);
+        let event = timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert!(matches!(
+            event.unwrap(),
+            Event::PortMapped {
+                protocol: Protocol::Udp,
+                method: Method::Upnp,
+                ..
+            }
+        ));
+        mapper.shutdown().await;
+        assert_eq!(
+            calls.lock().unwrap().last().unwrap(),
+            "DeletePortMapping 6881"
+        );
+    }
+}
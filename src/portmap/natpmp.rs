@@ -0,0 +1,325 @@
+//! NAT-PMP (RFC 6886) and its successor PCP (RFC 6887): ask the gateway over UDP to forward a
+//! port to us. PCP is tried first; a gateway that only speaks NAT-PMP answers it with an
+//! unsupported-version error and gets NAT-PMP from then on.
+
+use crate::portmap::error::PortMapError;
+use crate::portmap::{Method, Protocol};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// Where gateways listen for both protocols
+pub const PORT: u16 = 5351;
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const RESPONSE: u8 = 0x80;
+const UNSUPPORTED_VERSION: u8 = 1;
+// RFC 6886 starts at 250 ms and doubles; four tries give up after under 4 seconds
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+const TRIES: u32 = 4;
+
+/// A port forwarded by the gateway
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapped {
+    pub external: SocketAddrV4,
+    pub lifetime: Duration,
+    pub method: Method,
+}
+
+pub struct PmpClient {
+    gateway: SocketAddrV4,
+    // Ties PCP requests for the same mapping together
+    nonce: [u8; 12],
+    natpmp_only: AtomicBool,
+}
+
+impl PmpClient {
+    pub fn new(gateway: SocketAddrV4) -> Self {
+        Self {
+            gateway,
+            nonce: rand::random(),
+            natpmp_only: AtomicBool::new(false),
+        }
+    }
+
+    /// Ask for `port` to be forwarded on the same external port for `lifetime`
+    pub async fn map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+    ) -> Result<Mapped, PortMapError> {
+        let lifetime = lifetime.as_secs() as u32;
+        if !self.natpmp_only.load(Ordering::Relaxed) {
+            match self.pcp_map(protocol, port, lifetime).await? {
+                Some(mapped) => return Ok(mapped),
+                None => self.natpmp_only.store(true, Ordering::Relaxed),
+            }
+        }
+        self.natpmp_map(protocol, port, lifetime).await
+    }
+
+    /// Remove a mapping made by `map`
+    pub async fn unmap(&self, protocol: Protocol, port: u16) -> Result<(), PortMapError> {
+        self.map(protocol, port, Duration::ZERO).await?;
+        Ok(())
+    }
+
+    // None when the gateway only speaks NAT-PMP
+    async fn pcp_map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> Result<Option<Mapped>, PortMapError> {
+        let socket = self.socket().await?;
+        // Our address on the gateway's side, which PCP wants in every request
+        let IpAddr::V4(client) = socket.local_addr()?.ip() else {
+            return Err(PortMapError::NoGateway);
+        };
+        let mut request = vec![PCP_VERSION, PCP_MAP, 0, 0];
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&client.to_ipv6_mapped().octets());
+        request.extend_from_slice(&self.nonce);
+        request.push(protocol.iana_number());
+        request.extend_from_slice(&[0; 3]);
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        let reply = exchange(&socket, &request, |reply| {
+            // A NAT-PMP gateway answers in its own format
+            (reply.len() >= 4 && reply[0] == NATPMP_VERSION)
+                || (reply.len() >= 60
+                    && reply[1] == RESPONSE | PCP_MAP
+                    && reply[24..36] == self.nonce)
+        })
+        .await?;
+        if reply[0] == NATPMP_VERSION {
+            return match reply[3] {
+                UNSUPPORTED_VERSION => Ok(None),
+                code => Err(PortMapError::Refused(natpmp_result(code))),
+            };
+        }
+        if reply[3] != 0 {
+            return Err(PortMapError::Refused(pcp_result(reply[3])));
+        }
+        let lifetime = u32::from_be_bytes(reply[4..8].try_into().unwrap());
+        let external_port = u16::from_be_bytes([reply[42], reply[43]]);
+        let ip: [u8; 16] = reply[44..60].try_into().unwrap();
+        let ip = Ipv6Addr::from(ip)
+            .to_ipv4_mapped()
+            .ok_or_else(|| PortMapError::Malformed("external address is not IPv4".into()))?;
+        Ok(Some(Mapped {
+            external: SocketAddrV4::new(ip, external_port),
+            lifetime: Duration::from_secs(lifetime.into()),
+            method: Method::Pcp,
+        }))
+    }
+
+    async fn natpmp_map(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: u32,
+    ) -> Result<Mapped, PortMapError> {
+        let socket = self.socket().await?;
+        let op = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        // Deleting a mapping suggests external port 0
+        let suggested = if lifetime == 0 { 0 } else { port };
+        let mut request = vec![NATPMP_VERSION, op, 0, 0];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&suggested.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        let reply = exchange(&socket, &request, |reply| {
+            reply.len() >= 16 && reply[1] == RESPONSE | op
+        })
+        .await?;
+        check_natpmp(&reply)?;
+        let external_port = u16::from_be_bytes([reply[10], reply[11]]);
+        let lifetime = u32::from_be_bytes(reply[12..16].try_into().unwrap());
+
+        // The address comes from a separate request
+        let reply = exchange(&socket, &[NATPMP_VERSION, 0], |reply| {
+            reply.len() >= 12 && reply[1] == RESPONSE
+        })
+        .await?;
+        check_natpmp(&reply)?;
+        let ip = Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]);
+        Ok(Mapped {
+            external: SocketAddrV4::new(ip, external_port),
+            lifetime: Duration::from_secs(lifetime.into()),
+            method: Method::NatPmp,
+        })
+    }
+
+    async fn socket(&self) -> Result<UdpSocket, PortMapError> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        socket.connect(self.gateway).await?;
+        Ok(socket)
+    }
+}
+
+// Send `request` until a reply passing `accept` arrives, backing off between tries
+async fn exchange(
+    socket: &UdpSocket,
+    request: &[u8],
+    accept: impl Fn(&[u8]) -> bool,
+) -> Result<Vec<u8>, PortMapError> {
+    let mut buf = [0u8; 1100];
+    let mut wait = FIRST_TIMEOUT;
+    for _ in 0..TRIES {
+        socket.send(request).await?;
+        let reply = timeout(wait, async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if accept(&buf[..len]) {
+                    return Ok::<_, PortMapError>(buf[..len].to_vec());
+                }
+            }
+        });
+        match reply.await {
+            Ok(reply) => return reply,
+            Err(_) => wait *= 2,
+        }
+    }
+    Err(PortMapError::Timeout)
+}
+
+fn check_natpmp(reply: &[u8]) -> Result<(), PortMapError> {
+    match reply[3] {
+        0 => Ok(()),
+        code => Err(PortMapError::Refused(natpmp_result(code))),
+    }
+}
+
+fn natpmp_result(code: u8) -> &'static str {
+    match code {
+        UNSUPPORTED_VERSION => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown result code",
+    }
+}
+
+fn pcp_result(code: u8) -> &'static str {
+    match code {
+        UNSUPPORTED_VERSION => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        7 => "network failure",
+        8 => "out of resources",
+        9 => "unsupported protocol",
+        10 => "user quota exceeded",
+        11 => "cannot provide external port",
+        12 => "address mismatch",
+        _ => "unknown result code",
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    pub(crate) const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /// A gateway on loopback that maps every port one higher and records the lifetimes
+    /// it was asked for. Without `pcp` it only speaks NAT-PMP.
+    pub(crate) async fn mock_gateway(pcp: bool) -> (SocketAddrV4, Arc<Mutex<Vec<u32>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = socket.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1100];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let req = &buf[..len];
+                let mut reply = Vec::new();
+                match (req[0], req[1]) {
+                    (PCP_VERSION, PCP_MAP) if pcp => {
+                        let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+                        seen.lock().unwrap().push(lifetime);
+                        let port = u16::from_be_bytes([req[40], req[41]]) + 1;
+                        reply.extend_from_slice(&[PCP_VERSION, RESPONSE | PCP_MAP, 0, 0]);
+                        reply.extend_from_slice(&lifetime.to_be_bytes());
+                        reply.extend_from_slice(&[0; 16]);
+                        reply.extend_from_slice(&req[24..42]);
+                        reply.extend_from_slice(&port.to_be_bytes());
+                        reply.extend_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                    }
+                    (PCP_VERSION, _) => {
+                        reply.extend_from_slice(&[NATPMP_VERSION, RESPONSE | req[1], 0]);
+                        reply.push(UNSUPPORTED_VERSION);
+                        reply.extend_from_slice(&[0; 4]);
+                    }
+                    (NATPMP_VERSION, 0) => {
+                        reply.extend_from_slice(&[NATPMP_VERSION, RESPONSE, 0, 0, 0, 0, 0, 1]);
+                        reply.extend_from_slice(&EXTERNAL_IP.octets());
+                    }
+                    (NATPMP_VERSION, op) => {
+                        let lifetime = u32::from_be_bytes(req[8..12].try_into().unwrap());
+                        seen.lock().unwrap().push(lifetime);
+                        let port = u16::from_be_bytes([req[4], req[5]]) + 1;
+                        reply.extend_from_slice(&[NATPMP_VERSION, RESPONSE | op, 0, 0]);
+                        reply.extend_from_slice(&[0, 0, 0, 1]);
+                        reply.extend_from_slice(&req[4..6]);
+                        reply.extend_from_slice(&port.to_be_bytes());
+                        reply.extend_from_slice(&lifetime.to_be_bytes());
+                    }
+                    _ => continue,
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn maps_with_pcp_and_falls_back_to_natpmp() {
+        for (pcp, method) in [(true, Method::Pcp), (false, Method::NatPmp)] {
+            let (gateway, requests) = mock_gateway(pcp).await;
+            let client = PmpClient::new(gateway);
+            let mapped = client
+                .map(Protocol::Tcp, 6881, Duration::from_secs(3600))
+                .await
+                .unwrap();
+            assert_eq!(
+                mapped,
+                Mapped {
+                    external: SocketAddrV4::new(EXTERNAL_IP, 6882),
+                    lifetime: Duration::from_secs(3600),
+                    method,
+                }
+            );
+            client.unmap(Protocol::Tcp, 6881).await.unwrap();
+            assert_eq!(*requests.lock().unwrap(), [3600, 0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_gateway() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(addr) = silent.local_addr().unwrap() else {
+            unreachable!()
+        };
+        let client = PmpClient::new(addr);
+        let result = client
+            .map(Protocol::Udp, 6881, Duration::from_secs(60))
+            .await;
+        assert!(matches!(result, Err(PortMapError::Timeout)));
+    }
+}
@@ -0,0 +1,356 @@
+//! UPnP Internet Gateway Device port mapping: find the router with an SSDP search, read its
+//! device description for the WAN connection service, and ask that service over SOAP to
+//! forward ports.
+
+use crate::portmap::Protocol;
+use crate::portmap::error::PortMapError;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout_at};
+use url::Url;
+
+/// The SSDP multicast group gateways listen on
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SEARCH_TIME: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Some routers refuse leases and only take permanent mappings
+const ONLY_PERMANENT_LEASES: u16 = 725;
+const DESCRIPTION: &str = "RusTor";
+
+pub struct Gateway {
+    control_url: Url,
+    service_type: String,
+    // Our address on the router's network, which mappings point at
+    local_ip: Ipv4Addr,
+    client: reqwest::Client,
+}
+
+impl Gateway {
+    /// Search for a gateway with an SSDP M-SEARCH sent to `ssdp`, normally [`SSDP_ADDR`], and
+    /// take the first one that offers a WAN connection service
+    pub async fn discover(ssdp: SocketAddr) -> Result<Self, PortMapError> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nST: {SEARCH_TARGET}\r\n\
+             MAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n"
+        );
+        socket.send_to(search.as_bytes(), ssdp).await?;
+        let client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+        let deadline = Instant::now() + SEARCH_TIME;
+        let mut buf = [0u8; 2048];
+        let mut tried = Vec::new();
+        let mut last_error = PortMapError::Timeout;
+        while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, _) = received?;
+            let Some(location) = header(&String::from_utf8_lossy(&buf[..len]), "location")
+                .and_then(|l| Url::parse(l).ok())
+            else {
+                continue;
+            };
+            if tried.contains(&location) {
+                continue;
+            }
+            tried.push(location.clone());
+            match Self::from_description(&client, location).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn from_description(
+        client: &reqwest::Client,
+        location: Url,
+    ) -> Result<Self, PortMapError> {
+        let description = client.get(location.clone()).send().await?.text().await?;
+        let (service_type, control) = wan_service(&description)
+            .ok_or_else(|| PortMapError::Malformed("no WAN connection service".into()))?;
+        let base = match element(&description, "URLBase") {
+            Some(base) if !base.is_empty() => Url::parse(base)
+                .map_err(|e| PortMapError::Malformed(format!("bad URLBase: {e}")))?,
+            _ => location.clone(),
+        };
+        let control_url = base
+            .join(control)
+            .map_err(|e| PortMapError::Malformed(format!("bad controlURL: {e}")))?;
+        Ok(Self {
+            local_ip: local_ip_towards(&location).await?,
+            control_url,
+            service_type: service_type.to_string(),
+            client: client.clone(),
+        })
+    }
+
+    pub async fn external_ip(&self) -> Result<Ipv4Addr, PortMapError> {
+        let reply = self.soap("GetExternalIPAddress", &[]).await?;
+        element(&reply, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| PortMapError::Malformed("no external IP address".into()))
+    }
+
+    /// Forward `port` on the router to the same port here. Returns the lease granted, zero
+    /// for a permanent mapping.
+    pub async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease: Duration,
+    ) -> Result<Duration, PortMapError> {
+        match self.try_add(protocol, port, lease).await {
+            Err(PortMapError::Upnp {
+                code: ONLY_PERMANENT_LEASES,
+                ..
+            }) => {
+                self.try_add(protocol, port, Duration::ZERO).await?;
+                Ok(Duration::ZERO)
+            }
+            result => result.map(|_| lease),
+        }
+    }
+
+    pub async fn delete_port_mapping(
+        &self,
+        protocol: Protocol,
+        port: u16,
+    ) -> Result<(), PortMapError> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+        ];
+        self.soap("DeletePortMapping", &args).await?;
+        Ok(())
+    }
+
+    async fn try_add(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lease: Duration,
+    ) -> Result<(), PortMapError> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.to_string()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", self.local_ip.to_string()),
+            ("NewEnabled", "1".to_string()),
+            ("NewPortMappingDescription", DESCRIPTION.to_string()),
+            ("NewLeaseDuration", lease.as_secs().to_string()),
+        ];
+        self.soap("AddPortMapping", &args).await?;
+        Ok(())
+    }
+
+    // Call an action on the WAN connection service; SOAP faults become `PortMapError::Upnp`
+    async fn soap(&self, action: &str, args: &[(&str, String)]) -> Result<String, PortMapError> {
+        let service = &self.service_type;
+        let args: String = args
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+             <u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>"
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{service}#{action}\""))
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let reply = response.text().await?;
+        if let Some(code) = element(&reply, "errorCode").and_then(|c| c.parse().ok()) {
+            return Err(PortMapError::Upnp {
+                code,
+                description: element(&reply, "errorDescription")
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+        if !status.is_success() {
+            return Err(PortMapError::Malformed(format!(
+                "{action} returned {status}"
+            )));
+        }
+        Ok(reply)
+    }
+}
+
+// The address of ours that the router at `location` would see; connecting a UDP socket
+// picks the route without sending anything
+async fn local_ip_towards(location: &Url) -> Result<Ipv4Addr, PortMapError> {
+    let host = location.host_str().unwrap_or_default();
+    let port = location.port_or_known_default().unwrap_or(80);
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.connect((host, port)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(PortMapError::Malformed("gateway is not on IPv4".into())),
+    }
+}
+
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+// The type and control URL of the first WANIPConnection or WANPPPConnection service
+fn wan_service(description: &str) -> Option<(&str, &str)> {
+    description.split("<service>").skip(1).find_map(|service| {
+        let service_type = element(service, "serviceType")?;
+        let wan = service_type.contains(":WANIPConnection:")
+            || service_type.contains(":WANPPPConnection:");
+        Some((service_type, element(service, "controlURL")?)).filter(|_| wan)
+    })
+}
+
+// Text of the first `<name>` element; enough XML for device descriptions and SOAP replies
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{name}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    pub(crate) const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 4);
+    const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+
+    /// A router on loopback answering SSDP searches and SOAP calls. It only takes permanent
+    /// mappings, and records the actions called with their port and lease.
+    pub(crate) async fn mock_router() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", http.local_addr().unwrap());
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let seen = calls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = http.accept().await.unwrap();
+                tokio::spawn(answer_http(stream, seen.clone()));
+            }
+        });
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            loop {
+                let (len, from) = ssdp.recv_from(&mut buf).await.unwrap();
+                if buf[..len].starts_with(b"M-SEARCH") {
+                    let reply = format!(
+                        "HTTP/1.1 200 OK\r\nST: {SEARCH_TARGET}\r\nLOCATION: {location}\r\n\r\n"
+                    );
+                    ssdp.send_to(reply.as_bytes(), from).await.unwrap();
+                }
+            }
+        });
+        (ssdp_addr, calls)
+    }
+
+    async fn answer_http(mut stream: TcpStream, calls: Arc<Mutex<Vec<String>>>) {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let (head, body) = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize =
+                    header(head, "content-length").map_or(0, |l| l.parse().unwrap());
+                if body.len() >= length || n == 0 {
+                    break (head.to_string(), body.to_string());
+                }
+            }
+        };
+        let (status, reply) = if head.starts_with("GET /rootDesc.xml") {
+            let description = format!(
+                "<?xml version=\"1.0\"?><root><device><deviceList><device><serviceList>\
+                 <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1\
+                 </serviceType><controlURL>/ctl/L3F</controlURL></service>\
+                 <service><serviceType>{SERVICE}</serviceType>\
+                 <controlURL>/ctl/IPConn</controlURL></service>\
+                 </serviceList></device></deviceList></device></root>"
+            );
+            ("200 OK", description)
+        } else {
+            let action = header(&head, "soapaction").unwrap().trim_matches('"');
+            let action = action.split_once('#').unwrap().1.to_string();
+            let port = element(&body, "NewExternalPort").unwrap_or_default();
+            let lease = element(&body, "NewLeaseDuration").unwrap_or_default();
+            calls
+                .lock()
+                .unwrap()
+                .push(format!("{action} {port} {lease}").trim().to_string());
+            match action.as_str() {
+                "AddPortMapping" if lease != "0" => (
+                    "500 Internal Server Error",
+                    "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                     <errorCode>725</errorCode>\
+                     <errorDescription>OnlyPermanentLeasesSupported</errorDescription>\
+                     </UPnPError></detail></s:Fault></s:Body></s:Envelope>"
+                        .to_string(),
+                ),
+                "GetExternalIPAddress" => (
+                    "200 OK",
+                    format!(
+                        "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                         <NewExternalIPAddress>{EXTERNAL_IP}</NewExternalIPAddress>\
+                         </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                    ),
+                ),
+                _ => ("200 OK", "<s:Envelope><s:Body/></s:Envelope>".to_string()),
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {status}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{reply}",
+            reply.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn maps_ports_through_a_discovered_router() {
+        let (ssdp, calls) = mock_router().await;
+        let gateway = Gateway::discover(ssdp).await.unwrap();
+        assert_eq!(gateway.control_url.path(), "/ctl/IPConn");
+        assert_eq!(gateway.local_ip, Ipv4Addr::LOCALHOST);
+
+        let lease = gateway
+            .add_port_mapping(Protocol::Tcp, 6881, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(lease, Duration::ZERO);
+        assert_eq!(gateway.external_ip().await.unwrap(), EXTERNAL_IP);
+        gateway
+            .delete_port_mapping(Protocol::Tcp, 6881)
+            .await
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "AddPortMapping 6881 3600",
+                "AddPortMapping 6881 0",
+                "GetExternalIPAddress",
+                "DeletePortMapping 6881",
+            ]
+        );
+    }
+}
@@ -0,0 +1,31 @@
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("invalid proxy URL: {0}")]
+    InvalidUrl(String),
+    /// HTTP CONNECT only tunnels TCP
+    #[error("an HTTP proxy can't relay UDP")]
+    NoUdp,
+    #[error("the proxy wants a username and password")]
+    AuthRequired,
+    #[error("the proxy refused our username and password")]
+    AuthFailed,
+    /// A SOCKS5 reply code other than success, or an HTTP status other than 200
+    #[error("the proxy refused the request: {0}")]
+    Refused(String),
+    #[error("malformed proxy reply: {0}")]
+    Malformed(&'static str),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(e: ProxyError) -> Self {
+        match e {
+            ProxyError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
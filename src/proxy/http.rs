@@ -0,0 +1,106 @@
+//! Tunnels through HTTP proxies with the CONNECT method
+
+use crate::proxy::Proxy;
+use crate::proxy::error::ProxyError;
+use data_encoding::BASE64;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+// Proxies answer CONNECT with a status line and a few headers
+const MAX_REPLY_HEAD: usize = 8192;
+
+/// Open a TCP stream to `host:port` through the proxy
+pub async fn connect(proxy: &Proxy, host: &str, port: u16) -> Result<TcpStream, ProxyError> {
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    let authority = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((username, password)) = &proxy.credentials {
+        let credentials = BASE64.encode(format!("{username}:{password}").as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // A byte at a time, so nothing the other end sends after the reply is lost
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_REPLY_HEAD {
+            return Err(ProxyError::Malformed("reply head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        Some("407") if proxy.credentials.is_some() => Err(ProxyError::AuthFailed),
+        Some("407") => Err(ProxyError::AuthRequired),
+        Some(_) => Err(ProxyError::Refused(status_line.to_string())),
+        None => Err(ProxyError::Malformed("no HTTP status line")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyKind;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    // Tunnels for anyone sending `Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=` (user:secret)
+    async fn mock_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(client.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    if !head.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n") {
+                        let reply = "HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+                        client.write_all(reply.as_bytes()).await.unwrap();
+                        return;
+                    }
+                    let target = head.split_whitespace().nth(1).unwrap().to_string();
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    let reply = "HTTP/1.1 200 Connection established\r\n\r\n";
+                    client.write_all(reply.as_bytes()).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn tunnels_with_a_password() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+        });
+        let addr = mock_proxy().await;
+        let mut proxy = Proxy {
+            kind: ProxyKind::Http,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            credentials: None,
+        };
+
+        let anonymous = connect(&proxy, "127.0.0.1", port).await;
+        assert!(matches!(anonymous, Err(ProxyError::AuthRequired)));
+        proxy.credentials = Some(("user".into(), "secret".into()));
+        let mut stream = connect(&proxy, "127.0.0.1", port).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+}
@@ -0,0 +1,364 @@
+//! SOCKS5 client (RFC 1928) with username/password authentication (RFC 1929): TCP streams
+//! with CONNECT, datagrams with UDP ASSOCIATE.
+
+use crate::proxy::error::ProxyError;
+use crate::proxy::{Proxy, Target};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 1;
+const CONNECT: u8 = 1;
+const UDP_ASSOCIATE: u8 = 3;
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+/// Open a TCP stream to `target` through the proxy
+pub async fn connect(proxy: &Proxy, target: Target<'_>) -> Result<TcpStream, ProxyError> {
+    let mut stream = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+    request(&mut stream, proxy, CONNECT, target).await?;
+    Ok(stream)
+}
+
+/// A UDP ASSOCIATE session. Datagrams go to the proxy's relay with a SOCKS header in front,
+/// and the relay keeps forwarding for as long as the control connection stays open.
+pub struct UdpAssociation {
+    _control: TcpStream,
+    socket: UdpSocket,
+}
+
+impl UdpAssociation {
+    pub async fn new(proxy: &Proxy) -> Result<Self, ProxyError> {
+        let mut control = TcpStream::connect((proxy.host.as_str(), proxy.port)).await?;
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        // We can't know the address the relay will see us at, so we leave it unspecified
+        let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+        let mut relay = request(&mut control, proxy, UDP_ASSOCIATE, unspecified.into()).await?;
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        socket.connect(relay).await?;
+        Ok(Self {
+            _control: control,
+            socket,
+        })
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: Target<'_>) -> io::Result<usize> {
+        self.socket.send(&datagram(buf, target)?).await?;
+        Ok(buf.len())
+    }
+
+    pub fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.socket.try_send(&datagram(buf, target.into())?)?;
+        Ok(buf.len())
+    }
+
+    /// Receive a datagram relayed to us, with the SOCKS header taken off. Fragments and
+    /// datagrams from named hosts are dropped.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let len = self.socket.recv(buf).await?;
+            if let Some((from, header_len)) = parse_header(&buf[..len]) {
+                buf.copy_within(header_len..len, 0);
+                return Ok((len - header_len, from));
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+// Negotiate authentication, send `command` for `target` and return the address the proxy
+// bound for it
+async fn request(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    command: u8,
+    target: Target<'_>,
+) -> Result<SocketAddr, ProxyError> {
+    let methods: &[u8] = match proxy.credentials {
+        Some(_) => &[NO_AUTH, USER_PASS],
+        None => &[NO_AUTH],
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, &proxy.credentials) {
+        ([VERSION, NO_AUTH], _) => {}
+        ([VERSION, USER_PASS], Some((username, password))) => {
+            authenticate(stream, username, password).await?
+        }
+        ([VERSION, NO_ACCEPTABLE_METHOD], None) => return Err(ProxyError::AuthRequired),
+        ([VERSION, _], _) => return Err(ProxyError::Refused("no acceptable auth method".into())),
+        _ => return Err(ProxyError::Malformed("not a SOCKS5 proxy")),
+    }
+
+    let mut request = vec![VERSION, command, 0];
+    encode_target(target, &mut request)?;
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 3];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(ProxyError::Malformed("not a SOCKS5 reply"));
+    }
+    if reply[1] != 0 {
+        return Err(ProxyError::Refused(reply_message(reply[1]).into()));
+    }
+    read_addr(stream).await
+}
+
+async fn authenticate(
+    stream: &mut TcpStream,
+    username: &str,
+    password: &str,
+) -> Result<(), ProxyError> {
+    if username.len() > 255 || password.len() > 255 {
+        return Err(ProxyError::InvalidUrl("credentials over 255 bytes".into()));
+    }
+    let mut auth = vec![USER_PASS_VERSION, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.push(password.len() as u8);
+    auth.extend_from_slice(password.as_bytes());
+    stream.write_all(&auth).await?;
+    let mut status = [0u8; 2];
+    stream.read_exact(&mut status).await?;
+    match status[1] {
+        0 => Ok(()),
+        _ => Err(ProxyError::AuthFailed),
+    }
+}
+
+async fn read_addr(stream: &mut TcpStream) -> Result<SocketAddr, ProxyError> {
+    let ip = match stream.read_u8().await? {
+        ATYP_V4 => IpAddr::V4(Ipv4Addr::from(stream.read_u32().await?)),
+        ATYP_V6 => IpAddr::V6(Ipv6Addr::from(stream.read_u128().await?)),
+        ATYP_DOMAIN => {
+            // Only UDP relays need the bound address, and those are never named
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => return Err(ProxyError::Malformed("unknown address type")),
+    };
+    Ok(SocketAddr::new(ip, stream.read_u16().await?))
+}
+
+fn encode_target(target: Target<'_>, out: &mut Vec<u8>) -> Result<(), ProxyError> {
+    let port = match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            out.push(ATYP_V4);
+            out.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            out.push(ATYP_V6);
+            out.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Host(host, port) => {
+            let len = u8::try_from(host.len())
+                .map_err(|_| ProxyError::InvalidUrl(format!("host name too long: {host}")))?;
+            out.extend_from_slice(&[ATYP_DOMAIN, len]);
+            out.extend_from_slice(host.as_bytes());
+            port
+        }
+    };
+    out.extend_from_slice(&port.to_be_bytes());
+    Ok(())
+}
+
+fn datagram(payload: &[u8], target: Target<'_>) -> io::Result<Vec<u8>> {
+    // Reserved bytes and fragment number 0, as we never fragment
+    let mut packet = vec![0, 0, 0];
+    encode_target(target, &mut packet)?;
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+fn parse_header(packet: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (&[0, 0, 0, atyp], rest) = packet.split_first_chunk::<4>()? else {
+        return None;
+    };
+    let (ip, rest) = match atyp {
+        ATYP_V4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        ATYP_V6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (IpAddr::from(*ip), rest)
+        }
+        _ => return None,
+    };
+    let (port, rest) = rest.split_first_chunk::<2>()?;
+    let addr = SocketAddr::new(ip, u16::from_be_bytes(*port));
+    Some((addr, packet.len() - rest.len()))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown reply code",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyKind;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    // A SOCKS5 proxy on loopback that wants `user`/`secret`, and can CONNECT to IPs and
+    // relay UDP
+    async fn mock_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream));
+            }
+        });
+        addr
+    }
+
+    async fn serve(mut client: TcpStream) {
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).await.unwrap();
+        let mut methods = vec![0u8; head[1] as usize];
+        client.read_exact(&mut methods).await.unwrap();
+        if !methods.contains(&USER_PASS) {
+            client
+                .write_all(&[VERSION, NO_ACCEPTABLE_METHOD])
+                .await
+                .unwrap();
+            return;
+        }
+        client.write_all(&[VERSION, USER_PASS]).await.unwrap();
+        let mut version_len = [0u8; 2];
+        client.read_exact(&mut version_len).await.unwrap();
+        let mut username = vec![0u8; version_len[1] as usize];
+        client.read_exact(&mut username).await.unwrap();
+        let mut password = vec![0u8; client.read_u8().await.unwrap() as usize];
+        client.read_exact(&mut password).await.unwrap();
+        let ok = username == b"user" && password == b"secret";
+        client
+            .write_all(&[USER_PASS_VERSION, !ok as u8])
+            .await
+            .unwrap();
+        if !ok {
+            return;
+        }
+
+        let mut request = [0u8; 3];
+        client.read_exact(&mut request).await.unwrap();
+        let target = read_addr(&mut client).await.unwrap();
+        let mut reply = vec![VERSION, 0, 0];
+        match request[1] {
+            CONNECT => {
+                let mut upstream = TcpStream::connect(target).await.unwrap();
+                encode_target(upstream.local_addr().unwrap().into(), &mut reply).unwrap();
+                client.write_all(&reply).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            }
+            UDP_ASSOCIATE => {
+                let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+                encode_target(relay.local_addr().unwrap().into(), &mut reply).unwrap();
+                client.write_all(&reply).await.unwrap();
+                let relaying = tokio::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    let mut client_addr = None;
+                    loop {
+                        let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+                        if client_addr.is_none_or(|c| c == from) {
+                            client_addr = Some(from);
+                            let (to, header_len) = parse_header(&buf[..len]).unwrap();
+                            relay.send_to(&buf[header_len..len], to).await.unwrap();
+                        } else {
+                            let packet = datagram(&buf[..len], from.into()).unwrap();
+                            relay.send_to(&packet, client_addr.unwrap()).await.unwrap();
+                        }
+                    }
+                });
+                // The association ends with the control connection
+                let _ = client.read_u8().await;
+                relaying.abort();
+            }
+            _ => client.write_all(&[VERSION, 7, 0]).await.unwrap(),
+        }
+    }
+
+    fn proxy(addr: SocketAddr, credentials: Option<(&str, &str)>) -> Proxy {
+        Proxy {
+            kind: ProxyKind::Socks5,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            credentials: credentials.map(|(u, p)| (u.to_string(), p.to_string())),
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_with_a_password() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut rd, mut wr) = stream.split();
+            tokio::io::copy(&mut rd, &mut wr).await.unwrap();
+        });
+        let addr = mock_proxy().await;
+
+        let anonymous = connect(&proxy(addr, None), target.into()).await;
+        assert!(matches!(anonymous, Err(ProxyError::AuthRequired)));
+        let wrong = connect(&proxy(addr, Some(("user", "guess"))), target.into()).await;
+        assert!(matches!(wrong, Err(ProxyError::AuthFailed)));
+
+        let mut stream = connect(&proxy(addr, Some(("user", "secret"))), target.into())
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        let addr = mock_proxy().await;
+        let association = UdpAssociation::new(&proxy(addr, Some(("user", "secret"))))
+            .await
+            .unwrap();
+
+        association
+            .send_to(b"query", peer_addr.into())
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let (len, relay) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"query");
+        peer.send_to(b"reply", relay).await.unwrap();
+        let (len, from) = association.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from), (&b"reply"[..], peer_addr));
+    }
+}
@@ -0,0 +1,1115 @@
+// A session runs any number of torrents behind one listen port, one DHT node,
+// one global rate limit and one disk I/O pool
+#[cfg(feature = "dht")]
+use crate::dht::node::{DEFAULT_BOOTSTRAP, Dht};
+use crate::error::{Error, Result};
+use crate::events::{Event, Events};
+use crate::ipfilter::error::IpFilterError;
+use crate::ipfilter::{IpFilter, IpFilterStatus, PeerFilter};
+use crate::peers::bitfield::Bitfield;
+use crate::peers::error::PeerError;
+use crate::peers::mse;
+use crate::peers::peer::Handshake;
+use crate::peers::ratelimit::{RateLimit, RateLimiter};
+use crate::peers::stats::PeerInfo;
+use crate::peers::swarm::SwarmConfig;
+use crate::peers::transport::{BoxedStream, Transports};
+use crate::portmap::{Mapping, PortMapConfig, PortMapper, Protocol};
+use crate::proxy::{DatagramSocket, ProxyConfig, ProxyKind};
+use crate::session::reader::FileReader;
+use crate::session::torrent::{
+    FileInfo, ManagedTorrent, SessionContext, TorrentSource, TorrentState, TorrentStatus,
+    TrackerInfo,
+};
+use crate::storage::disk::{DEFAULT_DISK_THREADS, DiskPool};
+use crate::storage::files::FilePriority;
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::magnet::MagnetLink;
+use crate::tracker::http::DEFAULT_USER_AGENT;
+use crate::utp::socket::UtpSocket;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// TCP for peers, and UDP for uTP peers and the DHT; 0 picks a free port
+    pub listen_port: u16,
+    /// Start of our peer id, Azureus style; the rest is random
+    pub peer_id_prefix: String,
+    /// Sent to HTTP trackers
+    pub user_agent: String,
+    pub download_dir: PathBuf,
+    /// Besides `download_dir`, where API clients may save torrents and read .torrent files
+    pub allowed_dirs: Vec<PathBuf>,
+    pub rate: RateLimit,
+    pub swarm: SwarmConfig,
+    pub disk_threads: usize,
+    /// Reach and accept peers over uTP as well as TCP
+    pub utp: bool,
+    /// Forward the listen port on the router; None leaves the router alone
+    pub port_mapping: Option<PortMapConfig>,
+    pub proxy: ProxyConfig,
+    /// Blocklist of peer addresses, reloadable with [`Session::reload_ip_filter`]
+    pub ip_filter: Option<PathBuf>,
+    #[cfg(feature = "dht")]
+    pub dht: bool,
+    #[cfg(feature = "dht")]
+    pub dht_bootstrap: Vec<String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            peer_id_prefix: DEFAULT_PEER_ID_PREFIX.to_string(),
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            download_dir: PathBuf::from("."),
+            allowed_dirs: Vec::new(),
+            rate: RateLimit::default(),
+            swarm: SwarmConfig::default(),
+            disk_threads: DEFAULT_DISK_THREADS,
+            utp: true,
+            port_mapping: Some(PortMapConfig::default()),
+            proxy: ProxyConfig::default(),
+            ip_filter: None,
+            #[cfg(feature = "dht")]
+            dht: true,
+            #[cfg(feature = "dht")]
+            dht_bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+type Torrents = Arc<Mutex<HashMap<[u8; 20], Arc<ManagedTorrent>>>>;
+
+pub struct Session {
+    ctx: Arc<SessionContext>,
+    download_dir: PathBuf,
+    allowed_dirs: Vec<PathBuf>,
+    torrents: Torrents,
+    listeners: Vec<JoinHandle<()>>,
+    port_mapper: Option<PortMapper>,
+    ip_filter_file: Option<PathBuf>,
+}
+
+// `path` with its longest existing ancestor canonicalized, so a symlink can't lead out of a
+// directory; the rest may not exist yet
+fn resolve_existing(path: &Path) -> PathBuf {
+    let mut rest = Vec::new();
+    let mut existing = path;
+    loop {
+        if let Ok(real) = std::fs::canonicalize(existing) {
+            return rest.iter().rev().fold(real, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name);
+                existing = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+// Incoming connections still in their handshake, each of which may cost an MSE key exchange;
+// more are closed straight away
+const MAX_INCOMING_HANDSHAKES: usize = 32;
+
+/// Client and version tag at the start of our peer ids
+pub const DEFAULT_PEER_ID_PREFIX: &str = "-RS0001-";
+
+/// A random peer id starting with `prefix`, which is cut short at 20 bytes
+pub fn new_peer_id(prefix: &str) -> [u8; 20] {
+    use rand::Rng;
+
+    let mut peer_id = [0u8; 20];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(20)];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+    rand::thread_rng().fill(&mut peer_id[prefix.len()..]);
+    peer_id
+}
+
+impl Session {
+    pub async fn new(config: SessionConfig) -> Result<Self> {
+        let ip_filter = match &config.ip_filter {
+            Some(path) => IpFilter::load(path)?,
+            None => IpFilter::default(),
+        };
+        let listener = TcpListener::bind(("0.0.0.0", config.listen_port)).await?;
+        let listen_port = listener.local_addr()?.port();
+        let events = Events::default();
+        // The DHT and uTP share one UDP socket, which relays through a SOCKS5 peer proxy.
+        // An HTTP proxy can't carry UDP, so both stay off behind one.
+        #[cfg(feature = "dht")]
+        let wants_udp = config.utp || config.dht;
+        #[cfg(not(feature = "dht"))]
+        let wants_udp = config.utp;
+        let udp = match &config.proxy.peer {
+            _ if !wants_udp => None,
+            Some(proxy) if proxy.kind == ProxyKind::Http => None,
+            proxy => {
+                let addr = ("0.0.0.0", listen_port);
+                Some(DatagramSocket::bind_via(addr, proxy.as_ref()).await?)
+            }
+        };
+        // Only worth forwarding on the router when we aren't behind the proxy's relay
+        let udp_on_listen_port = matches!(udp, Some(DatagramSocket::Direct(_)));
+        #[cfg(feature = "dht")]
+        let (dht, utp) = match udp {
+            Some(udp) if config.dht => {
+                let (dht, forwarded) = Dht::with_socket(udp);
+                let node = dht.clone();
+                let routers = config.dht_bootstrap.clone();
+                let events = events.clone();
+                tokio::spawn(async move {
+                    let nodes = node.bootstrap(&routers).await;
+                    events.publish(Event::DhtBootstrapped { nodes });
+                });
+                let utp = config
+                    .utp
+                    .then(|| UtpSocket::with_socket(dht.socket().clone(), forwarded));
+                (Some(dht), utp)
+            }
+            udp => (None, udp.map(UtpSocket::from_socket)),
+        };
+        #[cfg(not(feature = "dht"))]
+        let utp = udp.map(UtpSocket::from_socket);
+        let transports = Transports::with_parts(utp.clone(), config.proxy.peer);
+        let ctx = Arc::new(SessionContext {
+            peer_id: new_peer_id(&config.peer_id_prefix),
+            user_agent: config.user_agent,
+            tracker_proxy: config.proxy.tracker,
+            listen_port,
+            limiter: Arc::new(RateLimiter::new(config.rate)),
+            disk: DiskPool::new(config.disk_threads),
+            #[cfg(feature = "dht")]
+            dht,
+            swarm_config: config.swarm,
+            transports,
+            ip_filter: Arc::new(PeerFilter::new(ip_filter)),
+            events,
+        });
+        let port_mapper = config.port_mapping.map(|portmap| {
+            let mut ports = vec![(Protocol::Tcp, listen_port)];
+            if udp_on_listen_port {
+                ports.push((Protocol::Udp, listen_port));
+            }
+            PortMapper::start(ports, portmap, ctx.events.clone())
+        });
+        let torrents: Torrents = Arc::default();
+        let handshakes = Arc::new(Semaphore::new(MAX_INCOMING_HANDSHAKES));
+        let mut listeners = vec![tokio::spawn(accept_loop(
+            listener,
+            torrents.clone(),
+            ctx.clone(),
+            handshakes.clone(),
+        ))];
+        if let Some(utp) = utp {
+            listeners.push(tokio::spawn(accept_utp_loop(
+                utp,
+                torrents.clone(),
+                ctx.clone(),
+                handshakes,
+            )));
+        }
+        Ok(Self {
+            ctx,
+            download_dir: config.download_dir,
+            allowed_dirs: config.allowed_dirs,
+            torrents,
+            listeners,
+            port_mapper,
+            ip_filter_file: config.ip_filter,
+        })
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.ctx.listen_port
+    }
+
+    /// Receive every event published from now on, for all torrents in the session
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.ctx.events.subscribe()
+    }
+
+    /// Add and start a torrent, saving it under `download_dir` or the session default
+    pub fn add(&self, source: TorrentSource, download_dir: Option<PathBuf>) -> Result<[u8; 20]> {
+        let info_hash = match &source {
+            TorrentSource::File(tf) => tf.info_hash,
+            TorrentSource::Magnet(m) => m.infohash.ok_or(TorrentError::V2Only)?,
+        };
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&info_hash) {
+            return Err(Error::DuplicateTorrent(info_hash));
+        }
+        let dir = download_dir.unwrap_or_else(|| self.download_dir.clone());
+        let torrent = Arc::new(ManagedTorrent::new(
+            source,
+            info_hash,
+            dir,
+            self.ctx.events.clone(),
+        ));
+        self.ctx.events.publish(Event::TorrentAdded { info_hash });
+        torrent.start(self.ctx.clone());
+        torrents.insert(info_hash, torrent);
+        Ok(info_hash)
+    }
+
+    /// Fetch the metadata of `magnet` from the peers a download of it would find, and return
+    /// it as a .torrent without adding the torrent
+    pub async fn fetch_torrent(&self, magnet: MagnetLink) -> Result<Vec<u8>> {
+        let info_hash = magnet.infohash.ok_or(TorrentError::V2Only)?;
+        let dir = self.download_dir.clone();
+        // Its events stay out of the session's; nobody was told it was added
+        let torrent = ManagedTorrent::new(
+            TorrentSource::Magnet(magnet),
+            info_hash,
+            dir,
+            Events::default(),
+        );
+        torrent.fetch_metadata(&self.ctx).await
+    }
+
+    /// Stop a torrent and forget it; downloaded data stays on disk
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Result<()> {
+        let torrent = self
+            .torrents
+            .lock()
+            .unwrap()
+            .remove(info_hash)
+            .ok_or(Error::UnknownTorrent(*info_hash))?;
+        torrent.pause();
+        self.ctx.events.publish(Event::TorrentRemoved {
+            info_hash: *info_hash,
+        });
+        Ok(())
+    }
+
+    pub fn pause(&self, info_hash: &[u8; 20]) -> Result<()> {
+        self.get(info_hash)?.pause();
+        Ok(())
+    }
+
+    pub fn resume(&self, info_hash: &[u8; 20]) -> Result<()> {
+        self.get(info_hash)?.start(self.ctx.clone());
+        Ok(())
+    }
+
+    pub fn status(&self, info_hash: &[u8; 20]) -> Result<TorrentStatus> {
+        Ok(self.get(info_hash)?.status())
+    }
+
+    pub fn peers(&self, info_hash: &[u8; 20]) -> Result<Vec<PeerInfo>> {
+        Ok(self.get(info_hash)?.peers())
+    }
+
+    pub fn trackers(&self, info_hash: &[u8; 20]) -> Result<Vec<TrackerInfo>> {
+        Ok(self.get(info_hash)?.trackers())
+    }
+
+    pub fn files(&self, info_hash: &[u8; 20]) -> Result<Vec<FileInfo>> {
+        Ok(self.get(info_hash)?.files())
+    }
+
+    /// Download a file with `priority`, or skip it; a finished torrent starts
+    /// downloading again when a file it lacks is wanted
+    pub fn set_file_priority(
+        &self,
+        info_hash: &[u8; 20],
+        index: usize,
+        priority: FilePriority,
+    ) -> Result<()> {
+        let torrent = self.get(info_hash)?;
+        torrent.set_file_priority(index, priority)?;
+        let unfinished = torrent.swarm().is_some_and(|s| !s.is_complete());
+        if torrent.state() == TorrentState::Finished && unfinished {
+            torrent.start(self.ctx.clone());
+        }
+        Ok(())
+    }
+
+    /// Fetch pieces in order from a playback position instead of rarest first, so a file
+    /// can be played while it downloads
+    pub fn set_sequential(&self, info_hash: &[u8; 20], sequential: bool) -> Result<()> {
+        self.get(info_hash)?.set_sequential(sequential);
+        Ok(())
+    }
+
+    /// Move the playback position of sequential mode to byte `offset` of file `index`,
+    /// turning the mode on
+    pub fn seek(&self, info_hash: &[u8; 20], index: usize, offset: u64) -> Result<()> {
+        self.get(info_hash)?.seek(index, offset)
+    }
+
+    /// Fetch `piece` ahead of others; once `within` has passed, blocks slow peers still owe
+    /// are requested from other peers as well
+    pub fn set_piece_deadline(
+        &self,
+        info_hash: &[u8; 20],
+        piece: u32,
+        within: Duration,
+    ) -> Result<()> {
+        self.get(info_hash)?.set_piece_deadline(piece, within)
+    }
+
+    /// Read file `index` of a torrent while it downloads; see [`FileReader`]. Waits for a
+    /// magnet link's metadata and for data already on disk to be checked, and fails if the
+    /// torrent is removed or stops on an error first. Callers that can't wait that long wrap
+    /// it in a timeout.
+    pub async fn open_file(&self, info_hash: &[u8; 20], index: usize) -> Result<FileReader> {
+        let mut events = self.subscribe();
+        loop {
+            let torrent = self.get(info_hash)?;
+            let unknown = Error::UnknownFile {
+                info_hash: *info_hash,
+                index,
+            };
+            if let Some(swarm) = torrent.swarm() {
+                return FileReader::new(swarm, index).ok_or(unknown);
+            }
+            let files = torrent.files().len();
+            if files > 0 && index >= files {
+                return Err(unknown);
+            }
+            if torrent.state() == TorrentState::Error {
+                return Err(Error::TorrentFailed {
+                    info_hash: *info_hash,
+                    error: torrent.status().error.unwrap_or_default(),
+                });
+            }
+            // Any event may be the swarm starting or the torrent going; a lagging receiver
+            // just looks again
+            if let Err(broadcast::error::RecvError::Closed) = events.recv().await {
+                return Err(Error::UnknownTorrent(*info_hash));
+            }
+        }
+    }
+
+    pub fn pieces(&self, info_hash: &[u8; 20]) -> Result<Bitfield> {
+        Ok(self.get(info_hash)?.pieces())
+    }
+
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        let mut list: Vec<_> = self
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .map(|t| t.status())
+            .collect();
+        list.sort_by(|a, b| a.info_hash.cmp(&b.info_hash));
+        list
+    }
+
+    /// Where torrents added without a directory of their own are saved
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    /// `path` taken relative to the download directory, with symlinks resolved, if it lies
+    /// within the download directory or one of the allowed directories. Paths from API
+    /// clients go through this, so they can't have the daemon read or write elsewhere.
+    pub fn allowed_path(&self, path: &Path) -> Result<PathBuf> {
+        let path = self.download_dir.join(path);
+        let not_allowed = || Error::PathNotAllowed(path.clone());
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Err(not_allowed());
+        }
+        let resolved = resolve_existing(&path);
+        std::iter::once(&self.download_dir)
+            .chain(&self.allowed_dirs)
+            .any(|root| resolved.starts_with(resolve_existing(root)))
+            .then_some(resolved)
+            .ok_or_else(not_allowed)
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.ctx.limiter.limit()
+    }
+
+    /// Change the limit shared by all torrents while they run
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.ctx.limiter.set_limit(limit);
+    }
+
+    /// Ports the router currently forwards to us
+    pub fn port_mappings(&self) -> Vec<Mapping> {
+        self.port_mapper
+            .as_ref()
+            .map(PortMapper::mappings)
+            .unwrap_or_default()
+    }
+
+    pub fn ip_filter_status(&self) -> IpFilterStatus {
+        IpFilterStatus {
+            file: self.ip_filter_file.clone(),
+            ranges: self.ctx.ip_filter.ranges(),
+            blocked: self.ctx.ip_filter.blocked(),
+        }
+    }
+
+    /// Read the filter file again; the old filter stays in place if it can't be read
+    pub fn reload_ip_filter(&self) -> Result<IpFilterStatus> {
+        let path = self.ip_filter_file.as_ref().ok_or(IpFilterError::NoFile)?;
+        self.ctx.ip_filter.set(IpFilter::load(path)?);
+        Ok(self.ip_filter_status())
+    }
+
+    /// Pause every torrent and remove our port mappings from the router. Dropping the
+    /// session also pauses torrents, but leaves the mappings to expire on their own.
+    pub async fn shutdown(&self) {
+        for torrent in self.torrents.lock().unwrap().values() {
+            torrent.pause();
+        }
+        if let Some(port_mapper) = &self.port_mapper {
+            port_mapper.shutdown().await;
+        }
+    }
+
+    fn get(&self, info_hash: &[u8; 20]) -> Result<Arc<ManagedTorrent>> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .cloned()
+            .ok_or(Error::UnknownTorrent(*info_hash))
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        for torrent in self.torrents.lock().unwrap().values() {
+            torrent.pause();
+        }
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    torrents: Torrents,
+    ctx: Arc<SessionContext>,
+    handshakes: Arc<Semaphore>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let Ok(permit) = handshakes.clone().try_acquire_owned() else {
+                    continue;
+                };
+                let stream = Box::new(stream);
+                let (torrents, ctx) = (torrents.clone(), ctx.clone());
+                tokio::spawn(handle_incoming(stream, addr, torrents, ctx, permit));
+            }
+            Err(e) => ctx.events.publish(Event::ListenError {
+                error: e.to_string(),
+            }),
+        }
+    }
+}
+
+async fn accept_utp_loop(
+    socket: UtpSocket,
+    torrents: Torrents,
+    ctx: Arc<SessionContext>,
+    handshakes: Arc<Semaphore>,
+) {
+    while let Ok((stream, addr)) = socket.accept().await {
+        let Ok(permit) = handshakes.clone().try_acquire_owned() else {
+            continue;
+        };
+        let stream = Box::new(stream);
+        let (torrents, ctx) = (torrents.clone(), ctx.clone());
+        tokio::spawn(handle_incoming(stream, addr, torrents, ctx, permit));
+    }
+}
+
+// Incoming peers are routed to a torrent by the info hash in their handshake
+async fn handle_incoming(
+    stream: BoxedStream,
+    addr: SocketAddr,
+    torrents: Torrents,
+    ctx: Arc<SessionContext>,
+    handshake_permit: OwnedSemaphorePermit,
+) {
+    if ctx.ip_filter.blocks(addr.ip()) {
+        return;
+    }
+    let encryption = ctx.swarm_config.encryption;
+    let handshake = async {
+        let info_hashes: Vec<[u8; 20]> = torrents.lock().unwrap().keys().copied().collect();
+        let mut stream = mse::accept(stream, &info_hashes, encryption).await?;
+        let remote = Handshake::read_from(&mut stream).await?;
+        Ok::<_, PeerError>((stream, remote))
+    };
+    let Ok(Ok((stream, remote))) = timeout(ctx.swarm_config.timeouts.handshake, handshake).await
+    else {
+        return;
+    };
+    drop(handshake_permit);
+    let torrent = torrents.lock().unwrap().get(&remote.infohash).cloned();
+    let Some(torrent) = torrent else { return };
+    if torrent.state() != TorrentState::Downloading {
+        return;
+    }
+    if let Some(swarm) = torrent.swarm() {
+        let _ = swarm.accept(stream, remote, addr).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::connection::{PeerConnection, PeerTimeouts};
+    use crate::peers::message::Message;
+    use crate::peers::mse::EncryptionPolicy;
+    use crate::torrentfile::create::TorrentBuilder;
+    use crate::torrentfile::torrent::TorrentFile;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    async fn wait_for(session: &Session, info_hash: &[u8; 20], state: TorrentState) {
+        for _ in 0..100 {
+            if session.status(info_hash).unwrap().state == state {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("torrent never reached {state:?}");
+    }
+
+    #[tokio::test]
+    async fn keeps_api_paths_within_allowed_dirs() {
+        let root = std::env::temp_dir().join(format!("rustor-allowed-{}", std::process::id()));
+        let downloads = root.join("downloads");
+        let extra = root.join("extra");
+        std::fs::create_dir_all(&downloads).unwrap();
+        std::fs::create_dir_all(&extra).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/etc", downloads.join("escape")).unwrap();
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: downloads.clone(),
+            allowed_dirs: vec![extra.clone()],
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let downloads = downloads.canonicalize().unwrap();
+        let extra = extra.canonicalize().unwrap();
+
+        let allowed = |path: &str| session.allowed_path(Path::new(path));
+        assert_eq!(allowed("movies/new").unwrap(), downloads.join("movies/new"));
+        let in_extra = extra.join("a.torrent");
+        assert_eq!(allowed(in_extra.to_str().unwrap()).unwrap(), in_extra);
+        for path in ["/etc", "/etc/passwd", "../extra", "movies/../../extra"] {
+            assert!(
+                matches!(allowed(path), Err(Error::PathNotAllowed(_))),
+                "{path}"
+            );
+        }
+        #[cfg(unix)]
+        assert!(matches!(
+            allowed("escape/passwd"),
+            Err(Error::PathNotAllowed(_))
+        ));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn manages_torrents_already_on_disk() {
+        let dir = std::env::temp_dir().join(format!("rustor-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![7u8; 100_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+        let again = TorrentFile::from_bytes(&tf.to_bytes().unwrap()).unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.clone(),
+            #[cfg(feature = "dht")]
+            dht_bootstrap: Vec::new(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        #[cfg(feature = "dht")]
+        assert_eq!(
+            session.ctx.dht.as_ref().unwrap().port(),
+            session.listen_port()
+        );
+
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        assert!(matches!(
+            session.add(TorrentSource::File(again), None),
+            Err(Error::DuplicateTorrent(h)) if h == info_hash
+        ));
+        wait_for(&session, &info_hash, TorrentState::Finished).await;
+        let status = session.status(&info_hash).unwrap();
+        assert_eq!((status.pieces_done, status.pieces_total), (7, 7));
+        assert_eq!(status.name.as_deref(), Some("data.bin"));
+
+        session.pause(&info_hash).unwrap();
+        assert_eq!(session.list()[0].state, TorrentState::Paused);
+        session.resume(&info_hash).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Finished).await;
+
+        session.remove(&info_hash).unwrap();
+        assert!(session.list().is_empty());
+        assert!(matches!(
+            session.pause(&info_hash),
+            Err(Error::UnknownTorrent(_))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_torrent_events() {
+        let dir = std::env::temp_dir().join(format!("rustor-events-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![3u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.clone(),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.subscribe();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+
+        let mut states = Vec::new();
+        let first = events.recv().await.unwrap();
+        assert!(matches!(first, Event::TorrentAdded { info_hash: h } if h == info_hash));
+        while states.last() != Some(&TorrentState::Finished) {
+            let event = timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let Event::StateChanged { state, .. } = event {
+                states.push(state);
+            }
+        }
+        assert_eq!(states, [TorrentState::Checking, TorrentState::Finished]);
+
+        let json = serde_json::to_value(Event::StateChanged {
+            info_hash,
+            state: TorrentState::Finished,
+        })
+        .unwrap();
+        assert_eq!(json["type"], "state_changed");
+        assert_eq!(json["info_hash"], hex::encode(info_hash));
+        assert_eq!(json["state"], "finished");
+
+        session.remove(&info_hash).unwrap();
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            Event::StateChanged {
+                state: TorrentState::Paused,
+                ..
+            }
+        ));
+        assert!(matches!(
+            events.recv().await.unwrap(),
+            Event::TorrentRemoved { .. }
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_only_encrypted_peers_when_forced() {
+        let dir = std::env::temp_dir().join(format!("rustor-mse-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![5u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        // The content isn't in the download directory, so the torrent waits for peers
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            swarm: SwarmConfig {
+                encryption: EncryptionPolicy::Forced,
+                ..Default::default()
+            },
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Downloading).await;
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.listen_port());
+        let handshake = Handshake::new(info_hash, [9; 20]);
+        let tcp = Transports::default();
+        let timeouts = PeerTimeouts::default();
+        let (_conn, remote) =
+            PeerConnection::establish(addr, &handshake, &tcp, timeouts, EncryptionPolicy::Forced)
+                .await
+                .unwrap();
+        assert_eq!(remote.infohash, info_hash);
+        let plaintext =
+            PeerConnection::establish(addr, &handshake, &tcp, timeouts, EncryptionPolicy::Disabled);
+        assert!(plaintext.await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepts_peers_over_utp() {
+        let dir = std::env::temp_dir().join(format!("rustor-utp-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![6u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        // With the DHT on, uTP shares its UDP socket
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            #[cfg(feature = "dht")]
+            dht_bootstrap: Vec::new(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Downloading).await;
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.listen_port());
+        let utp = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let utp_only = Transports::new(vec![Arc::new(utp)]);
+        let handshake = Handshake::new(info_hash, [9; 20]);
+        let (mut conn, remote) = PeerConnection::establish(
+            addr,
+            &handshake,
+            &utp_only,
+            PeerTimeouts::default(),
+            EncryptionPolicy::Enabled,
+        )
+        .await
+        .unwrap();
+        assert_eq!(remote.infohash, info_hash);
+        // The torrent's swarm takes the connection over and wants what we have
+        loop {
+            let msg = conn.recv_timeout(Duration::from_secs(5), "interest").await;
+            if matches!(msg.unwrap(), Message::Interested) {
+                break;
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_filtered_peers_until_reloaded() {
+        let dir = std::env::temp_dir().join(format!("rustor-ipfilter-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let content = dir.join("data.bin");
+        std::fs::write(&content, vec![8u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(&content)
+            .piece_length(16384)
+            .build()
+            .unwrap();
+        let blocklist = dir.join("blocklist.p2p");
+        std::fs::write(&blocklist, "Loopback:127.0.0.0-127.255.255.255\n").unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            ip_filter: Some(blocklist.clone()),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Downloading).await;
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.listen_port());
+        let handshake = Handshake::new(info_hash, [9; 20]);
+        let tcp = Transports::default();
+        let connect = || {
+            let timeouts = PeerTimeouts::default();
+            PeerConnection::establish(addr, &handshake, &tcp, timeouts, EncryptionPolicy::Disabled)
+        };
+        assert!(connect().await.is_err());
+        let status = session.ip_filter_status();
+        assert_eq!((status.ranges, status.blocked), (1, 1));
+
+        std::fs::write(&blocklist, "# nothing blocked\n").unwrap();
+        assert_eq!(session.reload_ip_filter().unwrap().ranges, 0);
+        let (_conn, remote) = connect().await.unwrap();
+        assert_eq!(remote.infohash, info_hash);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_for_a_file_of_a_removed_torrent() {
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: std::env::temp_dir(),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let magnet = crate::torrentfile::magnet::parse_magnet_link(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+        )
+        .unwrap();
+        let info_hash = session.add(TorrentSource::Magnet(magnet), None).unwrap();
+        let open = session.open_file(&info_hash, 0);
+        tokio::pin!(open);
+        // Nobody has the metadata, so this waits until the torrent goes
+        assert!(futures::poll!(&mut open).is_pending());
+        session.remove(&info_hash).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), open).await;
+        assert!(matches!(result, Ok(Err(Error::UnknownTorrent(_)))));
+    }
+
+    #[tokio::test]
+    async fn reads_a_file_while_it_downloads() {
+        use std::io::SeekFrom;
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let dir = std::env::temp_dir().join(format!("rustor-reader-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let content: Vec<u8> = (0..40_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("data.bin"), &content).unwrap();
+        let tf = TorrentBuilder::new(dir.join("data.bin"))
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Downloading).await;
+        assert!(session.open_file(&info_hash, 1).await.is_err());
+        let mut reader = session.open_file(&info_hash, 0).await.unwrap();
+        assert_eq!(reader.len(), 40_000);
+        reader.seek(SeekFrom::Start(30_000)).await.unwrap();
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.listen_port());
+        let handshake = Handshake::new(info_hash, [9; 20]);
+        let (mut conn, _) = PeerConnection::establish(
+            addr,
+            &handshake,
+            &Transports::default(),
+            PeerTimeouts::default(),
+            EncryptionPolicy::Disabled,
+        )
+        .await
+        .unwrap();
+        let mut buf = vec![0; 5_000];
+        let mut requested = Vec::new();
+        {
+            let mut read = std::pin::pin!(reader.read_exact(&mut buf));
+            // Nothing is on disk yet; the first poll puts the pieces being read first
+            assert!(futures::poll!(&mut read).is_pending());
+            conn.send(Message::Bitfield(vec![0b1110_0000]))
+                .await
+                .unwrap();
+            conn.send(Message::Unchoke).await.unwrap();
+            loop {
+                tokio::select! {
+                    done = &mut read => {
+                        done.unwrap();
+                        break;
+                    }
+                    msg = conn.recv_timeout(Duration::from_secs(5), "request") => {
+                        let Message::Request { index, begin, length } = msg.unwrap() else {
+                            continue;
+                        };
+                        requested.push(index);
+                        let start = index as usize * 16384 + begin as usize;
+                        let block = content[start..start + length as usize].to_vec();
+                        conn.send(Message::Piece { index, begin, block }).await.unwrap();
+                    }
+                }
+            }
+        }
+        assert_eq!(requested[0], 1);
+        assert_eq!(buf, content[30_000..35_000]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bans_peers_that_send_pieces_failing_the_hash_check() {
+        let dir = std::env::temp_dir().join(format!("rustor-badhash-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("data.bin"), vec![1u8; 40_000]).unwrap();
+        let tf = TorrentBuilder::new(dir.join("data.bin"))
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            #[cfg(feature = "dht")]
+            dht: false,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.subscribe();
+        let info_hash = session.add(TorrentSource::File(tf), None).unwrap();
+        wait_for(&session, &info_hash, TorrentState::Downloading).await;
+
+        let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, session.listen_port());
+        let handshake = Handshake::new(info_hash, [9; 20]);
+        let tcp = Transports::default();
+        let connect = || {
+            let timeouts = PeerTimeouts::default();
+            PeerConnection::establish(addr, &handshake, &tcp, timeouts, EncryptionPolicy::Disabled)
+        };
+        let (mut conn, _) = connect().await.unwrap();
+        conn.send(Message::HaveAll).await.unwrap();
+        conn.send(Message::Unchoke).await.unwrap();
+        // Answer every request with the wrong bytes until we get thrown out
+        loop {
+            match conn.recv_timeout(Duration::from_secs(5), "request").await {
+                Ok(Message::Request {
+                    index,
+                    begin,
+                    length,
+                }) => {
+                    let block = vec![2u8; length as usize];
+                    let piece = Message::Piece {
+                        index,
+                        begin,
+                        block,
+                    };
+                    if conn.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(PeerError::Disconnected) => break,
+                Err(e) => panic!("expected to be disconnected, got {e}"),
+            }
+        }
+
+        let mut failed = false;
+        loop {
+            match timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+            {
+                Event::HashFailed { .. } => failed = true,
+                Event::PeerDisconnected { reason, .. } => {
+                    assert!(reason.unwrap().contains("failed the hash check"));
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert!(failed);
+        assert!(session.status(&info_hash).unwrap().pieces_done == 0);
+        // Coming back from another port doesn't help
+        assert!(connect().await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "dht")]
+    #[tokio::test]
+    async fn keeps_private_torrents_off_the_dht() {
+        let dir = std::env::temp_dir().join(format!("rustor-private-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        std::fs::write(dir.join("public.bin"), vec![1u8; 20_000]).unwrap();
+        std::fs::write(dir.join("private.bin"), vec![2u8; 20_000]).unwrap();
+        let public = TorrentBuilder::new(dir.join("public.bin")).build().unwrap();
+        let private = TorrentBuilder::new(dir.join("private.bin"))
+            .private(true)
+            .build()
+            .unwrap();
+
+        let router = Dht::bind(0).await.unwrap();
+        let router_addr = format!("127.0.0.1:{}", router.port());
+        let session = Session::new(SessionConfig {
+            listen_port: 0,
+            port_mapping: None,
+            download_dir: dir.join("empty"),
+            dht_bootstrap: vec![router_addr.clone()],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut events = session.subscribe();
+        loop {
+            let event = timeout(Duration::from_secs(5), events.recv()).await;
+            if let Event::DhtBootstrapped { nodes } = event.unwrap().unwrap() {
+                assert_eq!(nodes, 1);
+                break;
+            }
+        }
+        let private_hash = session.add(TorrentSource::File(private), None).unwrap();
+        let public_hash = session.add(TorrentSource::File(public), None).unwrap();
+
+        // Once the public torrent shows up on the DHT, the private one had its chance too
+        let lookup = Dht::bind(0).await.unwrap();
+        assert!(lookup.bootstrap(&[router_addr]).await > 0);
+        let mut found = Vec::new();
+        for _ in 0..100 {
+            found = lookup.get_peers(public_hash).await;
+            if !found.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(found.len(), 1);
+        assert!(lookup.get_peers(private_hash).await.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
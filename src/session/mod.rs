@@ -0,0 +1,5 @@
+//! Running many torrents together behind one listen port.
+
+pub mod manager;
+pub mod reader;
+pub mod torrent;
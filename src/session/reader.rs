@@ -0,0 +1,223 @@
+//! Reading a file of a torrent while it downloads
+
+use crate::peers::swarm::Swarm;
+use bytes::{Buf, Bytes};
+use futures::future::BoxFuture;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+// Pieces past the read position fetched ahead of others, and how far apart their deadlines
+// are; the piece being read gets the first one
+const READ_AHEAD: u32 = 4;
+const DEADLINE_SPACING: Duration = Duration::from_secs(2);
+
+/// One file of a torrent, read from verified pieces on disk. A read of data that isn't
+/// downloaded yet puts its piece and the next few ahead of everything else and waits for
+/// them; it waits indefinitely while the torrent is paused, and fails if the data is in
+/// skipped files only.
+pub struct FileReader {
+    swarm: Arc<Swarm>,
+    // Where the file starts in the torrent
+    start: u64,
+    length: u64,
+    pos: u64,
+    // The rest of the piece last read, from `pos` on, for callers reading less than a piece
+    // at a time
+    buffered: Bytes,
+    read: Option<BoxFuture<'static, io::Result<Vec<u8>>>>,
+}
+
+impl FileReader {
+    /// None if the torrent has no file `index`
+    pub fn new(swarm: Arc<Swarm>, index: usize) -> Option<Self> {
+        let file = swarm.files().get(index)?;
+        Some(Self {
+            start: file.offset,
+            length: file.length,
+            swarm,
+            pos: 0,
+            buffered: Bytes::new(),
+            read: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+}
+
+async fn read(swarm: Arc<Swarm>, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    if !swarm.prioritise(offset, READ_AHEAD + 1, DEADLINE_SPACING) {
+        return Err(io::Error::other(format!(
+            "byte {offset} of the torrent is in skipped files only"
+        )));
+    }
+    swarm
+        .read_verified(offset, len)
+        .await
+        .map_err(io::Error::other)
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let left = this.length.saturating_sub(this.pos);
+        if left == 0 || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if this.buffered.is_empty() {
+            // The rest of the piece at most, so the next reads don't verify it again
+            let pending = this.read.get_or_insert_with(|| {
+                let len = usize::try_from(left).unwrap_or(usize::MAX);
+                Box::pin(read(this.swarm.clone(), this.start + this.pos, len))
+            });
+            let result = ready!(pending.as_mut().poll(cx));
+            this.read = None;
+            this.buffered = result?.into();
+        }
+        let n = this.buffered.len().min(buf.remaining());
+        buf.put_slice(&this.buffered[..n]);
+        this.buffered.advance(n);
+        this.pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        this.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            )
+        })?;
+        this.buffered.clear();
+        this.read = None;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peers::swarm::SwarmConfig;
+    use crate::storage::files::FilePriority;
+    use crate::torrentfile::create::TorrentBuilder;
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    // Two files of 20000 and 15000 bytes in pieces of 16384, so piece 1 holds the end of
+    // the first and the start of the second
+    async fn swarm(dir: &Path, download_dir: &Path) -> (Arc<Swarm>, Vec<u8>) {
+        let content: Vec<u8> = (0..35_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::create_dir_all(dir.join("both")).unwrap();
+        std::fs::write(dir.join("both/a.bin"), &content[..20_000]).unwrap();
+        std::fs::write(dir.join("both/b.bin"), &content[20_000..]).unwrap();
+        let tf = TorrentBuilder::new(dir.join("both"))
+            .piece_length(16384)
+            .build()
+            .unwrap();
+        let swarm = Swarm::new(&tf, [1; 20], download_dir, SwarmConfig::default()).unwrap();
+        swarm.check_existing().await.unwrap();
+        (Arc::new(swarm), content)
+    }
+
+    #[tokio::test]
+    async fn reads_each_file_to_its_own_end() {
+        let dir = std::env::temp_dir().join(format!("rustor-filereader-{}", std::process::id()));
+        let (swarm, content) = swarm(&dir, &dir).await;
+
+        let mut first = FileReader::new(swarm.clone(), 0).unwrap();
+        let mut data = Vec::new();
+        first.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content[..20_000]);
+        assert_eq!(first.read(&mut [0; 16]).await.unwrap(), 0);
+
+        let mut second = FileReader::new(swarm.clone(), 1).unwrap();
+        let mut data = Vec::new();
+        second.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content[20_000..]);
+        assert!(FileReader::new(swarm, 2).is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_the_rest_of_a_piece_for_the_next_read() {
+        let dir = std::env::temp_dir().join(format!("rustor-filebuffer-{}", std::process::id()));
+        let (swarm, content) = swarm(&dir, &dir).await;
+        let mut reader = FileReader::new(swarm, 0).unwrap();
+        let mut buf = [0; 100];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, content[..100]);
+
+        // The rest of piece 0 comes from the first read, not the disk
+        std::fs::write(dir.join("both/a.bin"), [0; 20_000]).unwrap();
+        let mut buf = vec![0; 16384 - 100];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, content[100..16384]);
+
+        // A seek starts over from the disk
+        reader.seek(SeekFrom::Start(50)).await.unwrap();
+        let mut buf = [0xff; 10];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0; 10]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn seeks_within_and_past_the_file() {
+        let dir = std::env::temp_dir().join(format!("rustor-fileseek-{}", std::process::id()));
+        let (swarm, content) = swarm(&dir, &dir).await;
+        let mut reader = FileReader::new(swarm, 1).unwrap();
+        assert_eq!(reader.seek(SeekFrom::End(-10)).await.unwrap(), 14_990);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, content[34_990..]);
+
+        assert_eq!(reader.seek(SeekFrom::End(100)).await.unwrap(), 15_100);
+        assert_eq!(reader.read(&mut [0; 16]).await.unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Current(-15_100)).await.unwrap(), 0);
+        let error = reader.seek(SeekFrom::Current(-1)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_to_read_a_skipped_file() {
+        let dir = std::env::temp_dir().join(format!("rustor-fileskip-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        let (swarm, _) = swarm(&dir, &dir.join("empty")).await;
+        swarm
+            .set_file_priorities(&[FilePriority::Normal, FilePriority::Skip])
+            .await
+            .unwrap();
+        let mut reader = FileReader::new(swarm, 1).unwrap();
+        // Past piece 1, which the first file still needs
+        reader.seek(SeekFrom::Start(13_000)).await.unwrap();
+        let error = reader.read(&mut [0; 16]).await.unwrap_err();
+        assert!(error.to_string().contains("skipped files only"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,626 @@
+// One torrent inside a session and the task that moves it through its states
+#[cfg(feature = "dht")]
+use crate::dht::node::Dht;
+use crate::error::{Error, Result};
+use crate::events::{Event, Events};
+use crate::ipfilter::PeerFilter;
+use crate::peers::bitfield::Bitfield;
+use crate::peers::metadata::fetch_metadata_from_peers;
+use crate::peers::ratelimit::RateLimiter;
+use crate::peers::stats::PeerInfo;
+use crate::peers::swarm::{Swarm, SwarmConfig};
+use crate::peers::transport::Transports;
+use crate::proxy::Proxy;
+use crate::storage::disk::DiskPool;
+use crate::storage::files::{FilePriority, Storage};
+use crate::torrentfile::convert::magnet_to_torrent;
+use crate::torrentfile::magnet::MagnetLink;
+use crate::torrentfile::torrent::TorrentFile;
+use crate::tracker::announce::{announce, reply_peers};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+// How often to ask trackers and the DHT for more peers, and how soon to retry when none came back
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+pub enum TorrentSource {
+    File(TorrentFile),
+    Magnet(MagnetLink),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    FetchingMetadata,
+    Checking,
+    Downloading,
+    /// Every wanted piece is verified on disk. Nothing is uploaded, so peers are dropped.
+    Finished,
+    Paused,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: Option<String>,
+    pub state: TorrentState,
+    pub pieces_done: usize,
+    pub pieces_total: usize,
+    pub total_size: Option<u64>,
+    pub peers: usize,
+    pub download_dir: PathBuf,
+    pub error: Option<String>,
+    /// Payload bytes received this run
+    pub downloaded: u64,
+    /// Bytes per second, averaged over the last few seconds
+    pub download_rate: u64,
+    /// Seconds until the download finishes at the current rate
+    pub eta: Option<u64>,
+    /// Pieces are fetched in order from a playback position instead of rarest first
+    pub sequential: bool,
+}
+
+/// The last announce to one tracker
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrackerInfo {
+    pub url: String,
+    /// None until the tracker has answered
+    pub peers: Option<usize>,
+    pub warning: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// Relative to the download directory
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes in pieces that passed their hash check
+    pub done: u64,
+    pub priority: FilePriority,
+}
+
+/// What the torrents of one session share
+pub struct SessionContext {
+    pub peer_id: [u8; 20],
+    pub user_agent: String,
+    /// Announces go through this proxy when there is one
+    pub tracker_proxy: Option<Proxy>,
+    pub listen_port: u16,
+    pub limiter: Arc<RateLimiter>,
+    pub disk: DiskPool,
+    #[cfg(feature = "dht")]
+    pub dht: Option<Arc<Dht>>,
+    pub swarm_config: SwarmConfig,
+    pub transports: Transports,
+    /// Checked before connecting to or accepting a peer
+    pub ip_filter: Arc<PeerFilter>,
+    pub events: Events,
+}
+
+pub struct ManagedTorrent {
+    pub info_hash: [u8; 20],
+    magnet: Option<MagnetLink>,
+    trackers: Vec<String>,
+    download_dir: PathBuf,
+    state: Mutex<TorrentState>,
+    error: Mutex<Option<String>>,
+    metadata: Mutex<Option<Arc<TorrentFile>>>,
+    swarm: Mutex<Option<Arc<Swarm>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+    tracker_info: Mutex<Vec<TrackerInfo>>,
+    selection: Mutex<Selection>,
+    events: Events,
+}
+
+// What to fetch and in which order. Kept outside the swarm so it can be set before there is
+// one and survives a restart.
+#[derive(Default)]
+struct Selection {
+    // One per file once there is metadata; files past the end are normal
+    priorities: Vec<FilePriority>,
+    // Byte offset of the torrent sequential mode reads from
+    sequential: Option<u64>,
+    // Deadlines set before the swarm existed
+    deadlines: Vec<(u32, Duration)>,
+}
+
+impl Selection {
+    fn apply(&mut self, swarm: &Arc<Swarm>) {
+        self.priorities
+            .resize(swarm.file_priorities().len(), FilePriority::Normal);
+        swarm.set_file_priorities(&self.priorities);
+        swarm.set_sequential(self.sequential);
+        for (piece, within) in self.deadlines.drain(..) {
+            swarm.set_piece_deadline(piece, within);
+        }
+    }
+}
+
+impl ManagedTorrent {
+    pub fn new(
+        source: TorrentSource,
+        info_hash: [u8; 20],
+        download_dir: PathBuf,
+        events: Events,
+    ) -> Self {
+        let (magnet, trackers, metadata) = match source {
+            TorrentSource::File(tf) => (None, torrent_trackers(&tf), Some(Arc::new(tf))),
+            TorrentSource::Magnet(m) => {
+                let trackers = m.trackers.clone();
+                (Some(m), trackers, None)
+            }
+        };
+        let tracker_info = trackers
+            .iter()
+            .map(|url| TrackerInfo {
+                url: url.clone(),
+                ..Default::default()
+            })
+            .collect();
+        Self {
+            info_hash,
+            magnet,
+            trackers,
+            tracker_info: Mutex::new(tracker_info),
+            download_dir,
+            state: Mutex::new(TorrentState::Paused),
+            error: Mutex::new(None),
+            metadata: Mutex::new(metadata),
+            swarm: Mutex::new(None),
+            task: Mutex::new(None),
+            selection: Mutex::default(),
+            events,
+        }
+    }
+
+    pub fn state(&self) -> TorrentState {
+        *self.state.lock().unwrap()
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        let old = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        if old != state {
+            self.events.publish(Event::StateChanged {
+                info_hash: self.info_hash,
+                state,
+            });
+        }
+    }
+
+    pub fn swarm(&self) -> Option<Arc<Swarm>> {
+        self.swarm.lock().unwrap().clone()
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let metadata = self.metadata.lock().unwrap();
+        let info = metadata.as_ref().map(|tf| &tf.torrent.info);
+        let swarm = self.swarm();
+        let (pieces_done, pieces_total) = swarm.as_ref().map_or((0, 0), |s| s.progress());
+        let download_rate = swarm.as_ref().map_or(0, |s| s.downloaded().rate());
+        let eta = swarm
+            .as_ref()
+            .map(|s| s.bytes_left())
+            .filter(|&left| left > 0 && download_rate > 0)
+            .map(|left| left.div_ceil(download_rate));
+        TorrentStatus {
+            info_hash: hex::encode(self.info_hash),
+            name: info
+                .map(|i| i.name.clone())
+                .or_else(|| self.magnet.as_ref()?.display_name.clone()),
+            state: self.state(),
+            pieces_done,
+            pieces_total,
+            total_size: info
+                .map(|i| i.total_length())
+                .or_else(|| self.magnet.as_ref()?.exact_length),
+            peers: swarm.as_ref().map_or(0, |s| s.peer_count()),
+            download_dir: self.download_dir.clone(),
+            error: self.error.lock().unwrap().clone(),
+            downloaded: swarm.map_or(0, |s| s.downloaded().total()),
+            download_rate,
+            eta,
+            sequential: self.selection.lock().unwrap().sequential.is_some(),
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.swarm().map_or_else(Vec::new, |s| s.peers())
+    }
+
+    pub fn trackers(&self) -> Vec<TrackerInfo> {
+        self.tracker_info.lock().unwrap().clone()
+    }
+
+    /// Empty until a magnet link's metadata has arrived
+    pub fn files(&self) -> Vec<FileInfo> {
+        let download_dir = &self.download_dir;
+        let relative = |path: &Path| {
+            path.strip_prefix(download_dir)
+                .unwrap_or(path)
+                .to_path_buf()
+        };
+        let priorities = self.selection.lock().unwrap().priorities.clone();
+        let priority = |i: usize| priorities.get(i).copied().unwrap_or_default();
+        if let Some(swarm) = self.swarm() {
+            return swarm
+                .file_progress()
+                .into_iter()
+                .enumerate()
+                .map(|(i, (file, done))| FileInfo {
+                    path: relative(&file.path),
+                    length: file.length,
+                    done,
+                    priority: priority(i),
+                })
+                .collect();
+        }
+        let metadata = self.metadata.lock().unwrap();
+        let Some(tf) = metadata.as_ref() else {
+            return Vec::new();
+        };
+        match Storage::new(&tf.torrent.info, download_dir) {
+            Ok(storage) => storage
+                .files()
+                .iter()
+                .enumerate()
+                .map(|(i, file)| FileInfo {
+                    path: relative(&file.path),
+                    length: file.length,
+                    done: 0,
+                    priority: priority(i),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Download file `index` with `priority`, or skip it. Needs the metadata, so for magnet
+    /// links it has to wait until it has arrived.
+    pub fn set_file_priority(&self, index: usize, priority: FilePriority) -> Result<()> {
+        let count = self.file_lengths()?.len();
+        if index >= count {
+            return Err(Error::UnknownFile {
+                info_hash: self.info_hash,
+                index,
+            });
+        }
+        let mut selection = self.selection.lock().unwrap();
+        selection.priorities.resize(count, FilePriority::Normal);
+        selection.priorities[index] = priority;
+        if let Some(swarm) = self.swarm() {
+            swarm.set_file_priorities(&selection.priorities);
+        }
+        Ok(())
+    }
+
+    /// Fetch pieces in order, from the start or from where the last `seek` left off,
+    /// instead of rarest first
+    pub fn set_sequential(&self, sequential: bool) {
+        let mut selection = self.selection.lock().unwrap();
+        selection.sequential = match sequential {
+            true => Some(selection.sequential.unwrap_or(0)),
+            false => None,
+        };
+        if let Some(swarm) = self.swarm() {
+            swarm.set_sequential(selection.sequential);
+        }
+    }
+
+    /// Turn on sequential mode from byte `offset` of file `index`, where a player reading
+    /// the file is about to continue
+    pub fn seek(&self, index: usize, offset: u64) -> Result<()> {
+        let lengths = self.file_lengths()?;
+        if index >= lengths.len() {
+            return Err(Error::UnknownFile {
+                info_hash: self.info_hash,
+                index,
+            });
+        }
+        let start: u64 = lengths[..index].iter().sum();
+        let position = start + offset.min(lengths[index].saturating_sub(1));
+        let mut selection = self.selection.lock().unwrap();
+        selection.sequential = Some(position);
+        if let Some(swarm) = self.swarm() {
+            swarm.set_sequential(selection.sequential);
+        }
+        Ok(())
+    }
+
+    /// Fetch `piece` ahead of others, racing its outstanding blocks against other peers
+    /// once `within` has passed
+    pub fn set_piece_deadline(&self, piece: u32, within: Duration) -> Result<()> {
+        let count = match self.metadata.lock().unwrap().as_ref() {
+            Some(tf) => tf.torrent.info.pieces.len() / 20,
+            None => return Err(Error::NoMetadata(self.info_hash)),
+        };
+        if piece as usize >= count {
+            return Err(Error::UnknownPiece {
+                info_hash: self.info_hash,
+                piece,
+            });
+        }
+        let mut selection = self.selection.lock().unwrap();
+        match self.swarm() {
+            Some(swarm) => swarm.set_piece_deadline(piece, within),
+            None => selection.deadlines.push((piece, within)),
+        }
+        Ok(())
+    }
+
+    // Lengths of the files in torrent order, once there is metadata
+    fn file_lengths(&self) -> Result<Vec<u64>> {
+        let metadata = self.metadata.lock().unwrap();
+        let Some(tf) = metadata.as_ref() else {
+            return Err(Error::NoMetadata(self.info_hash));
+        };
+        let info = &tf.torrent.info;
+        Ok(match &info.files {
+            Some(files) => files.iter().map(|f| f.length).collect(),
+            None => vec![info.total_length()],
+        })
+    }
+
+    /// Which pieces are verified on disk; empty before the torrent is checked
+    pub fn pieces(&self) -> Bitfield {
+        self.swarm()
+            .map_or_else(|| Bitfield::new(0), |s| s.pieces())
+    }
+
+    /// Start or resume the torrent's task; does nothing while it is already running
+    pub fn start(self: &Arc<Self>, ctx: Arc<SessionContext>) {
+        let mut task = self.task.lock().unwrap();
+        if task.as_ref().is_some_and(|t| !t.is_finished()) {
+            return;
+        }
+        *self.error.lock().unwrap() = None;
+        if let Some(swarm) = self.swarm() {
+            swarm.set_paused(false);
+        }
+        let this = self.clone();
+        *task = Some(tokio::spawn(async move {
+            if let Err(e) = this.clone().drive(&ctx).await {
+                let error = e.to_string();
+                *this.error.lock().unwrap() = Some(error.clone());
+                this.events.publish(Event::TorrentError {
+                    info_hash: this.info_hash,
+                    error,
+                });
+                this.set_state(TorrentState::Error);
+            }
+        }));
+    }
+
+    pub fn pause(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        if let Some(swarm) = self.swarm() {
+            swarm.set_paused(true);
+        }
+        self.set_state(TorrentState::Paused);
+    }
+
+    async fn drive(self: Arc<Self>, ctx: &SessionContext) -> Result<()> {
+        let swarm = match self.swarm() {
+            Some(swarm) => swarm,
+            None => {
+                let tf = self.metadata(ctx).await?;
+                self.set_state(TorrentState::Checking);
+                let swarm = Swarm::new(&tf, ctx.peer_id, &self.download_dir, ctx.swarm_config)?
+                    .with_global_limiter(ctx.limiter.clone())
+                    .with_disk_pool(ctx.disk.clone())
+                    .with_transports(ctx.transports.clone())
+                    .with_ip_filter(ctx.ip_filter.clone())
+                    .with_events(self.events.clone());
+                let swarm = Arc::new(swarm);
+                let count = swarm.file_priorities().len();
+                let mut priorities = {
+                    let mut selection = self.selection.lock().unwrap();
+                    // Files a magnet's `so` leaves out are skipped until someone wants them
+                    if selection.priorities.is_empty()
+                        && let Some(magnet) = &self.magnet
+                    {
+                        selection.priorities = (0..count)
+                            .map(|i| match magnet.selects(i) {
+                                true => FilePriority::Normal,
+                                false => FilePriority::Skip,
+                            })
+                            .collect();
+                    }
+                    selection.priorities.clone()
+                };
+                priorities.resize(count, FilePriority::Normal);
+                // The check reads pieces from where the priorities put them
+                let _ = swarm.set_file_priorities(&priorities).await;
+                swarm.check_existing().await?;
+                // Anything set during the check is applied once the swarm is reachable
+                let mut selection = self.selection.lock().unwrap();
+                selection.apply(&swarm);
+                *self.swarm.lock().unwrap() = Some(swarm.clone());
+                drop(selection);
+                swarm
+            }
+        };
+        if !swarm.is_complete() {
+            self.set_state(TorrentState::Downloading);
+            let discovery = async {
+                loop {
+                    let peers = self.discover(ctx).await;
+                    let found = !peers.is_empty();
+                    swarm.add_peers(peers);
+                    sleep(if found {
+                        ANNOUNCE_INTERVAL
+                    } else {
+                        RETRY_INTERVAL
+                    })
+                    .await;
+                }
+            };
+            tokio::select! {
+                result = swarm.clone().run() => result?,
+                _ = discovery => {}
+            }
+        }
+        self.set_state(if swarm.is_complete() {
+            TorrentState::Finished
+        } else {
+            TorrentState::Paused
+        });
+        Ok(())
+    }
+
+    // The info dict, fetched from peers first for magnet links
+    async fn metadata(&self, ctx: &SessionContext) -> Result<Arc<TorrentFile>> {
+        if let Some(tf) = self.metadata.lock().unwrap().clone() {
+            return Ok(tf);
+        }
+        let tf = Arc::new(TorrentFile::from_bytes(&self.fetch_metadata(ctx).await?)?);
+        // Stored first, so file priorities can be set as soon as the event is out
+        *self.metadata.lock().unwrap() = Some(tf.clone());
+        self.events.publish(Event::MetadataReceived {
+            info_hash: self.info_hash,
+            name: tf.torrent.info.name.clone(),
+        });
+        Ok(tf)
+    }
+
+    /// A .torrent for this magnet link, made from the info dict of the first peer that hands
+    /// it over. Keeps looking for peers until one does.
+    pub async fn fetch_metadata(&self, ctx: &SessionContext) -> Result<Vec<u8>> {
+        let magnet = self
+            .magnet
+            .as_ref()
+            .expect("torrents without metadata are magnets");
+        self.set_state(TorrentState::FetchingMetadata);
+        loop {
+            let mut peers = self.discover(ctx).await;
+            peers.retain(|peer| !ctx.ip_filter.blocks(IpAddr::V4(*peer.ip())));
+            if !peers.is_empty() {
+                let config = &ctx.swarm_config;
+                let fetch = fetch_metadata_from_peers(
+                    &peers,
+                    self.info_hash,
+                    ctx.peer_id,
+                    &ctx.transports,
+                    config.timeouts,
+                    config.encryption,
+                );
+                // No peer having the metadata yet is normal for a fresh magnet; keep asking
+                if let Ok(info) = fetch.await {
+                    return Ok(magnet_to_torrent(magnet, &info)?);
+                }
+            }
+            sleep(RETRY_INTERVAL).await;
+        }
+    }
+
+    // Private torrents (BEP 27) only get peers from their trackers; the DHT must not learn of
+    // them. Before metadata arrives there is no telling.
+    #[cfg(feature = "dht")]
+    fn is_private(&self) -> bool {
+        let metadata = self.metadata.lock().unwrap();
+        metadata
+            .as_ref()
+            .is_some_and(|tf| tf.torrent.info.private == Some(1))
+    }
+
+    // Peers from the magnet link, the trackers and, unless the torrent is private, the DHT
+    async fn discover(&self, ctx: &SessionContext) -> Vec<SocketAddrV4> {
+        let left = self
+            .swarm()
+            .map(|s| s.bytes_left())
+            .or_else(|| self.magnet.as_ref()?.exact_length)
+            .unwrap_or(0);
+        let mut peers: Vec<SocketAddrV4> = self
+            .magnet
+            .iter()
+            .flat_map(|m| &m.peers)
+            .filter_map(|addr| match addr {
+                SocketAddr::V4(v4) => Some(*v4),
+                SocketAddr::V6(_) => None,
+            })
+            .collect();
+        let from_trackers = announce(
+            &self.trackers,
+            self.info_hash,
+            ctx.peer_id,
+            ctx.listen_port,
+            left,
+            &ctx.user_agent,
+            ctx.tracker_proxy.as_ref(),
+        );
+        #[cfg(feature = "dht")]
+        let from_dht = async {
+            match &ctx.dht {
+                Some(dht) if !self.is_private() => {
+                    dht.announce(self.info_hash, ctx.listen_port).await
+                }
+                _ => Vec::new(),
+            }
+        };
+        #[cfg(not(feature = "dht"))]
+        let from_dht = async { Vec::new() };
+        let (replies, from_dht) = tokio::join!(from_trackers, from_dht);
+        let mut tracker_info = self.tracker_info.lock().unwrap();
+        for reply in &replies {
+            if let Some(info) = tracker_info.iter_mut().find(|t| t.url == reply.tracker) {
+                info.warning = reply.warning.clone();
+                (info.peers, info.error) = match &reply.result {
+                    Ok(peers) => (Some(peers.len()), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+            }
+            let tracker = reply.tracker.clone();
+            if let Some(message) = &reply.warning {
+                self.events.publish(Event::TrackerWarning {
+                    info_hash: self.info_hash,
+                    tracker: tracker.clone(),
+                    message: message.clone(),
+                });
+            }
+            self.events.publish(match &reply.result {
+                Ok(peers) => Event::TrackerReply {
+                    info_hash: self.info_hash,
+                    tracker,
+                    peers: peers.len(),
+                },
+                Err(e) => Event::TrackerError {
+                    info_hash: self.info_hash,
+                    tracker,
+                    error: e.to_string(),
+                },
+            });
+        }
+        drop(tracker_info);
+        #[cfg(feature = "dht")]
+        if ctx.dht.is_some() {
+            self.events.publish(Event::DhtReply {
+                info_hash: self.info_hash,
+                peers: from_dht.len(),
+            });
+        }
+        for peer in reply_peers(&replies).into_iter().chain(from_dht) {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+}
+
+/// BEP 12: announce-list supersedes announce when present
+pub fn torrent_trackers(tf: &TorrentFile) -> Vec<String> {
+    match &tf.torrent.announce_list {
+        Some(tiers) if !tiers.is_empty() => tiers.iter().flatten().cloned().collect(),
+        _ if !tf.torrent.announce.is_empty() => vec![tf.torrent.announce.clone()],
+        _ => Vec::new(),
+    }
+}
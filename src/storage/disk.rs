@@ -0,0 +1,36 @@
+//! Blocking file I/O and hashing run here, off the async runtime. One pool is shared by
+//! every torrent so a busy torrent can't starve the runtime's blocking threads.
+
+use crate::storage::error::StorageError;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+pub const DEFAULT_DISK_THREADS: usize = 4;
+
+#[derive(Clone)]
+pub struct DiskPool {
+    permits: Arc<Semaphore>,
+}
+
+impl Default for DiskPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_DISK_THREADS)
+    }
+}
+
+impl DiskPool {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(threads.max(1))),
+        }
+    }
+
+    pub async fn run<T, F>(&self, job: F) -> Result<T, StorageError>
+    where
+        F: FnOnce() -> Result<T, StorageError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await.expect("the pool never closes");
+        tokio::task::spawn_blocking(job).await?
+    }
+}
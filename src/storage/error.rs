@@ -0,0 +1,24 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// A file name in the torrent that would land outside the download directory
+    #[error("unsafe path component {0:?} in torrent")]
+    UnsafePath(String),
+    #[error("{}: {error}", path.display())]
+    Io { path: PathBuf, error: io::Error },
+    #[error("disk task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl StorageError {
+    /// For `map_err`: an I/O error on `path`
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |error| StorageError::Io {
+            path: path.to_path_buf(),
+            error,
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Maps the torrent's contiguous byte stream onto the files it describes
+
+use crate::bittorrent::TorrentInfo;
+use crate::peers::bitfield::Bitfield;
+use crate::storage::error::StorageError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How eagerly a file's pieces are downloaded; skipped files are not downloaded or created
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for FilePriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "skip" => Ok(FilePriority::Skip),
+            "low" => Ok(FilePriority::Low),
+            "normal" => Ok(FilePriority::Normal),
+            "high" => Ok(FilePriority::High),
+            _ => Err(format!("{s:?} is not one of skip, low, normal, high")),
+        }
+    }
+}
+
+impl fmt::Display for FilePriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Position of the file's first byte in the torrent
+    pub offset: u64,
+}
+
+#[derive(Debug)]
+pub struct Storage {
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    total_length: u64,
+    // Pieces that straddle a file boundary, each with a slot in the part-file
+    boundary: Vec<u32>,
+    part_file: PathBuf,
+    skipped: Mutex<Vec<bool>>,
+    // Held while pieces move between files and the part-file, one change at a time
+    changing: Mutex<()>,
+}
+
+// Where a run of bytes lives on disk
+enum Place<'a> {
+    File(&'a StorageFile, u64),
+    Part(u64),
+}
+
+impl Storage {
+    /// Single-file torrents land in `download_dir/name`, multi-file ones under `download_dir/name/`
+    pub fn new(info: &TorrentInfo, download_dir: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let root = download_dir.as_ref().join(safe_component(&info.name)?);
+        let mut files = Vec::new();
+        let mut offset = 0;
+        match &info.files {
+            Some(entries) => {
+                for entry in entries {
+                    if entry.path.is_empty() {
+                        return Err(StorageError::UnsafePath(String::new()));
+                    }
+                    let mut path = root.clone();
+                    for part in &entry.path {
+                        path.push(safe_component(part)?);
+                    }
+                    files.push(StorageFile {
+                        path,
+                        length: entry.length,
+                        offset,
+                    });
+                    offset += entry.length;
+                }
+            }
+            None => files.push(StorageFile {
+                path: root,
+                length: info.total_length(),
+                offset: 0,
+            }),
+        }
+        let piece_length = info.piece_length;
+        let mut boundary: Vec<u32> = files
+            .iter()
+            .filter(|f| f.length > 0 && f.offset % piece_length != 0)
+            .map(|f| (f.offset / piece_length) as u32)
+            .collect();
+        boundary.dedup();
+        Ok(Self {
+            skipped: Mutex::new(vec![false; files.len()]),
+            changing: Mutex::new(()),
+            files,
+            piece_length,
+            total_length: offset.max(info.length.unwrap_or(0)),
+            boundary,
+            part_file: download_dir.as_ref().join(format!(".{}.parts", info.name)),
+        })
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    /// Holds the parts of pieces that belong to skipped files, when the piece is shared with
+    /// a wanted one
+    pub fn part_file(&self) -> &Path {
+        &self.part_file
+    }
+
+    /// The priority of each piece: the highest of the files it overlaps
+    pub fn piece_priorities(&self, files: &[FilePriority]) -> Vec<FilePriority> {
+        let count = self.total_length.div_ceil(self.piece_length) as usize;
+        let mut pieces = vec![FilePriority::Skip; count];
+        for (file, &priority) in self.files.iter().zip(files) {
+            if file.length == 0 {
+                continue;
+            }
+            let first = (file.offset / self.piece_length) as usize;
+            let last = ((file.offset + file.length - 1) / self.piece_length) as usize;
+            for piece in &mut pieces[first..=last] {
+                *piece = (*piece).max(priority);
+            }
+        }
+        pieces
+    }
+
+    /// Mark which files are skipped. Verified pieces shared between a file that changes and
+    /// its neighbours move between the file and the part-file, so `have` stays true.
+    pub fn set_skipped(&self, skipped: Vec<bool>, have: &Bitfield) -> Result<(), StorageError> {
+        let _changing = self.changing.lock().unwrap();
+        let old = self.skipped.lock().unwrap().clone();
+        let changed: Vec<&StorageFile> = self
+            .files
+            .iter()
+            .zip(old.iter().zip(&skipped))
+            .filter(|(_, (was, is))| was != is)
+            .map(|(file, _)| file)
+            .collect();
+        if changed.is_empty() {
+            return Ok(());
+        }
+        let mut moved = Vec::new();
+        for &piece in &self.boundary {
+            let start = piece as u64 * self.piece_length;
+            let end = start + self.piece_length;
+            let touches = changed
+                .iter()
+                .any(|f| f.offset < end && start < f.offset + f.length);
+            if touches && have.get(piece as usize) {
+                moved.push((piece, self.read_piece(piece)?));
+            }
+        }
+        *self.skipped.lock().unwrap() = skipped;
+        self.prepare()?;
+        for (piece, data) in moved {
+            self.write_piece(piece, &data)?;
+        }
+        if !self.skipped.lock().unwrap().contains(&true) {
+            match std::fs::remove_file(&self.part_file) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(StorageError::io(&self.part_file)(e));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Create directories and empty files up front, since no piece ever writes to them.
+    /// Skipped files are left out.
+    pub fn prepare(&self) -> Result<(), StorageError> {
+        let skipped = self.skipped.lock().unwrap().clone();
+        for (file, _) in self.files.iter().zip(skipped).filter(|(_, skip)| !skip) {
+            if let Some(parent) = file.path.parent() {
+                std::fs::create_dir_all(parent).map_err(StorageError::io(parent))?;
+            }
+            if file.length == 0 {
+                File::create(&file.path).map_err(StorageError::io(&file.path))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.write_at(index as u64 * self.piece_length, data)
+    }
+
+    pub fn read_piece(&self, index: u32) -> Result<Vec<u8>, StorageError> {
+        let start = index as u64 * self.piece_length;
+        let len = self
+            .piece_length
+            .min(self.total_length.saturating_sub(start));
+        let mut buf = vec![0; len as usize];
+        self.read_at(start, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write_at(&self, offset: u64, mut data: &[u8]) -> Result<(), StorageError> {
+        for (place, n) in self.places(offset, data.len() as u64) {
+            let (path, at) = self.locate(&place);
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(StorageError::io(path))?;
+            f.seek(SeekFrom::Start(at))
+                .and_then(|_| f.write_all(&data[..n]))
+                .map_err(StorageError::io(path))?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    pub fn read_at(&self, offset: u64, mut buf: &mut [u8]) -> Result<(), StorageError> {
+        for (place, n) in self.places(offset, buf.len() as u64) {
+            let (path, at) = self.locate(&place);
+            let mut f = File::open(path).map_err(StorageError::io(path))?;
+            f.seek(SeekFrom::Start(at))
+                .and_then(|_| f.read_exact(&mut buf[..n]))
+                .map_err(StorageError::io(path))?;
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    fn locate<'a>(&'a self, place: &Place<'a>) -> (&'a Path, u64) {
+        match *place {
+            Place::File(file, at) => (&file.path, at),
+            Place::Part(at) => (&self.part_file, at),
+        }
+    }
+
+    // Where each run of a byte range is stored, in order, with its length. Bytes of skipped
+    // files go to the part-file when their piece is shared with another file.
+    fn places(&self, offset: u64, len: u64) -> Vec<(Place<'_>, usize)> {
+        let skipped = self.skipped.lock().unwrap();
+        let end = offset + len;
+        let mut places = Vec::new();
+        for (file, &skip) in self.files.iter().zip(skipped.iter()) {
+            let start = offset.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            if start >= stop {
+                continue;
+            }
+            if !skip {
+                places.push((
+                    Place::File(file, start - file.offset),
+                    (stop - start) as usize,
+                ));
+                continue;
+            }
+            let mut pos = start;
+            while pos < stop {
+                let piece = pos / self.piece_length;
+                let piece_start = piece * self.piece_length;
+                let run_end = stop.min(piece_start + self.piece_length);
+                let place = match self.boundary.binary_search(&(piece as u32)) {
+                    Ok(slot) => Place::Part(slot as u64 * self.piece_length + pos - piece_start),
+                    Err(_) => Place::File(file, pos - file.offset),
+                };
+                places.push((place, (run_end - pos) as usize));
+                pos = run_end;
+            }
+        }
+        places
+    }
+}
+
+// Names come from the peer-supplied info dict, so refuse anything that could escape the download dir
+fn safe_component(name: &str) -> Result<&str, StorageError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => Ok(name),
+        _ => Err(StorageError::UnsafePath(name.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bittorrent::FileEntry;
+
+    fn info(files: &[(&str, u64)]) -> TorrentInfo {
+        TorrentInfo {
+            name: "multi".into(),
+            length: None,
+            piece_length: 4,
+            pieces: Vec::new(),
+            files: Some(
+                files
+                    .iter()
+                    .map(|(name, length)| FileEntry {
+                        length: *length,
+                        path: vec!["dir".into(), name.to_string()],
+                    })
+                    .collect(),
+            ),
+            private: None,
+            source: None,
+        }
+    }
+
+    #[test]
+    fn pieces_span_file_boundaries() {
+        let dir = std::env::temp_dir().join(format!("rustor-storage-{}", std::process::id()));
+        let storage = Storage::new(&info(&[("a", 3), ("empty", 0), ("b", 6)]), &dir).unwrap();
+        storage.prepare().unwrap();
+        // Written out of order, the last piece is short
+        storage.write_piece(2, b"9").unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(1, b"4567").unwrap();
+
+        let root = dir.join("multi").join("dir");
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), b"012");
+        assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+        assert_eq!(std::fs::read(root.join("b")).unwrap(), b"345679");
+        assert_eq!(storage.read_piece(1).unwrap(), b"4567");
+        assert_eq!(storage.read_piece(2).unwrap(), b"9");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn shared_pieces_of_skipped_files_go_to_the_part_file() {
+        use FilePriority::{High, Normal, Skip};
+        let dir = std::env::temp_dir().join(format!("rustor-parts-{}", std::process::id()));
+        let storage = Storage::new(&info(&[("a", 3), ("b", 6), ("c", 3)]), &dir).unwrap();
+        // Pieces 0123|4567|89ab: b only shares pieces with a and c
+        assert_eq!(storage.piece_priorities(&[Skip, Normal, Skip]), [Normal; 3]);
+        assert_eq!(
+            storage.piece_priorities(&[High, Skip, Skip]),
+            [High, Skip, Skip]
+        );
+
+        let mut have = Bitfield::new(3);
+        storage
+            .set_skipped(vec![false, true, false], &have)
+            .unwrap();
+        storage.prepare().unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(2, b"89ab").unwrap();
+        have.set(0);
+        have.set(2);
+        let root = dir.join("multi").join("dir");
+        assert!(!root.join("b").exists());
+        assert_eq!(std::fs::read(root.join("c")).unwrap(), b"9ab");
+        assert_eq!(storage.read_piece(2).unwrap(), b"89ab");
+
+        // Wanted after all: what the part-file held moves into b
+        storage.set_skipped(vec![false; 3], &have).unwrap();
+        storage.write_piece(1, b"4567").unwrap();
+        assert_eq!(std::fs::read(root.join("b")).unwrap(), b"345678");
+        assert!(!storage.part_file().exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_paths_escaping_the_download_dir() {
+        for bad in ["..", ".", "", "a/b", "/etc", "a\\b"] {
+            assert!(
+                matches!(
+                    Storage::new(&info(&[(bad, 1)]), "/tmp"),
+                    Err(StorageError::UnsafePath(_))
+                ),
+                "{bad:?}"
+            );
+        }
+    }
+}
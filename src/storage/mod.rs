@@ -0,0 +1,5 @@
+//! Mapping pieces onto files on disk.
+
+pub mod disk;
+pub mod error;
+pub mod files;
@@ -0,0 +1,95 @@
+use crate::bencode::decode::decode_bencode;
+use crate::bencode::encode::encode_bencode;
+use crate::bittorrent::TorrentInfo;
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::magnet::MagnetLink;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+
+/// Build a .torrent from metadata fetched for a magnet link. The info dict is written back
+/// byte-for-byte so the resulting file has exactly the magnet's info hash.
+pub fn magnet_to_torrent(magnet: &MagnetLink, info: &[u8]) -> Result<Vec<u8>, TorrentError> {
+    let got: [u8; 20] = Sha1::digest(info).into();
+    if magnet.infohash != Some(got) {
+        return Err(TorrentError::InfoHashMismatch);
+    }
+    // Reject garbage before writing it out as a torrent
+    let parsed: TorrentInfo = decode_bencode(info)?;
+    if !parsed.pieces.len().is_multiple_of(20) {
+        return Err(TorrentError::Invalid(
+            "pieces length not divisible by 20".into(),
+        ));
+    }
+
+    // Bencode dictionaries are sorted by key, which BTreeMap gives us for free
+    let mut entries: BTreeMap<&str, Vec<u8>> = BTreeMap::new();
+    if let Some(first) = magnet.trackers.first() {
+        entries.insert("announce", encode_bencode(first)?);
+    }
+    if magnet.trackers.len() > 1 {
+        // Magnets carry no tier information, so each tracker gets its own tier
+        let tiers: Vec<Vec<&String>> = magnet.trackers.iter().map(|t| vec![t]).collect();
+        entries.insert("announce-list", encode_bencode(&tiers)?);
+    }
+    if !magnet.web_seeds.is_empty() {
+        entries.insert("url-list", encode_bencode(&magnet.web_seeds)?);
+    }
+    entries.insert(
+        "created by",
+        encode_bencode(&concat!("RusTor/", env!("CARGO_PKG_VERSION")))?,
+    );
+    entries.insert("info", info.to_vec());
+
+    let mut out = vec![b'd'];
+    for (key, value) in entries {
+        out.extend_from_slice(format!("{}:{}", key.len(), key).as_bytes());
+        out.extend_from_slice(&value);
+    }
+    out.push(b'e');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::magnet::parse_magnet_link;
+    use crate::torrentfile::torrent::TorrentFile;
+
+    // Includes a key TorrentInfo doesn't model, which must survive into the torrent
+    const INFO: &[u8] = b"d6:lengthi5e4:name5:a.txt12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:x-extra4:keepe";
+
+    #[test]
+    fn writes_metadata_back_with_the_magnets_hash_and_trackers() {
+        let hash = hex::encode(Sha1::digest(INFO));
+        let magnet = parse_magnet_link(&format!(
+            "magnet:?xt=urn:btih:{hash}&tr=http://a.example/announce&tr=udp://b.example:80&ws=http://seed.example/a.txt"
+        ))
+        .unwrap();
+        let bytes = magnet_to_torrent(&magnet, INFO).unwrap();
+        let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+
+        assert_eq!(hex::encode(torrent.info_hash), hash);
+        assert_eq!(torrent.torrent.announce, "http://a.example/announce");
+        assert_eq!(
+            torrent.torrent.announce_list.unwrap(),
+            vec![
+                vec!["http://a.example/announce".to_string()],
+                vec!["udp://b.example:80".to_string()]
+            ]
+        );
+        assert_eq!(
+            torrent.torrent.url_list.unwrap(),
+            vec!["http://seed.example/a.txt"]
+        );
+        assert_eq!(torrent.torrent.info.name, "a.txt");
+    }
+
+    #[test]
+    fn refuses_metadata_for_another_torrent() {
+        let magnet = parse_magnet_link(&format!("magnet:?xt=urn:btih:{}", "0".repeat(40))).unwrap();
+        assert!(matches!(
+            magnet_to_torrent(&magnet, INFO),
+            Err(TorrentError::InfoHashMismatch)
+        ));
+    }
+}
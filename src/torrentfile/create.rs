@@ -0,0 +1,376 @@
+use crate::bittorrent::{FileEntry, Torrent, TorrentInfo};
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::torrent::TorrentFile;
+use rayon::prelude::*;
+use sha1::{Digest, Sha1};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+// Aim for roughly this many pieces, the usual trade-off between .torrent size and piece granularity
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// Power of two between 16 KiB and 16 MiB that gives close to TARGET_PIECE_COUNT pieces
+pub fn choose_piece_length(total_length: u64) -> u64 {
+    let ideal = total_length / TARGET_PIECE_COUNT;
+    ideal
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u64>,
+    tiers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            tiers: Vec::new(),
+            comment: None,
+            created_by: Some(concat!("RusTor/", env!("CARGO_PKG_VERSION")).to_string()),
+            creation_date: Some(unix_now()),
+            private: false,
+            source: None,
+            web_seeds: Vec::new(),
+        }
+    }
+
+    pub fn piece_length(mut self, piece_length: u64) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    /// Adds a tracker in a tier of its own
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.tiers.push(vec![url.into()]);
+        self
+    }
+
+    /// Adds a BEP 12 tier; trackers within a tier are tried in random order
+    pub fn tracker_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.tiers.push(tier);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    pub fn creation_date(mut self, creation_date: Option<i64>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    pub fn build(self) -> Result<TorrentFile, TorrentError> {
+        // `.`, `..` and paths ending in them only get a name once resolved; symlinks are kept
+        // so a linked root is named after the link
+        let root = resolve_dots(&self.path).map_err(TorrentError::io(&self.path))?;
+        let name = root
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| TorrentError::Create(format!("no name to give {}", root.display())))?
+            .to_string();
+
+        let meta = std::fs::metadata(&root).map_err(TorrentError::io(&root))?;
+        let mut files = Vec::new();
+        if meta.is_dir() {
+            walk_dir(&root, &mut files)?;
+            files.sort();
+            if files.is_empty() {
+                return Err(TorrentError::Create(format!(
+                    "{} contains no files",
+                    root.display()
+                )));
+            }
+        } else {
+            files.push(root.clone());
+        }
+
+        let mut layout = Vec::with_capacity(files.len());
+        for path in files {
+            let length = std::fs::metadata(&path)
+                .map_err(TorrentError::io(&path))?
+                .len();
+            layout.push((path, length));
+        }
+        let total_length: u64 = layout.iter().map(|(_, len)| len).sum();
+        if total_length == 0 {
+            return Err(TorrentError::Create("no content".into()));
+        }
+
+        let piece_length = self
+            .piece_length
+            .unwrap_or_else(|| choose_piece_length(total_length));
+        if !piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH {
+            return Err(TorrentError::Create(
+                "piece length must be a power of two of at least 16 KiB".into(),
+            ));
+        }
+
+        let pieces = hash_pieces(&layout, piece_length, total_length)?;
+
+        let (length, files) = if meta.is_dir() {
+            let entries = layout
+                .iter()
+                .map(|(path, length)| {
+                    let rel = path.strip_prefix(&root).expect("walked from root");
+                    let components = rel
+                        .components()
+                        .map(|c| {
+                            c.as_os_str().to_str().map(str::to_string).ok_or_else(|| {
+                                TorrentError::Create(format!("non UTF-8 path {}", path.display()))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    Ok(FileEntry {
+                        length: *length,
+                        path: components,
+                    })
+                })
+                .collect::<Result<Vec<_>, TorrentError>>()?;
+            (None, Some(entries))
+        } else {
+            (Some(total_length), None)
+        };
+
+        let info = TorrentInfo {
+            name,
+            length,
+            piece_length,
+            pieces,
+            files,
+            private: self.private.then_some(1),
+            source: self.source,
+        };
+        let torrent = Torrent {
+            announce: self
+                .tiers
+                .first()
+                .and_then(|t| t.first())
+                .cloned()
+                .unwrap_or_default(),
+            announce_list: (self.tiers.len() > 1 || self.tiers.iter().any(|t| t.len() > 1))
+                .then_some(self.tiers),
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date,
+            url_list: (!self.web_seeds.is_empty()).then_some(self.web_seeds),
+            info,
+        };
+        TorrentFile::from_torrent(torrent)
+    }
+}
+
+// `path` made absolute with `.` and `..` resolved lexically, without following symlinks
+fn resolve_dots(path: &Path) -> std::io::Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    Ok(resolved)
+}
+
+fn walk_dir(dir: &Path, out: &mut Vec<PathBuf>) -> Result<(), TorrentError> {
+    for entry in std::fs::read_dir(dir).map_err(TorrentError::io(dir))? {
+        let entry = entry.map_err(TorrentError::io(dir))?;
+        let file_type = entry.file_type().map_err(TorrentError::io(&entry.path()))?;
+        if file_type.is_dir() {
+            walk_dir(&entry.path(), out)?;
+        } else if file_type.is_file() {
+            out.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// Pieces span file boundaries, so every piece reads its own byte range and they hash independently
+fn hash_pieces(
+    layout: &[(PathBuf, u64)],
+    piece_length: u64,
+    total_length: u64,
+) -> Result<Vec<u8>, TorrentError> {
+    let count = total_length.div_ceil(piece_length);
+    let hashes = (0..count)
+        .into_par_iter()
+        .map(|index| {
+            let start = index * piece_length;
+            let len = std::cmp::min(piece_length, total_length - start);
+            let mut buf = vec![0u8; len as usize];
+            read_range(layout, start, &mut buf)?;
+            let digest: [u8; 20] = Sha1::digest(&buf).into();
+            Ok(digest)
+        })
+        .collect::<Result<Vec<_>, TorrentError>>()?;
+    Ok(hashes.concat())
+}
+
+fn read_range(
+    layout: &[(PathBuf, u64)],
+    mut offset: u64,
+    mut buf: &mut [u8],
+) -> Result<(), TorrentError> {
+    let mut file_start = 0u64;
+    for (path, length) in layout {
+        if buf.is_empty() {
+            break;
+        }
+        let file_end = file_start + length;
+        if offset < file_end {
+            let n = std::cmp::min(buf.len() as u64, file_end - offset) as usize;
+            let mut file = File::open(path).map_err(TorrentError::io(path))?;
+            file.seek(SeekFrom::Start(offset - file_start))
+                .map_err(TorrentError::io(path))?;
+            file.read_exact(&mut buf[..n]).map_err(|_| {
+                TorrentError::Create(format!("{} changed while hashing", path.display()))
+            })?;
+            buf = &mut buf[n..];
+            offset += n as u64;
+        }
+        file_start = file_end;
+    }
+    Ok(())
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustor-create-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn builds_a_single_file_torrent() {
+        let dir = temp_dir("file");
+        let content: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        std::fs::write(dir.join("data.bin"), &content).unwrap();
+        let tf = TorrentBuilder::new(dir.join("data.bin"))
+            .piece_length(16384)
+            .tracker("http://tracker.example/announce")
+            .creation_date(None)
+            .build()
+            .unwrap();
+
+        let info = &tf.torrent.info;
+        assert_eq!(info.name, "data.bin");
+        assert_eq!(info.length, Some(40_000));
+        let hashes = info.piece_hashes().unwrap();
+        assert_eq!(hashes.len(), 3);
+        for (hash, piece) in hashes.iter().zip(content.chunks(16384)) {
+            assert_eq!(hash[..], Sha1::digest(piece)[..]);
+        }
+
+        let parsed = TorrentFile::from_bytes(&tf.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash, tf.info_hash);
+        let info_dict = serde_bencode::to_bytes(&parsed.torrent.info).unwrap();
+        assert_eq!(tf.info_hash[..], Sha1::digest(&info_dict)[..]);
+        assert_eq!(parsed.torrent.announce, "http://tracker.example/announce");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn builds_a_directory_torrent_with_pieces_across_files() {
+        let dir = temp_dir("dir");
+        let root = dir.join("album");
+        std::fs::create_dir_all(root.join("disc 2")).unwrap();
+        std::fs::write(root.join("a.bin"), vec![1u8; 20_000]).unwrap();
+        std::fs::write(root.join("disc 2/b.bin"), vec![2u8; 15_000]).unwrap();
+        // A trailing `..` has no file name of its own
+        let tf = TorrentBuilder::new(root.join("disc 2/.."))
+            .piece_length(16384)
+            .build()
+            .unwrap();
+
+        let info = &tf.torrent.info;
+        assert_eq!(info.name, "album");
+        assert_eq!(info.length, None);
+        let files: Vec<_> = info
+            .files
+            .iter()
+            .flatten()
+            .map(|f| (f.path.join("/"), f.length))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                ("a.bin".to_string(), 20_000),
+                ("disc 2/b.bin".to_string(), 15_000)
+            ]
+        );
+        let mut content = vec![1u8; 20_000];
+        content.extend(vec![2u8; 15_000]);
+        let hashes = info.piece_hashes().unwrap();
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes[1][..], Sha1::digest(&content[16384..32768])[..]);
+
+        let parsed = TorrentFile::from_bytes(&tf.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.info_hash, tf.info_hash);
+        assert_eq!(parsed.torrent.info.total_length(), 35_000);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_a_symlinked_root_after_the_link() {
+        let dir = temp_dir("link");
+        std::fs::create_dir_all(dir.join("target")).unwrap();
+        std::fs::write(dir.join("target/a.bin"), vec![3u8; 1000]).unwrap();
+        std::os::unix::fs::symlink(dir.join("target"), dir.join("shows")).unwrap();
+
+        let tf = TorrentBuilder::new(dir.join("shows")).build().unwrap();
+        assert_eq!(tf.torrent.info.name, "shows");
+        assert_eq!(tf.torrent.info.total_length(), 1000);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
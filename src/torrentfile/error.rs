@@ -0,0 +1,34 @@
+use crate::bencode::error::BencodeError;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TorrentError {
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    /// Metainfo that decodes but breaks the spec, e.g. a truncated piece hash
+    #[error("invalid torrent: {0}")]
+    Invalid(String),
+    #[error("invalid magnet link: {0}")]
+    InvalidMagnet(String),
+    /// Metadata from a peer that hashes to a different info hash than we asked for
+    #[error("metadata does not match the info hash")]
+    InfoHashMismatch,
+    #[error("v2-only torrents are not supported yet")]
+    V2Only,
+    #[error("cannot create torrent: {0}")]
+    Create(String),
+    #[error("{}: {error}", path.display())]
+    Io { path: PathBuf, error: io::Error },
+}
+
+impl TorrentError {
+    /// For `map_err`: an I/O error on `path`
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |error| TorrentError::Io {
+            path: path.to_path_buf(),
+            error,
+        }
+    }
+}
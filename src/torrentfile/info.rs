@@ -0,0 +1,307 @@
+use crate::torrentfile::magnet::MagnetLink;
+use crate::torrentfile::torrent::TorrentFile;
+use data_encoding::BASE32;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Serialize)]
+pub struct FileSummary {
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+/// What `minibit info` reports; magnets only fill in what the link itself carries
+#[derive(Debug, Serialize)]
+pub struct TorrentSummary {
+    pub name: Option<String>,
+    pub info_hash: Option<String>,
+    pub info_hash_base32: Option<String>,
+    pub info_hash_v2: Option<String>,
+    pub piece_length: Option<u64>,
+    pub piece_count: Option<usize>,
+    pub total_size: Option<u64>,
+    pub private: bool,
+    pub files: Vec<FileSummary>,
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+}
+
+impl TorrentSummary {
+    pub fn from_torrent_file(tf: &TorrentFile) -> Self {
+        let torrent = &tf.torrent;
+        let info = &torrent.info;
+        let files = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|f| FileSummary {
+                    path: f.path.clone(),
+                    length: f.length,
+                })
+                .collect(),
+            None => vec![FileSummary {
+                path: vec![info.name.clone()],
+                length: info.total_length(),
+            }],
+        };
+        // BEP 12: announce-list supersedes announce when present
+        let trackers = match &torrent.announce_list {
+            Some(tiers) if !tiers.is_empty() => tiers.clone(),
+            _ if !torrent.announce.is_empty() => vec![vec![torrent.announce.clone()]],
+            _ => Vec::new(),
+        };
+
+        TorrentSummary {
+            name: Some(info.name.clone()),
+            info_hash: Some(hex::encode(tf.info_hash)),
+            info_hash_base32: Some(BASE32.encode(&tf.info_hash)),
+            info_hash_v2: None,
+            piece_length: Some(info.piece_length),
+            piece_count: Some(info.pieces.len() / 20),
+            total_size: Some(info.total_length()),
+            private: info.private == Some(1),
+            files,
+            trackers,
+            web_seeds: torrent.url_list.clone().unwrap_or_default(),
+            comment: torrent.comment.clone(),
+            created_by: torrent.created_by.clone(),
+            creation_date: torrent.creation_date,
+        }
+    }
+
+    pub fn from_magnet(m: &MagnetLink) -> Self {
+        TorrentSummary {
+            name: m.display_name.clone(),
+            info_hash: m.infohash.map(hex::encode),
+            info_hash_base32: m.infohash.map(|h| BASE32.encode(&h)),
+            info_hash_v2: m.infohash_v2.map(hex::encode),
+            piece_length: None,
+            piece_count: None,
+            total_size: m.exact_length,
+            private: false,
+            files: Vec::new(),
+            trackers: m.trackers.iter().map(|t| vec![t.clone()]).collect(),
+            web_seeds: m.web_seeds.clone(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let unknown = || "unknown".to_string();
+        let _ = writeln!(
+            out,
+            "Name:         {}",
+            self.name.clone().unwrap_or_else(unknown)
+        );
+        if let Some(hash) = &self.info_hash {
+            let _ = writeln!(out, "Info hash:    {hash}");
+        }
+        if let Some(hash) = &self.info_hash_base32 {
+            let _ = writeln!(out, "  (base32):   {hash}");
+        }
+        if let Some(hash) = &self.info_hash_v2 {
+            let _ = writeln!(out, "Info hash v2: {hash}");
+        }
+        if let (Some(len), Some(count)) = (self.piece_length, self.piece_count) {
+            let _ = writeln!(out, "Pieces:       {count} x {}", format_size(len));
+        }
+        if let Some(total) = self.total_size {
+            let _ = writeln!(out, "Total size:   {} ({total} bytes)", format_size(total));
+        }
+        let _ = writeln!(
+            out,
+            "Private:      {}",
+            if self.private { "yes" } else { "no" }
+        );
+        if let Some(comment) = &self.comment {
+            let _ = writeln!(out, "Comment:      {comment}");
+        }
+        if let Some(created_by) = &self.created_by {
+            let _ = writeln!(out, "Created by:   {created_by}");
+        }
+        if let Some(date) = self.creation_date {
+            let _ = writeln!(out, "Created:      {date} (unix time)");
+        }
+
+        if !self.trackers.is_empty() {
+            let _ = writeln!(out, "\nTrackers:");
+            for (tier, trackers) in self.trackers.iter().enumerate() {
+                let _ = writeln!(out, "  Tier {tier}:");
+                for tracker in trackers {
+                    let _ = writeln!(out, "    {tracker}");
+                }
+            }
+        }
+        if !self.web_seeds.is_empty() {
+            let _ = writeln!(out, "\nWeb seeds:");
+            for url in &self.web_seeds {
+                let _ = writeln!(out, "  {url}");
+            }
+        }
+        if !self.files.is_empty() {
+            let _ = writeln!(out, "\nFiles:");
+            let mut root = Dir::default();
+            for file in &self.files {
+                root.insert(&file.path, file.length);
+            }
+            root.render(&mut out, 1);
+        }
+        out
+    }
+}
+
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<String, Dir>,
+    files: Vec<(String, u64)>,
+}
+
+impl Dir {
+    fn insert(&mut self, path: &[String], length: u64) {
+        match path {
+            [] => {}
+            [name] => self.files.push((name.clone(), length)),
+            [dir, rest @ ..] => self
+                .dirs
+                .entry(dir.clone())
+                .or_default()
+                .insert(rest, length),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.files.iter().map(|(_, len)| len).sum::<u64>()
+            + self.dirs.values().map(Dir::size).sum::<u64>()
+    }
+
+    fn render(&self, out: &mut String, depth: usize) {
+        let indent = "  ".repeat(depth);
+        for (name, dir) in &self.dirs {
+            let _ = writeln!(out, "{indent}{name}/ ({})", format_size(dir.size()));
+            dir.render(out, depth + 1);
+        }
+        for (name, len) in &self.files {
+            let _ = writeln!(out, "{indent}{name} ({})", format_size(*len));
+        }
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.2} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrentfile::magnet::parse_magnet_link;
+
+    fn summary(torrent: &[u8]) -> TorrentSummary {
+        TorrentSummary::from_torrent_file(&TorrentFile::from_bytes(torrent).unwrap())
+    }
+
+    #[test]
+    fn summarises_a_single_file_torrent() {
+        let s = summary(
+            b"d8:announce26:http://tracker.example/ann7:comment5:hello\
+              4:infod6:lengthi3000000e4:name7:iso.img12:piece lengthi1048576e\
+              6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbcccccccccccccccccccc7:privatei1eee",
+        );
+        assert_eq!(s.name.as_deref(), Some("iso.img"));
+        assert_eq!(s.piece_count, Some(3));
+        assert_eq!(s.total_size, Some(3_000_000));
+        assert!(s.private);
+        assert_eq!(s.trackers, vec![vec!["http://tracker.example/ann"]]);
+
+        let out = s.render();
+        assert!(out.contains("Pieces:       3 x 1.00 MiB\n"));
+        assert!(out.contains("Total size:   2.86 MiB (3000000 bytes)\n"));
+        assert!(out.contains("Private:      yes\n"));
+        assert!(out.contains("Comment:      hello\n"));
+        assert!(out.contains("\nTrackers:\n  Tier 0:\n    http://tracker.example/ann\n"));
+        assert!(out.contains("\nFiles:\n  iso.img (2.86 MiB)\n"));
+        assert!(out.contains(&format!("Info hash:    {}\n", s.info_hash.unwrap())));
+    }
+
+    #[test]
+    fn summarises_a_multi_file_torrent_as_a_tree() {
+        let s = summary(
+            b"d8:announce15:http://a.ex/ann13:announce-listll15:http://a.ex/ann15:http://b.ex/ann\
+              el15:udp://c.ex:6969ee4:infod5:filesld6:lengthi100e4:pathl5:a.txteed6:lengthi2048e\
+              4:pathl3:sub5:b.bineed6:lengthi1024e4:pathl3:sub5:c.bineee4:name3:dir\
+              12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list14:http://seed.exe",
+        );
+        assert_eq!(s.total_size, Some(3172));
+        assert_eq!(s.files.len(), 3);
+        assert_eq!(
+            s.trackers,
+            vec![
+                vec!["http://a.ex/ann", "http://b.ex/ann"],
+                vec!["udp://c.ex:6969"]
+            ]
+        );
+        assert_eq!(s.web_seeds, vec!["http://seed.ex"]);
+
+        let out = s.render();
+        assert!(out.contains("Private:      no\n"));
+        assert!(out.contains(
+            "\nTrackers:\n  Tier 0:\n    http://a.ex/ann\n    http://b.ex/ann\n  Tier 1:\n    udp://c.ex:6969\n"
+        ));
+        assert!(out.contains("\nWeb seeds:\n  http://seed.ex\n"));
+        assert!(out.ends_with(
+            "\nFiles:\n  sub/ (3.00 KiB)\n    b.bin (2.00 KiB)\n    c.bin (1.00 KiB)\n  a.txt (100 B)\n"
+        ));
+    }
+
+    #[test]
+    fn summarises_only_what_a_magnet_carries() {
+        let s = TorrentSummary::from_magnet(
+            &parse_magnet_link(
+                "magnet:?xt=urn:btih:796ff310776a45036ca25f26b9ffc63fbfa3ecb6&dn=film&xl=1536\
+                 &tr=http://a.ex/ann&tr=udp://b.ex:80",
+            )
+            .unwrap(),
+        );
+        assert_eq!(s.piece_count, None);
+        assert!(s.files.is_empty());
+
+        let out = s.render();
+        assert!(out.starts_with(
+            "Name:         film\nInfo hash:    796ff310776a45036ca25f26b9ffc63fbfa3ecb6\n"
+        ));
+        assert!(out.contains("Total size:   1.50 KiB (1536 bytes)\n"));
+        assert!(!out.contains("Pieces:"));
+        assert!(out.contains("Tier 0:\n    http://a.ex/ann\n  Tier 1:\n    udp://b.ex:80\n"));
+        assert!(!out.contains("Files:"));
+
+        let bare = TorrentSummary::from_magnet(
+            &parse_magnet_link("magnet:?xt=urn:btih:796ff310776a45036ca25f26b9ffc63fbfa3ecb6")
+                .unwrap(),
+        );
+        assert!(bare.render().starts_with("Name:         unknown\n"));
+    }
+
+    #[test]
+    fn formats_sizes_in_binary_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.50 KiB");
+        assert_eq!(format_size(5 << 40), "5.00 TiB");
+    }
+}
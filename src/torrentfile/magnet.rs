@@ -0,0 +1,283 @@
+use crate::torrentfile::error::TorrentError;
+use crate::torrentfile::torrent::TorrentFile;
+use data_encoding::BASE32;
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use url::Url;
+
+// sha2-256 multihash prefix: function code 0x12, digest length 0x20
+const MULTIHASH_SHA256: [u8; 2] = [0x12, 0x20];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub infohash: Option<[u8; 20]>,
+    /// BitTorrent v2 info hash from xt=urn:btmh:
+    pub infohash_v2: Option<[u8; 32]>,
+    pub trackers: Vec<String>,
+    pub display_name: Option<String>,
+    pub exact_length: Option<u64>,
+    pub web_seeds: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+    /// BEP 53 file indices to download; the others start out skipped
+    pub select_only: Vec<RangeInclusive<usize>>,
+    pub keywords: Vec<String>,
+}
+
+pub fn parse_magnet_link(link: &str) -> Result<MagnetLink, TorrentError> {
+    let url = Url::parse(link).map_err(|e| TorrentError::InvalidMagnet(e.to_string()))?;
+    if url.scheme() != "magnet" {
+        return Err(invalid("not a magnet: URI"));
+    }
+
+    let mut magnet = MagnetLink::default();
+    for (key, value) in url.query_pairs() {
+        // Some clients number repeated parameters, e.g. tr.1=...&tr.2=...
+        let key = match key.split_once('.') {
+            Some((base, n)) if n.chars().all(|c| c.is_ascii_digit()) => base,
+            _ => &key,
+        };
+        match key {
+            "xt" => parse_exact_topic(&value, &mut magnet)?,
+            "tr" if !value.is_empty() => magnet.trackers.push(value.into_owned()),
+            "dn" => magnet.display_name = Some(value.into_owned()),
+            "xl" => {
+                magnet.exact_length = Some(
+                    value
+                        .parse()
+                        .map_err(|_| invalid("xl must be a byte count"))?,
+                )
+            }
+            "ws" if !value.is_empty() => magnet.web_seeds.push(value.into_owned()),
+            // Unresolvable peers are not fatal, the link is still usable with trackers
+            "x.pe" => {
+                if let Ok(addr) = value.parse() {
+                    magnet.peers.push(addr);
+                }
+            }
+            "so" => magnet.select_only = parse_select_only(&value)?,
+            "kt" => magnet
+                .keywords
+                .extend(value.split_whitespace().map(str::to_string)),
+            _ => {}
+        }
+    }
+
+    if magnet.infohash.is_none() && magnet.infohash_v2.is_none() {
+        return Err(invalid("missing xt parameter"));
+    }
+    Ok(magnet)
+}
+
+fn parse_exact_topic(xt: &str, magnet: &mut MagnetLink) -> Result<(), TorrentError> {
+    if let Some(s) = xt.strip_prefix("urn:btih:") {
+        let info_hash_str = if s.len() == 40 {
+            hex::decode(s.to_ascii_lowercase()).map_err(|_| invalid("btih is not valid hex"))?
+        } else if s.len() == 32 {
+            BASE32
+                .decode(s.to_ascii_uppercase().as_bytes())
+                .map_err(|_| invalid("btih is not valid base32"))?
+        } else {
+            return Err(invalid("btih must be 40 hex or 32 base32 chars"));
+        };
+
+        if info_hash_str.len() != 20 {
+            return Err(invalid("btih must decode to 20 bytes"));
+        }
+
+        let mut infohash = [0u8; 20];
+        infohash.copy_from_slice(&info_hash_str);
+        magnet.infohash = Some(infohash);
+    } else if let Some(s) = xt.strip_prefix("urn:btmh:") {
+        let multihash = hex::decode(s.to_ascii_lowercase())
+            .map_err(|_| invalid("btmh must be a hex sha2-256 multihash"))?;
+        if multihash.len() != 34 || multihash[..2] != MULTIHASH_SHA256 {
+            return Err(invalid("btmh must be a hex sha2-256 multihash"));
+        }
+        let mut infohash = [0u8; 32];
+        infohash.copy_from_slice(&multihash[2..]);
+        magnet.infohash_v2 = Some(infohash);
+    } else {
+        return Err(invalid("xt must start with urn:btih or urn:btmh"));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> TorrentError {
+    TorrentError::InvalidMagnet(msg.into())
+}
+
+// "0,2,4-6" => [0..=0, 2..=2, 4..=6]
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>, TorrentError> {
+    value
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|part| {
+            let parse = |s: &str| {
+                s.trim()
+                    .parse::<usize>()
+                    .map_err(|_| TorrentError::InvalidMagnet(format!("bad so index in {part}")))
+            };
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    if start > end {
+                        return Err(TorrentError::InvalidMagnet(format!(
+                            "so range {part} is reversed"
+                        )));
+                    }
+                    Ok(start..=end)
+                }
+                None => {
+                    let idx = parse(part)?;
+                    Ok(idx..=idx)
+                }
+            }
+        })
+        .collect()
+}
+
+impl MagnetLink {
+    /// Whether file `index` is among those `so` asks for; without `so` every file is
+    pub fn selects(&self, index: usize) -> bool {
+        self.select_only.is_empty() || self.select_only.iter().any(|r| r.contains(&index))
+    }
+
+    pub fn from_torrent_file(tf: &TorrentFile) -> Self {
+        let torrent = &tf.torrent;
+        let mut trackers: Vec<String> = torrent
+            .announce_list
+            .iter()
+            .flatten()
+            .flatten()
+            .cloned()
+            .collect();
+        if !torrent.announce.is_empty() && !trackers.contains(&torrent.announce) {
+            trackers.insert(0, torrent.announce.clone());
+        }
+
+        MagnetLink {
+            infohash: Some(tf.info_hash),
+            display_name: Some(torrent.info.name.clone()),
+            exact_length: Some(torrent.info.total_length()),
+            trackers,
+            web_seeds: torrent.url_list.clone().unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut params = Vec::new();
+        if let Some(hash) = self.infohash {
+            params.push(format!("xt=urn:btih:{}", hex::encode(hash)));
+        }
+        if let Some(hash) = self.infohash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{}{}",
+                hex::encode(MULTIHASH_SHA256),
+                hex::encode(hash)
+            ));
+        }
+        if let Some(name) = &self.display_name {
+            params.push(format!("dn={}", urlencoding::encode(name)));
+        }
+        if let Some(len) = self.exact_length {
+            params.push(format!("xl={len}"));
+        }
+        for tr in &self.trackers {
+            params.push(format!("tr={}", urlencoding::encode(tr)));
+        }
+        for ws in &self.web_seeds {
+            params.push(format!("ws={}", urlencoding::encode(ws)));
+        }
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", urlencoding::encode(&peer.to_string())));
+        }
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self
+                .select_only
+                .iter()
+                .map(|r| {
+                    if r.start() == r.end() {
+                        r.start().to_string()
+                    } else {
+                        format!("{}-{}", r.start(), r.end())
+                    }
+                })
+                .collect();
+            params.push(format!("so={}", ranges.join(",")));
+        }
+        if !self.keywords.is_empty() {
+            let keywords: Vec<_> = self
+                .keywords
+                .iter()
+                .map(|k| urlencoding::encode(k).into_owned())
+                .collect();
+            params.push(format!("kt={}", keywords.join("+")));
+        }
+        write!(f, "magnet:?{}", params.join("&"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "796ff310776a45036ca25f26b9ffc63fbfa3ecb6";
+
+    #[test]
+    fn parses_every_supported_parameter() {
+        let btmh = format!("1220{}", "ab".repeat(32));
+        let link = format!(
+            "magnet:?xt=urn:btih:{HASH}&xt=urn:btmh:{btmh}&dn=Some%20Name&xl=4235567\
+             &tr.1=http%3A%2F%2Fa.example%2Fannounce&tr.2=udp%3A%2F%2Fb.example%3A80\
+             &ws.1=http%3A%2F%2Fmirror.example%2F&x.pe=127.0.0.1%3A7101&x.pe=not-a-peer\
+             &so=0,2,4-6&kt=video+preview"
+        );
+        let magnet = parse_magnet_link(&link).unwrap();
+        assert_eq!(hex::encode(magnet.infohash.unwrap()), HASH);
+        assert_eq!(magnet.infohash_v2, Some([0xab; 32]));
+        assert_eq!(magnet.display_name.as_deref(), Some("Some Name"));
+        assert_eq!(magnet.exact_length, Some(4235567));
+        assert_eq!(
+            magnet.trackers,
+            ["http://a.example/announce", "udp://b.example:80"]
+        );
+        assert_eq!(magnet.web_seeds, ["http://mirror.example/"]);
+        assert_eq!(magnet.peers, ["127.0.0.1:7101".parse().unwrap()]);
+        assert_eq!(magnet.select_only, [0..=0, 2..=2, 4..=6]);
+        assert_eq!(magnet.keywords, ["video", "preview"]);
+        let selected: Vec<usize> = (0..8).filter(|&i| magnet.selects(i)).collect();
+        assert_eq!(selected, [0, 2, 4, 5, 6]);
+        assert!(MagnetLink::default().selects(3));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let magnet = MagnetLink {
+            infohash: Some([7; 20]),
+            infohash_v2: Some([9; 32]),
+            trackers: vec!["http://a.example/announce?x=1&y=2".into()],
+            display_name: Some("a & b".into()),
+            exact_length: Some(12),
+            web_seeds: vec!["http://mirror.example/".into()],
+            peers: vec!["[::1]:6881".parse().unwrap()],
+            select_only: vec![1..=1, 3..=5],
+            keywords: vec!["video".into(), "préview".into()],
+        };
+        let reparsed = parse_magnet_link(&magnet.to_string()).unwrap();
+        assert_eq!(reparsed, magnet);
+    }
+
+    #[test]
+    fn rejects_bad_lengths_and_selections() {
+        let base = format!("magnet:?xt=urn:btih:{HASH}");
+        assert!(parse_magnet_link(&format!("{base}&xl=12kb")).is_err());
+        assert!(parse_magnet_link(&format!("{base}&so=1-x")).is_err());
+        assert!(parse_magnet_link(&format!("{base}&so=6-4")).is_err());
+        assert!(parse_magnet_link("magnet:?xt=urn:btmh:1220ab").is_err());
+        assert!(parse_magnet_link("magnet:?dn=nothing").is_err());
+    }
+}
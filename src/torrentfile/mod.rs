@@ -0,0 +1,8 @@
+//! `.torrent` files and magnet links: parsing, creating and converting.
+
+pub mod convert;
+pub mod create;
+pub mod error;
+pub mod info;
+pub mod magnet;
+pub mod torrent;
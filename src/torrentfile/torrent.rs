@@ -0,0 +1,55 @@
+use crate::bencode::decode::{decode_bencode, dict_value};
+use crate::bencode::encode::encode_bencode;
+use crate::bittorrent::Torrent;
+use crate::torrentfile::error::TorrentError;
+use sha1::{Digest, Sha1};
+use std::path::Path;
+
+pub struct TorrentFile {
+    pub torrent: Torrent,
+    pub info_hash: [u8; 20],
+}
+
+impl TorrentFile {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, TorrentError> {
+        let path = path.as_ref();
+        let content = std::fs::read(path).map_err(TorrentError::io(path))?;
+        Self::from_bytes(&content)
+    }
+
+    pub fn from_bytes(content: &[u8]) -> Result<Self, TorrentError> {
+        let torrent: Torrent = decode_bencode(content)?;
+
+        // Validate pieces length
+        if !torrent.info.pieces.len().is_multiple_of(20) {
+            return Err(TorrentError::Invalid(
+                "pieces length not divisible by 20".into(),
+            ));
+        }
+
+        // Hash the info dict as stored; re-encoding would drop keys TorrentInfo doesn't model
+        let info = dict_value(content, b"info")?
+            .ok_or_else(|| TorrentError::Invalid("missing info dictionary".into()))?;
+        let info_hash: [u8; 20] = Sha1::digest(info).into();
+
+        Ok(TorrentFile { torrent, info_hash })
+    }
+
+    pub fn from_torrent(torrent: Torrent) -> Result<Self, TorrentError> {
+        let info_bencoded = encode_bencode(&torrent.info)?;
+        let mut hasher = Sha1::new();
+        hasher.update(&info_bencoded);
+        let info_hash: [u8; 20] = hasher.finalize().into();
+
+        Ok(TorrentFile { torrent, info_hash })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TorrentError> {
+        Ok(encode_bencode(&self.torrent)?)
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), TorrentError> {
+        std::fs::write(path, self.to_bytes()?).map_err(TorrentError::io(Path::new(path)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::proxy::Proxy;
+use crate::tracker::error::TrackerError;
+use crate::tracker::{http::query_http_tracker, udp::query_udp_tracker};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// UDP trackers never answer a lost packet, so every query needs a bound
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// What one tracker said to an announce
+#[derive(Debug)]
+pub struct TrackerReply {
+    pub tracker: String,
+    pub result: Result<Vec<SocketAddrV4>, TrackerError>,
+    pub warning: Option<String>,
+}
+
+/// Ask the trackers in order, through `proxy` if there is one, and stop at the first one
+/// that returns peers. Every tracker asked gets a reply in the result, failures included.
+pub async fn announce(
+    trackers: &[String],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    left: u64,
+    user_agent: &str,
+    proxy: Option<&Proxy>,
+) -> Vec<TrackerReply> {
+    let mut replies = Vec::new();
+    for tracker in trackers {
+        let query = async {
+            if tracker.starts_with("http") {
+                query_http_tracker(
+                    tracker, info_hash, peer_id, port, 0, 0, left, user_agent, proxy,
+                )
+                .await
+                .map(|r| (r.peers, r.warning))
+            } else if tracker.starts_with("udp") {
+                query_udp_tracker(tracker, info_hash, peer_id, port, left, proxy)
+                    .await
+                    .map(|r| (r.peers, None))
+            } else {
+                Err(TrackerError::UnsupportedProtocol(tracker.clone()))
+            }
+        };
+        let (result, warning) = match timeout(TRACKER_TIMEOUT, query).await {
+            Ok(Ok((peers, warning))) => (Ok(peers), warning),
+            Ok(Err(e)) => (Err(e), None),
+            Err(_) => (Err(TrackerError::Timeout), None),
+        };
+        let found = result.as_ref().is_ok_and(|peers| !peers.is_empty());
+        replies.push(TrackerReply {
+            tracker: tracker.clone(),
+            result,
+            warning,
+        });
+        if found {
+            break;
+        }
+    }
+    replies
+}
+
+/// Every peer in `replies`, without duplicates
+pub fn reply_peers(replies: &[TrackerReply]) -> Vec<SocketAddrV4> {
+    let mut peers = Vec::new();
+    for peer in replies
+        .iter()
+        .filter_map(|r| r.result.as_ref().ok())
+        .flatten()
+    {
+        if !peers.contains(peer) {
+            peers.push(*peer);
+        }
+    }
+    peers
+}
@@ -0,0 +1,24 @@
+use crate::proxy::error::ProxyError;
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker refused the announce and said why
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("invalid tracker URL: {0}")]
+    InvalidUrl(String),
+    #[error("unsupported tracker protocol: {0}")]
+    UnsupportedProtocol(String),
+    #[error("invalid tracker response: {0}")]
+    InvalidResponse(String),
+    #[error("tracker timed out")]
+    Timeout,
+    #[error("proxy error: {0}")]
+    Proxy(#[from] ProxyError),
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
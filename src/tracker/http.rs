@@ -0,0 +1,111 @@
+use crate::bencode::decode::decode_bencode;
+use crate::proxy::Proxy;
+use crate::tracker::error::TrackerError;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackerResponse {
+    pub peers: Vec<SocketAddrV4>,
+    /// The tracker's `warning message`, if it sent one
+    #[serde(default)]
+    pub warning: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PeersField {
+    Compact(ByteBuf),
+    NonCompact(Vec<PeerDict>),
+}
+
+// Defines PeerDict for non-compact format
+#[derive(Debug, Deserialize)]
+struct PeerDict {
+    ip: String,
+    port: u16,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawTrackerResponse {
+    #[serde(rename = "failure reason", default)]
+    failure_reason: Option<String>,
+
+    #[serde(rename = "warning message", default)]
+    warning_message: Option<String>,
+    peers: PeersField,
+}
+
+/// Sent with every HTTP announce unless the session is configured otherwise
+pub const DEFAULT_USER_AGENT: &str = "RusTor/0.1";
+
+#[allow(clippy::too_many_arguments)]
+pub async fn query_http_tracker(
+    announce: &str,
+    infohash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
+    user_agent: &str,
+    proxy: Option<&Proxy>,
+) -> Result<TrackerResponse, TrackerError> {
+    let client = match proxy {
+        Some(proxy) => Client::builder().proxy(proxy.reqwest()?).build()?,
+        None => Client::new(),
+    };
+    let infohash_encoded = urlencoding::encode_binary(&infohash);
+    let peer_id_encoded = urlencoding::encode_binary(&peer_id);
+
+    let url = format!(
+        "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        announce, infohash_encoded, peer_id_encoded, port, uploaded, downloaded, left
+    );
+
+    let body = client
+        .get(&url)
+        .header("User-Agent", user_agent)
+        .send()
+        .await?
+        .bytes()
+        .await?;
+    let raw: RawTrackerResponse =
+        decode_bencode(&body).map_err(|e| TrackerError::InvalidResponse(e.to_string()))?;
+    if let Some(msg) = raw.failure_reason {
+        return Err(TrackerError::Failure(msg));
+    }
+    let peers = match raw.peers {
+        PeersField::Compact(buf) => {
+            let b = buf.as_ref();
+            if !b.len().is_multiple_of(6) {
+                return Err(TrackerError::InvalidResponse(
+                    "compact peer list length".into(),
+                ));
+            }
+            b.chunks_exact(6)
+                .map(|c| {
+                    let ip = Ipv4Addr::new(c[0], c[1], c[2], c[3]);
+                    let port = u16::from_be_bytes([c[4], c[5]]);
+                    SocketAddrV4::new(ip, port)
+                })
+                .collect()
+        }
+        PeersField::NonCompact(list) => {
+            let mut out = Vec::with_capacity(list.len());
+            for p in list {
+                let ip: Ipv4Addr =
+                    p.ip.parse()
+                        .map_err(|_| TrackerError::InvalidResponse(format!("peer IP {}", p.ip)))?;
+                out.push(SocketAddrV4::new(ip, p.port));
+            }
+            out
+        }
+    };
+    Ok(TrackerResponse {
+        peers,
+        warning: raw.warning_message,
+    })
+}
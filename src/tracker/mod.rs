@@ -0,0 +1,6 @@
+//! Announcing to HTTP and UDP trackers.
+
+pub mod announce;
+pub mod error;
+pub mod http;
+pub mod udp;
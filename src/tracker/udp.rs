@@ -1,8 +1,8 @@
 use std::net::{Ipv4Addr, SocketAddrV4};
-// use crate::Tracker::tracker::TrackerResponse;
-use anyhow::{Result, anyhow};
+// use crate::tracker::http::TrackerResponse;
+use crate::proxy::{DatagramSocket, Proxy, Target};
+use crate::tracker::error::TrackerError;
 use rand;
-use tokio::net::UdpSocket;
 
 #[derive(Debug)]
 pub struct TrackerResponse {
@@ -15,14 +15,14 @@ pub async fn query_udp_tracker(
     peer_id: [u8; 20],
     port: u16,
     left: u64,
-) -> Result<TrackerResponse> {
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    let url = url::Url::parse(announce)?;
-    let addr = format!(
-        "{}:{}",
-        url.host_str().ok_or_else(|| anyhow!("Invalid host"))?,
-        url.port().ok_or_else(|| anyhow!("Missing port"))?
-    );
+    proxy: Option<&Proxy>,
+) -> Result<TrackerResponse, TrackerError> {
+    let url = url::Url::parse(announce).map_err(|_| TrackerError::InvalidUrl(announce.into()))?;
+    let addr = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => Target::Host(host.trim_matches(['[', ']']), port),
+        _ => return Err(TrackerError::InvalidUrl(announce.into())),
+    };
+    let socket = DatagramSocket::bind_via("0.0.0.0:0", proxy).await?;
 
     // Connection request
     let mut connect_request = Vec::with_capacity(16);
@@ -30,12 +30,12 @@ pub async fn query_udp_tracker(
     connect_request.extend_from_slice(&0u32.to_be_bytes());
     let txn_id: u32 = rand::random();
     connect_request.extend_from_slice(&txn_id.to_be_bytes());
-    socket.send_to(&connect_request, &addr).await?;
+    socket.send_to(&connect_request, addr).await?;
 
     let mut buf = [0u8; 2048];
     let (len, _) = socket.recv_from(&mut buf).await?;
-    if len < 16 || &buf[0..4] != &0u32.to_be_bytes() || &buf[4..8] != &txn_id.to_be_bytes() {
-        return Err(anyhow!("Invalid connection response!..."));
+    if len < 16 || buf[0..4] != 0u32.to_be_bytes() || buf[4..8] != txn_id.to_be_bytes() {
+        return Err(TrackerError::InvalidResponse("connect".into()));
     }
     let connection_id = &buf[8..16];
 
@@ -56,11 +56,11 @@ pub async fn query_udp_tracker(
     announce_request.extend_from_slice(&(!0u32).to_be_bytes()); // num want = -1
     announce_request.extend_from_slice(&port.to_be_bytes());
 
-    socket.send_to(&announce_request, &addr).await?;
+    socket.send_to(&announce_request, addr).await?;
 
     let (alen, _) = socket.recv_from(&mut buf).await?;
-    if alen < 20 || &buf[0..4] != &1u32.to_be_bytes() || &buf[4..8] != &txn_id2.to_be_bytes() {
-        return Err(anyhow!("Invalid announce response"));
+    if alen < 20 || buf[0..4] != 1u32.to_be_bytes() || buf[4..8] != txn_id2.to_be_bytes() {
+        return Err(TrackerError::InvalidResponse("announce".into()));
     }
 
     // Parse peers from response
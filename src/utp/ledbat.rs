@@ -0,0 +1,133 @@
+//! LEDBAT congestion control (RFC 6817) as used by uTP. The window grows while the one-way
+//! queuing delay stays under the target and shrinks as it rises, so uTP traffic gets out of
+//! the way of anything else sharing the link.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How much queuing delay we're willing to add to the link
+pub const TARGET_DELAY_MICROS: i64 = 100_000;
+const GAIN: f64 = 1.0;
+/// The base delay is the lowest delay seen over this many minutes
+const BASE_HISTORY: usize = 10;
+const MAX_WINDOW: usize = 1 << 20;
+
+#[derive(Debug)]
+pub struct Ledbat {
+    mss: usize,
+    window: usize,
+    slow_start: bool,
+    /// The minimum delay seen in each recent minute, newest last
+    base_delays: VecDeque<(Instant, u32)>,
+    last_decrease: Option<Instant>,
+}
+
+impl Ledbat {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            window: 2 * mss,
+            slow_start: true,
+            base_delays: VecDeque::new(),
+            last_decrease: None,
+        }
+    }
+
+    /// How many bytes may be in flight
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Record that `bytes` were newly acknowledged in a packet where the peer measured our
+    /// one-way delay as `delay` microseconds (on unsynchronised clocks, so only changes in it
+    /// mean anything).
+    pub fn on_ack(&mut self, bytes: usize, delay: u32, now: Instant) {
+        if delay == 0 {
+            return;
+        }
+        let base = self.update_base_delay(delay, now);
+        let queuing = delay.wrapping_sub(base) as i32 as i64;
+        if self.slow_start && queuing < TARGET_DELAY_MICROS / 2 {
+            self.window += bytes;
+        } else {
+            self.slow_start = false;
+            let off_target = (TARGET_DELAY_MICROS - queuing) as f64 / TARGET_DELAY_MICROS as f64;
+            let change = GAIN * off_target.clamp(-1.0, 1.0) * bytes as f64 * self.mss as f64
+                / self.window as f64;
+            self.window = (self.window as f64 + change) as usize;
+        }
+        self.window = self.window.clamp(self.min_window(), MAX_WINDOW);
+    }
+
+    /// Halve the window after a loss, at most once per round trip
+    pub fn on_loss(&mut self, rtt: Duration, now: Instant) {
+        if self.last_decrease.is_some_and(|at| now - at < rtt) {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.slow_start = false;
+        self.window = (self.window / 2).max(self.min_window());
+    }
+
+    /// Nothing was acknowledged before the retransmission timer ran out
+    pub fn on_timeout(&mut self) {
+        self.slow_start = false;
+        self.window = self.mss;
+    }
+
+    fn min_window(&self) -> usize {
+        2 * self.mss
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) -> u32 {
+        let lower = |a: u32, b: u32| (a.wrapping_sub(b) as i32) < 0;
+        match self.base_delays.back_mut() {
+            Some((minute, min)) if now - *minute < Duration::from_secs(60) => {
+                if lower(delay, *min) {
+                    *min = delay;
+                }
+            }
+            _ => {
+                self.base_delays.push_back((now, delay));
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+        self.base_delays
+            .iter()
+            .map(|&(_, d)| d)
+            .reduce(|a, b| if lower(b, a) { b } else { a })
+            .unwrap_or(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_when_delay_rises_above_target() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(1000);
+        // An idle link: the window keeps growing
+        for _ in 0..50 {
+            ledbat.on_ack(1000, 20_000, now);
+        }
+        let grown = ledbat.window();
+        assert!(grown > 20_000);
+
+        // A queue builds up 200 ms beyond the base delay
+        for _ in 0..50 {
+            ledbat.on_ack(1000, 220_000, now);
+        }
+        assert!(ledbat.window() < grown);
+
+        let before = ledbat.window();
+        ledbat.on_loss(Duration::from_millis(50), now);
+        ledbat.on_loss(Duration::from_millis(50), now);
+        assert_eq!(ledbat.window(), (before / 2).max(2000));
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), 1000);
+    }
+}
@@ -0,0 +1,8 @@
+//! The Micro Transport Protocol (BEP 29): reliable, ordered streams over UDP whose LEDBAT
+//! congestion control backs off as soon as it sees queues building, leaving the link to
+//! other traffic. Peers reached this way speak the same wire protocol as over TCP; see
+//! [`crate::peers::transport`].
+
+pub mod ledbat;
+pub mod packet;
+pub mod socket;
@@ -0,0 +1,133 @@
+//! The uTP packet header (BEP 29). Every packet starts with the same 20 bytes, optionally
+//! followed by a chain of extensions and then the payload.
+
+use std::io;
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_NONE: u8 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    /// A bare acknowledgement; the only type that doesn't use up a sequence number
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(n: u8) -> Option<Self> {
+        Some(match n {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    /// When the packet was sent, in microseconds on the sender's clock
+    pub timestamp: u32,
+    /// The sender's last measurement of our one-way delay, for LEDBAT
+    pub timestamp_difference: u32,
+    /// Bytes the sender can still take in
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+}
+
+impl Header {
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        packet.push((self.kind as u8) << 4 | VERSION);
+        packet.push(EXTENSION_NONE);
+        packet.extend_from_slice(&self.connection_id.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        packet.extend_from_slice(&self.wnd_size.to_be_bytes());
+        packet.extend_from_slice(&self.seq_nr.to_be_bytes());
+        packet.extend_from_slice(&self.ack_nr.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Split a datagram into its header and payload. Extensions such as selective acks are
+    /// skipped.
+    pub fn decode(packet: &[u8]) -> io::Result<(Self, &[u8])> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if packet.len() < HEADER_LEN || packet[0] & 0x0f != VERSION {
+            return Err(invalid("not a uTP packet"));
+        }
+        let kind =
+            PacketType::from_u8(packet[0] >> 4).ok_or_else(|| invalid("unknown packet type"))?;
+        let u16_at = |i: usize| u16::from_be_bytes([packet[i], packet[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(packet[i..i + 4].try_into().unwrap());
+        let header = Header {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+        let mut extension = packet[1];
+        let mut rest = &packet[HEADER_LEN..];
+        while extension != EXTENSION_NONE {
+            let [next, len, ..] = *rest else {
+                return Err(invalid("truncated extension"));
+            };
+            let len = len as usize;
+            if rest.len() < 2 + len {
+                return Err(invalid("truncated extension"));
+            }
+            extension = next;
+            rest = &rest[2 + len..];
+        }
+        Ok((header, rest))
+    }
+}
+
+/// Whether sequence number `a` comes before `b`, allowing for wraparound
+pub fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_skips_extensions() {
+        let header = Header {
+            kind: PacketType::Data,
+            connection_id: 4242,
+            timestamp: 123_456,
+            timestamp_difference: 789,
+            wnd_size: 1 << 20,
+            seq_nr: 65535,
+            ack_nr: 7,
+        };
+        let packet = header.encode(b"payload");
+        assert_eq!(Header::decode(&packet).unwrap(), (header, &b"payload"[..]));
+
+        // A selective ack extension between the header and the payload
+        let mut with_sack = packet[..HEADER_LEN].to_vec();
+        with_sack[1] = 1;
+        with_sack.extend_from_slice(&[0, 4, 0xff, 0, 0, 0]);
+        with_sack.extend_from_slice(b"payload");
+        assert_eq!(Header::decode(&with_sack).unwrap().1, b"payload");
+
+        assert!(Header::decode(b"d1:ad2:id20:").is_err());
+        assert!(seq_before(65535, 2) && !seq_before(2, 65535));
+    }
+}
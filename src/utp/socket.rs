@@ -0,0 +1,865 @@
+//! uTP connections multiplexed over one UDP socket: connection setup, acknowledgement and
+//! retransmission, with LEDBAT deciding how much may be in flight
+
+use crate::proxy::DatagramSocket;
+use crate::utp::ledbat::Ledbat;
+use crate::utp::packet::{HEADER_LEN, Header, PacketType, seq_before};
+use std::collections::{HashMap, VecDeque};
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+/// Datagrams and who sent them, for a socket whose UDP port is read by someone else
+pub type Datagrams = mpsc::Receiver<(Vec<u8>, SocketAddr)>;
+
+// Fits an Ethernet MTU along with the IP and UDP headers
+const MAX_PACKET: usize = 1400;
+const MAX_PAYLOAD: usize = MAX_PACKET - HEADER_LEN;
+const SEND_BUFFER: usize = 256 * 1024;
+const RECV_BUFFER: usize = 1 << 20;
+// How far past the next expected packet out-of-order data is kept
+const REORDER_WINDOW: u16 = 1024;
+const TICK: Duration = Duration::from_millis(50);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+const SYN_RTO: Duration = Duration::from_secs(1);
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+const MAX_TRANSMISSIONS: u32 = 6;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+// Dropped streams get this long to deliver what was written to them
+const LINGER: Duration = Duration::from_secs(30);
+const ACCEPT_BACKLOG: usize = 32;
+
+/// A UDP socket carrying any number of uTP connections. Clones share the socket.
+#[derive(Clone)]
+pub struct UtpSocket {
+    shared: Arc<Shared>,
+}
+
+type ConnRef = Arc<Mutex<Conn>>;
+
+struct Shared {
+    wire: Arc<Wire>,
+    // Keyed by the peer's address and the connection id it sends to us with
+    conns: Mutex<HashMap<(SocketAddr, u16), ConnRef>>,
+    backlog: mpsc::Sender<(ConnRef, SocketAddr)>,
+    accepted: tokio::sync::Mutex<mpsc::Receiver<(ConnRef, SocketAddr)>>,
+}
+
+impl UtpSocket {
+    /// Bind a UDP socket of our own
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self::from_socket(DatagramSocket::bind(addr).await?))
+    }
+
+    /// Run over a socket bound elsewhere, such as one relaying through a SOCKS5 proxy
+    pub fn from_socket(udp: DatagramSocket) -> Self {
+        let udp = Arc::new(udp);
+        let socket = Self::new(udp.clone());
+        // Like the timer, the receive loop only holds a weak reference to the socket
+        let weak = Arc::downgrade(&socket.shared);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            loop {
+                let Some(shared) = weak.upgrade() else { break };
+                if let Ok(Ok((len, from))) =
+                    timeout(Duration::from_secs(1), udp.recv_from(&mut buf)).await
+                {
+                    shared.handle_packet(&buf[..len], from);
+                }
+            }
+        });
+        socket
+    }
+
+    /// Run over a UDP socket that something else reads, such as the DHT node sharing our
+    /// listen port, which hands over the datagrams meant for uTP
+    pub fn with_socket(udp: Arc<DatagramSocket>, mut datagrams: Datagrams) -> Self {
+        let socket = Self::new(udp);
+        let weak = Arc::downgrade(&socket.shared);
+        tokio::spawn(async move {
+            loop {
+                let received = timeout(Duration::from_secs(1), datagrams.recv()).await;
+                let Some(shared) = weak.upgrade() else { break };
+                match received {
+                    Ok(Some((packet, from))) => shared.handle_packet(&packet, from),
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+            }
+        });
+        socket
+    }
+
+    fn new(udp: Arc<DatagramSocket>) -> Self {
+        let (backlog, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        let shared = Arc::new(Shared {
+            wire: Arc::new(Wire {
+                udp,
+                epoch: Instant::now(),
+                #[cfg(test)]
+                loss: Mutex::new(0.0),
+            }),
+            conns: Mutex::new(HashMap::new()),
+            backlog,
+            accepted: tokio::sync::Mutex::new(accepted),
+        });
+        let weak = Arc::downgrade(&shared);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK);
+            loop {
+                interval.tick().await;
+                let Some(shared) = weak.upgrade() else { break };
+                shared.tick();
+            }
+        });
+        Self { shared }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.wire.udp.local_addr()
+    }
+
+    /// Open a connection, retrying the SYN a few times before giving up
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let conn = {
+            let mut conns = self.shared.conns.lock().unwrap();
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let conn = Conn::connect(self.shared.wire.clone(), addr, recv_id);
+            let conn = Arc::new(Mutex::new(conn));
+            conns.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        let stream = UtpStream {
+            conn,
+            _shared: self.shared.clone(),
+            peer: addr,
+        };
+        poll_fn(|cx| stream.conn.lock().unwrap().poll_connected(cx)).await?;
+        Ok(stream)
+    }
+
+    /// Wait for a peer to connect
+    pub async fn accept(&self) -> io::Result<(UtpStream, SocketAddr)> {
+        let (conn, peer) = self
+            .shared
+            .accepted
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "uTP socket closed"))?;
+        let stream = UtpStream {
+            conn,
+            _shared: self.shared.clone(),
+            peer,
+        };
+        Ok((stream, peer))
+    }
+
+    /// Drop this fraction of outgoing packets
+    #[cfg(test)]
+    fn set_loss(&self, loss: f64) {
+        *self.shared.wire.loss.lock().unwrap() = loss;
+    }
+}
+
+impl Shared {
+    fn handle_packet(&self, packet: &[u8], from: SocketAddr) {
+        let Ok((header, payload)) = Header::decode(packet) else {
+            return;
+        };
+        let conn = {
+            let conns = self.conns.lock().unwrap();
+            let mut found = conns.get(&(from, header.connection_id));
+            if header.kind == PacketType::Reset {
+                // Resets may carry either of the connection's ids
+                found = found.or_else(|| conns.get(&(from, header.connection_id.wrapping_sub(1))));
+            }
+            found.cloned()
+        };
+        match (conn, header.kind) {
+            (Some(conn), _) => conn.lock().unwrap().on_packet(&header, payload),
+            (None, PacketType::Syn) => self.on_syn(&header, from),
+            (None, PacketType::Reset) => {}
+            (None, _) => self.wire.reset(&header, from),
+        }
+    }
+
+    fn on_syn(&self, syn: &Header, from: SocketAddr) {
+        let mut conns = self.conns.lock().unwrap();
+        let key = (from, syn.connection_id.wrapping_add(1));
+        if let Some(conn) = conns.get(&key) {
+            // Our answer was lost and the peer is trying again
+            conn.lock().unwrap().on_packet(syn, &[]);
+            return;
+        }
+        let conn = Arc::new(Mutex::new(Conn::accept(self.wire.clone(), from, syn)));
+        match self.backlog.try_send((conn.clone(), from)) {
+            Ok(()) => {
+                conns.insert(key, conn);
+            }
+            Err(_) => self.wire.reset(syn, from),
+        }
+    }
+
+    fn tick(&self) {
+        let now = Instant::now();
+        let conns: Vec<_> = self
+            .conns
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, conn)| (*key, conn.clone()))
+            .collect();
+        let finished: Vec<_> = conns
+            .into_iter()
+            .filter(|(_, conn)| conn.lock().unwrap().tick(now))
+            .map(|(key, _)| key)
+            .collect();
+        if !finished.is_empty() {
+            let mut conns = self.conns.lock().unwrap();
+            for key in finished {
+                conns.remove(&key);
+            }
+        }
+    }
+}
+
+struct Wire {
+    udp: Arc<DatagramSocket>,
+    epoch: Instant,
+    #[cfg(test)]
+    loss: Mutex<f64>,
+}
+
+impl Wire {
+    fn now_micros(&self) -> u32 {
+        self.epoch.elapsed().as_micros() as u32
+    }
+
+    // A full socket buffer loses the packet, which retransmission takes care of
+    fn send(&self, packet: &[u8], to: SocketAddr) {
+        #[cfg(test)]
+        if rand::random::<f64>() < *self.loss.lock().unwrap() {
+            return;
+        }
+        let _ = self.udp.try_send_to(packet, to);
+    }
+
+    fn reset(&self, to_packet: &Header, to: SocketAddr) {
+        let header = Header {
+            kind: PacketType::Reset,
+            connection_id: to_packet.connection_id,
+            timestamp: self.now_micros(),
+            timestamp_difference: 0,
+            wnd_size: 0,
+            seq_nr: rand::random(),
+            ack_nr: to_packet.seq_nr,
+        };
+        self.send(&header.encode(&[]), to);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Failed(io::ErrorKind),
+}
+
+// A packet waiting to be acknowledged
+struct Sent {
+    seq: u16,
+    kind: PacketType,
+    payload: Vec<u8>,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+struct Conn {
+    wire: Arc<Wire>,
+    peer: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    state: State,
+    // Next sequence number to send
+    seq_nr: u16,
+    // Last sequence number received in order
+    ack_nr: u16,
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    bytes_in_flight: usize,
+    fin_queued: bool,
+    fin_sent: bool,
+    peer_window: usize,
+    ledbat: Ledbat,
+    srtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    rto_deadline: Option<Instant>,
+    dup_acks: u32,
+    // Last packet in flight when a loss was detected; until it's acked, every partial ack
+    // retransmits the next hole
+    recovery: Option<u16>,
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    // Payload bytes held in `out_of_order`
+    out_of_order_len: usize,
+    // Sequence number of the peer's FIN
+    eof: Option<u16>,
+    // Echoed back so the peer can measure its one-way delay to us
+    reply_micros: u32,
+    last_sent: Instant,
+    dropped_at: Option<Instant>,
+    read_waker: Option<Waker>,
+    // Also woken once the connection is established
+    write_waker: Option<Waker>,
+}
+
+impl Conn {
+    fn new(wire: Arc<Wire>, peer: SocketAddr, recv_id: u16, send_id: u16, state: State) -> Self {
+        Self {
+            wire,
+            peer,
+            recv_id,
+            send_id,
+            state,
+            seq_nr: 1,
+            ack_nr: 0,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            fin_queued: false,
+            fin_sent: false,
+            peer_window: MAX_PACKET,
+            ledbat: Ledbat::new(MAX_PAYLOAD),
+            srtt: None,
+            rtt_var: Duration::ZERO,
+            rto: Duration::from_secs(1),
+            rto_deadline: None,
+            dup_acks: 0,
+            recovery: None,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_len: 0,
+            eof: None,
+            reply_micros: 0,
+            last_sent: Instant::now(),
+            dropped_at: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn connect(wire: Arc<Wire>, peer: SocketAddr, recv_id: u16) -> Self {
+        let mut conn = Self::new(wire, peer, recv_id, recv_id.wrapping_add(1), State::SynSent);
+        let now = Instant::now();
+        conn.rto = SYN_RTO;
+        conn.send(PacketType::Syn, conn.seq_nr, &[]);
+        conn.in_flight.push_back(Sent {
+            seq: conn.seq_nr,
+            kind: PacketType::Syn,
+            payload: Vec::new(),
+            sent_at: now,
+            transmissions: 1,
+        });
+        conn.seq_nr = conn.seq_nr.wrapping_add(1);
+        conn.rto_deadline = Some(now + conn.rto);
+        conn
+    }
+
+    fn accept(wire: Arc<Wire>, peer: SocketAddr, syn: &Header) -> Self {
+        let recv_id = syn.connection_id.wrapping_add(1);
+        let mut conn = Self::new(wire, peer, recv_id, syn.connection_id, State::Connected);
+        conn.seq_nr = rand::random();
+        conn.ack_nr = syn.seq_nr;
+        conn.peer_window = syn.wnd_size as usize;
+        conn.reply_micros = conn.wire.now_micros().wrapping_sub(syn.timestamp);
+        conn.ack();
+        conn
+    }
+
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.state {
+            State::Connected => Poll::Ready(Ok(())),
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            State::SynSent => {
+                self.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn send(&mut self, kind: PacketType, seq_nr: u16, payload: &[u8]) {
+        let header = Header {
+            kind,
+            // The SYN names the id we want to be sent on; everything else uses the peer's
+            connection_id: if kind == PacketType::Syn {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: self.wire.now_micros(),
+            timestamp_difference: self.reply_micros,
+            wnd_size: self.recv_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        };
+        self.wire.send(&header.encode(payload), self.peer);
+        self.last_sent = Instant::now();
+    }
+
+    // State packets don't take a sequence number, they carry the next one we'll use
+    fn ack(&mut self) {
+        self.send(PacketType::State, self.seq_nr, &[]);
+    }
+
+    fn recv_window(&self) -> usize {
+        RECV_BUFFER.saturating_sub(self.recv_buf.len() + self.out_of_order_len)
+    }
+
+    fn at_eof(&self) -> bool {
+        self.eof.is_some_and(|fin| !seq_before(self.ack_nr, fin))
+    }
+
+    fn on_packet(&mut self, header: &Header, payload: &[u8]) {
+        if matches!(self.state, State::Failed(_)) {
+            return;
+        }
+        let now = Instant::now();
+        self.reply_micros = self.wire.now_micros().wrapping_sub(header.timestamp);
+        self.peer_window = header.wnd_size as usize;
+        match header.kind {
+            PacketType::Reset => return self.fail(io::ErrorKind::ConnectionReset),
+            PacketType::Syn => return self.ack(),
+            PacketType::State if self.state == State::SynSent => {
+                self.state = State::Connected;
+                self.ack_nr = header.seq_nr.wrapping_sub(1);
+                wake(&mut self.write_waker);
+            }
+            _ if self.state == State::SynSent => return,
+            _ => {}
+        }
+        self.on_ack(header, now);
+        if matches!(header.kind, PacketType::Data | PacketType::Fin) {
+            self.receive(header.kind, header.seq_nr, payload);
+            self.ack();
+        }
+        self.flush(now);
+    }
+
+    fn on_ack(&mut self, header: &Header, now: Instant) {
+        let mut acked = 0;
+        let mut any = false;
+        let mut sample = None;
+        while let Some(front) = self.in_flight.front()
+            && !seq_before(header.ack_nr, front.seq)
+        {
+            let sent = self.in_flight.pop_front().unwrap();
+            any = true;
+            acked += sent.payload.len();
+            // Retransmitted packets don't say which copy was acked
+            if sent.transmissions == 1 {
+                sample = Some(now - sent.sent_at);
+            }
+        }
+        self.bytes_in_flight -= acked;
+        if any {
+            if let Some(sample) = sample {
+                self.update_rtt(sample);
+            }
+            self.ledbat.on_ack(acked, header.timestamp_difference, now);
+            self.dup_acks = 0;
+            self.rto_deadline = (!self.in_flight.is_empty()).then(|| now + self.rto);
+            if let Some(last) = self.recovery {
+                if seq_before(header.ack_nr, last) {
+                    self.retransmit_front(now);
+                } else {
+                    self.recovery = None;
+                }
+            }
+        } else if header.kind == PacketType::State
+            && self
+                .in_flight
+                .front()
+                .is_some_and(|sent| sent.seq == header.ack_nr.wrapping_add(1))
+        {
+            self.dup_acks += 1;
+            if self.dup_acks == 3 && self.recovery.is_none() {
+                let rtt = self.srtt.unwrap_or(self.rto);
+                self.ledbat.on_loss(rtt, now);
+                self.recovery = self.in_flight.back().map(|sent| sent.seq);
+                self.retransmit_front(now);
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rtt_var = sample / 2;
+                sample
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                (srtt * 7 + sample) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn receive(&mut self, kind: PacketType, seq: u16, payload: &[u8]) {
+        let next = self.ack_nr.wrapping_add(1);
+        // Duplicates, and packets too far ahead to buffer
+        if seq_before(seq, next) || seq.wrapping_sub(next) >= REORDER_WINDOW {
+            return;
+        }
+        // Data past the window we advertised goes unacked, so a peer ignoring the window can't
+        // make us buffer without end or read past the rate limiter. The next packet only has
+        // to fit beside what the reader hasn't taken, or a full reorder buffer would stall.
+        let buffered = if seq == next {
+            self.recv_buf.len()
+        } else {
+            self.recv_buf.len() + self.out_of_order_len
+        };
+        if buffered + payload.len() > RECV_BUFFER {
+            return;
+        }
+        if kind == PacketType::Fin {
+            self.eof = Some(seq);
+        }
+        self.out_of_order_len += payload.len();
+        if let Some(old) = self.out_of_order.insert(seq, payload.to_vec()) {
+            self.out_of_order_len -= old.len();
+        }
+        while let Some(data) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.out_of_order_len -= data.len();
+            self.recv_buf.extend(data);
+        }
+        if !self.recv_buf.is_empty() || self.at_eof() {
+            wake(&mut self.read_waker);
+        }
+    }
+
+    // Send as much of the buffered data as the window allows, then the FIN once it's all out
+    fn flush(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        let window = self.ledbat.window().min(self.peer_window);
+        loop {
+            let len = self.send_buf.len().min(MAX_PAYLOAD);
+            let fin = len == 0 && self.fin_queued && !self.fin_sent;
+            if len == 0 && !fin {
+                break;
+            }
+            // One packet may always be in flight, which probes a window the peer closed
+            if !self.in_flight.is_empty() && self.bytes_in_flight + len > window {
+                break;
+            }
+            let payload: Vec<u8> = self.send_buf.drain(..len).collect();
+            let kind = if fin {
+                self.fin_sent = true;
+                PacketType::Fin
+            } else {
+                PacketType::Data
+            };
+            let seq = self.seq_nr;
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.send(kind, seq, &payload);
+            self.bytes_in_flight += payload.len();
+            self.in_flight.push_back(Sent {
+                seq,
+                kind,
+                payload,
+                sent_at: now,
+                transmissions: 1,
+            });
+            self.rto_deadline.get_or_insert(now + self.rto);
+        }
+        if self.send_buf.len() < SEND_BUFFER {
+            wake(&mut self.write_waker);
+        }
+    }
+
+    fn retransmit_front(&mut self, now: Instant) {
+        let Some(sent) = self.in_flight.front_mut() else {
+            return;
+        };
+        sent.sent_at = now;
+        sent.transmissions += 1;
+        let (kind, seq, payload) = (sent.kind, sent.seq, std::mem::take(&mut sent.payload));
+        self.send(kind, seq, &payload);
+        self.in_flight[0].payload = payload;
+    }
+
+    fn on_timeout(&mut self, now: Instant) {
+        let Some(front) = self.in_flight.front() else {
+            self.rto_deadline = None;
+            return;
+        };
+        let limit = match self.state {
+            State::SynSent => MAX_SYN_TRANSMISSIONS,
+            _ => MAX_TRANSMISSIONS,
+        };
+        if front.transmissions >= limit {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        self.ledbat.on_timeout();
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.recovery = self.in_flight.back().map(|sent| sent.seq);
+        self.retransmit_front(now);
+        self.rto_deadline = Some(now + self.rto);
+    }
+
+    // Retransmit and keep the connection alive. True once a dropped stream can be forgotten.
+    fn tick(&mut self, now: Instant) -> bool {
+        if !matches!(self.state, State::Failed(_)) {
+            if self.rto_deadline.is_some_and(|deadline| now >= deadline) {
+                self.on_timeout(now);
+            } else if self.state == State::Connected && now - self.last_sent >= KEEP_ALIVE {
+                self.ack();
+            }
+        }
+        let Some(dropped_at) = self.dropped_at else {
+            return false;
+        };
+        matches!(self.state, State::Failed(_))
+            || (self.fin_sent && self.in_flight.is_empty())
+            || now - dropped_at > LINGER
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Failed(kind);
+        self.in_flight.clear();
+        self.bytes_in_flight = 0;
+        self.send_buf.clear();
+        self.rto_deadline = None;
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+
+    // The stream was dropped: finish sending what it wrote, then say goodbye
+    fn close(&mut self, now: Instant) {
+        self.dropped_at = Some(now);
+        match self.state {
+            State::SynSent => self.state = State::Failed(io::ErrorKind::ConnectionAborted),
+            State::Connected if !self.fin_queued => {
+                self.fin_queued = true;
+                self.flush(now);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// One uTP connection, used like a TCP stream
+pub struct UtpStream {
+    conn: ConnRef,
+    // Keeps the socket's receive loop and timer running
+    _shared: Arc<Shared>,
+    peer: SocketAddr,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        if !conn.recv_buf.is_empty() {
+            let was_full = conn.recv_window() < MAX_PACKET;
+            let (front, _) = conn.recv_buf.as_slices();
+            let n = front.len().min(buf.remaining());
+            buf.put_slice(&front[..n]);
+            conn.recv_buf.drain(..n);
+            // Tell a peer we had stopped that there is room again
+            if was_full && conn.recv_window() >= MAX_PACKET {
+                conn.ack();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if conn.at_eof() {
+            return Poll::Ready(Ok(()));
+        }
+        if let State::Failed(kind) = conn.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        if let State::Failed(kind) = conn.state {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if conn.fin_queued {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let space = SEND_BUFFER.saturating_sub(conn.send_buf.len());
+        if space == 0 {
+            conn.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(space);
+        conn.send_buf.extend(&buf[..n]);
+        conn.flush(Instant::now());
+        Poll::Ready(Ok(n))
+    }
+
+    // Written data is already on its way; there's nothing to push
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.conn.lock().unwrap().state {
+            State::Failed(kind) => Poll::Ready(Err(kind.into())),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        if !conn.fin_queued {
+            conn.fin_queued = true;
+            conn.flush(Instant::now());
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.conn.lock().unwrap().close(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn transfers_both_ways_over_a_lossy_link() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        server.set_loss(0.05);
+        client.set_loss(0.05);
+        let addr = server.local_addr().unwrap();
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+
+        let sent = data.clone();
+        let uploader = tokio::spawn(async move {
+            let mut stream = client.connect(addr).await.unwrap();
+            stream.write_all(&sent).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+        let transfer = async {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(b"thanks").await.unwrap();
+            stream.shutdown().await.unwrap();
+            (received, uploader.await.unwrap())
+        };
+        let (received, reply) = timeout(Duration::from_secs(60), transfer).await.unwrap();
+        assert!(received == data);
+        assert_eq!(reply, b"thanks");
+    }
+
+    #[tokio::test]
+    async fn drops_data_past_the_advertised_window() {
+        let socket = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let syn = Header {
+            kind: PacketType::Syn,
+            connection_id: 7,
+            timestamp: 0,
+            timestamp_difference: 0,
+            wnd_size: MAX_PACKET as u32,
+            seq_nr: 0,
+            ack_nr: 0,
+        };
+        let peer = "127.0.0.1:9".parse().unwrap();
+        let mut conn = Conn::accept(socket.shared.wire.clone(), peer, &syn);
+        let payload = [1u8; 1000];
+
+        // Out of order data counts against the window too
+        conn.receive(PacketType::Data, 3, &payload);
+        assert_eq!(conn.recv_window(), RECV_BUFFER - 1000);
+        // The peer keeps sending though nobody reads
+        for seq in 1..2000u16 {
+            conn.receive(PacketType::Data, seq, &payload);
+        }
+        assert_eq!(conn.recv_buf.len(), RECV_BUFFER / 1000 * 1000);
+        assert_eq!(conn.ack_nr as usize, RECV_BUFFER / 1000);
+        assert_eq!(conn.recv_window(), RECV_BUFFER % 1000);
+
+        // Once read, the next packet is taken again
+        conn.recv_buf.drain(..5000);
+        conn.receive(PacketType::Data, conn.ack_nr.wrapping_add(1), &payload);
+        assert_eq!(conn.ack_nr as usize, RECV_BUFFER / 1000 + 1);
+    }
+
+    #[tokio::test]
+    async fn resets_connections_nobody_knows() {
+        let server = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UtpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let accept = tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            // Forget the connection without a goodbye
+            let conn = stream.conn.clone();
+            conn.lock().unwrap().fail(io::ErrorKind::ConnectionAborted);
+            server.shared.tick();
+            drop(stream);
+            server.shared.tick();
+            server
+        });
+        let mut stream = client.connect(addr).await.unwrap();
+        let _server = accept.await.unwrap();
+        stream.write_all(b"hello?").await.unwrap();
+        let mut buf = [0u8; 8];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buf)).await;
+        assert_eq!(
+            read.unwrap().unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}